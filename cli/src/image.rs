@@ -0,0 +1,107 @@
+//! Image files.
+//!
+//! This implements `Disk` for a regular file holding a TFS image, such that images can be created
+//! and inspected without mounting anything.
+
+use futures::future;
+use slog;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tfs::{Disk, Error, Sector, SectorBuf, SECTOR_SIZE};
+
+/// An image file.
+pub struct Image {
+    /// The file.
+    file: Mutex<fs::File>,
+    /// The number of sectors of the image.
+    sectors: Sector,
+    /// Log every message, and not just warnings and errors.
+    verbose: bool,
+}
+
+impl Image {
+    /// Open an existing image.
+    ///
+    /// If `writable` is not set, the file is opened read-only, so the filesystem in it must be
+    /// opened read-only as well.
+    pub fn open<P: AsRef<Path>>(path: P, writable: bool, verbose: bool) -> io::Result<Image> {
+        let file = fs::OpenOptions::new().read(true).write(writable).open(path)?;
+        let len = file.metadata()?.len();
+
+        Ok(Image {
+            file: Mutex::new(file),
+            sectors: (len / SECTOR_SIZE as u64) as Sector,
+            verbose: verbose,
+        })
+    }
+
+    /// Create an image of some size.
+    ///
+    /// The size is rounded down to whole sectors. If the file exists, it is overwritten.
+    pub fn create<P: AsRef<Path>>(path: P, size: u64, verbose: bool) -> io::Result<Image> {
+        let sectors = size / SECTOR_SIZE as u64;
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(path)?;
+        // The file is sparse, so it doesn't take up space until written.
+        file.set_len(sectors * SECTOR_SIZE as u64)?;
+
+        Ok(Image {
+            file: Mutex::new(file),
+            sectors: sectors as Sector,
+            verbose: verbose,
+        })
+    }
+
+    /// Seek to the start of a sector.
+    fn seek(file: &mut fs::File, sector: Sector) -> io::Result<()> {
+        file.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64)).map(|_| ())
+    }
+}
+
+impl Disk for Image {
+    type ReadFuture = future::FutureResult<Box<SectorBuf>, Error>;
+    type WriteFuture = future::FutureResult<(), Error>;
+    type TrimFuture = future::FutureResult<(), Error>;
+
+    fn number_of_sectors(&self) -> Sector {
+        self.sectors
+    }
+
+    fn read(&self, sector: Sector) -> Self::ReadFuture {
+        let mut file = self.file.lock().unwrap();
+        let mut buf = Box::new([0; SECTOR_SIZE]);
+
+        future::result(Image::seek(&mut file, sector)
+            .and_then(|_| file.read_exact(&mut buf[..]))
+            .map(|_| buf)
+            .map_err(|err| Error::from(err).with_sector(sector as u64)))
+    }
+
+    fn write(&self, sector: Sector, buf: &SectorBuf) -> Self::WriteFuture {
+        let mut file = self.file.lock().unwrap();
+
+        future::result(Image::seek(&mut file, sector)
+            .and_then(|_| file.write_all(buf))
+            .map_err(|err| Error::from(err).with_sector(sector as u64)))
+    }
+
+    fn trim(&self, _: Sector) -> Self::TrimFuture {
+        // Trimming is only a hint, and regular files have no portable way of punching holes, so
+        // the data is simply left there.
+        future::ok(())
+    }
+}
+
+impl slog::Drain for Image {
+    type Error = io::Error;
+
+    fn log(&self, info: &slog::Record, _: &slog::OwnedKeyValueList) -> io::Result<()> {
+        if self.verbose || info.level().is_at_least(slog::Level::Warning) {
+            writeln!(io::stderr(), "{}: {}", info.level().as_str(), info.msg())?;
+        }
+
+        Ok(())
+    }
+}
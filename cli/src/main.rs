@@ -0,0 +1,496 @@
+//! The TFS command-line tool.
+//!
+//! This creates and inspects TFS images stored in regular files, without mounting them.
+
+extern crate futures;
+extern crate slog;
+extern crate tfs_core as tfs;
+
+mod image;
+
+use futures::Future;
+use std::{env, process};
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use image::Image;
+use tfs::{fsck, ChecksumAlgorithm, CompressionAlgorithm, DedupVerification, FileType, Filesystem,
+          MkfsOptions, OpenOptions, Vdev};
+
+/// The help page for this command.
+const HELP: &'static [u8] = br#"
+Introduction:
+    tfs - an utility to create and inspect TFS images.
+Usage:
+    tfs [-v] <command> <image> [arguments]
+Commands:
+    mkfs <image> <size> [options] : Create an image of <size> bytes (suffixes K, M and G are
+                                    allowed). Any existing file is overwritten.
+        --vdev <mirror>           : Add a vdev to the vdev stack (may be repeated). Encryption
+                                    (speck) is not supported (see Limitations).
+        --checksum <seahash>      : The checksum algorithm.
+        --compression <lz4|none>  : The compression algorithm (default: lz4).
+        --dedup <fingerprint|strict> : The deduplication verification (default: fingerprint).
+        --label <label>           : The label of the filesystem.
+    info <image>                  : Show the properties of the filesystem.
+    ls <image> [path]             : List a directory (default: the root directory).
+    cat <image> <path>            : Write a file to stdout.
+    put <image> <file> <path>     : Copy a local file (- for stdin) into the image. The file is
+                                    replaced atomically.
+    get <image> <path> <file>     : Copy a file out of the image into a local file (- for stdout).
+    rm <image> <path>             : Remove a file or an empty directory.
+    mkdir <image> <path>          : Create a directory.
+    clone <image> <path> <new>    : Clone a file or a directory tree to <new>, sharing the data
+                                    until either copy is modified.
+    snapshot <image> [name]       : Create a snapshot, or list the snapshots if no name is given.
+    df <image>                    : Show the space usage.
+    fsck <image> [--repair]       : Check the consistency of the image. With --repair, the
+                                    freelist is rebuilt from the reachable clusters.
+Limitations:
+    Encryption is not supported: Encrypted images can neither be created nor opened, so every
+    command but mkfs fails on them.
+Options:
+    -v : Log every message to stderr, and not just warnings and errors.
+    -h : Write this manpage to stdout.
+Exit status:
+    0 : Success.
+    1 : The command failed.
+    2 : The command was used wrongly.
+    Otherwise, fsck found problems, which it didn't repair. The exit status is the sum of:
+        4   : The disk header is invalid, or marks the system inconsistent.
+        8   : The state block is invalid.
+        16  : A metacluster of the freelist is corrupt.
+        32  : A reachable page is corrupt.
+        64  : A cluster is both free and referenced.
+        128 : A cluster is leaked.
+"#;
+
+/// The number of bytes copied at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A failed command.
+#[derive(Debug)]
+enum Failure {
+    /// The command was used wrongly.
+    Usage(String),
+    /// The command failed.
+    Fs(tfs::Error),
+    /// The image has problems.
+    ///
+    /// This holds the exit status, which tells the classes of the problems.
+    Problems(i32),
+}
+
+impl From<tfs::Error> for Failure {
+    fn from(err: tfs::Error) -> Failure {
+        Failure::Fs(err)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Fs(err.into())
+    }
+}
+
+/// Fail with a usage error.
+fn usage<T, S: Into<String>>(msg: S) -> Result<T, Failure> {
+    Err(Failure::Usage(msg.into()))
+}
+
+/// Check that the number of arguments is within `min..=max`.
+fn arguments(args: &[String], min: usize, max: usize) -> Result<(), Failure> {
+    if args.len() < min {
+        usage("missing argument")
+    } else if args.len() > max {
+        usage(format!("unexpected argument '{}'", args[max]))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a size in bytes.
+///
+/// The size can have a binary suffix (`K`, `M` or `G`).
+fn parse_size(size: &str) -> Result<u64, Failure> {
+    let (digits, unit) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    match digits.parse::<u64>().ok().and_then(|x| x.checked_mul(unit)) {
+        Some(size) => Ok(size),
+        None => usage(format!("invalid size '{}'", size)),
+    }
+}
+
+/// Parse the options of `mkfs`.
+fn parse_mkfs_options(args: &[String]) -> Result<MkfsOptions, Failure> {
+    // See `open()` for why no background threads are started.
+    let mut options = MkfsOptions {
+        gc: None,
+        compaction: None,
+        .. MkfsOptions::default()
+    };
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return usage(format!("missing value of '{}'", flag)),
+        };
+
+        match (&**flag, &**value) {
+            ("--vdev", "mirror") => options.vdev_stack.push(Vdev::Mirror),
+            ("--vdev", "speck") => return usage("encryption (speck) is not supported yet"),
+            ("--checksum", "seahash") => options.checksum_algorithm = ChecksumAlgorithm::SeaHash,
+            ("--compression", "lz4") => options.compression_algorithm = CompressionAlgorithm::Lz4,
+            ("--compression", "none") => {
+                options.compression_algorithm = CompressionAlgorithm::Identity;
+            },
+            ("--dedup", "fingerprint") => {
+                options.dedup_verification = DedupVerification::Fingerprint;
+            },
+            ("--dedup", "strict") => options.dedup_verification = DedupVerification::Strict,
+            ("--label", label) => options.label = label.as_bytes().to_vec(),
+            ("--vdev", _) | ("--checksum", _) | ("--compression", _) | ("--dedup", _) => {
+                return usage(format!("invalid value '{}' of '{}'", value, flag));
+            },
+            _ => return usage(format!("unknown option '{}'", flag)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Get the exit status bit of a class of problems.
+fn problem_status(class: fsck::Class) -> i32 {
+    match class {
+        fsck::Class::DiskHeader => 4,
+        fsck::Class::StateBlock => 8,
+        fsck::Class::Freelist => 16,
+        fsck::Class::Page => 32,
+        fsck::Class::FreeAndReferenced => 64,
+        fsck::Class::Leaked => 128,
+    }
+}
+
+/// Open the filesystem in an image.
+///
+/// If `writable` is not set, the filesystem is opened read-only.
+fn open(image: &str, writable: bool, verbose: bool) -> Result<Filesystem<Image>, Failure> {
+    let disk = Image::open(image, writable, verbose)?;
+
+    Ok(Filesystem::open(disk, OpenOptions {
+        read_only: !writable,
+        // Every command is a single operation, and unmounting collects the garbage anyway, so
+        // there is nothing for the background threads to do.
+        gc: None,
+        compaction: None,
+        .. OpenOptions::default()
+    }).wait()?)
+}
+
+/// Copy a file out of the filesystem.
+fn copy_out<W: Write>(fs: &Filesystem<Image>, path: &str, out: &mut W) -> Result<(), Failure> {
+    let handle = fs.open_file(path.as_bytes()).wait()?;
+
+    let mut offset = 0;
+    loop {
+        let buf = fs.read(handle, offset, CHUNK_SIZE).wait()?;
+        if buf.is_empty() {
+            break;
+        }
+
+        out.write_all(&buf)?;
+        offset += buf.len() as u64;
+    }
+
+    Ok(fs.close(handle).wait()?)
+}
+
+/// Copy a file into the filesystem.
+///
+/// If the file exists, it is overwritten. The file is written by a single commit, so the input is
+/// read into memory first.
+fn copy_in<R: Read>(fs: &Filesystem<Image>, input: &mut R, path: &str) -> Result<(), Failure> {
+    let mut buf = Vec::new();
+    input.read_to_end(&mut buf)?;
+
+    Ok(fs.put(path.as_bytes(), &buf).wait()?)
+}
+
+/// Describe the type of an object by a character.
+fn type_char(file_type: FileType) -> char {
+    match file_type {
+        FileType::File => '-',
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+    }
+}
+
+/// Run a command.
+fn run(args: &[String]) -> Result<(), Failure> {
+    let verbose = args.first().map_or(false, |x| x == "-v");
+    let args = if verbose { &args[1..] } else { args };
+    let (command, args) = match args.split_first() {
+        Some(x) => x,
+        None => return usage("missing command"),
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    match &**command {
+        "mkfs" => {
+            if args.len() < 2 {
+                return usage("missing argument");
+            }
+
+            let options = parse_mkfs_options(&args[2..])?;
+            let disk = Image::create(&args[0], parse_size(&args[1])?, verbose)?;
+            Filesystem::mkfs(disk, options).and_then(|fs| fs.unmount()).wait()?;
+        },
+        "info" => {
+            arguments(args, 1, 1)?;
+            let fs = open(&args[0], false, verbose)?;
+            let info = fs.info().wait()?;
+
+            writeln!(stdout, "label:       {}", String::from_utf8_lossy(&info.label))?;
+            writeln!(stdout, "version:     {:x}", info.image.version)?;
+            writeln!(stdout, "vdevs:       {:?}", info.image.vdev_stack)?;
+            writeln!(stdout, "checksum:    {:?}", info.image.checksum_algorithm)?;
+            writeln!(stdout, "compression: {:?}", info.compression_algorithm)?;
+            writeln!(stdout, "dedup:       {:?}", info.dedup_verification)?;
+            writeln!(stdout, "generation:  {}", info.generation)?;
+            writeln!(stdout, "created:     {}", info.created)?;
+            writeln!(stdout, "committed:   {}", info.committed)?;
+        },
+        "ls" => {
+            arguments(args, 1, 2)?;
+            let fs = open(&args[0], false, verbose)?;
+            let path = args.get(1).map_or("/", |x| &**x);
+            let mut entries = fs.readdir(path.as_bytes()).wait()?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+            for entry in entries {
+                writeln!(stdout, "{} {}", type_char(entry.file_type),
+                         String::from_utf8_lossy(&entry.name))?;
+            }
+        },
+        "cat" => {
+            arguments(args, 2, 2)?;
+            let fs = open(&args[0], false, verbose)?;
+            copy_out(&fs, &args[1], &mut stdout)?;
+        },
+        "put" => {
+            arguments(args, 3, 3)?;
+            let fs = open(&args[0], true, verbose)?;
+            if args[1] == "-" {
+                copy_in(&fs, &mut io::stdin(), &args[2])?;
+            } else {
+                copy_in(&fs, &mut File::open(&args[1])?, &args[2])?;
+            }
+            fs.unmount().wait()?;
+        },
+        "get" => {
+            arguments(args, 3, 3)?;
+            let fs = open(&args[0], false, verbose)?;
+            if args[2] == "-" {
+                copy_out(&fs, &args[1], &mut stdout)?;
+            } else {
+                copy_out(&fs, &args[1], &mut File::create(&args[2])?)?;
+            }
+        },
+        "rm" => {
+            arguments(args, 2, 2)?;
+            let fs = open(&args[0], true, verbose)?;
+            let path = args[1].as_bytes();
+            if fs.stat(path).wait()?.file_type == FileType::Directory {
+                fs.rmdir(path).wait()?;
+            } else {
+                fs.unlink(path).wait()?;
+            }
+            fs.unmount().wait()?;
+        },
+        "mkdir" => {
+            arguments(args, 2, 2)?;
+            let fs = open(&args[0], true, verbose)?;
+            fs.mkdir(args[1].as_bytes()).wait()?;
+            fs.unmount().wait()?;
+        },
+        "clone" => {
+            arguments(args, 3, 3)?;
+            let fs = open(&args[0], true, verbose)?;
+            fs.clone_entry(args[1].as_bytes(), args[2].as_bytes()).wait()?;
+            fs.unmount().wait()?;
+        },
+        "snapshot" => {
+            arguments(args, 1, 2)?;
+            if let Some(name) = args.get(1) {
+                let fs = open(&args[0], true, verbose)?;
+                fs.snapshot(name.as_bytes()).wait()?;
+                fs.unmount().wait()?;
+            } else {
+                let fs = open(&args[0], false, verbose)?;
+                for snapshot in fs.snapshots().wait()? {
+                    writeln!(stdout, "{}\tgeneration {}\tcreated {}",
+                             String::from_utf8_lossy(&snapshot.name), snapshot.generation,
+                             snapshot.created)?;
+                }
+            }
+        },
+        "df" => {
+            arguments(args, 1, 1)?;
+            let fs = open(&args[0], false, verbose)?;
+            let usage = fs.usage().wait()?;
+
+            writeln!(stdout, "size:         {}", usage.size)?;
+            writeln!(stdout, "live:         {}", usage.live)?;
+            writeln!(stdout, "snapshots:    {}", usage.snapshots)?;
+            writeln!(stdout, "inline files: {}", usage.inline_files)?;
+        },
+        "fsck" => {
+            arguments(args, 1, 2)?;
+            let repair = match args.get(1).map(|x| &**x) {
+                None => false,
+                Some("--repair") => true,
+                Some(option) => return usage(format!("unknown option '{}'", option)),
+            };
+            // Checking alone never writes to the image.
+            let disk = Image::open(&args[0], repair, verbose)?;
+            let report = fsck::check(disk, fsck::Options {
+                repair: repair,
+                .. fsck::Options::default()
+            })?;
+
+            let mut status = 0;
+            for problem in &report.problems {
+                if problem.repaired {
+                    writeln!(stdout, "repaired: {}", problem.error)?;
+                } else {
+                    writeln!(stdout, "{}", problem.error)?;
+                    status |= problem_status(problem.class);
+                }
+            }
+            writeln!(stdout, "{} clusters, {} free clusters, {} reachable pages", report.clusters,
+                     report.free_clusters, report.reachable_pages)?;
+
+            if status != 0 {
+                return Err(Failure::Problems(status));
+            }
+        },
+        "-h" | "help" => stdout.write_all(HELP)?,
+        _ => return usage(format!("unknown command '{}'", command)),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match run(&args) {
+        Ok(()) => (),
+        Err(Failure::Usage(msg)) => {
+            let _ = writeln!(io::stderr(), "tfs: {}", msg);
+            let _ = io::stderr().write_all(HELP);
+
+            process::exit(2);
+        },
+        Err(Failure::Fs(err)) => {
+            let _ = writeln!(io::stderr(), "tfs: {}", err);
+
+            process::exit(1);
+        },
+        // The problems are already listed.
+        Err(Failure::Problems(status)) => process::exit(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("2m").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn mkfs_options() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        let options = parse_mkfs_options(&args(&["--vdev", "mirror", "--compression", "none",
+                                                 "--label", "ci"]))
+            .unwrap();
+        assert_eq!(options.vdev_stack, [Vdev::Mirror]);
+        assert_eq!(options.compression_algorithm, CompressionAlgorithm::Identity);
+        assert_eq!(options.dedup_verification, DedupVerification::Fingerprint);
+        assert_eq!(options.label, b"ci");
+
+        assert!(parse_mkfs_options(&args(&["--vdev", "raid"])).is_err());
+        assert!(parse_mkfs_options(&args(&["--vdev", "speck"])).is_err());
+        assert!(parse_mkfs_options(&args(&["--label"])).is_err());
+        assert!(parse_mkfs_options(&args(&["--size", "1"])).is_err());
+    }
+
+    #[test]
+    fn end_to_end() {
+        // Use a fresh directory for every run, so concurrent runs don't interfere.
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut n = time.as_secs() * 1_000_000_000 + time.subsec_nanos() as u64;
+        let dir = loop {
+            let dir = env::temp_dir().join(format!("tfs-cli-end-to-end-{}", n));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(err) => panic!("failed to create {:?}: {}", dir, err),
+            }
+        };
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let cli = |args: &[&str]| run(&args.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+        let output = || {
+            let mut out = Vec::new();
+            File::open(path("out")).unwrap().read_to_end(&mut out).unwrap();
+            out
+        };
+        let image = path("image");
+        File::create(path("in")).unwrap().write_all(b"hello world").unwrap();
+
+        cli(&["mkfs", &image, "1M", "--label", "ci"]).unwrap();
+        cli(&["mkdir", &image, "/a"]).unwrap();
+        cli(&["put", &image, &path("in"), "/a/b"]).unwrap();
+        cli(&["get", &image, "/a/b", &path("out")]).unwrap();
+        assert_eq!(output(), b"hello world");
+
+        cli(&["clone", &image, "/a", "/c"]).unwrap();
+        cli(&["get", &image, "/c/b", &path("out")]).unwrap();
+        assert_eq!(output(), b"hello world");
+
+        cli(&["rm", &image, "/a/b"]).unwrap();
+        cli(&["rm", &image, "/a"]).unwrap();
+        cli(&["fsck", &image]).unwrap();
+        match cli(&["get", &image, "/a/b", &path("out")]) {
+            Err(Failure::Fs(ref err)) if err.kind == tfs::ErrorKind::NotFound => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        match cli(&["mkfs", &image, "1M", "--vdev", "speck"]) {
+            Err(Failure::Usage(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
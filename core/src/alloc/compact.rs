@@ -0,0 +1,404 @@
+//! Background recompaction of clusters.
+//!
+//! RACC packs pages greedily: Every thread appends to its own last used cluster, and when pages
+//! become unreachable, the clusters containing them are left partially dead. Over time, this
+//! leaves many sparse clusters, as well as uncompressed clusters which could have been compressed
+//! together with other pages.
+//!
+//! Compaction fixes this by regularly repacking the live pages of such clusters into fewer
+//! clusters. A compaction cycle goes through four steps:
+//!
+//! 1. The live pages are found by traversing the object graph, building a `Census`.
+//! 2. The allocator examines the clusters of the census, and repacks the live pages of the sparse
+//!    ones, producing a set of `Relocations`.
+//! 3. The relocations are applied to the object graph (copy-on-write), through
+//!    `fs::Object::relocate()`.
+//! 4. The allocator frees the old clusters.
+//!
+//! Clusters which are in use by the allocator (e.g. because a thread is currently appending to
+//! them) are pinned, and will be skipped.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
+use std::{thread, vec};
+
+use Error;
+use alloc::page;
+use disk::cluster;
+
+/// The atomic ordering used for the progress counters.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+
+/// The live pages, grouped by the cluster they're stored in.
+#[derive(Default)]
+pub struct Census {
+    /// The map from cluster to the live pages in said cluster.
+    clusters: HashMap<cluster::Pointer, Vec<page::Pointer>>,
+}
+
+impl Census {
+    /// Add a live page to the census.
+    ///
+    /// Adding the same page twice has no effect.
+    pub fn add(&mut self, page: page::Pointer) {
+        let pages = self.clusters.entry(page.cluster).or_insert_with(Vec::new);
+
+        // The same page may be reachable through multiple paths (e.g. deduplicated pages), so we
+        // must avoid moving it twice.
+        if !pages.contains(&page) {
+            pages.push(page);
+        }
+    }
+
+    /// Get the number of clusters in the census.
+    pub fn len(&self) -> usize {
+        self.clusters.len()
+    }
+}
+
+/// Iterate over the clusters of the census along with their live pages.
+///
+/// The clusters are yielded in ascending order, so the clusters examined by a cycle don't depend
+/// on the order of the hash map.
+impl IntoIterator for Census {
+    type Item = (cluster::Pointer, Vec<page::Pointer>);
+    type IntoIter = vec::IntoIter<(cluster::Pointer, Vec<page::Pointer>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let mut clusters: Vec<_> = self.clusters.into_iter().collect();
+        clusters.sort_by_key(|&(cluster, _)| u64::from(cluster));
+
+        clusters.into_iter()
+    }
+}
+
+/// The relocations of a compaction cycle.
+///
+/// This maps the old page pointers to their new location. Until the relocations are applied to
+/// every referrer, both the old and the new page are valid.
+#[derive(Default)]
+pub struct Relocations {
+    /// The map from old page pointers to new page pointers.
+    pages: HashMap<page::Pointer, page::Pointer>,
+    /// The clusters, which can be freed once the relocations are applied.
+    pub obsolete: Vec<cluster::Pointer>,
+}
+
+impl Relocations {
+    /// Record that page `from` was moved to `to`.
+    pub fn insert(&mut self, from: page::Pointer, to: page::Pointer) {
+        self.pages.insert(from, to);
+    }
+
+    /// Get the new location of some page.
+    ///
+    /// If the page was not relocated, `None` is returned.
+    pub fn get(&self, page: page::Pointer) -> Option<page::Pointer> {
+        self.pages.get(&page).cloned()
+    }
+
+    /// Iterate over the relocations as `(old, new)` pairs.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (page::Pointer, page::Pointer)> + 'a {
+        self.pages.iter().map(|(&from, &to)| (from, to))
+    }
+
+    /// Is the set of relocations empty?
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+/// Compaction options.
+#[derive(Clone, Copy)]
+pub struct Options {
+    /// The maximal number of clusters examined in a single cycle.
+    ///
+    /// This limits the amount of I/O a cycle can cause.
+    pub clusters_per_cycle: usize,
+    /// The pause between cycles.
+    pub interval: Duration,
+    /// The fill threshold (in percent).
+    ///
+    /// Compressed clusters with a fraction of live pages below this threshold are repacked.
+    /// Uncompressed clusters are repacked if their page is compressible.
+    pub max_fill: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            clusters_per_cycle: 256,
+            interval: Duration::from_secs(10),
+            max_fill: 50,
+        }
+    }
+}
+
+/// A report of the compaction progress.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// The number of completed cycles.
+    pub cycles: u64,
+    /// The number of clusters examined.
+    pub clusters_scanned: u64,
+    /// The number of clusters repacked.
+    pub clusters_compacted: u64,
+    /// The number of pages moved.
+    pub pages_moved: u64,
+    /// The number of clusters freed.
+    pub clusters_freed: u64,
+}
+
+/// The progress counters of the compaction.
+#[derive(Default)]
+pub struct Progress {
+    /// The number of completed cycles.
+    cycles: atomic::AtomicUsize,
+    /// The number of clusters examined.
+    clusters_scanned: atomic::AtomicUsize,
+    /// The number of clusters repacked.
+    clusters_compacted: atomic::AtomicUsize,
+    /// The number of pages moved.
+    pages_moved: atomic::AtomicUsize,
+    /// The number of clusters freed.
+    clusters_freed: atomic::AtomicUsize,
+}
+
+impl Progress {
+    /// Count a completed cycle.
+    pub fn cycle(&self) {
+        self.cycles.fetch_add(1, ORDERING);
+    }
+
+    /// Count a scanned cluster.
+    pub fn scanned(&self) {
+        self.clusters_scanned.fetch_add(1, ORDERING);
+    }
+
+    /// Count a repacked cluster with `pages` live pages.
+    pub fn compacted(&self, pages: usize) {
+        self.clusters_compacted.fetch_add(1, ORDERING);
+        self.pages_moved.fetch_add(pages, ORDERING);
+    }
+
+    /// Count `clusters` freed clusters.
+    pub fn freed(&self, clusters: usize) {
+        self.clusters_freed.fetch_add(clusters, ORDERING);
+    }
+
+    /// Take a snapshot of the counters.
+    pub fn report(&self) -> Report {
+        Report {
+            cycles: self.cycles.load(ORDERING) as u64,
+            clusters_scanned: self.clusters_scanned.load(ORDERING) as u64,
+            clusters_compacted: self.clusters_compacted.load(ORDERING) as u64,
+            pages_moved: self.pages_moved.load(ORDERING) as u64,
+            clusters_freed: self.clusters_freed.load(ORDERING) as u64,
+        }
+    }
+}
+
+/// The set of clusters which must not be compacted.
+///
+/// Two kinds of clusters are pinned: Clusters which are currently open for appending new pages
+/// into, and clusters which had pages referenced (e.g. by deduplication) while a cycle is running.
+/// The latter is needed as such references aren't part of the census, and thus wouldn't be
+/// relocated.
+#[derive(Default)]
+pub struct Pins {
+    /// The open clusters.
+    open: Mutex<HashSet<cluster::Pointer>>,
+    /// The clusters referenced during the current cycle.
+    ///
+    /// This is `None` if no cycle is running.
+    touched: Mutex<Option<HashSet<cluster::Pointer>>>,
+}
+
+impl Pins {
+    /// Mark a cluster as open.
+    pub fn open(&self, cluster: cluster::Pointer) {
+        self.open.lock().unwrap().insert(cluster);
+    }
+
+    /// Mark a cluster as no longer open.
+    pub fn close(&self, cluster: cluster::Pointer) {
+        self.open.lock().unwrap().remove(&cluster);
+    }
+
+    /// Note that a cluster was referenced.
+    ///
+    /// If no cycle is running, this does nothing.
+    pub fn touch(&self, cluster: cluster::Pointer) {
+        if let Some(ref mut touched) = *self.touched.lock().unwrap() {
+            touched.insert(cluster);
+        }
+    }
+
+    /// Start tracking referenced clusters.
+    pub fn begin_cycle(&self) {
+        *self.touched.lock().unwrap() = Some(HashSet::new());
+    }
+
+    /// Stop tracking referenced clusters.
+    pub fn end_cycle(&self) {
+        *self.touched.lock().unwrap() = None;
+    }
+
+    /// Is some cluster pinned?
+    pub fn is_pinned(&self, cluster: cluster::Pointer) -> bool {
+        self.open.lock().unwrap().contains(&cluster)
+            || self.touched.lock().unwrap().as_ref().map_or(false, |x| x.contains(&cluster))
+    }
+}
+
+/// The background compactor.
+///
+/// This drives the compaction cycles in a background thread, respecting the rate limits in the
+/// options, and keeps track of the progress.
+pub struct Compactor {
+    /// The compaction options.
+    pub options: Options,
+    /// The progress counters.
+    pub progress: Progress,
+    /// Has the compactor been requested to stop?
+    stop: atomic::AtomicBool,
+}
+
+impl Compactor {
+    /// Create a new compactor with some options.
+    pub fn new(options: Options) -> Compactor {
+        Compactor {
+            options: options,
+            progress: Progress::default(),
+            stop: atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Spawn the background thread.
+    ///
+    /// This runs `cycle` repeatedly in a new thread, pausing for the interval given in the options
+    /// between every cycle, until `self.stop()` is called. If a cycle fails, the thread stops and
+    /// returns the error.
+    pub fn spawn<F>(self: Arc<Self>, cycle: F) -> thread::JoinHandle<Result<(), Error>>
+    where F: Fn(&Compactor) -> Result<(), Error> + Send + 'static {
+        thread::spawn(move || {
+            while !self.stop.load(ORDERING) {
+                // Run a cycle.
+                cycle(&self)?;
+                self.progress.cycle();
+
+                // Rate limit by pausing between the cycles.
+                thread::park_timeout(self.options.interval);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Request the background thread to stop.
+    ///
+    /// The thread will stop after the current cycle has completed.
+    pub fn stop(&self) {
+        self.stop.store(true, ORDERING);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(cluster: u64, offset: u32) -> page::Pointer {
+        page::Pointer {
+            cluster: cluster::Pointer::new(cluster).unwrap(),
+            offset: Some(offset),
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn census() {
+        let mut census = Census::default();
+        census.add(page(1, 0));
+        census.add(page(1, 2));
+        census.add(page(1, 2));
+        census.add(page(3, 1));
+        census.add(page(2, 0));
+
+        assert_eq!(census.len(), 3);
+
+        // The clusters are ordered.
+        let clusters: Vec<_> = census.into_iter().collect();
+        assert_eq!(clusters[0].1, [page(1, 0), page(1, 2)]);
+        assert_eq!(clusters[1].1, [page(2, 0)]);
+        assert_eq!(clusters[2].1, [page(3, 1)]);
+    }
+
+    #[test]
+    fn relocations() {
+        let mut relocations = Relocations::default();
+        assert!(relocations.is_empty());
+
+        relocations.insert(page(1, 0), page(3, 0));
+        assert_eq!(relocations.get(page(1, 0)), Some(page(3, 0)));
+        assert_eq!(relocations.get(page(1, 1)), None);
+        assert!(!relocations.is_empty());
+    }
+
+    #[test]
+    fn pins() {
+        let pins = Pins::default();
+        let a = cluster::Pointer::new(1).unwrap();
+        let b = cluster::Pointer::new(2).unwrap();
+
+        pins.open(a);
+        assert!(pins.is_pinned(a));
+        pins.close(a);
+        assert!(!pins.is_pinned(a));
+
+        // Touches outside a cycle are not tracked.
+        pins.touch(b);
+        assert!(!pins.is_pinned(b));
+
+        pins.begin_cycle();
+        pins.touch(b);
+        assert!(pins.is_pinned(b));
+        pins.end_cycle();
+        assert!(!pins.is_pinned(b));
+    }
+
+    #[test]
+    fn progress() {
+        let progress = Progress::default();
+        progress.scanned();
+        progress.scanned();
+        progress.compacted(3);
+        progress.freed(1);
+        progress.cycle();
+
+        assert_eq!(progress.report(), Report {
+            cycles: 1,
+            clusters_scanned: 2,
+            clusters_compacted: 1,
+            pages_moved: 3,
+            clusters_freed: 1,
+        });
+    }
+
+    #[test]
+    fn stop() {
+        let compactor = Arc::new(Compactor::new(Options {
+            interval: Duration::from_millis(1),
+            .. Options::default()
+        }));
+
+        let handle = compactor.clone().spawn(|compactor| {
+            // Stop after the first cycle.
+            compactor.stop();
+            Ok(())
+        });
+
+        handle.join().unwrap().unwrap();
+        assert_eq!(compactor.progress.report().cycles, 1);
+    }
+}
@@ -0,0 +1,178 @@
+//! Compression algorithms.
+//!
+//! RACC (the cluster compression scheme) is agnostic to the compression algorithm in use. This
+//! module defines the interface between the allocator and the compression algorithms, as well as
+//! a registry, which maps the compression algorithm option from the state block to some
+//! implementation of this interface.
+//!
+//! The builtin algorithms (identity and LZ4) are always registered. In addition, the
+//! implementation-defined range of the option (`0x8000` to `0xFFFF`) is reserved for out-of-tree
+//! algorithms, which the user can register when opening or initializing the system.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use {lz4_compress, Error};
+use alloc::state_block::CompressionAlgorithm;
+
+/// The lower bound of the implementation-defined compression algorithm range.
+pub const IMPLEMENTATION_DEFINED_START: u16 = 0x8000;
+
+/// A compression algorithm.
+///
+/// This is the interface the allocator uses for compressing and decompressing clusters. The
+/// algorithm works on whole streams: The allocator takes care of padding and framing, so the
+/// implementation need not worry about the cluster size.
+pub trait Compressor: Send + Sync {
+    /// Compress some data.
+    ///
+    /// This compresses `input` and returns the compressed stream.
+    fn compress(&self, input: &[u8]) -> Vec<u8>;
+    /// Decompress some data.
+    ///
+    /// This decompresses the stream `input`, which was produced by `self.compress()`. If the
+    /// stream is invalid, an error is returned.
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// The identity function.
+///
+/// This "compresses" by returning the input unchanged. The allocator will never compress through
+/// it, as it stores the pages uncompressed when compression is disabled, but it is registered for
+/// completeness.
+pub struct Identity;
+
+impl Compressor for Identity {
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        input.to_vec()
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(input.to_vec())
+    }
+}
+
+/// The LZ4 compression algorithm.
+pub struct Lz4;
+
+impl Compressor for Lz4 {
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        lz4_compress::compress(input)
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        lz4_compress::decompress(input).map_err(|_| err!(Corruption, "invalid LZ4 stream"))
+    }
+}
+
+/// A registry of compression algorithms.
+///
+/// This maps compression algorithm options to their implementation. The default registry contains
+/// the builtin algorithms, and implementation-defined algorithms can be added through
+/// `Registry::register()`.
+#[derive(Clone)]
+pub struct Registry {
+    /// The implementation-defined algorithms, indexed by their option value.
+    implementation_defined: HashMap<u16, Arc<Compressor>>,
+}
+
+impl Registry {
+    /// Register an implementation-defined compression algorithm.
+    ///
+    /// This registers `compressor` as the algorithm with option value `id`. `id` must be in the
+    /// implementation-defined range, as the rest of the range is reserved for the specification.
+    /// If `id` is already registered, it is replaced.
+    pub fn register(&mut self, id: u16, compressor: Arc<Compressor>) -> Result<(), Error> {
+        if id < IMPLEMENTATION_DEFINED_START {
+            // The non-implementation-defined options are defined by the specification, and
+            // cannot be overridden.
+            return Err(err!(InvalidInput, "compression algorithm option {:x} is not in the \
+                                           implementation-defined range", id));
+        }
+
+        self.implementation_defined.insert(id, compressor);
+
+        Ok(())
+    }
+
+    /// Get the implementation of some compression algorithm.
+    ///
+    /// If the algorithm is implementation-defined and not registered, an error is returned.
+    pub fn get(&self, algorithm: CompressionAlgorithm) -> Result<Arc<Compressor>, Error> {
+        match algorithm {
+            CompressionAlgorithm::Identity => Ok(Arc::new(Identity)),
+            CompressionAlgorithm::Lz4 => Ok(Arc::new(Lz4)),
+            CompressionAlgorithm::ImplementationDefined(id) => {
+                self.implementation_defined.get(&id).cloned().ok_or_else(|| {
+                    err!(Unsupported, "unknown implementation-defined compression algorithm \
+                                       option {:x}", id)
+                })
+            },
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry {
+            implementation_defined: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error;
+
+    /// A dummy compressor, which reverses the stream.
+    struct Reverse;
+
+    impl Compressor for Reverse {
+        fn compress(&self, input: &[u8]) -> Vec<u8> {
+            input.iter().rev().cloned().collect()
+        }
+
+        fn decompress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(input.iter().rev().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn builtin() {
+        let registry = Registry::default();
+        let data = b"Hello, hello, hello, hello, hello, world!";
+
+        let lz4 = registry.get(CompressionAlgorithm::Lz4).unwrap();
+        assert_eq!(lz4.decompress(&lz4.compress(data)).unwrap(), &data[..]);
+
+        let identity = registry.get(CompressionAlgorithm::Identity).unwrap();
+        assert_eq!(identity.compress(data), &data[..]);
+        assert_eq!(identity.decompress(data).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn implementation_defined() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.get(CompressionAlgorithm::ImplementationDefined(0x8001)).unwrap_err().kind,
+                   error::Kind::Unsupported);
+
+        registry.register(0x8001, Arc::new(Reverse)).unwrap();
+        let reverse = registry.get(CompressionAlgorithm::ImplementationDefined(0x8001)).unwrap();
+        assert_eq!(reverse.compress(&[1, 2, 3]), [3, 2, 1]);
+        assert_eq!(reverse.decompress(&[3, 2, 1]).unwrap(), [1, 2, 3]);
+
+        assert_eq!(registry.get(CompressionAlgorithm::ImplementationDefined(0x8002)).unwrap_err().kind,
+                   error::Kind::Unsupported);
+    }
+
+    #[test]
+    fn reserved_range() {
+        let mut registry = Registry::default();
+
+        assert_eq!(registry.register(1, Arc::new(Reverse)).unwrap_err().kind,
+                   error::Kind::InvalidInput);
+        assert_eq!(registry.register(0x7FFF, Arc::new(Reverse)).unwrap_err().kind,
+                   error::Kind::InvalidInput);
+    }
+}
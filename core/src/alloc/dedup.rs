@@ -5,10 +5,12 @@
 
 use crossbeam::sync::AtomicOption;
 use ring::digest;
+use std::collections::HashSet;
 use std::sync::atomic;
 
 use {little_endian, disk};
 use alloc::page;
+use disk::cluster;
 
 /// The atomic ordering used in the table.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
@@ -17,18 +19,20 @@ const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
 ///
 /// It is broken into two `u128` since `u256` isn't supported yet.
 // TODO: ^^^^
-struct Fingerprint(u128, u128);
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Fingerprint(u128, u128);
 
 impl Fingerprint {
     /// Fingerprint a page.
     ///
     /// This calculates the fingerprint of page `buf` through SHA-2.
-    fn new(buf: &disk::SectorBuf) -> Fingerprint {
+    pub fn new(buf: &disk::SectorBuf) -> Fingerprint {
         // Hash it into a 256-bit value.
-        let hash = digest::digest(digest::SHA256, buf).as_ref();
+        let hash = digest::digest(&digest::SHA256, buf);
+        let hash = hash.as_ref();
 
         // Read it in two parts to get two `u128`s.
-        (little_endian::read(hash), little_endian::read(hash[16..]))
+        Fingerprint(little_endian::read(hash), little_endian::read(&hash[16..]))
     }
 }
 
@@ -55,13 +59,13 @@ struct Candidate {
 }
 
 impl Candidate {
-    /// Check if this candidate matches some data buffer.
+    /// Check if this candidate matches some fingerprint.
     ///
     /// If not, `false` is returned.
-    fn is_match(&self, buf: &disk::SectorBuf) -> bool {
-        // Check the fingerprint against the hash of the buffer. Again, this is strictly speak
-        // heuristic, but for all practical purposes, no collisions will ever be found.
-        self.fingerprint == Fingerprint::new(buf)
+    fn is_match(&self, fingerprint: Fingerprint) -> bool {
+        // Check the fingerprint against the fingerprint of the buffer. Again, this is strictly
+        // speak heuristic, but for all practical purposes, no collisions will ever be found.
+        self.fingerprint == fingerprint
     }
 }
 
@@ -83,23 +87,26 @@ pub struct Table {
 impl Table {
     /// Find a duplicate of some page.
     ///
-    /// This searches for a duplicate of `buf` which has checksum `cksum`. If no duplicate is
-    /// found, `None` is returned.
-    fn dedup(&self, buf: &disk::SectorBuf, cksum: u32) -> Option<page::Pointer> {
+    /// This searches for a duplicate of the page with fingerprint `fingerprint` and checksum
+    /// `cksum`. If no duplicate is found, `None` is returned.
+    ///
+    /// Whether the returned page should be trusted blindly or verified against the data is up to
+    /// the caller (see `state_block::DedupVerification`).
+    pub fn dedup(&self, fingerprint: Fingerprint, cksum: u32) -> Option<page::Pointer> {
         // We look up in the table with the checksum under some modulus, since that is faster to
         // calculate than a cryptographic hash, meaning that we can refine candidates based on a
         // rougher first-hand measure.
-        let entry = self.table[cksum % MAX_PAGES_IN_TABLE];
+        let entry = &self.table[cksum as usize % MAX_PAGES_IN_TABLE];
 
         // Temporarily remove the entry from the table.
         if let Some(candidate) = entry.take(ORDERING) {
             // A candidate exists.
 
             // Put it back into the entry.
-            entry.swap(candidate);
+            entry.swap(candidate, ORDERING);
 
             // Check if the checksum and fingerprint matches.
-            if cksum == candidate.page.checksum && candidate.isMatch(buf) {
+            if cksum == candidate.page.checksum && candidate.is_match(fingerprint) {
                 // Yup.
                 Some(candidate.page)
             } else {
@@ -114,56 +121,149 @@ impl Table {
 
     /// Insert a page into the table.
     ///
-    /// This inserts page `page` with data `buf` into the deduplication table.
-    fn insert(&mut self, buf: &disk::SectorBuf, page: page::Pointer) {
+    /// This inserts page `page` with fingerprint `fingerprint` into the deduplication table. The
+    /// fingerprint is passed by the caller, as it was already calculated in the lookup preceding
+    /// the allocation.
+    pub fn insert(&self, fingerprint: Fingerprint, page: page::Pointer) {
         // Overwrite the old entry with the new updated entry.
-        self.table[page.checksum % MAX_PAGES_IN_TABLE].swap(Candidate {
+        self.table[page.checksum as usize % MAX_PAGES_IN_TABLE].swap(Candidate {
             page: page,
-            // TODO: This fingerprint might be double-calculated due to the use in `dedup`.
-            fingerprint: Fingerprint::new(buf),
+            fingerprint: fingerprint,
         }, ORDERING);
     }
+
+    /// Update the table after a page has been moved.
+    ///
+    /// If page `from` is a candidate in the table, it is replaced by page `to`. This is needed
+    /// when the old page is freed, as it would otherwise be used as a duplicate.
+    pub fn relocate(&self, from: page::Pointer, to: page::Pointer) {
+        let entry = &self.table[from.checksum as usize % MAX_PAGES_IN_TABLE];
+
+        // Temporarily remove the entry from the table.
+        if let Some(mut candidate) = entry.take(ORDERING) {
+            if candidate.page == from {
+                // The candidate was moved.
+                candidate.page = to;
+            }
+
+            // Put it back into the entry.
+            entry.swap(candidate, ORDERING);
+        }
+    }
+
+    /// Remove the candidates in some clusters.
+    ///
+    /// This removes every candidate stored in one of the clusters of `clusters`. It must be called
+    /// before the clusters are freed, as the candidates would otherwise be used as duplicates.
+    pub fn forget(&self, clusters: &HashSet<cluster::Pointer>) {
+        for entry in self.table.iter() {
+            // Temporarily remove the entry from the table.
+            if let Some(candidate) = entry.take(ORDERING) {
+                if !clusters.contains(&candidate.page.cluster) {
+                    // The candidate is kept, so put it back into the entry.
+                    entry.swap(candidate, ORDERING);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use disk::cluster;
 
     #[test]
     fn duplicate() {
-        let mut table = Table::default();
+        let table = Table::default();
         let p1 = page::Pointer {
-            cksum: 7,
+            checksum: 7,
             .. Default::default()
         };
         let p2 = page::Pointer {
-            cksum: 13,
+            checksum: 13,
             .. Default::default()
         };
 
-        table.insert(&Default::default(), p1);
-        table.insert(&Default::default(), p2);
+        let fingerprint = Fingerprint::new(&[0; disk::SECTOR_SIZE]);
+        table.insert(fingerprint, p1);
+        table.insert(fingerprint, p2);
 
-        assert_eq!(table.dedup(&Default::default(), 7), p1);
-        assert_eq!(table.dedup(&Default::default(), 13), p2);
+        assert_eq!(table.dedup(fingerprint, 7), Some(p1));
+        assert_eq!(table.dedup(fingerprint, 13), Some(p2));
     }
 
     #[test]
     fn checksum_collision() {
-        let mut table = Table::default();
+        let table = Table::default();
         let p1 = page::Pointer {
-            cksum: 7,
+            checksum: 7,
             .. Default::default()
         };
         let p2 = page::Pointer {
-            cksum: 7,
+            checksum: 7,
             cluster: cluster::Pointer::new(100).unwrap(),
             .. Default::default()
         };
 
-        table.insert([0; disk::SECTOR_SIZE], p1);
-        table.insert([1; disk::SECTOR_SIZE], p2);
+        table.insert(Fingerprint::new(&[0; disk::SECTOR_SIZE]), p1);
+        table.insert(Fingerprint::new(&[1; disk::SECTOR_SIZE]), p2);
+
+        assert_eq!(table.dedup(Fingerprint::new(&[1; disk::SECTOR_SIZE]), 7), Some(p2));
+        assert_eq!(table.dedup(Fingerprint::new(&[0; disk::SECTOR_SIZE]), 7), None);
+    }
+
+    #[test]
+    fn relocate() {
+        let table = Table::default();
+        let p1 = page::Pointer {
+            checksum: 7,
+            cluster: cluster::Pointer::new(100).unwrap(),
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            checksum: 7,
+            cluster: cluster::Pointer::new(200).unwrap(),
+            .. Default::default()
+        };
+
+        let fingerprint = Fingerprint::new(&[0; disk::SECTOR_SIZE]);
+        table.insert(fingerprint, p1);
+        table.relocate(p1, p2);
+
+        assert_eq!(table.dedup(fingerprint, 7), Some(p2));
+    }
+
+    #[test]
+    fn forget() {
+        let table = Table::default();
+        let p1 = page::Pointer {
+            checksum: 7,
+            cluster: cluster::Pointer::new(100).unwrap(),
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            checksum: 8,
+            cluster: cluster::Pointer::new(200).unwrap(),
+            .. Default::default()
+        };
+
+        let fingerprint = Fingerprint::new(&[0; disk::SECTOR_SIZE]);
+        table.insert(fingerprint, p1);
+        table.insert(fingerprint, p2);
+        table.forget(&[p1.cluster].iter().cloned().collect());
+
+        assert_eq!(table.dedup(fingerprint, 7), None);
+        assert_eq!(table.dedup(fingerprint, 8), Some(p2));
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        let a = Fingerprint::new(&buf);
+        assert_eq!(a, Fingerprint::new(&buf));
 
-        assert_eq!(table.dedup(&Default::default(), 7), p2);
+        buf[200] = 1;
+        assert!(a != Fingerprint::new(&buf));
     }
 }
@@ -0,0 +1,122 @@
+//! Framing of compressed clusters.
+//!
+//! A compressed cluster stores a compressed stream, which is generally shorter than the cluster.
+//! To be able to tell the stream apart from the padding, the stream is framed by a small header:
+//!
+//! 1. Byte 0-2: The compression algorithm option (little-endian), which the stream was compressed
+//!    with.
+//! 2. Byte 2-4: The length of the compressed stream in bytes (little-endian).
+//! 3. The compressed stream.
+//! 4. Zero padding up to the cluster size.
+//!
+//! The framing is bijective: Every stream (short enough to fit) has exactly one encoding, and
+//! decoding rejects every cluster, which is not the encoding of some stream (e.g. because of a
+//! non-zero padding).
+
+use little_endian;
+use disk::{self, cluster};
+use Error;
+
+/// The size of the frame header.
+pub const HEADER_SIZE: usize = 4;
+/// The maximal length of a compressed stream in a cluster.
+pub const MAX_STREAM_LEN: usize = disk::SECTOR_SIZE - HEADER_SIZE;
+
+/// Frame a compressed stream.
+///
+/// This encodes compressed stream `stream` (compressed through compression algorithm option
+/// `algorithm`) into a cluster. If the stream is too long to fit, `None` is returned.
+pub fn encode(algorithm: u16, stream: &[u8]) -> Option<Box<disk::SectorBuf>> {
+    if stream.len() > MAX_STREAM_LEN {
+        // The stream doesn't fit.
+        return None;
+    }
+
+    // Zero-initialize the buffer, meaning that the padding is already set.
+    let mut buf = Box::new([0; disk::SECTOR_SIZE]);
+
+    // Write the header.
+    little_endian::write(&mut buf[..], algorithm);
+    little_endian::write(&mut buf[2..], stream.len() as u16);
+    // Write the stream itself.
+    // TODO: Find a way to eliminate this memcpy.
+    buf[HEADER_SIZE..][..stream.len()].copy_from_slice(stream);
+
+    Some(buf)
+}
+
+/// Unframe a compressed stream.
+///
+/// This decodes the framing of cluster `cluster` with content `buf`, and returns the compression
+/// algorithm option and the compressed stream. If the framing is invalid, an error mentioning
+/// `cluster` is returned.
+pub fn decode(cluster: cluster::Pointer, buf: &disk::SectorBuf) -> Result<(u16, &[u8]), Error> {
+    // Read the header.
+    let algorithm = little_endian::read(&buf[..]);
+    let len: u16 = little_endian::read(&buf[2..]);
+    let len = len as usize;
+
+    if len > MAX_STREAM_LEN {
+        // The length exceeds the cluster, so the cluster is corrupt.
+        return Err(err!(Corruption, "invalid compressed stream length {} in cluster {:?}",
+                        len, cluster));
+    }
+
+    // Split the stream and the padding.
+    let (stream, padding) = buf[HEADER_SIZE..].split_at(len);
+
+    if let Some(pos) = padding.iter().position(|&x| x != 0) {
+        // The padding contains non-zero data, which is not allowed in order to keep the framing
+        // bijective.
+        return Err(err!(Corruption, "non-zero padding at byte {} in cluster {:?}",
+                        HEADER_SIZE + len + pos, cluster));
+    }
+
+    Ok((algorithm, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error;
+
+    fn cluster() -> cluster::Pointer {
+        cluster::Pointer::new(42).unwrap()
+    }
+
+    #[test]
+    fn inverse_identity() {
+        for stream in &[&b""[..], &b"abc"[..], &b"\0\0\0"[..], &[0xFF; MAX_STREAM_LEN][..]] {
+            let buf = encode(1, stream).unwrap();
+            assert_eq!(decode(cluster(), &buf).unwrap(), (1, *stream));
+        }
+    }
+
+    #[test]
+    fn trailing_zeros() {
+        // Streams ending in zeros are distinct from streams without them, which the old delimiter
+        // scheme got wrong.
+        let a = encode(1, b"a").unwrap();
+        let b = encode(1, b"a\0").unwrap();
+
+        assert!(a[..] != b[..]);
+        assert_eq!(decode(cluster(), &a).unwrap().1, b"a");
+        assert_eq!(decode(cluster(), &b).unwrap().1, b"a\0");
+    }
+
+    #[test]
+    fn too_long() {
+        assert!(encode(1, &[0; MAX_STREAM_LEN + 1]).is_none());
+
+        let mut buf = [0; disk::SECTOR_SIZE];
+        little_endian::write(&mut buf[2..], MAX_STREAM_LEN as u16 + 1);
+        assert_eq!(decode(cluster(), &buf).unwrap_err().kind, error::Kind::Corruption);
+    }
+
+    #[test]
+    fn non_zero_padding() {
+        let mut buf = encode(1, b"abc").unwrap();
+        buf[disk::SECTOR_SIZE - 1] = 1;
+        assert_eq!(decode(cluster(), &buf).unwrap_err().kind, error::Kind::Corruption);
+    }
+}
@@ -0,0 +1,395 @@
+//! Garbage collection of clusters.
+//!
+//! Copy-on-write means that every mutation leaves the old pages behind, and once no committed
+//! superpage refers to them, they're garbage. The garbage collector finds and frees such clusters
+//! through mark-and-sweep:
+//!
+//! 1. The reachable set (a Bloom filter) is cleared, and the candidates of the cycle are chosen.
+//! 2. Everything reachable from the superpage is marked, by inserting its clusters into the
+//!    reachable set (through `fs::Object::visit()`).
+//! 3. Every candidate, which is definitely not in the reachable set, is freed.
+//!
+//! The cycle runs concurrently with the rest of the system. Pages allocated while a cycle is
+//! running are marked on allocation, and clusters allocated after the previous cycle started are
+//! not candidates ("young"), as they may hold pages, which are still being linked into the object
+//! graph. Consequently, an object must be committed within one cycle of its allocation.
+//!
+//! Bloom filters have false positives, but no false negatives, so a live cluster is never freed,
+//! while a dead cluster is occasionally kept until a later cycle.
+
+use std::collections::HashSet;
+use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
+use std::{mem, thread};
+
+use Error;
+use disk::cluster;
+
+/// The atomic ordering used for the progress counters.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+
+/// The state of the tracker.
+#[derive(Default)]
+struct Generations {
+    /// The clusters, which are candidates of the next cycle.
+    old: HashSet<cluster::Pointer>,
+    /// The clusters allocated (or discovered) since the current cycle started.
+    ///
+    /// These will become candidates in the cycle after the next one.
+    young: HashSet<cluster::Pointer>,
+    /// The candidates of the running cycle.
+    ///
+    /// This is `None` if no cycle is running.
+    candidates: Option<HashSet<cluster::Pointer>>,
+}
+
+/// The tracker of allocated clusters.
+///
+/// The freelist only tells which clusters are free, so the allocated clusters must be tracked
+/// separately, in order to know which clusters to sweep. Clusters are tracked from their
+/// allocation, or from the first time they're marked, if they were allocated before the system
+/// was opened.
+#[derive(Default)]
+pub struct Tracker {
+    /// The tracked clusters.
+    generations: Mutex<Generations>,
+}
+
+impl Tracker {
+    /// Track a newly allocated cluster.
+    pub fn allocate(&self, cluster: cluster::Pointer) {
+        self.generations.lock().unwrap().young.insert(cluster);
+    }
+
+    /// Track a marked cluster, if it isn't already tracked.
+    pub fn discover(&self, cluster: cluster::Pointer) {
+        let mut generations = self.generations.lock().unwrap();
+
+        if !generations.old.contains(&cluster)
+            && !generations.candidates.as_ref().map_or(false, |x| x.contains(&cluster)) {
+            generations.young.insert(cluster);
+        }
+    }
+
+    /// Make a tracked cluster young again.
+    ///
+    /// This must be called when new references to an existing cluster are handed out (e.g. by
+    /// deduplication), as they may not be linked into the object graph before the next cycle.
+    /// The cluster is taken out of the candidates of the running cycle, if any, so it isn't freed.
+    pub fn rejuvenate(&self, cluster: cluster::Pointer) {
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.old.remove(&cluster);
+        if let Some(ref mut candidates) = generations.candidates {
+            candidates.remove(&cluster);
+        }
+        generations.young.insert(cluster);
+    }
+
+    /// Stop tracking a cluster.
+    ///
+    /// This must be called when a cluster is freed by other means than the collector (e.g. by
+    /// compaction), so it won't be freed twice.
+    pub fn forget(&self, cluster: cluster::Pointer) {
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.old.remove(&cluster);
+        generations.young.remove(&cluster);
+        if let Some(ref mut candidates) = generations.candidates {
+            candidates.remove(&cluster);
+        }
+    }
+
+    /// Start a cycle.
+    ///
+    /// The old clusters become the candidates, and the young clusters become old.
+    pub fn begin_cycle(&self) {
+        let mut generations = self.generations.lock().unwrap();
+        let young = mem::replace(&mut generations.young, HashSet::new());
+        let old = mem::replace(&mut generations.old, young);
+
+        generations.candidates = Some(old);
+    }
+
+    /// End a cycle.
+    ///
+    /// The candidates, which weren't freed, are kept for the next cycle.
+    pub fn end_cycle(&self) {
+        let mut generations = self.generations.lock().unwrap();
+
+        if let Some(candidates) = generations.candidates.take() {
+            generations.old.extend(candidates);
+        }
+    }
+
+    /// Sweep the candidates of the running cycle.
+    ///
+    /// `is_live` tells if a cluster is (possibly) live. The clusters, which are not, are first
+    /// passed to `forget` (which must remove every way of obtaining new references to them, such
+    /// as deduplication), then checked once again (as they could have been referenced in the
+    /// meantime), and finally passed to `free`. The number of examined and freed clusters is
+    /// returned.
+    ///
+    /// This ends the cycle.
+    pub fn sweep<L, F, G>(&self, is_live: L, forget: F, mut free: G) -> (usize, usize)
+    where L: Fn(cluster::Pointer) -> bool,
+          F: FnOnce(&HashSet<cluster::Pointer>),
+          G: FnMut(cluster::Pointer) {
+        // Find the garbage among the candidates.
+        let (scanned, garbage) = match self.generations.lock().unwrap().candidates {
+            Some(ref candidates) => (candidates.len(), candidates.iter().cloned()
+                .filter(|&x| !is_live(x)).collect::<HashSet<_>>()),
+            None => (0, HashSet::new()),
+        };
+
+        forget(&garbage);
+
+        let mut freed = 0;
+        for cluster in garbage {
+            // Take the cluster out of the candidates, unless it was forgotten (freed by others)
+            // in the meantime, or became reachable.
+            let taken = !is_live(cluster) && self.generations.lock().unwrap().candidates.as_mut()
+                .map_or(false, |x| x.remove(&cluster));
+
+            if taken {
+                free(cluster);
+                freed += 1;
+            }
+        }
+
+        self.end_cycle();
+
+        (scanned, freed)
+    }
+}
+
+/// Garbage collection options.
+#[derive(Clone, Copy)]
+pub struct Options {
+    /// The pause between cycles.
+    pub interval: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A report of the garbage collection progress.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// The number of completed cycles.
+    pub cycles: u64,
+    /// The number of clusters examined.
+    pub clusters_scanned: u64,
+    /// The number of clusters freed.
+    pub clusters_freed: u64,
+}
+
+/// The progress counters of the garbage collection.
+#[derive(Default)]
+pub struct Progress {
+    /// The number of completed cycles.
+    cycles: atomic::AtomicUsize,
+    /// The number of clusters examined.
+    clusters_scanned: atomic::AtomicUsize,
+    /// The number of clusters freed.
+    clusters_freed: atomic::AtomicUsize,
+}
+
+impl Progress {
+    /// Count a completed cycle, which examined `scanned` clusters and freed `freed` clusters.
+    pub fn cycle(&self, scanned: usize, freed: usize) {
+        self.cycles.fetch_add(1, ORDERING);
+        self.clusters_scanned.fetch_add(scanned, ORDERING);
+        self.clusters_freed.fetch_add(freed, ORDERING);
+    }
+
+    /// Take a snapshot of the counters.
+    pub fn report(&self) -> Report {
+        Report {
+            cycles: self.cycles.load(ORDERING) as u64,
+            clusters_scanned: self.clusters_scanned.load(ORDERING) as u64,
+            clusters_freed: self.clusters_freed.load(ORDERING) as u64,
+        }
+    }
+}
+
+/// The background garbage collector.
+///
+/// This drives the cycles in a background thread, and keeps track of the progress.
+pub struct Collector {
+    /// The garbage collection options.
+    pub options: Options,
+    /// The progress counters.
+    pub progress: Progress,
+    /// Has the collector been requested to stop?
+    stop: atomic::AtomicBool,
+}
+
+impl Collector {
+    /// Create a new collector with some options.
+    pub fn new(options: Options) -> Collector {
+        Collector {
+            options: options,
+            progress: Progress::default(),
+            stop: atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Spawn the background thread.
+    ///
+    /// This runs `cycle` repeatedly in a new thread, pausing for the interval given in the options
+    /// between every cycle, until `self.stop()` is called. If a cycle fails, the thread stops and
+    /// returns the error.
+    pub fn spawn<F>(self: Arc<Self>, cycle: F) -> thread::JoinHandle<Result<(), Error>>
+    where F: Fn(&Collector) -> Result<(), Error> + Send + 'static {
+        thread::spawn(move || {
+            while !self.stop.load(ORDERING) {
+                cycle(&self)?;
+
+                // Don't hog the I/O by collecting continuously.
+                thread::park_timeout(self.options.interval);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Request the background thread to stop.
+    ///
+    /// The thread will stop after the current cycle has completed.
+    pub fn stop(&self) {
+        self.stop.store(true, ORDERING);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbloom;
+
+    fn cluster(n: u64) -> cluster::Pointer {
+        cluster::Pointer::new(n).unwrap()
+    }
+
+    /// Run a cycle with some live clusters, and return the freed clusters.
+    fn cycle(tracker: &Tracker, live: &[u64]) -> Vec<u64> {
+        let reachable = cbloom::Filter::new(1024, 1000);
+        for &n in live {
+            reachable.insert(n);
+        }
+
+        let mut freed = Vec::new();
+        tracker.begin_cycle();
+        tracker.sweep(|x| reachable.maybe_contains(x.into()), |_| (), |x| freed.push(x.into()));
+        freed.sort();
+
+        freed
+    }
+
+    #[test]
+    fn young_clusters_survive() {
+        let tracker = Tracker::default();
+        tracker.allocate(cluster(1));
+
+        // The cluster is young in the first cycle.
+        assert!(cycle(&tracker, &[]).is_empty());
+        // ... but not in the second.
+        assert_eq!(cycle(&tracker, &[]), [1]);
+        // It was freed, so it is no longer tracked.
+        assert!(cycle(&tracker, &[]).is_empty());
+    }
+
+    #[test]
+    fn live_clusters_are_never_freed() {
+        let tracker = Tracker::default();
+        for n in 1..1000 {
+            tracker.allocate(cluster(n));
+        }
+        tracker.begin_cycle();
+        tracker.end_cycle();
+
+        // Some dead clusters may be kept due to false positives, but no live cluster may be
+        // freed.
+        let live: Vec<_> = (1..1000).filter(|x| x % 3 == 0).collect();
+        let freed = cycle(&tracker, &live);
+        assert!(!freed.is_empty());
+        assert!(freed.iter().all(|x| x % 3 != 0));
+
+        // The live clusters are still tracked.
+        assert_eq!(cycle(&tracker, &[]).len(), 999 - freed.len());
+    }
+
+    #[test]
+    fn forgotten_clusters() {
+        let tracker = Tracker::default();
+        tracker.allocate(cluster(1));
+        tracker.allocate(cluster(2));
+        tracker.begin_cycle();
+        tracker.end_cycle();
+
+        // Freed by compaction in the middle of the cycle.
+        tracker.begin_cycle();
+        let mut freed = Vec::new();
+        tracker.sweep(|_| false, |garbage| {
+            assert_eq!(garbage.len(), 2);
+            tracker.forget(cluster(1));
+        }, |x| freed.push(x));
+        assert_eq!(freed, [cluster(2)]);
+    }
+
+    #[test]
+    fn rejuvenated_clusters() {
+        let tracker = Tracker::default();
+        tracker.allocate(cluster(1));
+        tracker.allocate(cluster(2));
+        tracker.begin_cycle();
+        tracker.end_cycle();
+
+        // Referenced again while being an old cluster.
+        tracker.rejuvenate(cluster(1));
+        assert_eq!(cycle(&tracker, &[]), [2]);
+        assert_eq!(cycle(&tracker, &[]), [1]);
+
+        // Referenced again while being a candidate of the running cycle.
+        tracker.allocate(cluster(3));
+        tracker.begin_cycle();
+        tracker.end_cycle();
+        tracker.begin_cycle();
+        tracker.rejuvenate(cluster(3));
+        let mut freed = Vec::new();
+        tracker.sweep(|_| false, |_| (), |x| freed.push(x));
+        assert!(freed.is_empty());
+        assert!(cycle(&tracker, &[]).is_empty());
+        assert_eq!(cycle(&tracker, &[]), [3]);
+    }
+
+    #[test]
+    fn discovered_clusters() {
+        let tracker = Tracker::default();
+        tracker.discover(cluster(1));
+        tracker.discover(cluster(1));
+
+        assert!(cycle(&tracker, &[1]).is_empty());
+        tracker.discover(cluster(1));
+        assert!(cycle(&tracker, &[1]).is_empty());
+        assert_eq!(cycle(&tracker, &[]), [1]);
+    }
+
+    #[test]
+    fn progress() {
+        let progress = Progress::default();
+        progress.cycle(10, 2);
+        progress.cycle(5, 1);
+
+        assert_eq!(progress.report(), Report {
+            cycles: 2,
+            clusters_scanned: 15,
+            clusters_freed: 3,
+        });
+    }
+}
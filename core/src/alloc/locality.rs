@@ -0,0 +1,199 @@
+//! Locality-aware allocation.
+//!
+//! By default, the allocator packs pages into whichever cluster the current thread last used,
+//! meaning that pages of unrelated objects get interleaved when allocated from the same thread.
+//! Locality hints allow the caller to group related pages (e.g. the pages of a single file), by
+//! keeping a separate open cluster per hint. Related pages thus share clusters, which both
+//! improves the compression ratio and reduces the number of reads for sequential access.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use alloc::page;
+
+/// The default maximal number of simultaneously open hinted clusters.
+pub const DEFAULT_MAX_OPEN: usize = 1024;
+
+/// A locality hint.
+///
+/// Pages allocated with the same hint are packed together when possible. The value is chosen by
+/// the caller, and typically identifies an object or stream (e.g. a file).
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Hint(pub u64);
+
+impl From<page::Pointer> for Hint {
+    fn from(ptr: page::Pointer) -> Hint {
+        // Collisions are harmless, as they merely make unrelated pages share clusters.
+        Hint(u64::from(ptr.cluster).rotate_left(16) ^ ptr.offset.map_or(0, |x| u64::from(x) + 1))
+    }
+}
+
+/// An entry in the table of open clusters.
+struct Entry<T> {
+    /// The time of the last use, measured by the table's clock.
+    last_use: u64,
+    /// The open cluster of the hint.
+    ///
+    /// This has its own lock, so allocations with different hints don't block each other.
+    open: Arc<Mutex<Option<T>>>,
+}
+
+/// The table of open clusters, indexed by locality hint.
+///
+/// To bound the memory use, the number of open clusters is limited. When the limit is exceeded,
+/// the least recently used hint is evicted, meaning that its next allocation will start a new
+/// cluster.
+pub struct OpenClusters<T> {
+    /// The maximal number of open clusters.
+    max_open: usize,
+    /// The entries and the clock used to track the recency of use.
+    entries: Mutex<(u64, HashMap<Hint, Entry<T>>)>,
+}
+
+impl<T> OpenClusters<T> {
+    /// Create a table with some limit of open clusters.
+    pub fn new(max_open: usize) -> OpenClusters<T> {
+        OpenClusters {
+            max_open: max_open,
+            entries: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Get the open cluster of some hint.
+    ///
+    /// This runs `f` with the open cluster of `hint` (`None` if the hint has no open cluster),
+    /// and returns the output of `f`.
+    pub fn with<F, R>(&self, hint: Hint, f: F) -> R
+    where F: FnOnce(&mut Option<T>) -> R {
+        // Look up the entry, and update its recency.
+        let open = {
+            let mut entries = self.entries.lock().unwrap();
+            let (ref mut clock, ref mut map) = *entries;
+            *clock += 1;
+
+            let entry = map.entry(hint).or_insert_with(|| Entry {
+                last_use: 0,
+                open: Arc::new(Mutex::new(None)),
+            });
+            entry.last_use = *clock;
+
+            entry.open.clone()
+        };
+
+        // Run the closure with the lock of the entry, but not the table, held.
+        let mut open = open.lock().unwrap();
+        f(&mut open)
+    }
+
+    /// Evict the least recently used hints, until the limit is no longer exceeded.
+    ///
+    /// The open clusters of the evicted hints are returned. Hints, which are in use by
+    /// `self.with()` at the time, are never evicted, as their open cluster might be replaced after
+    /// it was taken, leaving the new (pinned) cluster behind in an entry, which isn't in the table.
+    pub fn evict(&self) -> Vec<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let map = &mut entries.1;
+        let mut evicted = Vec::new();
+
+        while map.len() > self.max_open {
+            // Find the least recently used hint, which isn't in use. Entries are only shared
+            // while they're in use, and they're only handed out with the table locked, so they
+            // cannot become used, while we hold the lock.
+            let hint = match map.iter()
+                .filter(|&(_, entry)| Arc::strong_count(&entry.open) == 1)
+                .min_by_key(|&(_, entry)| entry.last_use) {
+                Some((&hint, _)) => hint,
+                // Every entry is in use, so we'll try again on the next eviction.
+                None => break,
+            };
+
+            // Remove it and take its cluster.
+            let entry = map.remove(&hint).unwrap();
+            let mut open = entry.open.lock().unwrap();
+            evicted.extend(open.take());
+        }
+
+        evicted
+    }
+
+    /// Close the open cluster of some hint.
+    ///
+    /// This should be called when no more pages are expected with `hint` (e.g. when the file is
+    /// closed). The open cluster is returned.
+    pub fn close(&self, hint: Hint) -> Option<T> {
+        self.entries.lock().unwrap().1.remove(&hint).and_then(|entry| {
+            entry.open.lock().unwrap().take()
+        })
+    }
+}
+
+impl<T> Default for OpenClusters<T> {
+    fn default() -> OpenClusters<T> {
+        OpenClusters::new(DEFAULT_MAX_OPEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separate_hints() {
+        let table = OpenClusters::default();
+
+        table.with(Hint(1), |open| {
+            assert!(open.is_none());
+            *open = Some(1);
+        });
+        table.with(Hint(2), |open| {
+            assert!(open.is_none());
+            *open = Some(2);
+        });
+
+        assert_eq!(table.with(Hint(1), |open| *open), Some(1));
+        assert_eq!(table.with(Hint(2), |open| *open), Some(2));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let table = OpenClusters::new(2);
+
+        table.with(Hint(1), |open| *open = Some(1));
+        table.with(Hint(2), |open| *open = Some(2));
+        assert!(table.evict().is_empty());
+
+        // Use 1, so that 2 becomes the least recently used.
+        table.with(Hint(1), |_| ());
+        table.with(Hint(3), |open| *open = Some(3));
+
+        assert_eq!(table.evict(), [2]);
+        assert!(table.evict().is_empty());
+        assert_eq!(table.with(Hint(2), |open| *open), None);
+    }
+
+    #[test]
+    fn evict_skips_hints_in_use() {
+        let table = OpenClusters::new(1);
+
+        table.with(Hint(1), |open| {
+            *open = Some(1);
+            // This makes 1 the least recently used hint, but as it is still in use, 2 is evicted
+            // instead.
+            table.with(Hint(2), |open| *open = Some(2));
+            assert_eq!(table.evict(), [2]);
+        });
+
+        assert!(table.evict().is_empty());
+        assert_eq!(table.with(Hint(1), |open| *open), Some(1));
+    }
+
+    #[test]
+    fn close() {
+        let table = OpenClusters::default();
+
+        table.with(Hint(1), |open| *open = Some(1));
+        assert_eq!(table.close(Hint(1)), Some(1));
+        assert_eq!(table.close(Hint(1)), None);
+        assert_eq!(table.with(Hint(1), |open| *open), None);
+    }
+}
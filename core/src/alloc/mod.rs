@@ -11,15 +11,21 @@
 //! The allocator is a basic unrolled list of clusters.
 
 mod dedup;
+pub mod compact;
+pub mod compress;
+pub mod frame;
+pub mod gc;
+pub mod locality;
 pub mod page;
 pub mod state_block;
 
 use crossbeam::sync::SegQueue;
 use futures::{future, Future};
 use std::mem;
-use std::sync::atomic;
+use std::sync::{atomic, Arc, Mutex};
 use disk::{self, cluster, Disk};
-use {little_endian, lz4_compress, thread_object, Error};
+use metrics::{self, Counter, Histogram};
+use {little_endian, thread_object, Error};
 
 /// The atomic ordering used in the allocator.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
@@ -32,15 +38,31 @@ const CLUSTERS_IN_FREELIST_NODE: u64 = disk::SECTOR_SIZE / cluster::POINTER_SIZE
 /// When an allocator system is provided, the user must provide some option, so they can adjust the
 /// behavior to their needs. This struct contain the parameters used to construct the allocator
 /// system.
-struct Options {
+pub struct Options {
     /// The options from the state block.
-    state_block: state_block::Options,
+    pub state_block: state_block::Options,
     /// The options from the disk header.
-    disk_header: disk::header::Options,
+    pub disk_header: disk::header::Options,
+    /// The compression algorithms to make available.
+    ///
+    /// If the state block options specify an implementation-defined compression algorithm, it
+    /// must be registered here.
+    pub codecs: compress::Registry,
+    /// The metrics sink to report to.
+    pub metrics: metrics::Metrics,
 
     // In the future, allocator specific options may be added here.
 }
 
+/// The content of the on-disk freelist.
+#[derive(Default)]
+pub struct Freelist {
+    /// The metaclusters, i.e. the clusters storing the freelist itself.
+    pub metaclusters: Vec<cluster::Pointer>,
+    /// The free clusters.
+    pub free: Vec<cluster::Pointer>,
+}
+
 /// The state of some cluster.
 ///
 /// This caches a cluster uncompressed such that there is no need for decompression when appending
@@ -68,28 +90,48 @@ pub struct Allocator<D> {
     /// This is the state as stored in the state block. The reason we do not store the whole state
     /// block in one is that, we want to avoid the lock when reading the static parts of the state
     /// block (e.g. configuration).
-    state: conc::sync::Stm<state_block::State>,
+    ///
+    /// The lock is held while the state block is flushed, so the flushes happen in the same order
+    /// as the updates.
+    state: Mutex<state_block::State>,
     /// The configuration options.
     ///
     /// This is the configuration part of the state block. We don't need a lock, since we won't
     /// mutate it while the system is initialized.
     options: state_block::Options,
+    /// The compression algorithm in use.
+    ///
+    /// This is looked up in the compression algorithm registry through the compression algorithm
+    /// option when the allocator is opened.
+    compressor: Arc<compress::Compressor>,
     /// The free-cache.
     ///
     /// This contains some number of pointers to free clusters, allowing multiple threads to
     /// efficiently allocate simultaneously.
-    free: conc::sync::Treiber<cluster::Pointer>,
+    free: SegQueue<cluster::Pointer>,
     /// The last allocated cluster for this thread.
     ///
     /// If possible, newly allocated pages will be appended to this cluster. When it is filled
     /// (i.e. the pages cannot compress to the cluster size or less), a new cluster will be
     /// allocated.
     last_cluster: thread_object::Object<Option<ClusterState>>,
+    /// The open clusters of the locality hints.
+    ///
+    /// Pages allocated with a locality hint are packed into the open cluster of the hint, rather
+    /// than the last allocated cluster of the thread.
+    hinted_clusters: locality::OpenClusters<ClusterState>,
     /// The deduplication table.
     ///
     /// This table allows the allocator for searching for candidates to use instead of allocating a
     /// new cluster. In particular, it searches for duplicates of the allocated page.
     dedup_table: dedup::Table,
+    /// The clusters which must not be compacted.
+    ///
+    /// This contains the open clusters (the last allocated cluster of every thread), as well as
+    /// the clusters which had pages deduplicated during a compaction cycle.
+    pins: compact::Pins,
+    /// The clusters tracked by the garbage collector.
+    gc: gc::Tracker,
 }
 
 impl<D: Disk> Allocator<D> {
@@ -97,49 +139,109 @@ impl<D: Disk> Allocator<D> {
     ///
     /// This future creates a future, which loads the state page and other things from a the disk
     /// `disk`. If it fails, the future will return an error.
-    pub fn open(disk: D) -> future!(Allocator<D>) {
+    ///
+    /// If `read_only` is set, nothing is written to the disk. `codecs` provides the compression
+    /// algorithms. If the system uses an implementation-defined compression algorithm, which is
+    /// not registered in `codecs`, an error is returned. The metrics are reported to `metrics`.
+    pub fn open(
+        disk: D,
+        read_only: bool,
+        codecs: &compress::Registry,
+        metrics: metrics::Metrics,
+    ) -> future!(Allocator<D>) {
         // Initialize the disk and cache.
-        let cache = disk::open(disk);
+        let cache = disk::open(disk, read_only, metrics);
         // Read the state block.
-        cache.read(0).map(|state_block| {
+        cache.read(0).and_then(|state_block| {
             // Parse the state block.
-            let state_block::StateBlock { state, options } =
-                state_block::StateBlock::decode(state_block, cache.disk_header().checksum_algorithm);
-
-            // I'm sure you're smart enough to figure out what is happening here. I trust you ^^.
-            Allocator {
-                cache: cache,
-                state: conc::sync::Stm::new(state),
-                options: options,
-                free: SegQueue::new(),
-                last_cluster: thread_object::Object::default(),
-                dedup_table: dedup::Table::default(),
-            }
+            let state_block = state_block::StateBlock::decode(
+                &state_block,
+                cache.disk_header().options.checksum_algorithm,
+            )?;
+
+            Allocator::load(cache, state_block, codecs)
         })
     }
 
+    /// Set up an allocator from an opened disk and its decoded state block.
+    ///
+    /// This is used by `Allocator::open()`, and by offline tools, which read the state block
+    /// themselves. `codecs` provides the compression algorithms (see `Allocator::open()`).
+    pub fn load(
+        cache: disk::TfsDisk<D>,
+        state_block: state_block::StateBlock,
+        codecs: &compress::Registry,
+    ) -> Result<Allocator<D>, Error> {
+        // Find the compression algorithm.
+        let compressor = codecs.get(state_block.options.compression_algorithm)?;
+
+        Ok(Allocator::new(cache, state_block.state, state_block.options, compressor))
+    }
+
+    /// Set up an allocator around an opened disk.
+    fn new(
+        cache: disk::TfsDisk<D>,
+        state: state_block::State,
+        options: state_block::Options,
+        compressor: Arc<compress::Compressor>,
+    ) -> Allocator<D> {
+        // I'm sure you're smart enough to figure out what is happening here. I trust you ^^.
+        Allocator {
+            cache: cache,
+            state: Mutex::new(state),
+            options: options,
+            compressor: compressor,
+            free: SegQueue::new(),
+            last_cluster: thread_object::Object::default(),
+            hinted_clusters: locality::OpenClusters::default(),
+            dedup_table: dedup::Table::default(),
+            pins: compact::Pins::default(),
+            gc: gc::Tracker::default(),
+        }
+    }
+
     /// Initialize a new system given a set of options.
     ///
     /// This uses the parameters from `options` to initialize a new, empty system on the disk
     /// `disk`. This doesn't open the disk, as `Allocator::open()` does: Instead it creates a new
     /// fresh system, ignoring the existing data.
     ///
+    /// The disk header is written, every cluster but the state block is put in the freelist, and
+    /// the state block is written last, so the system cannot be opened until it is complete. No
+    /// superpage is set.
+    ///
     /// The initialization is complete when the returned future completes.
     pub fn init(disk: D, options: Options) -> future!(Allocator<D>) {
-        unimplemented!();
+        // Find the compression algorithm before touching the disk, so we don't leave behind a
+        // half-initialized system if it isn't registered.
+        let compressor = match options.codecs.get(options.state_block.compression_algorithm) {
+            Ok(compressor) => compressor,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let state_block = options.state_block;
 
         // Initialize the disk (below the allocator stack).
-        disk::init(disk, options.disk_header).and_then(|cache| {
-            // Write the state block to the start of the disk.
-            cache.write(0, options.state_block.encode()).map(|_| cache)
-        }).map(|cache| Allocator {
-            cache: cache,
-            state: conc::sync::Stm::new(state),
-            options: options.state_block,
-            free: SegQueue::new(),
-            last_cluster: thread_object::Object::default(),
-            dedup_table: dedup::Table::default(),
-        })
+        future::Either::B(disk::init(disk, options.disk_header, options.metrics)
+            .and_then(move |cache| {
+                let alloc = Allocator::new(cache, state_block::State {
+                    superpage: None,
+                    freelist_head: None,
+                }, state_block, compressor);
+                info!(alloc, "initializing the freelist"; "clusters" => alloc.number_of_clusters());
+
+                // Every cluster but the state block (cluster 0) is free.
+                let free = (1..alloc.number_of_clusters()).filter_map(cluster::Pointer::new)
+                    .collect();
+
+                {
+                    let mut state = alloc.state.lock().unwrap();
+                    state.freelist_head = alloc.write_freelist(free, None)?;
+                    // Write the state block to the start of the disk.
+                    alloc.flush_state_block(&state).wait()?;
+                }
+
+                Ok(alloc)
+            }))
     }
 
     /// Allocate a page in a new cluster.
@@ -164,7 +266,11 @@ impl<D: Disk> Allocator<D> {
         cksum: u32,
     ) -> future!(page::Pointer) {
         // Pop the cluster from the freelist, then attempt to compress the data.
-        self.freelist_pop().and_then(|cluster| if let Some(compressed) = self.compress(buf) {
+        self.freelist_pop().and_then(|cluster| {
+            // Track the cluster, so the garbage collector can free it once it becomes garbage.
+            self.gc.allocate(cluster);
+            Ok(cluster)
+        }).and_then(|cluster| if let Some((compressed, len)) = self.compress(buf) {
             // We were able to compress the page to fit into the cluster. At first, compressing the
             // first page seems unnecessary as it is guaranteed to fit in without compression, but
             // it has a purpose: namely that it allows us to extend the cluster. Enabling
@@ -173,14 +279,20 @@ impl<D: Disk> Allocator<D> {
             // there is no change in how the other pages are read.
             trace!(self, "storing compressible page in cluster"; "cluster" => cluster);
 
-            // Update the "last cluster" state variable to point to the new cluster.
-            last_cluster = Some(ClusterState {
+            // Update the "last cluster" state variable to point to the new cluster. The new
+            // cluster is pinned, so it won't be compacted while we're appending to it, and the
+            // old one is unpinned.
+            self.pins.open(cluster);
+            if let Some(old) = mem::replace(last_cluster, Some(ClusterState {
                 cluster: cluster,
                 // So far, it only contains one page.
-                uncompressed: buf.as_vec(),
-            });
+                uncompressed: buf.to_vec(),
+            })) {
+                self.pins.close(old.cluster);
+            }
 
             // Write the compressed data into the cluster.
+            self.record_compression(disk::SECTOR_SIZE, len);
             self.cache.write(cluster, compressed).map(|_| page::Pointer {
                 cluster: cluster,
                 offset: Some(0),
@@ -213,7 +325,8 @@ impl<D: Disk> Allocator<D> {
     /// Allocate a page eagerly and without deduplication.
     ///
     /// This allocates buffer `buf` with checksum (as calculated by `self.checksum()`) `cksum`, and
-    /// returns the page pointer wrapped in a future.
+    /// returns the page pointer wrapped in a future. If `hint` is given, the page is packed into
+    /// the open cluster of the hint.
     ///
     /// This **does not** update the deduplication table, nor does it try to look for duplicates.
     /// Futhermore, some of the logic acts eagerly, and thus it ought to be wrapped in
@@ -222,51 +335,99 @@ impl<D: Disk> Allocator<D> {
         &self,
         buf: Box<disk::SectorBuf>,
         cksum: u32,
+        hint: Option<locality::Hint>,
     ) -> future!(page::Pointer) {
         // Handle the case where compression is disabled.
         if self.options.compression_algorithm == state_block::CompressionAlgorithm::Identity {
             // Pop a cluster from the freelist.
-            return self.freelist_pop()
+            return future::Either::A(self.freelist_pop()
+                .map(move |cluster| {
+                    // Track the cluster, so the garbage collector can free it once it becomes
+                    // garbage.
+                    self.gc.allocate(cluster);
+                    cluster
+                })
                 // Write the cluster with the raw, uncompressed data.
                 .and_then(|cluster| self.cache.write(cluster, buf).map(|_| cluster))
                 .map(|cluster| page::Pointer {
                     cluster: cluster,
                     offset: None,
                     checksum: cksum,
-                });
+                }));
         }
 
         // If you have followed this path, compression is enabled (we won't use `else` in order to
         // flatten the code).
 
-        self.last_cluster.with(|last_cluster| {
-            if let Some(state) = last_cluster {
-                // We have earlier allocated a cluster, meaning that we can potentially append
-                // more pages into the cluster.
-                trace!(self, "extending existing cluster";
-                       "old length" => state.uncompressed.len());
-
-                // Extend the buffer of uncompressed data in the last allocated cluster.
-                state.uncompressed.extend_from_slice(buf);
-
-                // Try to compress the extended buffer into a single cluster.
-                if let Some(compressed) = self.compress(state.uncompressed) {
-                    // It succeeded! Write the compressed data into the cluster.
-                    return self.cache.write(state.cluster, compressed).map(|_| page::Pointer {
-                        cluster: state.cluster,
+        if let Some(hint) = hint {
+            // Pack the page into the open cluster of the hint.
+            let page = self.hinted_clusters.with(hint, |open| self.alloc_packed(buf, cksum, open));
+
+            // Opening a new hint might have exceeded the limit of open clusters. The evicted
+            // clusters are no longer appended to, and can thus be unpinned.
+            for state in self.hinted_clusters.evict() {
+                self.pins.close(state.cluster);
+            }
+
+            future::Either::B(future::Either::A(page))
+        } else {
+            // Pack the page into this thread's last allocated cluster.
+            future::Either::B(future::Either::B(self.last_cluster.with(|last_cluster| {
+                self.alloc_packed(buf, cksum, last_cluster)
+            })))
+        }
+    }
+
+    /// Allocate a page by packing it into some open cluster.
+    ///
+    /// This tries to append `buf` (with checksum `cksum`) to the open cluster `open`. If there is
+    /// no open cluster, or the page doesn't fit, a new cluster is allocated, and `open` is updated
+    /// accordingly.
+    ///
+    /// This **does not** update the deduplication table.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if compression is disabled.
+    fn alloc_packed(
+        &self,
+        buf: Box<disk::SectorBuf>,
+        cksum: u32,
+        open: &mut Option<ClusterState>,
+    ) -> future!(page::Pointer) {
+        if let Some(ref mut state) = *open {
+            // We have earlier allocated a cluster, meaning that we can potentially append more
+            // pages into the cluster.
+            let old_len = state.uncompressed.len();
+            trace!(self, "extending existing cluster"; "old length" => old_len);
+
+            // Extend the buffer of uncompressed data in the open cluster.
+            state.uncompressed.extend_from_slice(&buf[..]);
+
+            // Try to compress the extended buffer into a single cluster.
+            if let Some((compressed, len)) = self.compress(&state.uncompressed) {
+                // It succeeded! Write the compressed data into the cluster.
+                let cluster = state.cluster;
+                self.record_compression(state.uncompressed.len(), len);
+                return future::Either::A(self.cache.write(cluster, compressed).map(|_| {
+                    page::Pointer {
+                        cluster: cluster,
                         // The offset is determined by simple division to get the number of
-                        // sectors the uncompressed buffer spans.
-                        offset: Some(state.uncompressed.len() / disk::SECTOR_SIZE),
+                        // sectors the uncompressed buffer spanned before the page was appended.
+                        offset: Some((old_len / disk::SECTOR_SIZE) as u32),
                         checksum: cksum,
-                    });
-                }
+                    }
+                }));
             }
 
-            // We were unable to extend the last allocated cluster, either because there is no
-            // last allocated cluster, or because the cluster could not contain the page. We'll
-            // allocate a new cluster to contain our page.
-            self.alloc_in_new_cluster(buf, last_cluster)
-        })
+            // Revert the extension, as the page didn't fit.
+            state.uncompressed.truncate(old_len);
+        }
+
+        // We were unable to extend the open cluster, either because there is no open cluster, or
+        // because the cluster could not contain the page. We'll allocate a new cluster to contain
+        // our page.
+        future::Either::B(self.alloc_in_new_cluster(buf, open, cksum))
     }
 
     /// Allocate a page.
@@ -276,7 +437,42 @@ impl<D: Disk> Allocator<D> {
     ///
     /// The algorithm works greedily by fitting as many pages as possible into the most recently
     /// used cluster.
-    pub fn alloc(&mut self, buf: Box<disk::SectorBuf>) -> future!(page::Pointer) {
+    pub fn alloc(&self, buf: Box<disk::SectorBuf>) -> future!(page::Pointer) {
+        self.alloc_hinted(buf, None)
+    }
+
+    /// Allocate a page near related pages.
+    ///
+    /// This is similar to `self.alloc()`, but rather than packing the page into the most recently
+    /// used cluster of the thread, it is packed into the most recently used cluster of locality
+    /// hint `hint`. Related pages (e.g. of the same file) should be allocated with the same hint,
+    /// so they share clusters.
+    pub fn alloc_near(
+        &self,
+        buf: Box<disk::SectorBuf>,
+        hint: locality::Hint,
+    ) -> future!(page::Pointer) {
+        self.alloc_hinted(buf, Some(hint))
+    }
+
+    /// Close a locality hint.
+    ///
+    /// This should be called when no more pages are expected to be allocated with `hint`, so its
+    /// open cluster can be released.
+    pub fn close_hint(&self, hint: locality::Hint) {
+        trace!(self, "closing locality hint"; "hint" => hint.0);
+
+        if let Some(state) = self.hinted_clusters.close(hint) {
+            self.pins.close(state.cluster);
+        }
+    }
+
+    /// Allocate a page with an optional locality hint.
+    fn alloc_hinted(
+        &self,
+        buf: Box<disk::SectorBuf>,
+        hint: Option<locality::Hint>,
+    ) -> future!(page::Pointer) {
         // TODO: The variables are named things like `ptr`, which kinda contradicts the style of
         //       the rest of the code.
 
@@ -284,29 +480,97 @@ impl<D: Disk> Allocator<D> {
         let cksum = self.checksum(buf) as u32;
         debug!(self, "allocating page"; "checksum" => cksum);
 
-        // Check if duplicate exists. This isn't wrapped in a future, because it isn't an I/O
-        // operation.
-        if let Some(page) = self.dedup_table.dedup(buf, cksum) {
-            debug!(self, "found duplicate page"; "page" => page);
-            // Deduplicate and simply use the already stored page.
-            return Ok(page);
-        }
+        // Fingerprint the buffer. This is used both for searching for duplicates and for inserting
+        // the allocated page into the deduplication table afterwards, so we only calculate it
+        // once.
+        let fingerprint = dedup::Fingerprint::new(&buf);
+
+        // Find and verify a duplicate, if any, and then either use the duplicate or allocate.
+        self.find_duplicate(&buf, fingerprint, cksum).and_then(|duplicate| {
+            if let Some(page) = duplicate {
+                debug!(self, "found duplicate page"; "page" => page);
+                self.cache.metrics().increment(Counter::DedupHits);
+
+                // The page is now referenced from a place, which a running compaction cycle
+                // doesn't know about, so we must make sure it isn't moved.
+                self.pins.touch(page.cluster);
+                // For the same reason, the garbage collector must not free it, even if it is an
+                // old cluster with no other references, so we make it young again.
+                self.gc.rejuvenate(page.cluster);
+
+                // Deduplicate and simply use the already stored page.
+                return future::Either::A(future::ok(page));
+            }
 
-        // To make sure the operations are executed in correct sequence (directly after each
-        // other), we use a lazy evaluated future.
-        future::lazy(|| {
-            // Do the core of the allocation.
-            self.alloc_eager(buf, cksum)
-        }).map(|page| {
-            // Insert the page pointer into the deduplication table to allow future use as
-            // duplicate.
-            self.dedup_table.insert(buf, page);
-
-            // Return the allocated pointer.
-            page
+            // To make sure the operations are executed in correct sequence (directly after each
+            // other), we use a lazy evaluated future.
+            future::Either::B(future::lazy(|| {
+                // Do the core of the allocation.
+                self.alloc_eager(buf, cksum, hint)
+            }).map(|page| {
+                self.cache.metrics().increment(Counter::PagesAllocated);
+
+                // Insert the page pointer into the deduplication table to allow future use as
+                // duplicate.
+                self.dedup_table.insert(fingerprint, page);
+
+                // Return the allocated pointer.
+                page
+            }))
         })
     }
 
+    /// Find a duplicate of some page.
+    ///
+    /// This looks up a duplicate of the data `buf` (with fingerprint `fingerprint` and checksum
+    /// `cksum`) in the deduplication table, and verifies it according to the deduplication
+    /// verification policy from the options. The future yields `None` if no (verified) duplicate
+    /// exists.
+    fn find_duplicate(
+        &self,
+        buf: &disk::SectorBuf,
+        fingerprint: dedup::Fingerprint,
+        cksum: u32,
+    ) -> future!(Option<page::Pointer>) {
+        // Check if a candidate exists. This isn't wrapped in a future, because it isn't an I/O
+        // operation.
+        let candidate = match self.dedup_table.dedup(fingerprint, cksum) {
+            Some(candidate) => candidate,
+            // No candidate, so there is nothing to verify.
+            None => return future::Either::A(future::ok(None)),
+        };
+
+        match self.options.dedup_verification {
+            // The fingerprints matched, and we trust that to be sufficient.
+            state_block::DedupVerification::Fingerprint => future::Either::A(future::ok(Some(candidate))),
+            // We need to read the candidate back and compare it byte-for-byte.
+            state_block::DedupVerification::Strict => {
+                trace!(self, "verifying deduplication candidate"; "page" => candidate);
+
+                future::Either::B(self.read(candidate).then(|res| Ok(match res {
+                    // The data matched, so the candidate is indeed a duplicate.
+                    Ok(ref found) if found[..] == buf[..] => Some(candidate),
+                    // The data mismatched (i.e. a fingerprint collision). We fall back to a normal
+                    // allocation.
+                    Ok(_) => {
+                        warn!(self, "fingerprint collision in deduplication table";
+                              "page" => candidate);
+
+                        None
+                    },
+                    // The candidate could not be read (e.g. it is corrupt). We don't want the
+                    // allocation to fail because of an unrelated page, so we allocate normally.
+                    Err(err) => {
+                        warn!(self, "failed to read deduplication candidate";
+                              "page" => candidate, "error" => err);
+
+                        None
+                    },
+                })))
+            },
+        }
+    }
+
     /// Read/dereference a page.
     ///
     /// This reads page `page` and returns the content, wrapped in a future.
@@ -323,12 +587,21 @@ impl<D: Disk> Allocator<D> {
                 // The page is compressed, decompress it and read at some offset `offset` (in pages).
 
                 // Decompress the cluster.
-                let decompressed = self.decompress(cluster)?;
+                let decompressed = self.decompress(page.cluster, &cluster)?;
+
+                // Make sure that the page is within the decompressed stream.
+                let start = offset as usize * disk::SECTOR_SIZE;
+                if start + disk::SECTOR_SIZE > decompressed.len() {
+                    return Err(err!(Corruption, "page offset {} out of bounds in cluster {:?} \
+                                    ({} pages)", offset, page.cluster,
+                                    decompressed.len() / disk::SECTOR_SIZE)
+                               .with_cluster(page.cluster.into()));
+                }
 
                 // Read the decompressed stream from some offset, into a sector buffer.
                 let mut tmp = disk::SectorBuf::default();
                 // TODO: Find a way to eliminate this memcpy.
-                tmp.copy_from_slice(decompressed[offset * disk::SECTOR_SIZE..][..disk::SECTOR_SIZE]);
+                tmp.copy_from_slice(&decompressed[start..][..disk::SECTOR_SIZE]);
 
                 tmp
             } else {
@@ -338,16 +611,67 @@ impl<D: Disk> Allocator<D> {
 
             // Check the data against the stored checksum.
             let cksum = self.checksum(buf) as u32;
-            if cksum as u32 != page.checksum {
+            if cksum != page.checksum {
                 // The checksums mismatched, thrown an error.
-                Err(err!(Corruption, "mismatching checksums in {} - expected {:x}, found {:x}",
-                         page, page.checksum, cksum));
+                Err(match page.offset {
+                    Some(offset) => err!(Corruption, "mismatching checksums in cluster {:?}, page \
+                                         offset {} - expected {:x}, found {:x}", page.cluster,
+                                         offset, page.checksum, cksum),
+                    None => err!(Corruption, "mismatching checksums in uncompressed cluster {:?} \
+                                 - expected {:x}, found {:x}", page.cluster, page.checksum,
+                                 cksum),
+                }.with_cluster(page.cluster.into()))
             } else {
                 Ok(buf)
             }
         })
     }
 
+    /// Get the configuration options of the state block.
+    pub fn options(&self) -> &state_block::Options {
+        &self.options
+    }
+
+    /// Get the number of clusters of the disk.
+    pub fn number_of_clusters(&self) -> u64 {
+        self.cache.number_of_sectors() as u64
+    }
+
+    /// Get the metrics, which the I/O stack reports to.
+    pub fn metrics(&self) -> &metrics::Metrics {
+        self.cache.metrics()
+    }
+
+    /// Get the disk header.
+    pub fn disk_header(&self) -> &disk::header::DiskHeader {
+        self.cache.disk_header()
+    }
+
+    /// Get the superpage pointer of the state block.
+    ///
+    /// If no superpage is initialized, `None` is returned.
+    pub fn superpage(&self) -> Option<page::Pointer> {
+        self.state.lock().unwrap().superpage
+    }
+
+    /// Set the superpage pointer of the state block.
+    ///
+    /// This points the state block to `superpage`, and flushes it. The state block is a single
+    /// sector, so the update is atomic: If the system crashes, the state block points to either
+    /// the old or the new superpage.
+    ///
+    /// The superpage (and every page reachable from it) must have been written before this is
+    /// called.
+    pub fn set_superpage(&self, superpage: page::Pointer) -> future!(()) {
+        debug!(self, "updating the superpage pointer"; "superpage" => superpage);
+
+        future::lazy(move || {
+            let mut state = self.state.lock().unwrap();
+            state.superpage = Some(superpage);
+            self.flush_state_block(&state).wait()
+        })
+    }
+
     /// Calculate the checksum of some buffer, based on the user choice.
     fn checksum(&self, buf: &disk::SectorBuf) -> u64 {
         trace!(self, "calculating checksum");
@@ -357,223 +681,609 @@ impl<D: Disk> Allocator<D> {
 
     /// Compress some data based on the compression option.
     ///
+    /// The framed cluster is returned along with the length of the compressed stream.
+    ///
     /// # Panics
     ///
     /// This will panic if compression is disabled.
-    fn compress(&self, input: &[u8]) -> Option<Box<disk::SectorBuf>> {
+    fn compress(&self, input: &[u8]) -> Option<(Box<disk::SectorBuf>, usize)> {
         trace!(self, "compressing data");
 
-        // Compress the input.
-        let compressed = match self.options.compression_algorithm {
-            // We'll panic if compression is disabled, as it is assumed that the caller handles
-            // this case.
-            state_block::CompressionAlgorithm::Identity => panic!("Compression was disabled."),
-            // Compress via LZ4.
-            state_block::CompressionAlgorithm::Lz4 => lz4_compress::compress(input),
-        };
+        // We'll panic if compression is disabled, as it is assumed that the caller handles this
+        // case.
+        assert!(self.options.compression_algorithm != state_block::CompressionAlgorithm::Identity,
+                "Compression was disabled.");
 
-        if compressed.len() < disk::SECTOR_SIZE {
-            // We were able to compress the input into at least one cluster. Now, we apply padding.
+        // Compress the input through the chosen algorithm.
+        let compressed = self.compressor.compress(input);
 
-            // Write a delimiter to make the padding distinguishable from the actual data (e.g. if
-            // it ends in zero).
-            // TODO: This is not bijective. Very bad! FAKE NEWS
-            compressed.push(0xFF);
+        // Frame the compressed stream, so it can be distinguished from the padding. If the stream
+        // is too long, we were unable to compress the input into one cluster, and `None` is
+        // returned.
+        frame::encode(u16::from(self.options.compression_algorithm), &compressed)
+            .map(|buf| (buf, compressed.len()))
+    }
 
-            // Convert it to type `disk::SectorBuf`.
-            let mut buf = disk::SectorBuf::default();
-            // TODO: Find a way to eliminate this memcpy.
-            buf[..compressed.len()].copy_from_slice(&compressed);
-        } else {
-            // We were unable to compress the input into one cluster.
-            None
-        }
+    /// Report the compression ratio of a cluster to the metrics.
+    ///
+    /// `uncompressed` bytes of pages were compressed into a stream of `compressed` bytes.
+    fn record_compression(&self, uncompressed: usize, compressed: usize) {
+        self.cache.metrics().record(Histogram::CompressionRatio,
+                                    (compressed * 1000 / uncompressed) as u64);
     }
 
     /// Decompress some data based on the compression option.
     ///
+    /// This decompresses the content `buf` of cluster `cluster`. The cluster number is only used
+    /// for error reporting.
+    ///
     /// # Panics
     ///
     /// This will panic if compression is disabled.
-    fn decompress(&self, cluster: Box<disk::SectorBuf>) -> Result<Box<[u8]>, Error> {
-        trace!(self, "decompressing data");
-
-        // Find the padding delimited (i.e. the last non-zero byte).
-        if let Some((len, _)) = cluster.enumerate().rev().find(|(_, x)| x != 0) {
-            // We found the delimiter and can now distinguish padding from data.
-            Ok(match self.options.compression_algorithm {
-                // We'll panic if compression is disabled, as it is assumed that the caller handles
-                // this case.
-                state_block::CompressionAlgorithm::Identity => panic!("Compression was disabled."),
-                // Decompress the non-padding section from LZ4.
-                state_block::CompressionAlgorithm::Lz4 => lz4_compress::decompress(cluster[..len])?,
-            })
-        } else {
-            // No delimiter was found, indicating data corruption.
-            // TODO: Provide the sector number.
-            Err(err!(Corruption, "invalid compression"))
+    fn decompress(
+        &self,
+        cluster: cluster::Pointer,
+        buf: &disk::SectorBuf,
+    ) -> Result<Box<[u8]>, Error> {
+        trace!(self, "decompressing data"; "cluster" => cluster);
+
+        // We'll panic if compression is disabled, as it is assumed that the caller handles this
+        // case.
+        assert!(self.options.compression_algorithm != state_block::CompressionAlgorithm::Identity,
+                "Compression was disabled.");
+
+        // Unframe the compressed stream.
+        let (algorithm, stream) = frame::decode(cluster, buf)?;
+
+        // Make sure that the cluster was compressed with the algorithm we're using.
+        if algorithm != u16::from(self.options.compression_algorithm) {
+            return Err(err!(Corruption, "cluster {:?} compressed with algorithm {:x}, expected \
+                            {:x}", cluster, algorithm,
+                            u16::from(self.options.compression_algorithm))
+                       .with_cluster(cluster.into()));
+        }
+
+        // Decompress the stream through the chosen algorithm.
+        let decompressed = self.compressor.decompress(stream).map_err(|err| {
+            err!(Corruption, "invalid compressed stream in cluster {:?}: {:?}", cluster, err)
+                .with_cluster(cluster.into())
+        })?;
+
+        // The decompressed stream must consist of whole pages.
+        if decompressed.len() % disk::SECTOR_SIZE != 0 {
+            return Err(err!(Corruption, "decompressed cluster {:?} has length {}, which is not a \
+                            multiple of the page size", cluster, decompressed.len())
+                       .with_cluster(cluster.into()));
         }
+
+        Ok(decompressed.into_boxed_slice())
+    }
+
+    /// Start a compaction cycle.
+    ///
+    /// From now on, clusters which get referenced are pinned, so they won't be compacted. This
+    /// must be called before the census is taken, and the cycle is ended by
+    /// `self.finish_compaction()` or `self.abort_compaction()`.
+    pub fn begin_compaction(&self) {
+        info!(self, "starting compaction cycle");
+
+        self.pins.begin_cycle();
+    }
+
+    /// Abort a compaction cycle.
+    ///
+    /// This is used if the census or the relocation fails. Nothing is freed, and the pages moved
+    /// by the cycle are left for the garbage collector.
+    pub fn abort_compaction(&self) {
+        warn!(self, "aborting compaction cycle");
+
+        self.pins.end_cycle();
+    }
+
+    /// Compact some clusters.
+    ///
+    /// This examines the clusters of `census` (the live pages, grouped by cluster) and repacks
+    /// the live pages of the sparse or uncompressed clusters into fewer clusters. At most
+    /// `compactor.options.clusters_per_cycle` clusters are examined, and pinned clusters are
+    /// skipped. The cycle must have been started by `self.begin_compaction()` before the census
+    /// was taken.
+    ///
+    /// The returned relocations must be applied to every referrer of the moved pages (through
+    /// `fs::Object::relocate()`) before being passed to `self.finish_compaction()`, which frees the
+    /// old clusters. Until then, the old pages remain valid.
+    ///
+    /// This blocks, as it is meant to be run from the background compaction thread.
+    pub fn compact(
+        &self,
+        census: compact::Census,
+        compactor: &compact::Compactor,
+    ) -> Result<compact::Relocations, Error> {
+        let mut relocations = compact::Relocations::default();
+
+        if self.options.compression_algorithm == state_block::CompressionAlgorithm::Identity {
+            // Without compression, every cluster holds exactly one page, so there is nothing to
+            // gain from compaction.
+            return Ok(relocations);
+        }
+
+        debug!(self, "repacking clusters"; "clusters" => census.len());
+
+        // The cluster we pack the moved pages into. We intentionally don't use the thread's
+        // last allocated cluster, as that would interleave the moved pages with new data.
+        let mut packing = None;
+
+        for (cluster, pages) in census.into_iter().take(compactor.options.clusters_per_cycle) {
+            compactor.progress.scanned();
+
+            if self.pins.is_pinned(cluster)
+                || !self.is_sparse(cluster, &pages, compactor.options.max_fill)? {
+                // Either the cluster is in use, or it isn't worth repacking.
+                continue;
+            }
+
+            trace!(self, "repacking cluster"; "cluster" => cluster, "live pages" => pages.len());
+
+            // Move every live page into the packing cluster.
+            for &page in &pages {
+                let buf = self.read(page).wait()?;
+                let new = self.alloc_packed(Box::new(*buf), page.checksum, &mut packing).wait()?;
+                relocations.insert(page, new);
+            }
+
+            // Once the relocations are applied, the old cluster can be freed.
+            relocations.obsolete.push(cluster);
+            compactor.progress.compacted(pages.len());
+        }
+
+        // Unpin the packing cluster, if any.
+        if let Some(state) = packing {
+            self.pins.close(state.cluster);
+        }
+
+        Ok(relocations)
+    }
+
+    /// Finish a compaction cycle.
+    ///
+    /// This must be called when `relocations` (as returned by `self.compact()`) have been applied
+    /// to every referrer. It updates the deduplication table and frees the obsolete clusters.
+    pub fn finish_compaction(
+        &self,
+        relocations: compact::Relocations,
+        compactor: &compact::Compactor,
+    ) {
+        // Point the deduplication table to the new pages, as the old ones are about to be freed.
+        for (from, to) in relocations.iter() {
+            self.dedup_table.relocate(from, to);
+        }
+
+        // Free the old clusters. They must not be freed again by the garbage collector.
+        for &cluster in &relocations.obsolete {
+            self.gc.forget(cluster);
+            self.freelist_push(cluster);
+        }
+        compactor.progress.freed(relocations.obsolete.len());
+
+        // Stop tracking referenced clusters.
+        self.pins.end_cycle();
+
+        info!(self, "finished compaction cycle"; "freed clusters" => relocations.obsolete.len());
+    }
+
+    /// Note that a cluster was marked during garbage collection.
+    ///
+    /// This makes sure that clusters allocated before the system was opened are tracked.
+    pub fn discover(&self, cluster: cluster::Pointer) {
+        self.gc.discover(cluster);
+    }
+
+    /// Start a garbage collection cycle.
+    ///
+    /// This chooses the candidates of the cycle. It must be called before the marking starts.
+    pub fn begin_collection(&self) {
+        info!(self, "starting garbage collection cycle");
+
+        self.gc.begin_cycle();
+    }
+
+    /// Abort a garbage collection cycle.
+    ///
+    /// This is used if the marking fails. The candidates are kept for the next cycle.
+    pub fn abort_collection(&self) {
+        warn!(self, "aborting garbage collection cycle");
+
+        self.gc.end_cycle();
+    }
+
+    /// Finish a garbage collection cycle by sweeping.
+    ///
+    /// This frees every candidate of the cycle, which `is_reachable` tells is definitely
+    /// unreachable, and ends the cycle. Clusters which are in use by the allocator are kept.
+    ///
+    /// This blocks, as it is meant to be run from the background collection thread.
+    pub fn sweep<F>(&self, is_reachable: F, collector: &gc::Collector) -> Result<(), Error>
+    where F: Fn(cluster::Pointer) -> bool {
+        let mut trims = Vec::new();
+
+        let (scanned, freed) = self.gc.sweep(|cluster| {
+            is_reachable(cluster) || self.pins.is_pinned(cluster)
+        }, |garbage| {
+            // Make sure that the garbage isn't handed out as duplicates, before freeing it.
+            self.dedup_table.forget(garbage);
+        }, |cluster| {
+            trace!(self, "freeing unreachable cluster"; "cluster" => cluster);
+            self.cache.metrics().increment(Counter::ClustersFreed);
+
+            trims.push(self.cache.trim(cluster));
+            self.freelist_push(cluster);
+        });
+
+        collector.progress.cycle(scanned, freed);
+        info!(self, "finished garbage collection cycle"; "scanned clusters" => scanned,
+              "freed clusters" => freed);
+
+        future::join_all(trims).wait().map(|_| ())
+    }
+
+    /// Check if a cluster should be repacked.
+    ///
+    /// A compressed cluster should be repacked if the fraction of its pages, which are live (given
+    /// by `pages`) is below `max_fill` percent. An uncompressed cluster should be repacked if its
+    /// page is compressible, as it could then share cluster with other pages.
+    fn is_sparse(
+        &self,
+        cluster: cluster::Pointer,
+        pages: &[page::Pointer],
+        max_fill: u32,
+    ) -> Result<bool, Error> {
+        self.cache.read_then(cluster, |buf| Ok(if pages.iter().any(|page| page.offset.is_none()) {
+            // The cluster is uncompressed.
+            self.compress(&buf[..]).is_some()
+        } else {
+            // The cluster is compressed. Count the number of pages it contains.
+            let total = self.decompress(cluster, &buf)?.len() / disk::SECTOR_SIZE;
+
+            (pages.len() * 100) < total * max_fill as usize
+        })).wait()
     }
 
     /// Flush the state block.
     ///
     /// This creates a future, which will flush the state block when executed.
     ///
-    /// It takes a mutable reference to the state in order to avoid clogging up the transaction and
-    /// flushing asynchronized.
-    fn flush_state_block(&mut self, state: &mut state_block::State) -> future!(()) {
+    /// The caller must hold the lock of `self.state`, so concurrent flushes cannot be reordered.
+    fn flush_state_block(&self, state: &state_block::State) -> future!(()) {
         trace!(self, "flushing the state block");
 
         // Encode and write to virtual sector 0, the state block's sector.
-        self.cache.write(0, state_block::StateBlock {
+        self.cache.write(0, Box::new(state_block::StateBlock {
             options: self.options,
-            state: state,
-        }.encode())
+            state: *state,
+        }.encode(self.disk_header().options.checksum_algorithm)))
     }
 
     /// Pop from the freelist.
     ///
     /// This returns a future, which wraps a cluster pointer popped from the freelist.
-    fn freelist_pop(&mut self) -> future!(page::Pointer) {
+    ///
+    /// The allocation is reported to the metrics.
+    fn freelist_pop(&self) -> future!(cluster::Pointer) {
         // In order to avoid eager evaluation (and potentially prematurely exhausting the
         // freelist), we use lazy popping by constructing the future when evaluated.
-        future::lazy(|| {
+        future::lazy(move || {
             trace!(self, "popping from freelist");
 
-            if let Some(free) = self.free.pop() {
+            if let Some(free) = self.free.try_pop() {
                 // We had a cluster in the free-cache.
-                free
-            } else {
-                // We were unable to pop from the free-cache, so we must grab the next metacluster
-                // and load it.
-
-                // Lock the state.
-                self.state.with(|state| {
-                    // Grab the next metacluster. If no other metacluster exists, we return an
-                    // error.
-                    let head = state.freelist_head.ok_or(err!(OutOfSpace, "out of free clusters"))?;
-                    // Load the new metacluster, and return the old metacluster.
-                    self.cache.read_then(head.cluster, |buf| {
-                        // Check that the checksum matches.
-                        let found = self.checksum(buf);
-                        if head.checksum != found {
-                            // Checksums do not match; throw an error.
-                            return Err(err!(Corruption, "mismatching checksums in metacluster {:x} \
-                                            - expected {:x}, found {:x}", head.cluster,
-                                            head.checksum, found));
-                        }
-
-                        // Now, we'll replace the old head metacluster with the chained
-                        // metacluster.
-                        trace!(self, "metacluster checksum matched"; "checksum" => found);
-
-                        // Replace the checksum of the head metacluster with the checksum of the
-                        // chained metacluster, which will soon be the head metacluster.
-                        head.checksum = little_endian::read(buf);
-                        // We'll initialize a window iterating over the pointers in this
-                        // metacluster.
-                        let mut window = &buf[cluster::POINTER_SIZE..];
-                        // The first pointer points to the chained metacluster.
-                        let old_head = mem::replace(&mut head.cluster, little_endian::read(window));
-
-                        // It is absolutely crucial that we don't simply push directly to
-                        // `self.free` as we're in an atomic transaction, which can potentially be
-                        // run multiple times. Hence, such behavior could cause weird bugs such as
-                        // double free.
-                        let mut free = Vec::with_capacity(CLUSTERS_IN_FREELIST_NODE);
-                        // The rest are free.
-                        while window.len() >= 8 {
-                            // Slide the window to the right.
-                            window = &window[cluster::POINTER_SIZE..];
-                            // Read the pointer.
-                            if let Some(cluster) = little_endian::read(window) {
-                                // There was anohter pointer in the metacluster, push it to the
-                                // free-cache.
-                                free.push(cluster)
-                            } else {
-                                // The pointer was a null pointer, so the metacluster is over.
-                                break;
-                            }
-                        }
-
-                        // Finally we push the old head to the vector, as it is free now.
-                        free.push(old_head);
-
-                        // Trim the old metacluster.
-                        self.cache.trim(old_head).map(|_| free)
-                    })
-                }).and_then(|free| {
-                    // Finally, we must flush the state block before we can add the found clutters
-                    // to the free cache.
-                    // FIXME: The `map` here is ugly.
-                    self.flush_state_block(state).map(|_| free)
-                }).map(|free| {
-                    // At this point, the transaction have run and the state block is flushed.
-
-                    // Push every (except one) element of our temporary vector of free clusters.
-                    for i in free[1..] {
-                        self.free.push(i);
-                    }
+                return Ok(free);
+            }
 
-                    // We use the last cluster as the popped cluster.
-                    free[0]
-                })
+            // We were unable to pop from the free-cache, so we must grab the next metacluster
+            // and load it. Lock the state, so only one thread loads it.
+            let mut state = self.state.lock().unwrap();
+            // Another thread might have loaded a metacluster while we waited for the lock.
+            if let Some(free) = self.free.try_pop() {
+                return Ok(free);
             }
+
+            self.load_metacluster(&mut state)
+        }).map(move |cluster| {
+            self.cache.metrics().increment(Counter::ClustersAllocated);
+            cluster
         })
     }
 
+    /// Move the head metacluster of the freelist into the free-cache.
+    ///
+    /// This pops the head metacluster from the on-disk freelist `state`, flushes the state block,
+    /// and pushes the free clusters of the metacluster to the free-cache. The metacluster itself
+    /// is free now, and is returned. If the freelist is empty, an error is returned.
+    ///
+    /// This blocks, and must be called with the lock of `self.state` held.
+    fn load_metacluster(&self, state: &mut state_block::State) -> Result<cluster::Pointer, Error> {
+        // Grab the next metacluster. If no other metacluster exists, we return an error.
+        let head = state.freelist_head.ok_or(err!(OutOfSpace, "out of free clusters"))?;
+        let buf = self.cache.read_then(head.cluster, |buf| Ok(*buf)).wait()?;
+
+        // Check that the checksum matches.
+        let found = self.checksum(&buf);
+        if head.checksum != found {
+            return Err(err!(Corruption, "mismatching checksums in metacluster {:x} - expected \
+                            {:x}, found {:x}", u64::from(head.cluster), head.checksum, found)
+                       .with_cluster(head.cluster.into()));
+        }
+        trace!(self, "metacluster checksum matched"; "checksum" => found);
+
+        // The first pointer points to the chained metacluster, which will be the new head. The
+        // checksum of the chained metacluster precedes it.
+        state.freelist_head = little_endian::read::<Option<cluster::Pointer>>(
+            &buf[cluster::POINTER_SIZE..]
+        ).map(|cluster| state_block::FreelistHead {
+            cluster: cluster,
+            checksum: little_endian::read(&buf),
+        });
+
+        // The state block must be flushed before any of the clusters are handed out, as they
+        // would otherwise still be in the on-disk freelist after a crash.
+        self.flush_state_block(state).wait()?;
+
+        // The rest are free, terminated by a null pointer, if the metacluster isn't full.
+        for window in buf[2 * cluster::POINTER_SIZE..].chunks(cluster::POINTER_SIZE) {
+            match little_endian::read(window) {
+                Some(cluster) => self.free.push(cluster),
+                None => break,
+            }
+        }
+
+        // The old metacluster is free now, so we use it as the popped cluster.
+        Ok(head.cluster)
+    }
+
     /// Push to the freelist.
     ///
     /// No I/O logic happens, since pushes are buffered.
-    fn freelist_push(&mut self, cluster: cluster::Pointer) {
+    fn freelist_push(&self, cluster: cluster::Pointer) {
         trace!(self, "pushing to freelist"; "cluster" => cluster);
 
         // Push the cluster to the freelist.
         self.free.push(cluster);
     }
 
-    pub fn flush_free(&self) {
-        // TODO: Important!! Remember to trim the sectors which gets dealloc'd. This can be done
-        //       through `self.cache.trim`
+    /// Read the on-disk freelist.
+    ///
+    /// This walks the chain of metaclusters, checks every metacluster against its checksum, and
+    /// adds its content to `freelist`. If a metacluster is corrupt, an error is returned, and
+    /// `freelist` holds the content of the metaclusters before it.
+    ///
+    /// The free clusters buffered in memory are not included. This blocks, as it is meant to be
+    /// used by offline tools.
+    pub fn walk_freelist(&self, freelist: &mut Freelist) -> Result<(), Error> {
+        let mut next = self.state.lock().unwrap().freelist_head;
+
+        while let Some(head) = next {
+            // Every metacluster is a distinct cluster, so a longer chain must contain a cycle.
+            if freelist.metaclusters.len() as u64 >= self.number_of_clusters() {
+                return Err(err!(Corruption, "the freelist contains a cycle")
+                           .with_cluster(head.cluster.into()));
+            }
 
-        /* TODO (buggy and incomplete)
-        let state = self.state.lock();
-        let mut (cluster, cksum) = state.freelist_head.map_or(|x| (x.cluster, x.checksum), (0, 0));
+            let buf = self.cache.read_then(head.cluster, |buf| Ok(*buf)).wait()?;
 
-        let mut buf = disk::SectorBuf::default();
-        let mut window = 8 + cluster::POINTER_SIZE;
-        while let Some(free) = self.free.pop() {
-            if window == disk::SECTOR_SIZE {
-                little_endian::write(&mut buf, cksum);
-                little_endian::write(&mut buf[cluster::POINTER_SIZE..], cluster);
+            // Check that the checksum matches.
+            let found = self.checksum(&buf);
+            if head.checksum != found {
+                return Err(err!(Corruption, "mismatching checksums in metacluster {:x} - \
+                                expected {:x}, found {:x}", head.cluster, head.checksum, found)
+                           .with_cluster(head.cluster.into()));
+            }
+            freelist.metaclusters.push(head.cluster);
+
+            // The free clusters follow the checksum and the pointer of the chained metacluster,
+            // terminated by a null pointer, if the metacluster isn't full.
+            for window in buf[2 * cluster::POINTER_SIZE..].chunks(cluster::POINTER_SIZE) {
+                match little_endian::read(window) {
+                    Some(cluster) => freelist.free.push(cluster),
+                    None => break,
+                }
+            }
 
-                cluster = free;
-                cksum = self.checksum(buf);
+            // Go to the chained metacluster, if any.
+            next = little_endian::read::<Option<cluster::Pointer>>(&buf[cluster::POINTER_SIZE..])
+                .map(|cluster| state_block::FreelistHead {
+                    cluster: cluster,
+                    checksum: little_endian::read(&buf),
+                });
+        }
 
-                self.cache.write(cluster, &buf)?;
+        Ok(())
+    }
 
-                window = 8 + cluster::POINTER_SIZE;
-            } else {
-                little_endian::write(&mut buf[window..], x);
-                window += cluster::POINTER_SIZE;
+    /// Replace the on-disk freelist.
+    ///
+    /// This writes a new chain of metaclusters holding exactly the clusters of `free` (some of
+    /// which are used as the metaclusters), and points the state block to it. It is used to repair
+    /// the freelist from the set of reachable clusters.
+    ///
+    /// This blocks, as it is meant to be used by offline tools.
+    pub fn rebuild_freelist(&mut self, free: Vec<cluster::Pointer>) -> Result<(), Error> {
+        info!(self, "rebuilding the freelist"; "free clusters" => free.len());
+
+        let head = self.write_freelist(free, None)?;
+
+        // Point the state block to the new freelist. We have exclusive access, so the state can be
+        // updated without locking.
+        let state = self.state.get_mut().unwrap();
+        state.freelist_head = head;
+        self.cache.write(0, Box::new(state_block::StateBlock {
+            options: self.options,
+            state: *state,
+        }.encode(self.cache.disk_header().options.checksum_algorithm))).wait()
+    }
+
+    /// Write a chain of metaclusters.
+    ///
+    /// This writes a new chain of metaclusters holding exactly the clusters of `free` (some of
+    /// which are used as the metaclusters), followed by the existing chain `tail`, and returns its
+    /// head. Nothing refers to the chain, until the state block is pointed to it.
+    ///
+    /// This blocks.
+    fn write_freelist(
+        &self,
+        mut free: Vec<cluster::Pointer>,
+        tail: Option<state_block::FreelistHead>,
+    ) -> Result<Option<state_block::FreelistHead>, Error> {
+        // Besides the checksum and the pointer of the chained metacluster, every metacluster holds
+        // as many free clusters as it has room for.
+        let capacity = disk::SECTOR_SIZE / cluster::POINTER_SIZE - 2;
+
+        // Split the free clusters into metaclusters and their content.
+        let mut nodes = Vec::new();
+        while let Some(metacluster) = free.pop() {
+            let len = free.len();
+            let content = free.split_off(len.saturating_sub(capacity));
+            nodes.push((metacluster, content));
+        }
+
+        // Write the metaclusters, starting from the end of the chain, as every metacluster stores
+        // the checksum of the chained metacluster.
+        let mut head = tail;
+        for (metacluster, content) in nodes.into_iter().rev() {
+            let mut buf = disk::SectorBuf::default();
+            if let Some(state_block::FreelistHead { cluster, checksum }) = head {
+                little_endian::write(&mut buf, checksum);
+                little_endian::write(&mut buf[cluster::POINTER_SIZE..], cluster);
+            }
+            for (n, &cluster) in content.iter().enumerate() {
+                little_endian::write(&mut buf[(n + 2) * cluster::POINTER_SIZE..], cluster);
             }
+
+            let checksum = self.checksum(&buf);
+            self.cache.write(metacluster, Box::new(buf)).wait()?;
+            head = Some(state_block::FreelistHead {
+                cluster: metacluster,
+                checksum: checksum,
+            });
         }
 
-        state.freelist_head = Some(FreelistHead {
-            cluster: cluster,
-            checksum: cksum,
-        });
+        Ok(head)
+    }
+
+    /// Flush the free-cache to the on-disk freelist.
+    ///
+    /// The buffered free clusters are written as new metaclusters in front of the on-disk
+    /// freelist, and the state block is pointed to them. Buffered clusters which aren't flushed
+    /// are leaked when the allocator is dropped.
+    ///
+    /// The clusters are trimmed when they're freed, so they aren't trimmed here. This blocks.
+    pub fn flush_free(&self) -> Result<(), Error> {
+        // Lock the state, so no metacluster is loaded while we prepend to the freelist.
+        let mut state = self.state.lock().unwrap();
+
+        let mut free = Vec::new();
+        while let Some(cluster) = self.free.try_pop() {
+            free.push(cluster);
+        }
+        if free.is_empty() {
+            return Ok(());
+        }
 
-        self.flush_state_block()
-        */
+        debug!(self, "flushing free clusters"; "clusters" => free.len());
+
+        state.freelist_head = self.write_freelist(free, state.freelist_head)?;
+        self.flush_state_block(&state).wait()
     }
 }
 
 impl<D: Disk> Drop for Allocator<D> {
     fn drop(&mut self) {
         // Flush the buffered free clusters to avoid leaking space.
-        self.flush_free();
+        if let Err(err) = self.flush_free() {
+            error!(self, "failed to flush the free clusters"; "error" => err);
+        }
     }
 }
 
 delegate_log!(Allocator.cache);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::memory::Memory;
+
+    /// Create an allocator on an in-memory disk of some number of sectors.
+    fn allocator(sectors: disk::Sector) -> Allocator<Memory> {
+        allocator_with_metrics(sectors, metrics::Metrics::default())
+    }
+
+    /// Create an allocator reporting to some metrics (see `allocator()`).
+    fn allocator_with_metrics(
+        sectors: disk::Sector,
+        metrics: metrics::Metrics,
+    ) -> Allocator<Memory> {
+        Allocator::init(Memory::new(sectors), Options {
+            state_block: state_block::Options {
+                compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+                dedup_verification: state_block::DedupVerification::Fingerprint,
+            },
+            disk_header: disk::header::Options {
+                vdev_stack: Vec::new(),
+                checksum_algorithm: disk::header::ChecksumAlgorithm::SeaHash,
+            },
+            codecs: compress::Registry::default(),
+            metrics: metrics,
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn flush_free() {
+        let alloc = allocator(256);
+        let cluster = alloc.freelist_pop().wait().unwrap();
+        alloc.freelist_push(cluster);
+
+        alloc.flush_free().unwrap();
+        assert!(alloc.free.try_pop().is_none());
+
+        // The last pushed cluster heads the on-disk freelist, so it is loaded as a metacluster.
+        assert_eq!(alloc.freelist_pop().wait().unwrap(), cluster);
+    }
+
+    #[test]
+    fn metrics() {
+        let sink = Arc::new(metrics::MemorySink::default());
+        let alloc = allocator_with_metrics(256, metrics::Metrics::new(sink.clone()));
+        let hint = locality::Hint(1);
+
+        let page = alloc.alloc_near(Box::new([1; disk::SECTOR_SIZE]), hint).wait().unwrap();
+        assert_eq!(alloc.alloc_near(Box::new([1; disk::SECTOR_SIZE]), hint).wait().unwrap(), page);
+        assert_eq!(sink.counter(Counter::PagesAllocated), 1);
+        assert_eq!(sink.counter(Counter::DedupHits), 1);
+        assert_eq!(sink.counter(Counter::ClustersAllocated), 1);
+        assert!(sink.counter(Counter::DiskWrites { vdev: None }) > 0);
+        // A page of equal bytes compresses well.
+        let ratio = sink.histogram(Histogram::CompressionRatio);
+        assert_eq!(ratio.count, 1);
+        assert!(ratio.max < 1000);
+
+        // The written cluster is cached.
+        let hits = sink.counter(Counter::CacheHits);
+        alloc.read(page).wait().unwrap();
+        assert_eq!(sink.counter(Counter::CacheHits), hits + 1);
+
+        // Nothing refers to the page, so the cluster is freed, once it is no longer young.
+        alloc.close_hint(hint);
+        let collector = gc::Collector::new(gc::Options::default());
+        for _ in 0..2 {
+            alloc.begin_collection();
+            alloc.sweep(|_| false, &collector).unwrap();
+        }
+        assert_eq!(sink.counter(Counter::ClustersFreed), 1);
+        assert_eq!(sink.counter(Counter::DiskTrims { vdev: None }), 1);
+
+        // Freeing the cluster dropped it from the cache.
+        let (misses, reads) = (sink.counter(Counter::CacheMisses),
+                               sink.counter(Counter::DiskReads { vdev: None }));
+        alloc.read(page).wait().unwrap();
+        assert_eq!(sink.counter(Counter::CacheMisses), misses + 1);
+        assert_eq!(sink.counter(Counter::DiskReads { vdev: None }), reads + 1);
+    }
+}
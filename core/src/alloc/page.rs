@@ -30,9 +30,10 @@ pub const POINTER_SIZE: usize = 16;
 /// 1. The cluster the page is stored in.
 /// 2. _How_ to read the page from the cluster.
 /// 3. A checksum of the page.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Pointer {
     /// The cluster in which the page is stored.
-    cluster: cluster::Pointer,
+    pub cluster: cluster::Pointer,
     /// The offset into the decompressed stream.
     ///
     /// Clusters can be either uncompressed (containing one page) or compressed (containing some
@@ -46,7 +47,7 @@ pub struct Pointer {
     /// If this is `Some(offset)`, the cluster must be decompressed and the page can be read
     /// `offset` pages into the decompressed stream. `offset` is assumed to never be `!0` in order
     /// to ensure the serialization to be injective.
-    offset: Option<u32>,
+    pub offset: Option<u32>,
     /// Checksum of the page.
     ///
     /// This checksum is calculated through the algorithm specified in the disk header, and when
@@ -64,7 +65,7 @@ pub struct Pointer {
     ///
     /// Most other approaches have the issue of not detecting phantom writes or not preserving
     /// consistency on crashes.
-    checksum: u32,
+    pub checksum: u32,
 }
 
 impl little_endian::Encode for Pointer {
@@ -84,7 +85,7 @@ impl little_endian::Encode for Pointer {
             !0
         });
         // Lastly, we write the checksum.
-        little_endian::write(&mut into[cluster::POINTER_SIZE..][32..], self.checksum);
+        little_endian::write(&mut into[cluster::POINTER_SIZE + 4..], self.checksum);
     }
 }
 
@@ -101,7 +102,7 @@ impl little_endian::Decode for Option<Pointer> {
                 n => Some(n),
             },
             // The highest 32 bit then store the checksum.
-            checksum: little_endian::read(&from[cluster::POINTER_SIZE..][32..]),
+            checksum: little_endian::read(&from[cluster::POINTER_SIZE + 4..]),
         })
     }
 }
@@ -110,7 +111,7 @@ impl little_endian::Encode for Option<Pointer> {
     fn write_le(self, into: &mut [u8]) {
         if let Some(ptr) = self {
             // Simply write the inner pointer into the buffer.
-            little_endian::write(into, self)
+            little_endian::write(into, ptr)
         } else {
             // Zero the first `POINTER_SIZE` bytes of the buffer (null pointer).
             for i in &mut into[..POINTER_SIZE] {
@@ -4,15 +4,24 @@ use alloc::page;
 use disk::{self, cluster};
 
 /// A compression algorithm configuration option.
+///
+/// The implementation of the algorithms is found through `compress::Registry`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum CompressionAlgorithm {
     /// Identity function/compression disabled.
-    Identity = 0,
+    Identity,
     /// LZ4 compression.
     ///
     /// LZ4 is a very fast LZ77-family compression algorithm. Like other LZ77 compressors, it is
     /// based on streaming data reduplication. The details are described
     /// [here](http://ticki.github.io/blog/how-lz4-works/).
-    Lz4 = 1,
+    Lz4,
+    /// An implementation-defined compression algorithm.
+    ///
+    /// This holds the raw option value, which is in the range `0x8000` to `0xFFFF`. Whether the
+    /// algorithm is supported depends on which algorithms were registered when opening the
+    /// system.
+    ImplementationDefined(u16),
 }
 
 impl TryFrom<u16> for CompressionAlgorithm {
@@ -22,30 +31,76 @@ impl TryFrom<u16> for CompressionAlgorithm {
         match from {
             0 => Ok(CompressionAlgorithm::Identity),
             1 => Ok(CompressionAlgorithm::Lz4),
-            0x8000...0xFFFF => Err(err!(Corruption, "unknown implementation-defined compression algorithm option {:x}", from)),
+            // We can't know at this point if the algorithm is supported, so we defer that check
+            // to the compression algorithm registry.
+            0x8000...0xFFFF => Ok(CompressionAlgorithm::ImplementationDefined(from)),
             _ => Err(err!(Corruption, "invalid compression algorithm option {:x}", from)),
         }
     }
 }
 
+impl From<CompressionAlgorithm> for u16 {
+    fn from(from: CompressionAlgorithm) -> u16 {
+        match from {
+            CompressionAlgorithm::Identity => 0,
+            CompressionAlgorithm::Lz4 => 1,
+            CompressionAlgorithm::ImplementationDefined(id) => id,
+        }
+    }
+}
+
+/// A deduplication verification policy configuration option.
+///
+/// When the deduplication table finds a candidate with a matching fingerprint, this policy decides
+/// what is required before the candidate page is reused in place of a newly allocated page.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DedupVerification {
+    /// Trust the fingerprint.
+    ///
+    /// The candidate is used if its SHA-256 fingerprint matches. Collisions are practically
+    /// impossible to find, so this is sufficient for most purposes.
+    Fingerprint = 0,
+    /// Compare the data byte-for-byte.
+    ///
+    /// The candidate page is read back from the disk and compared to the allocated data, and only
+    /// if they are equal, the candidate is reused. This costs a read per deduplication, but it
+    /// eliminates the reliance on the fingerprint being collision-free.
+    Strict = 1,
+}
+
+impl TryFrom<u16> for DedupVerification {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<DedupVerification, Error> {
+        match from {
+            0 => Ok(DedupVerification::Fingerprint),
+            1 => Ok(DedupVerification::Strict),
+            0x8000...0xFFFF => Err(err!(Unsupported, "unknown implementation-defined deduplication verification option {:x}", from)),
+            _ => Err(err!(Corruption, "invalid deduplication verification option {:x}", from)),
+        }
+    }
+}
+
 /// The freelist head.
 ///
 /// The freelist chains some number of blocks containing pointers to free blocks. This allows for
 /// simple and efficient allocation. This struct stores information about the head block in the
 /// freelist.
-struct FreelistHead {
+#[derive(Clone, Copy)]
+pub struct FreelistHead {
     /// A pointer to the head of the freelist.
     ///
     /// This cluster contains pointers to other free clusters. If not full, it is padded with
     /// zeros.
-    cluster: cluster::Pointer,
+    pub cluster: cluster::Pointer,
     /// The checksum of the freelist head up to the last free cluster.
     ///
     /// This is the checksum of the metacluster (at `self.cluster`).
-    checksum: u64,
+    pub checksum: u64,
 }
 
 /// The state sub-block.
+#[derive(Clone, Copy)]
 pub struct State {
     /// A pointer to the superpage.
     pub superpage: Option<page::Pointer>,
@@ -56,9 +111,12 @@ pub struct State {
 }
 
 /// The options sub-block.
+#[derive(Clone, Copy)]
 pub struct Options {
     /// The chosen compression algorithm.
     pub compression_algorithm: CompressionAlgorithm,
+    /// The chosen deduplication verification policy.
+    pub dedup_verification: DedupVerification,
 }
 
 /// The TFS state block.
@@ -71,7 +129,7 @@ pub struct StateBlock {
 
 impl StateBlock {
     /// Parse the binary representation of a state block.
-    fn decode(
+    pub fn decode(
         buf: &disk::SectorBuf,
         checksum_algorithm: disk::header::ChecksumAlgorithm,
     ) -> Result<StateBlock, Error> {
@@ -87,6 +145,8 @@ impl StateBlock {
             options: Options {
                 // Load the compression algorithm config field.
                 compression_algorithm: CompressionAlgorithm::try_from(little_endian::read(buf[8..]))?,
+                // Load the deduplication verification config field.
+                dedup_verification: DedupVerification::try_from(little_endian::read(&buf[10..]))?,
             },
             state: State {
                 // Load the superpage pointer.
@@ -104,12 +164,14 @@ impl StateBlock {
     }
 
     /// Encode the state block into a sector-sized buffer.
-    fn encode(&self, checksum_algorithm: disk::header::ChecksumAlgorithm) -> disk::SectorBuf {
+    pub fn encode(&self, checksum_algorithm: disk::header::ChecksumAlgorithm) -> disk::SectorBuf {
         // Create a buffer to hold the data.
         let mut buf = disk::SectorBuf::default();
 
         // Write the compression algorithm.
-        little_endian::write(&mut buf[8..], self.options.compression_algorithm as u16);
+        little_endian::write(&mut buf[8..], u16::from(self.options.compression_algorithm));
+        // Write the deduplication verification policy.
+        little_endian::write(&mut buf[10..], self.options.dedup_verification as u16);
         // Write the superpage pointer. If no superpage is initialized, we simply write a null
         // pointer.
         little_endian::write(&mut buf[16..], self.state.superpage);
@@ -144,6 +206,12 @@ mod tests {
         block.options.compression_algorithm = CompressionAlgorithm::Identity;
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
 
+        block.options.compression_algorithm = CompressionAlgorithm::ImplementationDefined(0x8A01);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.options.dedup_verification = DedupVerification::Strict;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
         block.state.superpage = 200;
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
 
@@ -164,6 +232,11 @@ mod tests {
         little_endian::write(&mut sector, seahash::hash(sector[8..]));
         assert_eq!(sector, block.encode());
 
+        block.options.dedup_verification = DedupVerification::Strict;
+        sector[10] = 1;
+        little_endian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
         block.state.superpage = 29;
         sector[16] = 29;
         little_endian::write(&mut sector, seahash::hash(sector[8..]));
@@ -194,5 +267,14 @@ mod tests {
 
         sector[8] = 0xFF;
         assert_eq!(StateBlock::decode(sector).unwrap_err().kind, error::Kind::Corruption);
+
+        sector = StateBlock::default().encode();
+
+        sector[10] = 0xFF;
+        little_endian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector).unwrap_err().kind, error::Kind::Corruption);
+        sector[11] = 0xFF;
+        little_endian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector).unwrap_err().kind, error::Kind::Unsupported);
     }
 }
@@ -3,13 +3,15 @@ use atomic_hashmap::AtomicHashMap;
 use {mlcr, Error};
 use disk::{self, vdev, Disk};
 use disk::header::DiskHeader;
+use metrics::{Counter, Metrics};
 
 /// The default initial capacity of the sector map.
 const INITIAL_CAPACITY: usize = 256;
 
 /// A cached disk.
 ///
-/// This wrapper manages caching of the disk.
+/// This wrapper manages caching of the disk. The sectors of the inner disk are the clusters, so
+/// the errors of the inner disk are given the cluster as context.
 pub struct Cached<D> {
     /// The inner disk.
     disk: D,
@@ -22,18 +24,31 @@ pub struct Cached<D> {
     tracker: mlcr::ConcurrentCache,
     /// The sector-number-to-data block map.
     sectors: AtomicHashMap<disk::Sector, disk::SectorBuf>,
+    /// The metrics, which the cache hits and misses are reported to.
+    metrics: Metrics,
 }
 
 impl<D: Disk> Cached<D> {
     /// Create a cache from a backing disk.
-    fn new(disk: D) -> Cached<D> {
+    fn new(disk: D, metrics: Metrics) -> Cached<D> {
         Cached {
             disk: disk,
             tracker: mlcr::ConcurrentCache::new(),
             sectors: AtomicHashMap::with_capacity(INITIAL_CAPACITY),
+            metrics: metrics,
         }
     }
 
+    /// Get the number of sectors of the inner disk.
+    pub fn number_of_sectors(&self) -> disk::Sector {
+        self.disk.number_of_sectors()
+    }
+
+    /// Get the metrics, which the disk operations are reported to.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     /// Write a sector.
     ///
     /// This writes `buf` into sector `sector`. If it fails, the error is returned.
@@ -47,7 +62,7 @@ impl<D: Disk> Cached<D> {
         // Then insert it into the cache.
         self.sectors.insert(sector, buf);
         // Write the data to the disk.
-        self.disk.write(sector, &buf)
+        self.disk.write(sector, &buf).map_err(move |err| err.with_cluster(sector as u64))
     }
 
     /// Drop a sector from the cache and trim it.
@@ -64,7 +79,7 @@ impl<D: Disk> Cached<D> {
         // Update the sector map.
         self.sectors.remove(sector);
         // Finally, trim the sector.
-        self.disk.trim(sector)
+        self.disk.trim(sector).map_err(move |err| err.with_cluster(sector as u64))
     }
 
     /// Read a sector.
@@ -82,6 +97,7 @@ impl<D: Disk> Cached<D> {
         if let Some(buf) = self.sectors.get(sector) {
             // Yup, we found the sector in the cache.
             trace!(self, "cache hit; reading from cache"; "sector" => sector);
+            self.metrics.increment(Counter::CacheHits);
 
             // Touch the sector.
             self.tracker.touch(sector);
@@ -89,15 +105,17 @@ impl<D: Disk> Cached<D> {
             map(buf)
         } else {
             trace!(self, "cache miss; reading from disk"; "sector" => sector);
+            self.metrics.increment(Counter::CacheMisses);
 
             // Insert the sector into the cache tracker.
             self.tracker.touch(sector);
 
             // Fetch the data from the disk.
-            self.disk.read(sector).map(|buf| {
-                // Insert the read data into the hash table.
-                self.sectors.get_mut_or(sector, buf)
-            }).and_then(map)
+            self.disk.read(sector).map_err(move |err| err.with_cluster(sector as u64))
+                .map(|buf| {
+                    // Insert the read data into the hash table.
+                    self.sectors.get_mut_or(sector, buf)
+                }).and_then(map)
             // TODO: If the above failed, try to recover the data through the vdev redundancy.
         }
     }
@@ -7,8 +7,28 @@ pub const POINTER_SIZE: usize = 8;
 
 /// A pointer to some cluster.
 // TODO: Use `NonZero`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Pointer(u64);
 
+impl Pointer {
+    /// Create a new cluster pointer.
+    ///
+    /// If `cluster` is zero (the null pointer), `None` is returned.
+    pub fn new(cluster: u64) -> Option<Pointer> {
+        if cluster == 0 {
+            None
+        } else {
+            Some(Pointer(cluster))
+        }
+    }
+}
+
+impl From<Pointer> for u64 {
+    fn from(ptr: Pointer) -> u64 {
+        ptr.0
+    }
+}
+
 impl little_endian::Encode for Pointer {
     fn write_le(self, into: &mut [u8]) {
         if let Some(ptr) = self {
@@ -85,6 +85,7 @@ impl Into<&'static [u8]> for MagicNumber {
 }
 
 /// A checksum algorithm configuration option.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ChecksumAlgorithm {
     /// SeaHash checksum.
     ///
@@ -110,7 +111,7 @@ impl TryFrom<u16> for ChecksumAlgorithm {
     fn try_from(from: u16) -> Result<ChecksumAlgorithm, Error> {
         match from {
             1 => Ok(ChecksumAlgorithm::SeaHash),
-            0x8000...0xFFFF => Err(err!(Implementation, "unknown implementation-defined checksum algorithm {:x}", from)),
+            0x8000...0xFFFF => Err(err!(Unsupported, "unknown implementation-defined checksum algorithm {:x}", from)),
             _ => Err(err!(Corruption, "invalid checksum algorithm {:x}", from)),
         }
     }
@@ -137,6 +138,7 @@ pub enum StateFlag {
 ///
 /// Vdevs transforms one disk to another, in the sense that it changes the behavior of I/O
 /// operations to give the disk some particular feature, such as error correction etc.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Vdev {
     /// A mirror.
     ///
@@ -148,7 +150,11 @@ pub enum Vdev {
     Speck = 1,
 }
 
-struct Uid(u128);
+/// The unique, secret identifier of a disk.
+///
+/// This is used e.g. as a salt for key stretching and as a seed for hashing.
+#[derive(Default, PartialEq, Eq, Clone, Copy)]
+pub struct Uid(pub u128);
 
 impl Uid {
     /// Generate a random UID.
@@ -215,7 +221,7 @@ impl DiskHeader {
     ///
     /// This will construct it into memory while performing error checks on the header to ensure
     /// correctness.
-    fn decode(buf: &disk::SectorBuf) -> Result<DiskHeader, Error> {
+    pub fn decode(buf: &disk::SectorBuf) -> Result<DiskHeader, Error> {
         // # Introducer Section
         //
         // This section has the purpose of defining the implementation, version, and type of the
@@ -231,7 +237,7 @@ impl DiskHeader {
         // version, it's compatible.
         if version_number >> 16 != VERSION_NUMBER >> 16 || version_number > VERSION_NUMBER {
             // The version is not compatible; abort.
-            return Err(err!(Implementation, "incompatible version {:x}", version_number));
+            return Err(err!(Unsupported, "incompatible version {:x}", version_number));
         }
 
         // # Unique identifier
@@ -279,7 +285,7 @@ impl DiskHeader {
                 // A SPECK encryption cipher.
                 2 => vdev_stack.push(Vdev::Speck),
                 // Implementation defined vdev, which this implementation does not support.
-                0xFFFF => return Err(err!(Implementation, "unknown implementation-defined vdev")),
+                0xFFFF => return Err(err!(Unsupported, "unknown implementation-defined vdev")),
                 // Invalid vdevs (vdevs that are necessarily invalid under this version).
                 _ => return Err(err!(Corruption, "invalid vdev label {:x}", label)),
             }
@@ -313,7 +319,7 @@ impl DiskHeader {
     }
 
     /// Encode the header into a sector-sized buffer.
-    fn encode(&self) -> disk::SectorBuf {
+    pub fn encode(&self) -> disk::SectorBuf {
         // Create a buffer to hold the data.
         let mut buf = [0; disk::SECTOR_SIZE];
 
@@ -453,7 +459,7 @@ mod tests {
         sector[11] = 0xFF;
 
         little_endian::write(&mut sector[504..], seahash::hash(sector[..504]));
-        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Implementation);
+        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Unsupported);
     }
 
     #[test]
@@ -473,7 +479,7 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Corruption);
         sector[33] = 0x80;
         little_endian::write(&mut sector[504..], seahash::hash(sector[..504]));
-        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Implementation);
+        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Unsupported);
     }
 
     #[test]
@@ -484,7 +490,7 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Corruption);
         sector[65] = 0xFF;
         little_endian::write(&mut sector[504..], seahash::hash(sector[..504]));
-        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Implementation);
+        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Unsupported);
 
         sector = DiskHeader::default().encode();
         sector[64] = 1;
@@ -493,7 +499,7 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Corruption);
         sector[67] = 0xFF;
         little_endian::write(&mut sector[504..], seahash::hash(sector[..504]));
-        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Implementation);
+        assert_eq!(DiskHeader::decode(sector).unwrap_err().kind, Kind::Unsupported);
     }
 
     #[test]
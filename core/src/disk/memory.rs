@@ -0,0 +1,68 @@
+//! In-memory disks.
+//!
+//! This is a `Disk` backed by a vector of sectors. It is used to test the I/O stack and the
+//! filesystem without touching real storage.
+
+use futures::future;
+use slog;
+use std::sync::{Arc, Mutex};
+
+use Error;
+use disk::{self, Disk};
+
+/// An in-memory disk.
+///
+/// Clones share the sectors, so a test can keep a clone around to reopen or inspect the disk,
+/// after the filesystem on it was dropped.
+#[derive(Clone)]
+pub struct Memory {
+    /// The sectors.
+    sectors: Arc<Mutex<Vec<disk::SectorBuf>>>,
+}
+
+impl Memory {
+    /// Create a zeroed disk with some number of sectors.
+    pub fn new(sectors: disk::Sector) -> Memory {
+        Memory {
+            sectors: Arc::new(Mutex::new(vec![[0; disk::SECTOR_SIZE]; sectors])),
+        }
+    }
+
+    /// Get the content of the disk.
+    pub fn dump(&self) -> Vec<u8> {
+        self.sectors.lock().unwrap().iter().flat_map(|x| x.iter().cloned()).collect()
+    }
+}
+
+impl Disk for Memory {
+    type ReadFuture = future::FutureResult<Box<disk::SectorBuf>, Error>;
+    type WriteFuture = future::FutureResult<(), Error>;
+    type TrimFuture = future::FutureResult<(), Error>;
+
+    fn number_of_sectors(&self) -> disk::Sector {
+        self.sectors.lock().unwrap().len()
+    }
+
+    fn read(&self, sector: disk::Sector) -> Self::ReadFuture {
+        future::result(self.sectors.lock().unwrap().get(sector).map(|&buf| Box::new(buf))
+            .ok_or_else(|| err!(Io, "read out of bounds").with_sector(sector as u64)))
+    }
+
+    fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> Self::WriteFuture {
+        future::result(self.sectors.lock().unwrap().get_mut(sector).map(|x| *x = *buf)
+            .ok_or_else(|| err!(Io, "write out of bounds").with_sector(sector as u64)))
+    }
+
+    fn trim(&self, _: disk::Sector) -> Self::TrimFuture {
+        // Trimming is only a hint, so the data is left there.
+        future::ok(())
+    }
+}
+
+impl slog::Drain for Memory {
+    type Error = ();
+
+    fn log(&self, _: &slog::Record, _: &slog::OwnedKeyValueList) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
 mod cache;
 mod crypto;
 mod vdev;
+#[cfg(test)]
+pub mod memory;
 pub mod cluster;
 pub mod header;
 
 use futures::Future;
 use {slog, Error};
+use metrics::Metrics;
 
 /// The logical sector size.
 pub const SECTOR_SIZE: usize = 512;
@@ -22,17 +25,33 @@ pub type TfsDisk<D> = cache::Cached<vdev::Driver<D>>;
 
 /// Load the TFS disk.
 ///
-/// This does not initialize or create the structure. It will merely load the disk.
-pub fn open<D: Disk>(disk: D, password: &[u8]) -> future!(TfsDisk<D>) {
-    vdev::Driver::open(disk).cached()
+/// This does not initialize or create the structure. It will merely load the disk. If `read_only`
+/// is set, nothing is written to the disk. The disk operations are reported to `metrics`.
+pub fn open<D: Disk>(disk: D, read_only: bool, metrics: Metrics) -> future!(TfsDisk<D>) {
+    vdev::Driver::open(disk, read_only, metrics.clone())
+        .map(|driver| driver.cached(metrics))
+}
+
+/// Load the TFS disk from a decoded disk header.
+///
+/// This is similar to `open()`, but the disk header is neither checked nor changed (see
+/// `vdev::Driver::load()`). It is used by offline tools.
+pub fn load<D: Disk>(
+    disk: D,
+    header: header::DiskHeader,
+    read_only: bool,
+    metrics: Metrics,
+) -> Result<TfsDisk<D>, Error> {
+    vdev::Driver::load(disk, header, read_only, metrics.clone())
+        .map(|driver| driver.cached(metrics))
 }
 
 /// Initialize/create the TFS disk.
 ///
 /// This creates the structure (given some options given in `options`) of the disk, and effectively
-/// initializes a system.
-pub fn init<D: Disk>(disk: D, options: header::Options) -> future!(TfsDisk<D>) {
-    vdev::Driver::init(disk, options).cached()
+/// initializes a system. The disk operations are reported to `metrics`.
+pub fn init<D: Disk>(disk: D, options: header::Options, metrics: Metrics) -> future!(TfsDisk<D>) {
+    vdev::Driver::init(disk, options, metrics.clone()).map(|driver| driver.cached(metrics))
 }
 
 /// A storage device.
@@ -68,7 +87,9 @@ pub trait Disk: slog::Drain {
     fn trim(&self, sector: Sector) -> Self::TrimFuture;
 
     /// Create a cached version of the disk.
-    fn cached(self) -> cache::Cached<Self> {
-        cache::Cached::new(self)
+    ///
+    /// The cache hits and misses are reported to `metrics`.
+    fn cached(self, metrics: Metrics) -> cache::Cached<Self> {
+        cache::Cached::new(self, metrics)
     }
 }
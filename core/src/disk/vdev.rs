@@ -13,11 +13,13 @@
 //! leave to an inconsistent state, unless the inner vdev does.
 
 use std::mem;
+use std::time::Instant;
 use futures::{future, Future};
 
 use Error;
 use disk::{self, Disk};
 use disk::header::{self, DiskHeader};
+use metrics::{Counter, Histogram, Metrics};
 
 /// A driver transforming a normal disk into a disk respecting the vdev setup.
 ///
@@ -38,71 +40,128 @@ pub struct Driver<D> {
     /// The inner disk.
     // TODO: Remove this vtable?
     disk: D,
+    /// Is the disk opened read-only?
+    ///
+    /// If so, the disk header is left untouched, and writes fail.
+    read_only: bool,
+    /// Did the driver set the state flag to open?
+    ///
+    /// If so, the state flag is set to closed, when the driver is dropped.
+    flagged_open: bool,
+    /// The metrics of the operations on the inner disk.
+    metrics: Metrics,
 }
 
 impl<D: Disk> Driver<D> {
     /// Set up the driver from some disk.
     ///
     /// This will load the disk header from `disk` and construct the driver. It will also set the
-    /// disk to be in open state. If any encryption is enabled, `password` will be used as the
-    /// password.
+    /// disk to be in open state, unless `read_only` is set, in which case nothing is written to
+    /// the disk at all. Encrypted disks are not supported yet, so they're refused. The operations
+    /// on the disk are reported to `metrics`.
     ///
     /// The result is wrapped in a future, which represents the operation, such that it can be
     /// executed asynchronously.
-    fn open<D: Disk>(disk: D, password: &[u8]) -> future!(Driver<D>) {
+    fn open<D: Disk>(disk: D, read_only: bool, metrics: Metrics) -> future!(Driver<D>) {
         info!(disk, "loading the state and initializing the driver");
 
         // Read the disk header.
         debug!(disk, "read the disk header");
         disk.read(0).and_then(|header| {
-            let driver = Driver {
-                header: DiskHeader::decode(header)?,
-                disk: disk,
-            };
+            let decoded = DiskHeader::decode(header).map_err(|err| err.with_sector(0))?;
+            let mut driver = Driver::load(disk, decoded, read_only, metrics)?;
 
             match driver.header.state_flag {
+                header::StateFlag::Closed => (),
                 // Throw a warning if it wasn't properly shut down.
                 header::StateFlag::Open => {
                     warn!(driver, "the disk's state flag is still open, likely wasn't properly shut \
                                    down last time; beware of data loss");
                 },
                 // The state inconsistent; throw an error.
-                header::StateFlag::Inconsistent => return Err(err!(Corruption, "the file system is in an inconsistent state, possibly due to crash")),
+                header::StateFlag::Inconsistent => {
+                    return Err(err!(Corruption, "the file system is in an inconsistent state, \
+                                                 possibly due to crash").with_sector(0));
+                },
+            }
+
+            if read_only {
+                // Leave the header as it is.
+                return Ok(driver);
             }
 
             // Set the state flag to open.
             debug!(driver, "setting the state flag to 'open'");
             driver.header.state_flag = header::StateFlag::Open;
+            driver.flagged_open = true;
 
             // Update the version.
             debug!(driver, "updating the version number";
-                   "old version" => header.version_number,
+                   "old version" => driver.header.version_number,
                    "new version" => header::VERSION_NUMBER);
             driver.header.version_number = header::VERSION_NUMBER;
 
             Ok(driver)
         }).and_then(|driver| {
+            if driver.read_only {
+                return future::Either::A(future::ok(driver));
+            }
+
             // Flush the updated header.
-            driver.flush_header().map(|_| driver)
+            future::Either::B(driver.flush_header().map(|_| driver))
+        })
+    }
+
+    /// Set up the driver from a decoded disk header.
+    ///
+    /// Unlike `Driver::open()`, this neither checks the state flag of `header`, nor changes the
+    /// disk header, even if the disk is writable. This is used by offline tools, which inspect the
+    /// disk header themselves. If `read_only` is set, writes fail. Encrypted disks and `metrics`
+    /// are treated as in `Driver::open()`.
+    pub fn load(
+        disk: D,
+        header: DiskHeader,
+        read_only: bool,
+        metrics: Metrics,
+    ) -> Result<Driver<D>, Error> {
+        if header.options.vdev_stack.contains(&header::Vdev::Speck) {
+            return Err(err!(Unsupported, "encrypted disks are not supported yet"));
+        }
+
+        Ok(Driver {
+            header: header,
+            disk: disk,
+            read_only: read_only,
+            flagged_open: false,
+            metrics: metrics,
         })
     }
 
     /// Initialize a disk with a new header.
     ///
     /// This sets the disk header (provided by the `header` argument) of disk `disk` and returns
-    /// the driver representing the disk.
+    /// the driver representing the disk. The operations on the disk are reported to `metrics`.
     ///
     /// It is used as an entry point to create a new file system.
-    fn init<D: Disk>(disk: D, options: header::Options) -> future!(Driver<D>) {
+    fn init<D: Disk>(disk: D, options: header::Options, metrics: Metrics) -> future!(Driver<D>) {
         info!(disk, "creating a new system");
 
+        if options.vdev_stack.contains(&header::Vdev::Speck) {
+            // Refuse early, rather than failing on the first write.
+            return future::Either::A(future::err(err!(Unsupported,
+                                                      "encrypted disks are not supported yet")));
+        }
+
         // Create the new header from the user-specified options.
         let header = DiskHeader::new(options);
         // Write the header to the disk.
-        disk.write(0, header.encode()).map(|_| Driver {
+        future::Either::B(disk.write(0, header.encode()).map(|_| Driver {
             header: header,
             disk: disk,
-        })
+            read_only: false,
+            flagged_open: true,
+            metrics: metrics,
+        }))
     }
 
     /// Flush the stored disk header.
@@ -115,12 +174,69 @@ impl<D: Disk> Driver<D> {
         // Encode and write it to the disk.
         self.disk.write(0, &self.header.encode())
     }
+
+    /// Read a sector of the inner disk.
+    ///
+    /// `vdev` is the index of the vdev issuing the read, or `None` if the read was issued from
+    /// above. The read is reported to the metrics, and errors are given the sector as context.
+    fn read_inner(
+        &self,
+        vdev: Option<usize>,
+        sector: disk::Sector,
+    ) -> future!(Box<disk::SectorBuf>) {
+        self.metrics.increment(Counter::DiskReads { vdev: vdev });
+
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        self.disk.read(sector).then(move |res| {
+            metrics.time(Histogram::DiskReadLatency { vdev: vdev }, start);
+            res.map_err(|err| err.with_sector(sector as u64))
+        })
+    }
+
+    /// Write a sector of the inner disk.
+    ///
+    /// `vdev` is the index of the issuing vdev (see `Driver::read_inner()`).
+    fn write_inner(
+        &self,
+        vdev: Option<usize>,
+        sector: disk::Sector,
+        buf: &disk::SectorBuf,
+    ) -> future!(()) {
+        self.metrics.increment(Counter::DiskWrites { vdev: vdev });
+
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        self.disk.write(sector, buf).then(move |res| {
+            metrics.time(Histogram::DiskWriteLatency { vdev: vdev }, start);
+            res.map_err(|err| err.with_sector(sector as u64))
+        })
+    }
+
+    /// Trim a sector of the inner disk.
+    ///
+    /// `vdev` is the index of the issuing vdev (see `Driver::read_inner()`).
+    fn trim_inner(&self, vdev: Option<usize>, sector: disk::Sector) -> future!(()) {
+        self.metrics.increment(Counter::DiskTrims { vdev: vdev });
+
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        self.disk.trim(sector).then(move |res| {
+            metrics.time(Histogram::DiskTrimLatency { vdev: vdev }, start);
+            res.map_err(|err| err.with_sector(sector as u64))
+        })
+    }
 }
 
 impl<D: Disk> Drop for Driver<D> {
     fn drop(&mut self) {
         info!(self, "closing the driver");
 
+        if !self.flagged_open {
+            // The header was never changed.
+            return;
+        }
+
         // Set the state flag to close so we know that it was a proper shutdown.
         debug!(self, "setting state flag to 'closed'");
         self.header.state_flag = header::StateFlag::Closed;
@@ -132,53 +248,63 @@ impl<D: Disk> Drop for Driver<D> {
 delegate_log!(Driver.disk);
 
 impl<D: Disk> Disk for Driver<D> {
-    type ReadFuture  = D::ReadFuture;
-    type WriteFuture = D::WriteFuture;
-    type TrimFuture  = D::TrimFuture;
+    type ReadFuture  = Box<Future<Item = Box<disk::SectorBuf>, Error = Error>>;
+    type WriteFuture = Box<Future<Item = (), Error = Error>>;
+    type TrimFuture  = Box<Future<Item = (), Error = Error>>;
 
     fn number_of_sectors(&self) -> disk::Sector {
         // Start out with the raw number of sectors. We subtract one to cut of the disk header.
         let mut sectors = self.disk.number_of_sectors() - 1;
 
         // Go over the vdev stack.
-        for vdev in self.header.vdev_stack {
-            match vdev {
+        for vdev in &self.header.options.vdev_stack {
+            match *vdev {
                 // Mirrors divide the disk in half, as the higher half must mirror the lower.
                 header::Vdev::Mirror => sectors /= 2,
                 header::Vdev::Speck => (),
             }
         }
+
+        sectors
     }
 
-    fn read(&self, sector: disk::Sector) -> D::ReadFuture {
+    fn read(&self, sector: disk::Sector) -> Self::ReadFuture {
         // We start out by reading the inner buffer. We subtract one to cut of the disk header.
-        let mut buf = self.disk.read(sector + 1);
+        let mut buf = self.read_inner(None, sector + 1);
 
         // Go over the vdev stack.
-        for vdev in self.header.vdev_stack {
+        for vdev in &self.header.options.vdev_stack {
             // Note that it is very important that `sector` gets updated to account for changed
             // address space.
 
-            match vdev {
+            match *vdev {
                 // TODO
                 header::Vdev::Speck => unimplemented!(),
                 _ => (),
             }
         }
+
+        Box::new(buf)
     }
 
-    fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> D::WriteFuture {
-        // Start a vector to hold the writes. This allows us to rewrite the write operations for
-        // every vdev transformation.
-        let mut writes = vec![(sector, buf)];
+    fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> Self::WriteFuture {
+        if self.read_only {
+            return Box::new(future::err(err!(ReadOnly, "the disk is read-only")
+                                        .with_sector(sector as u64)));
+        }
+
+        // Start a vector to hold the writes (along with the vdev issuing them). This allows us to
+        // rewrite the write operations for every vdev transformation. We add one to skip the disk
+        // header.
+        let mut writes = vec![(None, sector + 1, buf)];
 
         // Go over the vdev stack.
-        for vdev in self.header.vdev_stack {
-            match vdev {
+        for (n, vdev) in self.header.options.vdev_stack.iter().enumerate() {
+            match *vdev {
                 // Mirror the higher and lower half.
                 header::Vdev::Mirror => for i in 0..writes.len() {
                     // Write the higher half.
-                    writes.push((writes[i].0 * 2, writes[i].1));
+                    writes.push((Some(n), writes[i].1 * 2, writes[i].2));
                 },
                 // TODO
                 header::Vdev::Speck => unimplemented!(),
@@ -186,22 +312,28 @@ impl<D: Disk> Disk for Driver<D> {
         }
 
         // Execute all the writes, we've buffered.
-        future::join_all(writes.into_iter().map(|(sector, buf)| {
-            self.disk.write(sector, buf)
-        }))
+        Box::new(future::join_all(writes.into_iter().map(|(vdev, sector, buf)| {
+            self.write_inner(vdev, sector, buf)
+        })).map(|_| ()))
     }
 
-    fn trim(&self, sector: disk::Sector) -> D::TrimFuture {
-        // Start a vector to track what sectors to trim.
-        let mut trims = vec![sector];
+    fn trim(&self, sector: disk::Sector) -> Self::TrimFuture {
+        if self.read_only {
+            return Box::new(future::err(err!(ReadOnly, "the disk is read-only")
+                                        .with_sector(sector as u64)));
+        }
+
+        // Start a vector to track what sectors to trim (along with the vdev issuing the trims). We
+        // add one to skip the disk header, as with writes.
+        let mut trims = vec![(None, sector + 1)];
 
         // Go over the vdev stack.
-        for vdev in self.header.vdev_stack {
-            match vdev {
+        for (n, vdev) in self.header.options.vdev_stack.iter().enumerate() {
+            match *vdev {
                 // Mirror the higher and lower half.
-                header::Vdev::Mirror => for i in 0..writes.len() {
-                    // Trim the higher half's sector.
-                    trims.push(trims[i]);
+                header::Vdev::Mirror => for i in 0..trims.len() {
+                    // Trim the higher half's sector, which is the one mirroring the write.
+                    trims.push((Some(n), trims[i].1 * 2));
                 },
                 // Encryption doesn't matter for trimming.
                 header::Vdev::Speck => (),
@@ -209,8 +341,115 @@ impl<D: Disk> Disk for Driver<D> {
         }
 
         // Execute all the trims, we've buffered.
-        future::join_all(trims.into_iter().map(|sector| {
+        Box::new(future::join_all(trims.into_iter().map(|(vdev, sector)| {
+            self.trim_inner(vdev, sector)
+        })).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog;
+    use std::sync::{Arc, Mutex};
+    use disk::memory::Memory;
+    use metrics::MemorySink;
+
+    /// An in-memory disk, which records the sectors trimmed.
+    #[derive(Clone)]
+    struct Trims {
+        /// The disk.
+        disk: Memory,
+        /// The trimmed sectors.
+        trimmed: Arc<Mutex<Vec<disk::Sector>>>,
+    }
+
+    impl Disk for Trims {
+        type ReadFuture = <Memory as Disk>::ReadFuture;
+        type WriteFuture = <Memory as Disk>::WriteFuture;
+        type TrimFuture = <Memory as Disk>::TrimFuture;
+
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.disk.number_of_sectors()
+        }
+
+        fn read(&self, sector: disk::Sector) -> Self::ReadFuture {
+            self.disk.read(sector)
+        }
+
+        fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> Self::WriteFuture {
+            self.disk.write(sector, buf)
+        }
+
+        fn trim(&self, sector: disk::Sector) -> Self::TrimFuture {
+            self.trimmed.lock().unwrap().push(sector);
             self.disk.trim(sector)
-        }))
+        }
+    }
+
+    impl slog::Drain for Trims {
+        type Error = ();
+
+        fn log(&self, _: &slog::Record, _: &slog::OwnedKeyValueList) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// Set up a driver with a vdev stack on a recording disk.
+    fn setup(vdev_stack: Vec<header::Vdev>, metrics: Metrics) -> (Driver<Trims>, Trims) {
+        let disk = Trims {
+            disk: Memory::new(64),
+            trimmed: Arc::new(Mutex::new(Vec::new())),
+        };
+        let driver = Driver::init(disk.clone(), header::Options {
+            vdev_stack: vdev_stack,
+            checksum_algorithm: header::ChecksumAlgorithm::SeaHash,
+        }, metrics).wait().unwrap();
+
+        (driver, disk)
+    }
+
+    #[test]
+    fn mirror_trims() {
+        let (driver, disk) = setup(vec![header::Vdev::Mirror], Metrics::default());
+
+        // Both halves of the mirror are trimmed, at the sectors the write went to.
+        driver.trim(3).wait().unwrap();
+        assert_eq!(*disk.trimmed.lock().unwrap(), [4, 8]);
+
+        let (driver, disk) = setup(Vec::new(), Metrics::default());
+        driver.trim(3).wait().unwrap();
+        assert_eq!(*disk.trimmed.lock().unwrap(), [4]);
+    }
+
+    #[test]
+    fn header_is_kept() {
+        let (driver, disk) = setup(Vec::new(), Metrics::default());
+        let header = disk.read(0).wait().unwrap();
+
+        // Sector 0 of the driver is sector 1 of the inner disk.
+        driver.write(0, &[1; disk::SECTOR_SIZE]).wait().unwrap();
+        assert_eq!(disk.read(0).wait().unwrap()[..], header[..]);
+        assert_eq!(disk.read(1).wait().unwrap()[..], [1; disk::SECTOR_SIZE][..]);
+        assert_eq!(driver.read(0).wait().unwrap()[..], [1; disk::SECTOR_SIZE][..]);
+        assert_eq!(driver.number_of_sectors(), 63);
+    }
+
+    #[test]
+    fn io_metrics() {
+        let sink = Arc::new(MemorySink::default());
+        let (driver, _) = setup(vec![header::Vdev::Mirror], Metrics::new(sink.clone()));
+
+        // The mirror issues a write and a trim of its own for every one from above.
+        driver.write(3, &[1; disk::SECTOR_SIZE]).wait().unwrap();
+        driver.trim(3).wait().unwrap();
+        driver.read(3).wait().unwrap();
+        for &vdev in &[None, Some(0)] {
+            assert_eq!(sink.counter(Counter::DiskWrites { vdev: vdev }), 1);
+            assert_eq!(sink.counter(Counter::DiskTrims { vdev: vdev }), 1);
+            assert_eq!(sink.histogram(Histogram::DiskWriteLatency { vdev: vdev }).count, 1);
+        }
+        assert_eq!(sink.counter(Counter::DiskReads { vdev: None }), 1);
+        assert_eq!(sink.counter(Counter::DiskReads { vdev: Some(0) }), 0);
     }
 }
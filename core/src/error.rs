@@ -1,7 +1,9 @@
+use std::{error, fmt, io};
+
 /// The category of an error.
 ///
 /// This enum contains variants representing general categories of TFS errors.
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Kind {
     /// Data corruption.
     Corruption,
@@ -9,15 +11,205 @@ pub enum Kind {
     OutOfSpace,
     /// Implementation issue.
     Implementation,
+    /// The underlying storage failed.
+    ///
+    /// The error of the storage is the source of the error.
+    Io,
+    /// An object doesn't exist.
+    NotFound,
+    /// An object already exists.
+    AlreadyExists,
+    /// An argument is invalid, e.g. a malformed name or a path, which cannot be used.
+    InvalidInput,
+    /// The object is a directory, but the operation needs a non-directory.
+    IsDirectory,
+    /// The object isn't a directory, but the operation needs a directory.
+    NotDirectory,
+    /// The directory isn't empty.
+    NotEmpty,
+    /// A concurrent change conflicts with the operation.
+    ///
+    /// The operation had no effect, and can be retried.
+    Conflict,
+    /// The operation isn't permitted on the object.
+    PermissionDenied,
+    /// The password of an encrypted disk is wrong.
+    WrongPassword,
+    /// The filesystem is read-only.
+    ReadOnly,
+    /// The operation or format is not supported by this implementation.
+    Unsupported,
+}
+
+impl Kind {
+    /// Describe the kind.
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Corruption => "corruption",
+            Kind::OutOfSpace => "out of space",
+            Kind::Implementation => "implementation error",
+            Kind::Io => "I/O error",
+            Kind::NotFound => "not found",
+            Kind::AlreadyExists => "already exists",
+            Kind::InvalidInput => "invalid input",
+            Kind::IsDirectory => "is a directory",
+            Kind::NotDirectory => "not a directory",
+            Kind::NotEmpty => "directory not empty",
+            Kind::Conflict => "conflict",
+            Kind::PermissionDenied => "permission denied",
+            Kind::WrongPassword => "wrong password",
+            Kind::ReadOnly => "read-only filesystem",
+            Kind::Unsupported => "unsupported",
+        }
+    }
+}
+
+impl From<Kind> for io::ErrorKind {
+    fn from(kind: Kind) -> io::ErrorKind {
+        match kind {
+            Kind::NotFound => io::ErrorKind::NotFound,
+            Kind::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Kind::InvalidInput => io::ErrorKind::InvalidInput,
+            Kind::PermissionDenied | Kind::WrongPassword | Kind::ReadOnly => {
+                io::ErrorKind::PermissionDenied
+            },
+            Kind::Corruption => io::ErrorKind::InvalidData,
+            Kind::OutOfSpace | Kind::Implementation | Kind::Io | Kind::Unsupported
+                | Kind::IsDirectory | Kind::NotDirectory | Kind::NotEmpty | Kind::Conflict => {
+                io::ErrorKind::Other
+            },
+        }
+    }
+}
+
+/// The context, in which an error occurred.
+///
+/// Every field is optional, and is only set by the parts of the stack knowing of it.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Context {
+    /// The disk sector.
+    pub sector: Option<u64>,
+    /// The cluster.
+    pub cluster: Option<u64>,
+    /// The path of the object.
+    pub path: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(sector) = self.sector {
+            parts.push(format!("sector {}", sector));
+        }
+        if let Some(cluster) = self.cluster {
+            parts.push(format!("cluster {:x}", cluster));
+        }
+        if let Some(ref path) = self.path {
+            parts.push(format!("path '{}'", String::from_utf8_lossy(path)));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
 }
 
 /// A TFS error.
-#[derive(PartialEq)]
 pub struct Error {
     /// The type ("kind") of the error.
     pub kind: Kind,
     /// Description of the error.
     desc: Box<str>,
+    /// The context of the error.
+    context: Context,
+    /// The error causing this error, if any.
+    source: Option<Box<error::Error + Send + Sync>>,
+}
+
+impl Error {
+    /// Get the context of the error.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Set the disk sector of the context.
+    pub fn with_sector(mut self, sector: u64) -> Error {
+        self.context.sector = Some(sector);
+        self
+    }
+
+    /// Set the cluster of the context.
+    pub fn with_cluster(mut self, cluster: u64) -> Error {
+        self.context.cluster = Some(cluster);
+        self
+    }
+
+    /// Set the object path of the context.
+    pub fn with_path(mut self, path: &[u8]) -> Error {
+        self.context.path = Some(path.to_vec());
+        self
+    }
+
+    /// Set the error causing this error.
+    pub fn with_source<E>(mut self, source: E) -> Error
+    where E: Into<Box<error::Error + Send + Sync>> {
+        self.source = Some(source.into());
+        self
+    }
+}
+
+impl PartialEq for Error {
+    /// Compare the kind, description and context of two errors.
+    ///
+    /// The sources are not compared.
+    fn eq(&self, other: &Error) -> bool {
+        self.kind == other.kind && self.desc == other.desc && self.context == other.context
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Error")
+            .field("kind", &self.kind)
+            .field("desc", &self.desc)
+            .field("context", &self.context)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind.as_str(), self.desc)?;
+        if self.context != Context::default() {
+            write!(f, " ({})", self.context)?;
+        }
+        if let Some(ref source) = self.source {
+            write!(f, ": {}", source)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.desc
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        self.source.as_ref().map(|x| &**x as &error::Error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        err!(Io, "storage operation failed").with_source(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(err.kind.into(), err)
+    }
 }
 
 /// Create a TFS error.
@@ -30,12 +222,57 @@ pub struct Error {
 /// The rest arguments are the usual formatting syntax (like `println!()`) representing the
 /// `Display` implementation of the error. If none, it will simply use the second argument (the
 /// description).
+///
+/// The error has no context and no source. These can be added with `Error::with_sector()` and
+/// friends.
 #[macro_export]
 macro_rules! err {
     ($kind:ident, $($rest:tt)*) => {
         $crate::error::Error {
             kind: $crate::error::Kind::$kind,
-            desc: format!($($rest)*),
+            desc: format!($($rest)*).into_boxed_str(),
+            context: $crate::error::Context::default(),
+            source: None,
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let err = err!(Corruption, "mismatching checksums").with_cluster(0x2A).with_path(b"/a");
+        assert_eq!(err.to_string(),
+                   "corruption: mismatching checksums (cluster 2a, path '/a')");
+
+        let err = err!(NotFound, "'/b' doesn't exist");
+        assert_eq!(err.to_string(), "not found: '/b' doesn't exist");
+    }
+
+    #[test]
+    fn sources() {
+        let io = io::Error::new(io::ErrorKind::UnexpectedEof, "short read");
+        let err = Error::from(io).with_sector(7);
+
+        assert_eq!(err.kind, Kind::Io);
+        assert_eq!(err.context().sector, Some(7));
+        assert_eq!(error::Error::cause(&err).unwrap().to_string(), "short read");
+        assert_eq!(err.to_string(), "I/O error: storage operation failed (sector 7): short read");
+    }
+
+    #[test]
+    fn io_kinds() {
+        let io = |err: Error| io::Error::from(err).kind();
+
+        assert_eq!(io(err!(NotFound, "")), io::ErrorKind::NotFound);
+        assert_eq!(io(err!(AlreadyExists, "")), io::ErrorKind::AlreadyExists);
+        assert_eq!(io(err!(InvalidInput, "")), io::ErrorKind::InvalidInput);
+        assert_eq!(io(err!(IsDirectory, "")), io::ErrorKind::Other);
+        assert_eq!(io(err!(ReadOnly, "")), io::ErrorKind::PermissionDenied);
+        assert_eq!(io(err!(WrongPassword, "")), io::ErrorKind::PermissionDenied);
+        assert_eq!(io(err!(Corruption, "")), io::ErrorKind::InvalidData);
+        assert_eq!(io(err!(Unsupported, "")), io::ErrorKind::Other);
+    }
+}
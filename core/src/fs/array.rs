@@ -1,31 +1,766 @@
-use futures::Future;
+//! Persistent arrays.
+//!
+//! Arrays are the basic building block of larger on-disk objects, such as file contents and
+//! directories. They're sequences of (nullable) page pointers, stored as a radix tree of pointer
+//! nodes: Every node is a page holding `POINTERS_IN_NODE` page pointers. The leaf nodes point to
+//! the elements, and the other nodes point to their child nodes.
+//!
+//! Arrays are persistent: Nodes are never modified in place. Instead, a mutation copies the nodes
+//! on the path from the root to the modified leaf, and returns a new array, which shares every
+//! other node with the old array (copy-on-write). The old array remains valid.
+//!
+//! Null pointers are allowed both for elements and for nodes. A null node is equivalent to a node
+//! with only null pointers, which means that subtrees of null elements (holes) take no space.
+
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use std::cell::RefCell;
+use std::cmp;
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::Range;
+use std::rc::Rc;
+
+use {disk, fs, little_endian, Error};
+use alloc::{compact, page};
+
+/// The number of pointers in a node.
+pub const POINTERS_IN_NODE: u64 = (disk::SECTOR_SIZE / page::POINTER_SIZE) as u64;
+
+/// The number of levels of a tree holding some number of elements.
+///
+/// The leaves are at level 1, so a tree of depth 1 consists of a single leaf node.
+fn depth(len: u64) -> u32 {
+    let mut depth = 1;
+    let mut capacity = POINTERS_IN_NODE;
+
+    while capacity < len {
+        depth += 1;
+        // If the capacity overflows, it must be enough for any length.
+        capacity = match capacity.checked_mul(POINTERS_IN_NODE) {
+            Some(capacity) => capacity,
+            None => break,
+        };
+    }
+
+    depth
+}
+
+/// The slot of some index in the node at some level.
+///
+/// This gives the index into the pointers of the node at level `level + 1`, which is on the path
+/// to element `index`.
+fn slot(index: u64, level: u32) -> usize {
+    (index / POINTERS_IN_NODE.pow(level) % POINTERS_IN_NODE) as usize
+}
+
+/// A node in the tree.
+#[derive(Clone, Copy)]
+struct Node {
+    /// The pointers of the node.
+    ///
+    /// These point to elements if the node is a leaf, and to child nodes otherwise.
+    pointers: [Option<page::Pointer>; POINTERS_IN_NODE as usize],
+}
+
+impl Node {
+    /// Create a node of null pointers.
+    fn empty() -> Node {
+        Node {
+            pointers: [None; POINTERS_IN_NODE as usize],
+        }
+    }
+
+    /// Does this node consist solely of null pointers?
+    fn is_empty(&self) -> bool {
+        self.pointers.iter().all(Option::is_none)
+    }
+
+    /// Parse the binary representation of a node.
+    fn decode(buf: &disk::SectorBuf) -> Node {
+        let mut node = Node::empty();
+
+        for (n, ptr) in node.pointers.iter_mut().enumerate() {
+            *ptr = little_endian::read(&buf[n * page::POINTER_SIZE..]);
+        }
+
+        node
+    }
+
+    /// Encode the node into a sector-sized buffer.
+    fn encode(&self) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+
+        for (n, &ptr) in self.pointers.iter().enumerate() {
+            little_endian::write(&mut buf[n * page::POINTER_SIZE..], ptr);
+        }
+
+        buf
+    }
+}
+
+/// Read a node.
+///
+/// If `ptr` is null, the node of null pointers is returned.
+fn read_node(fs: &fs::State, ptr: Option<page::Pointer>) -> future!(Node) {
+    match ptr {
+        Some(ptr) => future::Either::A(fs.read(ptr).map(|buf| Node::decode(&buf))),
+        None => future::Either::B(future::ok(Node::empty())),
+    }
+}
+
+/// Read the nodes on the path to some element.
+///
+/// This starts from root node `root` of a tree of depth `depth` and reads the nodes on the path
+/// to element `index`. The path is returned with the root first and the leaf last.
+fn read_path(fs: &fs::State, root: Node, index: u64, depth: u32) -> future!(Vec<Node>) {
+    future::loop_fn(vec![root], move |mut path| {
+        // The level of the lowest node read so far.
+        let level = depth - path.len() as u32 + 1;
+
+        if level == 1 {
+            // We've reached the leaf.
+            return future::Either::A(future::ok(Loop::Break(path)));
+        }
+
+        // Read the child on the path.
+        let child = path.last().unwrap().pointers[slot(index, level - 1)];
+        future::Either::B(read_node(fs, child).map(move |node| {
+            path.push(node);
+            Loop::Continue(path)
+        }))
+    })
+}
+
+/// Write a modified path.
+///
+/// This sets element `index` in the leaf of `path` (as returned by `read_path()`) to `value`, and
+/// writes the nodes of the path bottom-up, updating every parent to point to the new copy of its
+/// child. Nodes which end up empty are not written, but replaced by null pointers.
+///
+/// The new root pointer is returned.
+fn write_path(
+    fs: &fs::State,
+    path: Vec<Node>,
+    index: u64,
+    value: Option<page::Pointer>,
+) -> future!(Option<page::Pointer>) {
+    // Go from the leaf and up. For the leaf, the accumulator is the value of the element, and for
+    // the rest, it is the pointer to the new copy of the child.
+    stream::iter_ok(path.into_iter().rev().enumerate()).fold(value, move |ptr, (level, mut node)| {
+        node.pointers[slot(index, level as u32)] = ptr;
 
-use {disk, fs, Error};
-use alloc::page;
+        if node.is_empty() {
+            // No need to store a node of null pointers.
+            future::Either::A(future::ok(None))
+        } else {
+            future::Either::B(fs.alloc(node.encode(), "array node").map(Some))
+        }
+    })
+}
+
+/// Find the first null or non-null element in a subtree.
+///
+/// This searches the subtree of node `ptr` at level `level`, whose first element is element
+/// `base`, for the first element in `range`, which is non-null if `present` is set, and null
+/// otherwise. Null subtrees consist solely of null elements, so they are never read.
+fn find_in(
+    fs: &fs::State,
+    ptr: Option<page::Pointer>,
+    level: u32,
+    base: u64,
+    range: Range<u64>,
+    present: bool,
+) -> fs::BoxFuture<Option<u64>> {
+    let ptr = match ptr {
+        Some(ptr) => ptr,
+        None => {
+            let first = cmp::max(base, range.start);
+            let found = if !present && first < range.end { Some(first) } else { None };
+
+            return Box::new(future::ok(found));
+        },
+    };
+
+    // The number of elements below every child of the node.
+    let span = POINTERS_IN_NODE.pow(level - 1);
+
+    Box::new(read_node(fs, Some(ptr)).and_then(move |node| {
+        // Skip the children before the range.
+        let first = range.start.saturating_sub(base) / span;
 
-const POINTERS_IN_NODE: u64 = disk::SECTOR_SIZE / page::POINTER_SIZE;
+        future::loop_fn(first, move |n| {
+            let start = base.saturating_add(span.saturating_mul(n));
+            if n >= POINTERS_IN_NODE || start >= range.end {
+                return future::Either::A(future::ok(Loop::Break(None)));
+            }
 
-struct Array<T> {
-    root: page::Pointer,
+            let child = node.pointers[n as usize];
+            let found = if level == 1 {
+                // The child is an element.
+                let found = if child.is_some() == present { Some(start) } else { None };
+                future::Either::A(future::ok(found))
+            } else {
+                future::Either::B(find_in(fs, child, level - 1, start, range.clone(), present))
+            };
+
+            future::Either::B(found.map(move |found| match found {
+                Some(index) => Loop::Break(Some(cmp::max(index, range.start))),
+                None => Loop::Continue(n + 1),
+            }))
+        })
+    }))
+}
+
+/// A persistent array of page pointers.
+///
+/// The elements are nullable page pointers to objects of type `T`.
+pub struct Array<T> {
+    /// The root node of the tree.
+    ///
+    /// This is `None` if the array is empty, or consists solely of null elements.
+    root: Option<page::Pointer>,
+    /// The number of elements in the array.
     len: u64,
     _phantom: PhantomData<T>,
 }
 
+impl<T> Clone for Array<T> {
+    fn clone(&self) -> Array<T> {
+        Array {
+            root: self.root,
+            len: self.len,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Copy for Array<T> {}
+
 impl<T> Array<T> {
-    fn is_leaf(&self) -> bool {
-        self.len <= POINTERS_IN_NODE
+    /// Create an empty array.
+    pub fn new() -> Array<T> {
+        Array::from_raw(None, 0)
+    }
+
+    /// Create an array from its root and length.
+    ///
+    /// This is used for loading arrays stored in other objects.
+    pub fn from_raw(root: Option<page::Pointer>, len: u64) -> Array<T> {
+        Array {
+            root: root,
+            len: len,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Get the root node pointer.
+    pub fn root(&self) -> Option<page::Pointer> {
+        self.root
     }
 
-    fn for_each<F>(&self, fs: &fs::State, range: Range<u64>, f: F) -> future!(())
-    where F: Fn(usize, page::Pointer) {
-        unimplemented!();
+    /// Get the number of elements in the array.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Is the array empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check that some index is within bounds.
+    fn check_bounds(&self, index: u64) -> Result<(), Error> {
+        if index < self.len {
+            Ok(())
+        } else {
+            Err(err!(Implementation, "array index {} out of bounds (length {})", index, self.len))
+        }
+    }
+
+    /// Get some element.
+    ///
+    /// This reads element `index`. If it is out of bounds, an error is returned.
+    pub fn get(&self, fs: &fs::State, index: u64) -> future!(Option<page::Pointer>) {
+        let depth = depth(self.len);
+        let root = self.root;
+
+        future::result(self.check_bounds(index)).and_then(move |_| {
+            // Descend through the tree, until we reach a leaf, or a null node.
+            future::loop_fn((root, depth), move |(node, level)| {
+                read_node(fs, node).map(move |node| {
+                    let ptr = node.pointers[slot(index, level - 1)];
+
+                    if level == 1 || ptr.is_none() {
+                        // We found the element, or a null node, in which all elements are null.
+                        Loop::Break(ptr)
+                    } else {
+                        Loop::Continue((ptr, level - 1))
+                    }
+                })
+            })
+        })
+    }
+
+    /// Set some element.
+    ///
+    /// This creates a new array, where element `index` is set to `value`. If it is out of
+    /// bounds, an error is returned.
+    pub fn set(
+        &self,
+        fs: &fs::State,
+        index: u64,
+        value: Option<page::Pointer>,
+    ) -> future!(Array<T>) {
+        let depth = depth(self.len);
+        let (root, len) = (self.root, self.len);
+
+        future::result(self.check_bounds(index))
+            .and_then(move |_| read_node(fs, root))
+            .and_then(move |root| read_path(fs, root, index, depth))
+            .and_then(move |path| write_path(fs, path, index, value))
+            .map(move |root| Array::from_raw(root, len))
+    }
+
+    /// Push an element.
+    ///
+    /// This creates a new array with `value` appended to the end.
+    pub fn push(&self, fs: &fs::State, value: Option<page::Pointer>) -> future!(Array<T>) {
+        let index = self.len;
+
+        // Make room for the new element, then set it.
+        self.grow(fs, self.len + 1).and_then(move |array| array.set(fs, index, value))
+    }
+
+    /// Resize the array.
+    ///
+    /// This creates a new array of length `len`. If it is shorter than the array, the excess
+    /// elements are removed, and if it is longer, the array is extended by null elements.
+    pub fn truncate(&self, fs: &fs::State, len: u64) -> future!(Array<T>) {
+        if len >= self.len {
+            future::Either::A(self.grow(fs, len))
+        } else {
+            future::Either::B(self.shrink(fs, len))
+        }
+    }
+
+    /// Extend the array by null elements.
+    ///
+    /// If the tree needs more levels, the new levels are added above the root, so the old root
+    /// becomes the first child of the new root.
+    fn grow(&self, fs: &fs::State, len: u64) -> future!(Array<T>) {
+        let old_depth = depth(self.len);
+        let new_depth = depth(len);
+
+        future::loop_fn((self.root, old_depth), move |(root, level)| {
+            if level >= new_depth || root.is_none() {
+                // Either the tree is deep enough, or the tree is all null, in which case it has
+                // the same representation at any depth.
+                return future::Either::A(future::ok(Loop::Break(root)));
+            }
+
+            // Add a level above the root.
+            let mut node = Node::empty();
+            node.pointers[0] = root;
+            future::Either::B(fs.alloc(node.encode(), "array node").map(move |root| {
+                Loop::Continue((Some(root), level + 1))
+            }))
+        }).map(move |root| Array::from_raw(root, len))
+    }
+
+    /// Remove the elements after some length.
+    ///
+    /// `len` is assumed to be less than the length of the array.
+    fn shrink(&self, fs: &fs::State, len: u64) -> future!(Array<T>) {
+        if len == 0 {
+            // Everything is removed.
+            return future::Either::A(future::ok(Array::new()));
+        }
+
+        let old_depth = depth(self.len);
+        let new_depth = depth(len);
+        // The last element which is kept.
+        let last = len - 1;
+
+        // Remove the levels, which are no longer needed. The remaining elements are all in the
+        // first child of the root, so we descend into it.
+        future::Either::B(future::loop_fn((self.root, old_depth), move |(root, level)| {
+            if level <= new_depth || root.is_none() {
+                return future::Either::A(future::ok(Loop::Break(root)));
+            }
+
+            future::Either::B(read_node(fs, root).map(move |node| {
+                Loop::Continue((node.pointers[0], level - 1))
+            }))
+        }).and_then(move |root| read_node(fs, root))
+          .and_then(move |root| read_path(fs, root, last, new_depth))
+          .and_then(move |mut path| {
+            // Clear the pointers after the path to the last element, at every level.
+            for (level, node) in path.iter_mut().rev().enumerate() {
+                for ptr in &mut node.pointers[slot(last, level as u32) + 1..] {
+                    *ptr = None;
+                }
+            }
+
+            // Write the path, keeping the last element as it is.
+            let value = path.last().unwrap().pointers[slot(last, 0)];
+            write_path(fs, path, last, value)
+        }).map(move |root| Array::from_raw(root, len)))
+    }
+
+    /// Apply a function to a range of elements.
+    ///
+    /// This calls `f` with the index and value of every element in `range` in order. The leaves
+    /// are read one at a time, so sequential iteration only reads every node once (modulo
+    /// caching). If the range is out of bounds, an error is returned.
+    pub fn for_each<F>(&self, fs: &fs::State, range: Range<u64>, mut f: F) -> future!(())
+    where F: FnMut(u64, Option<page::Pointer>) {
+        let depth = depth(self.len);
+        let root = self.root;
+        // Find the start of the leaf of every element in the range.
+        let first_leaf = range.start / POINTERS_IN_NODE;
+        let end_leaf = (range.end + POINTERS_IN_NODE - 1) / POINTERS_IN_NODE;
+        let leaves = (first_leaf..end_leaf).map(|n| n * POINTERS_IN_NODE);
+
+        let bounds = if range.end > self.len {
+            Err(err!(Implementation, "array range {:?} out of bounds (length {})", range,
+                     self.len))
+        } else {
+            Ok(())
+        };
+
+        future::result(bounds).and_then(move |_| stream::iter_ok(leaves).for_each(move |start| {
+            // Read the leaf, and apply the function to the elements of the leaf in the range.
+            read_node(fs, root)
+                .and_then(move |root| read_path(fs, root, start, depth))
+                .map(|path| {
+                    let leaf = path.last().unwrap();
+                    for index in start.max(range.start)..(start + POINTERS_IN_NODE).min(range.end) {
+                        f(index, leaf.pointers[slot(index, 0)]);
+                    }
+                })
+        }))
+    }
+
+    /// Find the first non-null or null element in a range.
+    ///
+    /// This returns the index of the first element in `range`, which is non-null if `present` is
+    /// set, and null otherwise. Null subtrees are skipped without reading them, so this is cheap
+    /// on sparse arrays. If there is no such element, `None` is returned.
+    pub fn find(&self, fs: &fs::State, range: Range<u64>, present: bool) -> future!(Option<u64>) {
+        let range = range.start..cmp::min(range.end, self.len);
+
+        find_in(fs, self.root, depth(self.len), 0, range, present)
+    }
+
+    /// Collect a range of elements.
+    ///
+    /// This reads the elements in `range` into a vector, in order. If the range is out of bounds,
+    /// an error is returned.
+    pub fn collect(
+        &self,
+        fs: &fs::State,
+        range: Range<u64>,
+    ) -> future!(Vec<Option<page::Pointer>>) {
+        let elements = Rc::new(RefCell::new(Vec::new()));
+        let sink = elements.clone();
+
+        self.for_each(fs, range, move |_, ptr| sink.borrow_mut().push(ptr))
+            .map(move |_| mem::replace(&mut *elements.borrow_mut(), Vec::new()))
+    }
+}
+
+/// Visit a subtree.
+///
+/// This visits node `ptr` at level `level` and everything below it.
+fn visit_node<T>(
+    fs: &fs::State,
+    ptr: page::Pointer,
+    level: u32,
+    visitor: &fs::Visitor,
+) -> fs::BoxFuture<()>
+where T: fs::Object + From<page::Pointer> {
+    // Visit the node itself.
+    visitor.page(ptr);
+
+    Box::new(read_node(fs, Some(ptr)).and_then(move |node| {
+        future::join_all(node.pointers.iter().filter_map(|&ptr| ptr).map(|ptr| {
+            if level == 1 {
+                // The pointer points to an element.
+                Box::new(T::from(ptr).visit(fs, visitor)) as fs::BoxFuture<()>
+            } else {
+                // The pointer points to a child node.
+                visit_node::<T>(fs, ptr, level - 1, visitor)
+            }
+        }).collect::<Vec<_>>()).map(|_| ())
+    }))
+}
+
+/// Relocate a subtree.
+///
+/// This applies `relocations` to node `ptr` at level `level` and everything below it, and returns
+/// the pointer to the updated node. The node is only copied if any of its children changed.
+fn relocate_node<T>(
+    fs: &fs::State,
+    ptr: page::Pointer,
+    level: u32,
+    relocations: &compact::Relocations,
+) -> fs::BoxFuture<page::Pointer>
+where T: fs::Object + From<page::Pointer> + Into<page::Pointer> {
+    Box::new(read_node(fs, Some(ptr)).and_then(move |node| {
+        future::join_all(node.pointers.iter().map(|&child| match child {
+            // Null pointers are left as-is.
+            None => Box::new(future::ok(None)) as fs::BoxFuture<Option<page::Pointer>>,
+            // Relocate the element.
+            Some(child) if level == 1 => {
+                Box::new(T::from(child).relocate(fs, relocations).map(|x| Some(x.into())))
+            },
+            // Relocate the child node.
+            Some(child) => {
+                Box::new(relocate_node::<T>(fs, child, level - 1, relocations).map(Some))
+            },
+        }).collect::<Vec<_>>()).and_then(move |children| {
+            if children[..] == node.pointers[..] {
+                // Nothing below the node changed, so we only need to follow the node itself, if it
+                // was moved.
+                future::Either::A(future::ok(relocations.get(ptr).unwrap_or(ptr)))
+            } else {
+                // Write a new copy of the node pointing to the updated children.
+                let mut node = Node::empty();
+                node.pointers.copy_from_slice(&children);
+                future::Either::B(fs.alloc(node.encode(), "array node"))
+            }
+        })
+    }))
+}
+
+impl<T: fs::Object + From<page::Pointer> + Into<page::Pointer>> fs::Object for Array<T> {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        match self.root {
+            Some(root) => future::Either::A(visit_node::<T>(fs, root, depth(self.len), visitor)),
+            // The array is empty, so there's nothing to visit.
+            None => future::Either::B(future::ok(())),
+        }
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Array<T>) {
+        let len = self.len;
+
+        match self.root {
+            Some(root) => future::Either::A(relocate_node::<T>(fs, root, depth(len), relocations)
+                .map(move |root| Array::from_raw(Some(root), len))),
+            None => future::Either::B(future::ok(*self)),
+        }
     }
 }
 
-impl<T: fs::Object + From<page::Pointer>> fs::Object for Array<T> {
-    fn gc_visit(&self, fs: &fs::State) -> future!(()) {
-        unimplemented!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+
+    #[test]
+    fn depth_of_length() {
+        assert_eq!(depth(0), 1);
+        assert_eq!(depth(1), 1);
+        assert_eq!(depth(POINTERS_IN_NODE), 1);
+        assert_eq!(depth(POINTERS_IN_NODE + 1), 2);
+        assert_eq!(depth(POINTERS_IN_NODE * POINTERS_IN_NODE), 2);
+        assert_eq!(depth(POINTERS_IN_NODE * POINTERS_IN_NODE + 1), 3);
+        // Make sure it doesn't overflow.
+        assert_eq!(depth(!0), 13);
+    }
+
+    #[test]
+    fn slots() {
+        assert_eq!(slot(0, 0), 0);
+        assert_eq!(slot(5, 0), 5);
+        assert_eq!(slot(POINTERS_IN_NODE + 5, 0), 5);
+        assert_eq!(slot(POINTERS_IN_NODE + 5, 1), 1);
+        assert_eq!(slot(POINTERS_IN_NODE * POINTERS_IN_NODE * 3 + 7, 2), 3);
+    }
+
+    #[test]
+    fn node_inverse_identity() {
+        let mut node = Node::empty();
+        assert!(node.is_empty());
+        assert_eq!(Node::decode(&node.encode()).pointers[..], node.pointers[..]);
+
+        node.pointers[0] = Some(page::Pointer {
+            cluster: cluster::Pointer::new(2).unwrap(),
+            offset: None,
+            checksum: 0xDEADBEEF,
+        });
+        node.pointers[31] = Some(page::Pointer {
+            cluster: cluster::Pointer::new(0xFFFFFFFF).unwrap(),
+            offset: Some(12),
+            checksum: 1,
+        });
+        assert!(!node.is_empty());
+        assert_eq!(Node::decode(&node.encode()).pointers[..], node.pointers[..]);
+    }
+
+    #[test]
+    fn find() {
+        let fs = fs::memory(1024);
+        let len = POINTERS_IN_NODE.pow(4);
+        let data = fs.alloc([1; disk::SECTOR_SIZE], "test").wait().unwrap();
+        let array = Array::<fs::Data>::new().truncate(&fs, len).wait().unwrap()
+            .set(&fs, 5, Some(data)).wait().unwrap()
+            .set(&fs, len - 3, Some(data)).wait().unwrap();
+
+        assert_eq!(array.find(&fs, 0..len, true).wait().unwrap(), Some(5));
+        assert_eq!(array.find(&fs, 6..len, true).wait().unwrap(), Some(len - 3));
+        assert_eq!(array.find(&fs, 6..len - 3, true).wait().unwrap(), None);
+        assert_eq!(array.find(&fs, 5..len, false).wait().unwrap(), Some(6));
+        assert_eq!(array.find(&fs, len - 3..len, false).wait().unwrap(), Some(len - 2));
+        assert_eq!(array.find(&fs, 0..len, false).wait().unwrap(), Some(0));
+        // The range is cut at the end of the array.
+        assert_eq!(array.find(&fs, len - 2..!0, true).wait().unwrap(), None);
+        assert_eq!(Array::<fs::Data>::new().find(&fs, 0..10, false).wait().unwrap(), None);
+    }
+
+    /// Allocate a data page filled with some byte.
+    fn data(fs: &fs::State, byte: u8) -> page::Pointer {
+        fs.alloc([byte; disk::SECTOR_SIZE], "test").wait().unwrap()
+    }
+
+    #[test]
+    fn get_set() {
+        let fs = fs::memory(1024);
+        let (a, b) = (data(&fs, 1), data(&fs, 2));
+        let array = Array::<fs::Data>::new().truncate(&fs, 10).wait().unwrap();
+
+        assert_eq!(array.len(), 10);
+        assert_eq!(array.root(), None);
+        assert_eq!(array.get(&fs, 3).wait().unwrap(), None);
+
+        let array = array.set(&fs, 3, Some(a)).wait().unwrap()
+            .set(&fs, 9, Some(b)).wait().unwrap();
+        assert_eq!(array.get(&fs, 3).wait().unwrap(), Some(a));
+        assert_eq!(array.get(&fs, 9).wait().unwrap(), Some(b));
+        assert_eq!(array.get(&fs, 4).wait().unwrap(), None);
+
+        // Out of bounds.
+        assert!(array.get(&fs, 10).wait().is_err());
+        assert!(array.set(&fs, 10, Some(a)).wait().is_err());
+
+        // Setting every element back to null frees the tree.
+        let array = array.set(&fs, 3, None).wait().unwrap()
+            .set(&fs, 9, None).wait().unwrap();
+        assert_eq!(array.root(), None);
+        assert_eq!(array.len(), 10);
+    }
+
+    #[test]
+    fn push_and_for_each() {
+        let fs = fs::memory(1024);
+        let pages: Vec<_> = (0..4).map(|n| data(&fs, n)).collect();
+        let mut array = Array::<fs::Data>::new();
+
+        for n in 0..POINTERS_IN_NODE + 3 {
+            let value = if n % 3 == 0 { None } else { Some(pages[n as usize % 4]) };
+            array = array.push(&fs, value).wait().unwrap();
+            assert_eq!(array.len(), n + 1);
+        }
+
+        let mut visited = Vec::new();
+        array.for_each(&fs, 2..POINTERS_IN_NODE + 2, |n, ptr| visited.push((n, ptr)))
+            .wait().unwrap();
+        assert_eq!(visited.len() as u64, POINTERS_IN_NODE);
+        for (i, &(n, ptr)) in visited.iter().enumerate() {
+            assert_eq!(n, i as u64 + 2);
+            let expected = if n % 3 == 0 { None } else { Some(pages[n as usize % 4]) };
+            assert_eq!(ptr, expected);
+        }
+
+        // An empty range visits nothing, and a range past the end fails.
+        array.for_each(&fs, 5..5, |_, _| panic!("visited an empty range")).wait().unwrap();
+        assert!(array.for_each(&fs, 0..POINTERS_IN_NODE + 4, |_, _| ()).wait().is_err());
+        assert_eq!(array.collect(&fs, 0..4).wait().unwrap(),
+                   vec![None, Some(pages[1]), Some(pages[2]), None]);
+    }
+
+    #[test]
+    fn grow_and_shrink_across_levels() {
+        let fs = fs::memory(1024);
+        let (a, b, c) = (data(&fs, 1), data(&fs, 2), data(&fs, 3));
+        let square = POINTERS_IN_NODE * POINTERS_IN_NODE;
+
+        // Fill the last slot of a single leaf, and grow past it.
+        let array = Array::<fs::Data>::new().truncate(&fs, POINTERS_IN_NODE).wait().unwrap()
+            .set(&fs, 0, Some(a)).wait().unwrap()
+            .set(&fs, POINTERS_IN_NODE - 1, Some(b)).wait().unwrap();
+        let leaf = array.root();
+        let array = array.push(&fs, Some(c)).wait().unwrap();
+        assert_eq!(array.len(), POINTERS_IN_NODE + 1);
+        // The old leaf became the first child of the new root.
+        assert_eq!(read_node(&fs, array.root()).wait().unwrap().pointers[0], leaf);
+        assert_eq!(array.get(&fs, 0).wait().unwrap(), Some(a));
+        assert_eq!(array.get(&fs, POINTERS_IN_NODE - 1).wait().unwrap(), Some(b));
+        assert_eq!(array.get(&fs, POINTERS_IN_NODE).wait().unwrap(), Some(c));
+
+        // Grow across the next level boundary.
+        let array = array.truncate(&fs, square).wait().unwrap()
+            .set(&fs, square - 1, Some(a)).wait().unwrap()
+            .push(&fs, Some(b)).wait().unwrap();
+        assert_eq!(array.len(), square + 1);
+        for &(index, value) in &[(0, Some(a)), (POINTERS_IN_NODE, Some(c)),
+                                 (square - 1, Some(a)), (square, Some(b)), (5, None)] {
+            assert_eq!(array.get(&fs, index).wait().unwrap(), value);
+        }
+
+        // Shrink back into two levels, dropping the element at the boundary.
+        let shrunk = array.truncate(&fs, square).wait().unwrap();
+        assert_eq!(shrunk.len(), square);
+        assert_eq!(shrunk.get(&fs, square - 1).wait().unwrap(), Some(a));
+        assert_eq!(shrunk.find(&fs, POINTERS_IN_NODE + 1..square - 1, true).wait().unwrap(),
+                   None);
+
+        // Shrink into a single leaf, cutting inside it.
+        let shrunk = array.truncate(&fs, POINTERS_IN_NODE - 1).wait().unwrap();
+        assert_eq!(shrunk.collect(&fs, 0..POINTERS_IN_NODE - 1).wait().unwrap()
+                   .iter().filter(|ptr| ptr.is_some()).count(), 1);
+        // Growing again gives null elements, not the removed ones.
+        let regrown = shrunk.truncate(&fs, square + 1).wait().unwrap();
+        for &index in &[POINTERS_IN_NODE - 1, POINTERS_IN_NODE, square - 1, square] {
+            assert_eq!(regrown.get(&fs, index).wait().unwrap(), None);
+        }
+        assert_eq!(regrown.get(&fs, 0).wait().unwrap(), Some(a));
+
+        // Shrinking to nothing empties the tree.
+        let empty = array.truncate(&fs, 0).wait().unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.root(), None);
+    }
+
+    #[test]
+    fn structural_sharing() {
+        let fs = fs::memory(1024);
+        let (a, b) = (data(&fs, 1), data(&fs, 2));
+        let old = Array::<fs::Data>::new().truncate(&fs, 3 * POINTERS_IN_NODE).wait().unwrap()
+            .set(&fs, 0, Some(a)).wait().unwrap()
+            .set(&fs, POINTERS_IN_NODE, Some(a)).wait().unwrap()
+            .set(&fs, 2 * POINTERS_IN_NODE, Some(a)).wait().unwrap();
+        let new = old.set(&fs, POINTERS_IN_NODE + 1, Some(b)).wait().unwrap();
+
+        // Only the root and the modified leaf are copied.
+        let old_root = read_node(&fs, old.root()).wait().unwrap();
+        let new_root = read_node(&fs, new.root()).wait().unwrap();
+        assert_ne!(old.root(), new.root());
+        assert_eq!(new_root.pointers[0], old_root.pointers[0]);
+        assert_ne!(new_root.pointers[1], old_root.pointers[1]);
+        assert_eq!(new_root.pointers[2], old_root.pointers[2]);
+
+        // The old array is unchanged.
+        assert_eq!(old.get(&fs, POINTERS_IN_NODE + 1).wait().unwrap(), None);
+        assert_eq!(new.get(&fs, POINTERS_IN_NODE + 1).wait().unwrap(), Some(b));
+        assert_eq!(old.get(&fs, POINTERS_IN_NODE).wait().unwrap(), Some(a));
+        assert_eq!(new.get(&fs, POINTERS_IN_NODE).wait().unwrap(), Some(a));
+    }
+
+    #[test]
+    fn empty_node_is_zero() {
+        assert_eq!(&Node::empty().encode()[..], &[0; disk::SECTOR_SIZE][..]);
     }
 }
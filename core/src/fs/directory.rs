@@ -0,0 +1,792 @@
+//! Directories.
+//!
+//! Directories are stored as nested hash tables (a form of hash trees). A table is a page of
+//! slots, each of which is either empty, a bucket, or a subtable. Buckets are pages holding some
+//! number of entries, and when a bucket overflows, it is replaced by a subtable, into which its
+//! entries are rehashed with a hash function of the next depth.
+//!
+//! The hash function is SeaHash keyed by the disk UID, which prevents an attacker (who doesn't
+//! know the UID) from constructing names, which all collide.
+//!
+//! Like any other object, directories are copy-on-write: Mutations copy the tables on the path to
+//! the modified bucket, and return a new directory sharing the rest with the old one.
+//!
+//! # Table format
+//!
+//! 1. Byte 0-4: A little-endian bitmap, where bit `n` is set if slot `n` is a subtable.
+//! 2. Byte 4-16: Reserved (zero).
+//! 3. Byte 16-512: `SLOTS` page pointers. Null pointers represent empty slots. Non-null pointers
+//!    point to subtables or buckets, depending on the bitmap.
+//!
+//! # Bucket format
+//!
+//! A bucket is a sequence of entries, terminated by a zero byte (or the end of the page). Every
+//! entry consists of:
+//!
+//! 1. The length of the name in bytes (1 byte, non-zero).
+//! 2. The name.
+//! 3. The kind of the entry (1 byte).
+//! 4. A page pointer to the object of the entry (16 bytes).
+
+use futures::future::{self, Loop};
+use futures::Future;
+use std::convert::TryFrom;
+
+use {disk, fs, little_endian, seahash, Error};
+use alloc::{compact, page};
+use fs::Object;
+
+/// The maximal length of a name (in bytes).
+pub const MAX_NAME_LEN: usize = 255;
+/// The number of slots in a table.
+const SLOTS: usize = 31;
+/// The size of the table header.
+const TABLE_HEADER_SIZE: usize = disk::SECTOR_SIZE - SLOTS * page::POINTER_SIZE;
+/// The maximal depth of nested tables.
+///
+/// Only a pathological set of names (colliding at every depth) can exceed this.
+const MAX_DEPTH: u32 = 16;
+/// An arbitrary constant to key the hash function with, in addition to the UID.
+const HASH_KEY: u64 = 0x2f1c6dd0e8e7b46a;
+
+/// The kind of a directory entry.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// A file.
+    File = 1,
+    /// A directory.
+    Directory = 2,
+    /// A snapshot of the filesystem.
+    ///
+    /// This is only used in the snapshot table of the superpage.
+    Snapshot = 3,
+    /// An inode, which holds the metadata of a file or a directory.
+    Inode = 4,
+    /// A hard link to an inode in the link table.
+    ///
+    /// See `fs::link`.
+    Link = 5,
+}
+
+impl TryFrom<u8> for Kind {
+    type Err = Error;
+
+    fn try_from(from: u8) -> Result<Kind, Error> {
+        match from {
+            1 => Ok(Kind::File),
+            2 => Ok(Kind::Directory),
+            3 => Ok(Kind::Snapshot),
+            4 => Ok(Kind::Inode),
+            5 => Ok(Kind::Link),
+            _ => Err(err!(Corruption, "invalid directory entry kind {:x}", from)),
+        }
+    }
+}
+
+/// A directory entry.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    /// The kind of object the entry refers to.
+    pub kind: Kind,
+    /// The page pointer to the object.
+    pub target: page::Pointer,
+}
+
+/// Check that a name is valid.
+///
+/// Names must be non-empty, at most `MAX_NAME_LEN` bytes long, and cannot contain slashes or null
+/// bytes.
+fn check_name(name: &[u8]) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        Err(err!(InvalidInput, "invalid name length {}", name.len()))
+    } else if name.iter().any(|&x| x == b'/' || x == 0) {
+        Err(err!(InvalidInput, "name contains '/' or null byte"))
+    } else {
+        Ok(())
+    }
+}
+
+/// The slot of some name at some depth.
+///
+/// Every depth uses an independent hash function, so names colliding in one table are spread out
+/// in the subtable.
+fn slot(uid: u128, name: &[u8], depth: u32) -> usize {
+    let hash = seahash::hash_seeded(name, uid as u64, (uid >> 64) as u64, depth as u64, HASH_KEY);
+
+    (hash % SLOTS as u64) as usize
+}
+
+/// A bucket of entries.
+#[derive(Clone, Default, PartialEq, Debug)]
+struct Bucket {
+    /// The entries of the bucket.
+    entries: Vec<(Vec<u8>, Entry)>,
+}
+
+impl Bucket {
+    /// The size of an encoded entry with some name.
+    fn entry_size(name: &[u8]) -> usize {
+        1 + name.len() + 1 + page::POINTER_SIZE
+    }
+
+    /// Does the bucket fit in a page?
+    fn fits(&self) -> bool {
+        self.entries.iter().map(|&(ref name, _)| Bucket::entry_size(name)).sum::<usize>()
+            <= disk::SECTOR_SIZE
+    }
+
+    /// Find the position of an entry by its name.
+    fn find(&self, name: &[u8]) -> Option<usize> {
+        self.entries.iter().position(|&(ref x, _)| x[..] == name[..])
+    }
+
+    /// Parse the binary representation of a bucket.
+    fn decode(buf: &disk::SectorBuf) -> Result<Bucket, Error> {
+        let mut bucket = Bucket::default();
+        let mut window = &buf[..];
+
+        // Read until the terminator or the end of the page.
+        while !window.is_empty() && window[0] != 0 {
+            let len = window[0] as usize;
+            if window.len() < 1 + len + 1 + page::POINTER_SIZE {
+                return Err(err!(Corruption, "directory entry exceeds bucket"));
+            }
+
+            let name = window[1..][..len].to_vec();
+            let kind = Kind::try_from(window[1 + len])?;
+            let target = little_endian::read(&window[2 + len..])
+                .ok_or_else(|| err!(Corruption, "null pointer in directory entry"))?;
+
+            bucket.entries.push((name, Entry {
+                kind: kind,
+                target: target,
+            }));
+
+            // Slide the window to the next entry.
+            window = &window[Bucket::entry_size(&bucket.entries.last().unwrap().0)..];
+        }
+
+        Ok(bucket)
+    }
+
+    /// Encode the bucket into a sector-sized buffer.
+    ///
+    /// The bucket is assumed to fit.
+    fn encode(&self) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        let mut pos = 0;
+
+        for &(ref name, entry) in &self.entries {
+            buf[pos] = name.len() as u8;
+            buf[pos + 1..][..name.len()].copy_from_slice(name);
+            buf[pos + 1 + name.len()] = entry.kind as u8;
+            little_endian::write(&mut buf[pos + 2 + name.len()..], entry.target);
+
+            pos += Bucket::entry_size(name);
+        }
+        // If there is room left, it is zero, which acts as the terminator.
+
+        buf
+    }
+}
+
+/// A slot of a table.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Slot {
+    /// No entries hash to this slot.
+    Empty,
+    /// The entries hashing to this slot are in a bucket.
+    Bucket(page::Pointer),
+    /// The entries hashing to this slot are in a subtable.
+    Table(page::Pointer),
+}
+
+/// A hash table.
+#[derive(Copy, Clone)]
+struct Table {
+    /// The slots of the table.
+    slots: [Slot; SLOTS],
+}
+
+impl Table {
+    /// Create a table of empty slots.
+    fn empty() -> Table {
+        Table {
+            slots: [Slot::Empty; SLOTS],
+        }
+    }
+
+    /// Are all the slots empty?
+    fn is_empty(&self) -> bool {
+        self.slots.iter().all(|&x| x == Slot::Empty)
+    }
+
+    /// Parse the binary representation of a table.
+    fn decode(buf: &disk::SectorBuf) -> Table {
+        let mut table = Table::empty();
+        let subtables: u32 = little_endian::read(&buf[..]);
+
+        for (n, slot) in table.slots.iter_mut().enumerate() {
+            let ptr = little_endian::read(&buf[TABLE_HEADER_SIZE + n * page::POINTER_SIZE..]);
+
+            *slot = match ptr {
+                None => Slot::Empty,
+                Some(ptr) if subtables & (1 << n) != 0 => Slot::Table(ptr),
+                Some(ptr) => Slot::Bucket(ptr),
+            };
+        }
+
+        table
+    }
+
+    /// Encode the table into a sector-sized buffer.
+    fn encode(&self) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        let mut subtables = 0u32;
+
+        for (n, &slot) in self.slots.iter().enumerate() {
+            let ptr = match slot {
+                Slot::Empty => None,
+                Slot::Bucket(ptr) => Some(ptr),
+                Slot::Table(ptr) => {
+                    subtables |= 1 << n;
+                    Some(ptr)
+                },
+            };
+
+            little_endian::write(&mut buf[TABLE_HEADER_SIZE + n * page::POINTER_SIZE..], ptr);
+        }
+        little_endian::write(&mut buf[..], subtables);
+
+        buf
+    }
+}
+
+/// Read a table.
+fn read_table(fs: &fs::State, ptr: page::Pointer) -> future!(Table) {
+    fs.read(ptr).map(|buf| Table::decode(&buf))
+}
+
+/// Read a bucket.
+fn read_bucket(fs: &fs::State, ptr: page::Pointer) -> future!(Bucket) {
+    fs.read(ptr).and_then(|buf| Bucket::decode(&buf))
+}
+
+/// Store some entries at some depth.
+///
+/// This writes `bucket` into a bucket page if it fits, or spreads it into a new subtable at
+/// depth `depth` otherwise. The resulting slot is returned.
+fn write_entries(fs: &fs::State, bucket: Bucket, depth: u32) -> fs::BoxFuture<Slot> {
+    if bucket.entries.is_empty() {
+        // No need to store an empty bucket.
+        Box::new(future::ok(Slot::Empty))
+    } else if bucket.fits() {
+        Box::new(fs.alloc(bucket.encode(), "directory bucket").map(Slot::Bucket))
+    } else if depth > MAX_DEPTH {
+        Box::new(future::err(err!(OutOfSpace, "directory hash tables nested too deeply")))
+    } else {
+        trace!(fs, "splitting directory bucket"; "depth" => depth);
+
+        // Rehash the entries into the slots of the subtable.
+        let uid = fs.uid();
+        let mut buckets = vec![Bucket::default(); SLOTS];
+        for (name, entry) in bucket.entries {
+            buckets[slot(uid, &name, depth)].entries.push((name, entry));
+        }
+
+        // Write the slots, and then the subtable.
+        Box::new(future::join_all(buckets.into_iter().map(|bucket| {
+            write_entries(fs, bucket, depth + 1)
+        }).collect::<Vec<_>>()).and_then(move |slots| {
+            let mut table = Table::empty();
+            table.slots.copy_from_slice(&slots);
+            fs.alloc(table.encode(), "directory table").map(Slot::Table)
+        }))
+    }
+}
+
+/// Write a modified path of tables.
+///
+/// `path` contains the tables from the root and down, and `slot` is the new value of the slot of
+/// `name` in the last table. The tables are written bottom-up, and the new root is returned.
+/// Subtables, which end up empty, are removed.
+fn write_path(fs: &fs::State, path: Vec<Table>, name: &[u8], slot: Slot) -> future!(page::Pointer) {
+    let uid = fs.uid();
+    let name = name.to_vec();
+
+    future::loop_fn((path, slot), move |(mut path, child)| {
+        let depth = path.len() as u32 - 1;
+        let mut table = path.pop().unwrap();
+        table.slots[self::slot(uid, &name, depth)] = child;
+
+        if depth == 0 {
+            // The root table is always written, even if it is empty.
+            future::Either::A(fs.alloc(table.encode(), "directory table").map(Loop::Break))
+        } else if table.is_empty() {
+            // Remove the empty subtable.
+            future::Either::B(future::ok(Loop::Continue((path, Slot::Empty))))
+        } else {
+            future::Either::A(fs.alloc(table.encode(), "directory table").map(move |ptr| {
+                Loop::Continue((path, Slot::Table(ptr)))
+            }))
+        }
+    })
+}
+
+/// Visit the object of an entry.
+pub fn visit_child(fs: &fs::State, entry: Entry, visitor: &fs::Visitor) -> fs::BoxFuture<()> {
+    match entry.kind {
+        Kind::Directory => Box::new(Directory::from_raw(entry.target).visit(fs, visitor)),
+        Kind::File => Box::new(fs::File::open(fs, entry.target).and_then(move |file| {
+            file.visit(fs, visitor)
+        })),
+        Kind::Snapshot => fs::superpage::visit_snapshot(fs, entry.target, visitor),
+        Kind::Inode => Box::new(fs::Inode::open(fs, entry.target).and_then(move |inode| {
+            inode.visit(fs, visitor)
+        })),
+        // The inode is visited through the link table.
+        Kind::Link => {
+            visitor.page(entry.target);
+            Box::new(future::ok(()))
+        },
+    }
+}
+
+/// Relocate the object of an entry.
+pub fn relocate_child(
+    fs: &fs::State,
+    entry: Entry,
+    relocations: &compact::Relocations,
+) -> fs::BoxFuture<Entry> {
+    let kind = entry.kind;
+    let target = match kind {
+        Kind::Directory => Box::new(Directory::from_raw(entry.target).relocate(fs, relocations)
+            .map(|dir| dir.root())) as fs::BoxFuture<page::Pointer>,
+        Kind::File => Box::new(fs::File::open(fs, entry.target).and_then(move |file| {
+            file.relocate(fs, relocations)
+        }).map(|file| file.root())),
+        Kind::Snapshot => fs::superpage::relocate_snapshot(fs, entry.target, relocations),
+        Kind::Inode => Box::new(fs::Inode::open(fs, entry.target).and_then(move |inode| {
+            inode.relocate(fs, relocations)
+        }).map(|inode| inode.root())),
+        Kind::Link => Box::new(future::ok(relocations.get(entry.target).unwrap_or(entry.target))),
+    };
+
+    Box::new(target.map(move |target| Entry {
+        kind: kind,
+        target: target,
+    }))
+}
+
+/// Visit a table and everything below it.
+fn visit_table(fs: &fs::State, ptr: page::Pointer, visitor: &fs::Visitor) -> fs::BoxFuture<()> {
+    visitor.page(ptr);
+
+    Box::new(read_table(fs, ptr).and_then(move |table| {
+        future::join_all(table.slots.iter().map(|&slot| match slot {
+            Slot::Empty => Box::new(future::ok(())) as fs::BoxFuture<()>,
+            Slot::Table(ptr) => visit_table(fs, ptr, visitor),
+            Slot::Bucket(ptr) => {
+                visitor.page(ptr);
+
+                // Visit the entries of the bucket.
+                Box::new(read_bucket(fs, ptr).and_then(move |bucket| {
+                    future::join_all(bucket.entries.into_iter().map(|(_, entry)| {
+                        visit_child(fs, entry, visitor)
+                    }).collect::<Vec<_>>())
+                }).map(|_| ()))
+            },
+        }).collect::<Vec<_>>()).map(|_| ())
+    }))
+}
+
+/// Relocate a table and everything below it.
+///
+/// The pointer to the updated table is returned. The table is only copied if anything below it
+/// changed.
+fn relocate_table(
+    fs: &fs::State,
+    ptr: page::Pointer,
+    relocations: &compact::Relocations,
+) -> fs::BoxFuture<page::Pointer> {
+    Box::new(read_table(fs, ptr).and_then(move |table| {
+        future::join_all(table.slots.iter().map(|&slot| match slot {
+            Slot::Empty => Box::new(future::ok(Slot::Empty)) as fs::BoxFuture<Slot>,
+            Slot::Table(ptr) => Box::new(relocate_table(fs, ptr, relocations).map(Slot::Table)),
+            Slot::Bucket(ptr) => Box::new(read_bucket(fs, ptr).and_then(move |bucket| {
+                // Relocate the objects of the entries.
+                future::join_all(bucket.entries.iter().map(|&(_, entry)| {
+                    relocate_child(fs, entry, relocations)
+                }).collect::<Vec<_>>()).and_then(move |entries| {
+                    if bucket.entries.iter().map(|&(_, entry)| entry).eq(entries.iter().cloned()) {
+                        // Nothing changed, so we only need to follow the bucket, if it was moved.
+                        let ptr = relocations.get(ptr).unwrap_or(ptr);
+                        future::Either::A(future::ok(Slot::Bucket(ptr)))
+                    } else {
+                        let bucket = Bucket {
+                            entries: bucket.entries.into_iter().map(|(name, _)| name)
+                                .zip(entries).collect(),
+                        };
+                        future::Either::B(fs.alloc(bucket.encode(), "directory bucket")
+                            .map(Slot::Bucket))
+                    }
+                })
+            })),
+        }).collect::<Vec<_>>()).and_then(move |slots| {
+            if slots[..] == table.slots[..] {
+                // Nothing below the table changed.
+                future::Either::A(future::ok(relocations.get(ptr).unwrap_or(ptr)))
+            } else {
+                let mut table = Table::empty();
+                table.slots.copy_from_slice(&slots);
+                future::Either::B(fs.alloc(table.encode(), "directory table"))
+            }
+        })
+    }))
+}
+
+/// A directory.
+///
+/// This is a persistent map from names to entries. Mutations return a new directory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Directory {
+    /// The root table.
+    table: page::Pointer,
+}
+
+impl Directory {
+    /// Create a new, empty directory.
+    pub fn create(fs: &fs::State) -> future!(Directory) {
+        fs.alloc(Table::empty().encode(), "directory table").map(Directory::from_raw)
+    }
+
+    /// Load a directory from its root table.
+    pub fn from_raw(table: page::Pointer) -> Directory {
+        Directory {
+            table: table,
+        }
+    }
+
+    /// Get the pointer to the root table.
+    ///
+    /// This is the pointer, which refers to the directory.
+    pub fn root(&self) -> page::Pointer {
+        self.table
+    }
+
+    /// Find the tables on the path to the bucket of some name.
+    ///
+    /// The tables are returned from the root and down, together with the bucket (or `None` if the
+    /// slot is empty).
+    fn find(&self, fs: &fs::State, name: &[u8]) -> future!((Vec<Table>, Option<Bucket>)) {
+        let uid = fs.uid();
+        let name = name.to_vec();
+
+        read_table(fs, self.table).and_then(move |root| {
+            future::loop_fn(vec![root], move |mut path| {
+                let depth = path.len() as u32 - 1;
+
+                match path.last().unwrap().slots[slot(uid, &name, depth)] {
+                    // The name is in a subtable, so we descend into it.
+                    Slot::Table(ptr) => future::Either::A(read_table(fs, ptr).map(move |table| {
+                        path.push(table);
+                        Loop::Continue(path)
+                    })),
+                    Slot::Bucket(ptr) => future::Either::B(future::Either::A(
+                        read_bucket(fs, ptr).map(move |bucket| Loop::Break((path, Some(bucket))))
+                    )),
+                    Slot::Empty => future::Either::B(future::Either::B(
+                        future::ok(Loop::Break((path, None)))
+                    )),
+                }
+            })
+        })
+    }
+
+    /// Look up some name.
+    ///
+    /// If no entry with name `name` exists, `None` is returned.
+    pub fn lookup(&self, fs: &fs::State, name: &[u8]) -> future!(Option<Entry>) {
+        let key = name.to_vec();
+
+        self.find(fs, name).map(move |(_, bucket)| bucket.and_then(|bucket| {
+            bucket.find(&key).map(|n| bucket.entries[n].1)
+        }))
+    }
+
+    /// Insert an entry.
+    ///
+    /// This creates a new directory, in which `name` maps to `entry`. If `name` already exists,
+    /// its entry is replaced.
+    pub fn insert(&self, fs: &fs::State, name: &[u8], entry: Entry) -> future!(Directory) {
+        if let Err(err) = check_name(name) {
+            return future::Either::A(future::err(err));
+        }
+
+        let key = name.to_vec();
+        future::Either::B(self.find(fs, name).and_then(move |(path, bucket)| {
+            let mut bucket = bucket.unwrap_or_default();
+
+            // Replace the entry, or add it.
+            match bucket.find(&key) {
+                Some(n) => bucket.entries[n].1 = entry,
+                None => bucket.entries.push((key.clone(), entry)),
+            }
+
+            // Write the bucket. If it overflows, it is split into a subtable one level below the
+            // table it is in.
+            let depth = path.len() as u32;
+            write_entries(fs, bucket, depth).and_then(move |slot| write_path(fs, path, &key, slot))
+        }).map(Directory::from_raw))
+    }
+
+    /// Remove an entry.
+    ///
+    /// This creates a new directory without `name`. The removed entry is returned, or `None` if
+    /// it doesn't exist (in which case the directory is unchanged).
+    pub fn remove(&self, fs: &fs::State, name: &[u8]) -> future!((Directory, Option<Entry>)) {
+        let key = name.to_vec();
+        let old = *self;
+
+        self.find(fs, name).and_then(move |(path, bucket)| {
+            let mut bucket = match bucket {
+                Some(ref bucket) if bucket.find(&key).is_some() => bucket.clone(),
+                // The entry doesn't exist.
+                _ => return future::Either::A(future::ok((old, None))),
+            };
+
+            let n = bucket.find(&key).unwrap();
+            let (_, entry) = bucket.entries.remove(n);
+
+            let depth = path.len() as u32;
+            future::Either::B(write_entries(fs, bucket, depth)
+                .and_then(move |slot| write_path(fs, path, &key, slot))
+                .map(move |root| (Directory::from_raw(root), Some(entry))))
+        })
+    }
+
+    /// List the entries.
+    ///
+    /// The entries are returned ordered by their name.
+    pub fn list(&self, fs: &fs::State) -> future!(Vec<(Vec<u8>, Entry)>) {
+        /// Collect the entries of a table and everything below it.
+        fn collect(fs: &fs::State, ptr: page::Pointer) -> fs::BoxFuture<Vec<(Vec<u8>, Entry)>> {
+            Box::new(read_table(fs, ptr).and_then(move |table| {
+                future::join_all(table.slots.iter().map(|&slot| match slot {
+                    Slot::Empty => Box::new(future::ok(Vec::new())) as fs::BoxFuture<_>,
+                    Slot::Table(ptr) => collect(fs, ptr),
+                    Slot::Bucket(ptr) => Box::new(read_bucket(fs, ptr).map(|x| x.entries)),
+                }).collect::<Vec<_>>())
+            }).map(|x| x.into_iter().flat_map(|x| x).collect()))
+        }
+
+        collect(fs, self.table).map(|mut entries| {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        })
+    }
+}
+
+impl fs::Object for Directory {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        visit_table(fs, self.table, visitor)
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Directory) {
+        relocate_table(fs, self.table, relocations).map(Directory::from_raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+
+    fn entry(kind: Kind, cluster: u64) -> Entry {
+        Entry {
+            kind: kind,
+            target: page::Pointer {
+                cluster: cluster::Pointer::new(cluster).unwrap(),
+                offset: Some(3),
+                checksum: 0xABCD,
+            },
+        }
+    }
+
+    #[test]
+    fn bucket_inverse_identity() {
+        let mut bucket = Bucket::default();
+        assert_eq!(Bucket::decode(&bucket.encode()).unwrap(), bucket);
+
+        bucket.entries.push((b"a".to_vec(), entry(Kind::File, 1)));
+        bucket.entries.push((b"hello world".to_vec(), entry(Kind::Directory, 2)));
+        bucket.entries.push((vec![0xFF; MAX_NAME_LEN], entry(Kind::File, 3)));
+        assert!(bucket.fits());
+        assert_eq!(Bucket::decode(&bucket.encode()).unwrap(), bucket);
+    }
+
+    #[test]
+    fn bucket_overflow() {
+        let mut bucket = Bucket::default();
+        bucket.entries.push((vec![b'a'; MAX_NAME_LEN], entry(Kind::File, 1)));
+        assert!(bucket.fits());
+        bucket.entries.push((vec![b'b'; MAX_NAME_LEN], entry(Kind::File, 2)));
+        assert!(!bucket.fits());
+    }
+
+    #[test]
+    fn invalid_bucket() {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        buf[0] = 1;
+        buf[1] = b'a';
+        // Invalid kind.
+        buf[2] = 0xEE;
+        assert_eq!(Bucket::decode(&buf).unwrap_err().kind, ::error::Kind::Corruption);
+
+        // Null pointer.
+        buf[2] = Kind::File as u8;
+        assert_eq!(Bucket::decode(&buf).unwrap_err().kind, ::error::Kind::Corruption);
+
+        // Entry exceeding the page: The second entry claims a name longer than the rest of the
+        // page.
+        let name = vec![b'a'; MAX_NAME_LEN];
+        let mut bucket = Bucket::default();
+        bucket.entries.push((name.clone(), entry(Kind::File, 1)));
+        let mut buf = bucket.encode();
+        let end = Bucket::entry_size(&name);
+        assert!(Bucket::decode(&buf).is_ok());
+        buf[end] = MAX_NAME_LEN as u8;
+        assert!(end + Bucket::entry_size(&name) > disk::SECTOR_SIZE);
+        assert_eq!(Bucket::decode(&buf).unwrap_err().kind, ::error::Kind::Corruption);
+    }
+
+    #[test]
+    fn table_inverse_identity() {
+        let mut table = Table::empty();
+        assert!(table.is_empty());
+
+        table.slots[0] = Slot::Bucket(entry(Kind::File, 5).target);
+        table.slots[SLOTS - 1] = Slot::Table(entry(Kind::File, 6).target);
+        assert!(!table.is_empty());
+
+        assert_eq!(Table::decode(&table.encode()).slots[..], table.slots[..]);
+    }
+
+    #[test]
+    fn hashing() {
+        // Deterministic.
+        assert_eq!(slot(1, b"abc", 0), slot(1, b"abc", 0));
+
+        // The slots are spread out, and depend on the depth and the UID.
+        let spread = |f: &Fn(u32) -> usize| {
+            (0..1000).map(f).collect::<::std::collections::HashSet<_>>().len()
+        };
+        assert_eq!(spread(&|n| slot(1, n.to_string().as_bytes(), 0)), SLOTS);
+        assert_eq!(spread(&|depth| slot(1, b"abc", depth)), SLOTS);
+        assert_eq!(spread(&|uid| slot(uid as u128, b"abc", 0)), SLOTS);
+    }
+
+    /// A long name, so few of them fit in a bucket.
+    fn long_name(n: usize) -> Vec<u8> {
+        format!("{:0>100}", n).into_bytes()
+    }
+
+    #[test]
+    fn insert_lookup_remove() {
+        let fs = fs::memory(1024);
+        let dir = Directory::create(&fs).wait().unwrap();
+        assert_eq!(dir.lookup(&fs, b"a").wait().unwrap(), None);
+        assert!(dir.list(&fs).wait().unwrap().is_empty());
+
+        let dir = dir.insert(&fs, b"b", entry(Kind::File, 1)).wait().unwrap()
+            .insert(&fs, b"a", entry(Kind::Directory, 2)).wait().unwrap();
+        assert_eq!(dir.lookup(&fs, b"a").wait().unwrap(), Some(entry(Kind::Directory, 2)));
+        assert_eq!(dir.lookup(&fs, b"b").wait().unwrap(), Some(entry(Kind::File, 1)));
+        assert_eq!(dir.lookup(&fs, b"c").wait().unwrap(), None);
+        // The entries are listed by name.
+        assert_eq!(dir.list(&fs).wait().unwrap(), vec![
+            (b"a".to_vec(), entry(Kind::Directory, 2)),
+            (b"b".to_vec(), entry(Kind::File, 1)),
+        ]);
+
+        // Inserting an existing name replaces its entry.
+        let replaced = dir.insert(&fs, b"b", entry(Kind::File, 3)).wait().unwrap();
+        assert_eq!(replaced.lookup(&fs, b"b").wait().unwrap(), Some(entry(Kind::File, 3)));
+        assert_eq!(replaced.list(&fs).wait().unwrap().len(), 2);
+        // The old directory is unchanged.
+        assert_eq!(dir.lookup(&fs, b"b").wait().unwrap(), Some(entry(Kind::File, 1)));
+
+        // Invalid names are rejected.
+        assert_eq!(dir.insert(&fs, b"x/y", entry(Kind::File, 4)).wait().unwrap_err().kind,
+                   ::error::Kind::InvalidInput);
+
+        // Removing a missing name leaves the directory as it is.
+        assert_eq!(dir.remove(&fs, b"c").wait().unwrap(), (dir, None));
+
+        let (removed, old) = dir.remove(&fs, b"a").wait().unwrap();
+        assert_eq!(old, Some(entry(Kind::Directory, 2)));
+        assert_eq!(removed.lookup(&fs, b"a").wait().unwrap(), None);
+        assert_eq!(removed.lookup(&fs, b"b").wait().unwrap(), Some(entry(Kind::File, 1)));
+        assert_eq!(removed.list(&fs).wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let fs = fs::memory(8192);
+        // With more than four long names per slot on average, some bucket must overflow.
+        let count = SLOTS * 5;
+        let mut dir = Directory::create(&fs).wait().unwrap();
+        let empty = read_table(&fs, dir.root()).wait().unwrap();
+
+        for n in 0..count {
+            dir = dir.insert(&fs, &long_name(n), entry(Kind::File, n as u64 + 1)).wait().unwrap();
+        }
+
+        // Some buckets were split into subtables.
+        let root = read_table(&fs, dir.root()).wait().unwrap();
+        assert!(root.slots.iter().any(|slot| match *slot {
+            Slot::Table(_) => true,
+            _ => false,
+        }));
+
+        // Every entry is still found, both by lookup and by listing.
+        for n in 0..count {
+            assert_eq!(dir.lookup(&fs, &long_name(n)).wait().unwrap(),
+                       Some(entry(Kind::File, n as u64 + 1)));
+        }
+        let list = dir.list(&fs).wait().unwrap();
+        assert_eq!(list.len(), count);
+        assert_eq!(list.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+                   (0..count).map(long_name).collect::<Vec<_>>());
+
+        // Remove every other entry, and then the rest.
+        for n in (0..count).filter(|n| n % 2 == 0) {
+            let (new, old) = dir.remove(&fs, &long_name(n)).wait().unwrap();
+            assert_eq!(old, Some(entry(Kind::File, n as u64 + 1)));
+            dir = new;
+        }
+        assert_eq!(dir.list(&fs).wait().unwrap().len(), count / 2);
+        for n in 0..count {
+            let expected = if n % 2 == 0 { None } else { Some(entry(Kind::File, n as u64 + 1)) };
+            assert_eq!(dir.lookup(&fs, &long_name(n)).wait().unwrap(), expected);
+        }
+
+        for n in (0..count).filter(|n| n % 2 == 1) {
+            dir = dir.remove(&fs, &long_name(n)).wait().unwrap().0;
+        }
+
+        // The emptied subtables are removed, leaving an empty root table.
+        assert!(dir.list(&fs).wait().unwrap().is_empty());
+        assert_eq!(read_table(&fs, dir.root()).wait().unwrap().slots[..], empty.slots[..]);
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name(b"abc").is_ok());
+        assert!(check_name(&[b'a'; MAX_NAME_LEN]).is_ok());
+        assert!(check_name(b"").is_err());
+        assert!(check_name(&[b'a'; MAX_NAME_LEN + 1]).is_err());
+        assert!(check_name(b"a/b").is_err());
+        assert!(check_name(b"a\0b").is_err());
+    }
+}
@@ -0,0 +1,1451 @@
+//! Files.
+//!
+//! A file is a sequence of bytes, stored as an array of data pages. The array and the length (in
+//! bytes) are kept in a header page, which is the page referring to the file (e.g. from a
+//! directory entry).
+//!
+//! Files are copy-on-write: A write only reallocates the pages it modifies (as well as the array
+//! nodes above them), and then writes a new header. The new version of the file thus becomes
+//! visible atomically, through the single pointer to the new header, and the old version remains
+//! valid.
+//!
+//! The bytes of the last page after the end of the file are always zero. Null pages (e.g. after
+//! extending the file through `truncate()`) read as zeros.
+//!
+//! # Sparse files
+//!
+//! Null pages take no space, so they serve as holes. Holes are created by extending the file, by
+//! punching them (`punch_hole()`), or by sparse writes (`write_sparse()`), which turn pages
+//! ending up entirely zero into holes. `seek_data()` and `seek_hole()` find the data and holes,
+//! like `SEEK_DATA` and `SEEK_HOLE` of `lseek`.
+//!
+//! # Inline content
+//!
+//! Files of up to `fs::inline::MAX_LEN` bytes store their content inline, in the unused tail of
+//! the header page, instead of in data pages of their own (see `fs::inline`). New files start out
+//! inline, and are promoted to data pages transparently, once a write or resize makes them exceed
+//! the limit. Promoted files stay in data pages, even if they shrink again.
+//!
+//! The extended attributes are stored inline as well, in the room left by the inline content, if
+//! they fit. The content takes precedence, so the attributes are moved to an attribute table,
+//! when the content grows into their room.
+//!
+//! # Revision history
+//!
+//! Every version of a file is a revision, numbered by its generation. The header keeps an array
+//! of the headers of the prior revisions (oldest first), so any of them can be read or reverted
+//! to. Since the revisions are copy-on-write versions, they share every unmodified page.
+//!
+//! The history grows with every version, and is bounded by pruning it according to a retention
+//! policy, which is applied whenever a new version is created (see `fs::State::retention()`), and
+//! can be applied explicitly by `File::prune()`. Pruned revisions are replaced by null pointers in
+//! the history, making them unreachable, so the garbage collector frees the pages, which no other
+//! revision uses.
+//!
+//! Only the history of the current version is retained: The history arrays of the prior revisions
+//! are not visited by the garbage collector, and must not be followed.
+//!
+//! # Header format
+//!
+//! 1. Byte 0-8: The length of the file in bytes (little-endian).
+//! 2. Byte 8-24: The page pointer to the root node of the array of data pages (null if none).
+//! 3. Byte 24-32: The generation of the revision (little-endian).
+//! 4. Byte 32-40: The time of the revision in seconds since the Unix epoch (little-endian).
+//! 5. Byte 40-56: The page pointer to the root node of the history array (null if none).
+//! 6. Byte 56-64: The length of the history array (little-endian).
+//! 7. Byte 64-80: The root pointer of the extended attribute table (null if none).
+//! 8. Byte 80: The inline flags. Bit 0 is set if the content is stored inline, and bit 1 if the
+//!    extended attributes are. The other bits are zero.
+//! 9. Byte 81-512: The inline content (the first $l$ bytes, where $l$ is the length of the file),
+//!    if stored inline, followed by the length of the inline attribute block (2 bytes,
+//!    little-endian) and the block itself, if the attributes are stored inline. The rest is zero.
+
+use futures::future::{self, Either};
+use futures::{stream, Future, Stream};
+use std::cmp;
+use std::ops::Range;
+use std::time::Duration;
+
+use {disk, fs, little_endian, Error};
+use alloc::{compact, locality, page};
+use fs::Object;
+
+/// The size of a data page in bytes.
+const PAGE_SIZE: u64 = disk::SECTOR_SIZE as u64;
+/// The number of prior revisions retained by the default retention policy.
+pub const DEFAULT_REVISIONS: u64 = 16;
+/// The inline flag of inline content.
+const INLINE_CONTENT: u8 = 1;
+/// The inline flag of inline extended attributes.
+const INLINE_XATTRS: u8 = 2;
+/// The size of the length of inline extended attributes.
+const XATTRS_LEN_SIZE: usize = 2;
+
+/// The number of pages needed to hold some number of bytes.
+fn pages(len: u64) -> u64 {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// The header of a file.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Header {
+    /// The length of the file in bytes.
+    len: u64,
+    /// The root of the array of data pages.
+    data: Option<page::Pointer>,
+    /// The generation of the revision.
+    generation: u64,
+    /// The time of the revision.
+    timestamp: u64,
+    /// The root of the history array.
+    history: Option<page::Pointer>,
+    /// The length of the history array.
+    history_len: u64,
+    /// The root of the extended attribute table.
+    xattrs: Option<page::Pointer>,
+    /// The content, if stored inline.
+    ///
+    /// If this is `Some`, there are no data pages.
+    inline: Option<fs::Inline>,
+    /// The extended attribute block, if stored inline.
+    ///
+    /// If this is `Some`, `xattrs` is `None`. It fills the room left by the inline content (see
+    /// `xattrs_room()`).
+    inline_xattrs: Option<fs::Inline>,
+}
+
+impl Header {
+    /// Parse the binary representation of a header.
+    fn decode(buf: &disk::SectorBuf) -> Result<Header, Error> {
+        let len = little_endian::read(&buf[..]);
+        let flags = buf[fs::inline::FLAG_OFFSET];
+        if flags & !(INLINE_CONTENT | INLINE_XATTRS) != 0 {
+            return Err(err!(Corruption, "invalid inline flags {:x}", flags));
+        }
+
+        let mut area = &buf[fs::inline::OFFSET..];
+        let inline = if flags & INLINE_CONTENT == 0 {
+            None
+        } else if len <= area.len() as u64 {
+            let inline = fs::Inline::new(&area[..len as usize]);
+            area = &area[len as usize..];
+            inline
+        } else {
+            return Err(err!(Corruption, "inline content of {} bytes exceeds the page", len));
+        };
+        let inline_xattrs = if flags & INLINE_XATTRS == 0 {
+            None
+        } else if area.len() >= XATTRS_LEN_SIZE
+            && little_endian::read::<u16>(area) as usize <= area.len() - XATTRS_LEN_SIZE {
+            let xattrs_len = little_endian::read::<u16>(area) as usize;
+            fs::Inline::new(&area[XATTRS_LEN_SIZE..][..xattrs_len])
+        } else {
+            return Err(err!(Corruption, "inline extended attributes exceed the page"));
+        };
+
+        Ok(Header {
+            len: len,
+            data: little_endian::read(&buf[8..]),
+            generation: little_endian::read(&buf[24..]),
+            timestamp: little_endian::read(&buf[32..]),
+            history: little_endian::read(&buf[40..]),
+            history_len: little_endian::read(&buf[56..]),
+            xattrs: little_endian::read(&buf[64..]),
+            inline: inline,
+            inline_xattrs: inline_xattrs,
+        })
+    }
+
+    /// Encode the header into a sector-sized buffer.
+    fn encode(&self) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+
+        little_endian::write(&mut buf[..], self.len);
+        little_endian::write(&mut buf[8..], self.data);
+        little_endian::write(&mut buf[24..], self.generation);
+        little_endian::write(&mut buf[32..], self.timestamp);
+        little_endian::write(&mut buf[40..], self.history);
+        little_endian::write(&mut buf[56..], self.history_len);
+        little_endian::write(&mut buf[64..], self.xattrs);
+
+        let mut pos = fs::inline::OFFSET;
+        if let Some(inline) = self.inline {
+            buf[fs::inline::FLAG_OFFSET] |= INLINE_CONTENT;
+            buf[pos..][..inline.as_slice().len()].copy_from_slice(inline.as_slice());
+            pos += inline.as_slice().len();
+        }
+        if let Some(xattrs) = self.inline_xattrs {
+            buf[fs::inline::FLAG_OFFSET] |= INLINE_XATTRS;
+            little_endian::write(&mut buf[pos..], xattrs.as_slice().len() as u16);
+            pos += XATTRS_LEN_SIZE;
+            buf[pos..][..xattrs.as_slice().len()].copy_from_slice(xattrs.as_slice());
+        }
+
+        buf
+    }
+
+    /// The room for inline extended attributes in bytes.
+    ///
+    /// This is the part of the inline area, which isn't used by inline content.
+    fn xattrs_room(&self) -> usize {
+        let content = self.inline.map_or(0, |x| x.as_slice().len());
+
+        fs::inline::MAX_LEN.saturating_sub(content + XATTRS_LEN_SIZE)
+    }
+
+    /// Get the extended attributes.
+    fn xattrs(&self, fs: &fs::State) -> future!(fs::Xattrs) {
+        match self.inline_xattrs {
+            Some(inline) => Either::A(future::result(fs::Xattrs::from_inline(&inline))),
+            None => Either::B(fs::Xattrs::load(fs, self.xattrs)),
+        }
+    }
+
+    /// Store the extended attributes.
+    ///
+    /// They are stored inline, if they fit in the room left by the inline content, and in an
+    /// attribute table otherwise. The updated header is returned.
+    fn set_xattrs(self, fs: &fs::State, xattrs: fs::Xattrs) -> future!(Header) {
+        match xattrs.to_inline(self.xattrs_room()) {
+            Some(inline) => Either::A(future::ok(Header {
+                xattrs: None,
+                inline_xattrs: Some(inline),
+                .. self
+            })),
+            None => Either::B(xattrs.write(fs).map(move |xattrs| Header {
+                xattrs: xattrs,
+                inline_xattrs: None,
+                .. self
+            })),
+        }
+    }
+
+    /// Fit the inline extended attributes into the room left by the inline content.
+    ///
+    /// The inline content takes precedence, so if it grew, the attributes are reencoded into the
+    /// smaller room, or moved to an attribute table, if they no longer fit.
+    fn fit_xattrs(self, fs: &fs::State) -> future!(Header) {
+        match self.inline_xattrs {
+            Some(inline) if inline.as_slice().len() != self.xattrs_room() => {
+                Either::A(future::result(fs::Xattrs::from_inline(&inline))
+                    .and_then(move |xattrs| self.set_xattrs(fs, xattrs)))
+            },
+            _ => Either::B(future::ok(self)),
+        }
+    }
+
+    /// Visit the extended attributes.
+    fn visit_xattrs(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        match self.inline_xattrs {
+            Some(inline) => Either::A(future::result(fs::Xattrs::from_inline(&inline))
+                .and_then(move |xattrs| xattrs.visit(fs, visitor))),
+            None => Either::B(fs::xattr::visit(fs, self.xattrs, visitor)),
+        }
+    }
+
+    /// Relocate the extended attributes.
+    ///
+    /// The new attribute table and inline attributes are returned.
+    fn relocate_xattrs(
+        &self,
+        fs: &fs::State,
+        relocations: &compact::Relocations,
+    ) -> future!((Option<page::Pointer>, Option<fs::Inline>)) {
+        let room = self.xattrs_room();
+
+        match self.inline_xattrs {
+            // The relocated attributes take the same space, so they still fit.
+            Some(inline) => Either::A(future::result(fs::Xattrs::from_inline(&inline))
+                .and_then(move |xattrs| xattrs.relocate(fs, relocations))
+                .map(move |xattrs| (None, xattrs.to_inline(room)))),
+            None => {
+                Either::B(fs::xattr::relocate(fs, self.xattrs, relocations).map(|x| (x, None)))
+            },
+        }
+    }
+
+    /// Get the array of data pages.
+    ///
+    /// If the content is stored inline, this consists of null pages.
+    fn data(&self) -> fs::Array<fs::Data> {
+        fs::Array::from_raw(self.data, pages(self.len))
+    }
+
+    /// Get the array of data pages, promoting inline content to data pages.
+    ///
+    /// The promoted pages are allocated with locality hint `hint`.
+    fn promote(&self, fs: &fs::State, hint: locality::Hint) -> future!(fs::Array<fs::Data>) {
+        match self.inline {
+            Some(inline) => {
+                Either::A(write_pages(fs, self.data(), 0, inline.as_slice().to_vec(), false, hint))
+            },
+            None => Either::B(future::ok(self.data())),
+        }
+    }
+
+    /// Get the history array.
+    fn history(&self) -> fs::Array<Previous> {
+        fs::Array::from_raw(self.history, self.history_len)
+    }
+}
+
+/// Split a byte range into the pages it covers entirely, and the partially covered parts at
+/// either end.
+///
+/// The ranges are returned as (whole pages by index, head bytes, tail bytes).
+fn split_range(range: Range<u64>) -> (Range<u64>, Range<u64>, Range<u64>) {
+    let (first, last) = (pages(range.start), range.end / PAGE_SIZE);
+    let head = range.start..cmp::min(first * PAGE_SIZE, range.end);
+    let tail = cmp::max(last * PAGE_SIZE, head.end)..range.end;
+
+    (first..cmp::max(first, last), head, tail)
+}
+
+/// Write bytes into the data pages.
+///
+/// The array must already hold the pages covering the range. Only the pages overlapping the range
+/// are reallocated, and pages, which are only partially covered, are read and merged with `buf`.
+/// If `sparse` is set, pages, which end up entirely zero, are replaced by null pages.
+///
+/// The pages are allocated with locality hint `hint`, so the pages of a file share clusters.
+fn write_pages(
+    fs: &fs::State,
+    data: fs::Array<fs::Data>,
+    offset: u64,
+    buf: Vec<u8>,
+    sparse: bool,
+    hint: locality::Hint,
+) -> future!(fs::Array<fs::Data>) {
+    let end = offset + buf.len() as u64;
+    let range = if buf.is_empty() { 0..0 } else { offset / PAGE_SIZE..pages(end) };
+
+    stream::iter_ok(range).fold(data, move |data, index| {
+        // Find the part of the page covered by the write.
+        let page_start = index * PAGE_SIZE;
+        let from = cmp::max(offset, page_start);
+        let to = cmp::min(end, page_start + PAGE_SIZE);
+        let chunk = buf[(from - offset) as usize..(to - offset) as usize].to_vec();
+
+        let old = if to - from == PAGE_SIZE {
+            // The page is overwritten entirely, so there's no need to read it.
+            Either::A(future::ok([0; disk::SECTOR_SIZE]))
+        } else {
+            Either::B(data.get(fs, index).and_then(move |ptr| read_page(fs, ptr)))
+        };
+
+        old.and_then(move |mut page| {
+            // Merge the written bytes into the page.
+            page[(from - page_start) as usize..(to - page_start) as usize]
+                .copy_from_slice(&chunk);
+
+            if sparse && page.iter().all(|&x| x == 0) {
+                Either::A(future::ok(None))
+            } else {
+                Either::B(fs.alloc_near(page, "file page", hint).map(Some))
+            }
+        }).and_then(move |ptr| data.set(fs, index, ptr))
+    })
+}
+
+/// Read a header.
+fn read_header(fs: &fs::State, ptr: page::Pointer) -> future!(Header) {
+    fs.read(ptr).and_then(|buf| Header::decode(&buf))
+}
+
+/// Read a data page.
+///
+/// Null pages are read as zeros.
+fn read_page(fs: &fs::State, ptr: Option<page::Pointer>) -> future!(disk::SectorBuf) {
+    match ptr {
+        Some(ptr) => Either::A(fs.read(ptr)),
+        None => Either::B(future::ok([0; disk::SECTOR_SIZE])),
+    }
+}
+
+/// A prior revision, as an element of the history.
+///
+/// This points to the header of the revision. Only its data pages are retained, not its own
+/// history.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Previous(page::Pointer);
+
+impl From<page::Pointer> for Previous {
+    fn from(ptr: page::Pointer) -> Previous {
+        Previous(ptr)
+    }
+}
+
+impl From<Previous> for page::Pointer {
+    fn from(previous: Previous) -> page::Pointer {
+        previous.0
+    }
+}
+
+impl fs::Object for Previous {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        visitor.page(self.0);
+
+        read_header(fs, self.0).and_then(move |header| {
+            header.data().visit(fs, visitor)
+                .join(header.visit_xattrs(fs, visitor))
+                .map(|_| ())
+        })
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Previous) {
+        let ptr = self.0;
+
+        read_header(fs, ptr).and_then(move |header| {
+            header.data().relocate(fs, relocations)
+                .join(header.relocate_xattrs(fs, relocations))
+                .and_then(move |(data, (xattrs, inline_xattrs))| {
+                    if data.root() == header.data && xattrs == header.xattrs
+                        && inline_xattrs == header.inline_xattrs {
+                        // Follow the header, if it was moved.
+                        Either::A(future::ok(Previous(relocations.get(ptr).unwrap_or(ptr))))
+                    } else {
+                        // Write a copy of the header pointing to the relocated pages.
+                        Either::B(fs.alloc(Header {
+                            data: data.root(),
+                            xattrs: xattrs,
+                            inline_xattrs: inline_xattrs,
+                            .. header
+                        }.encode(), "file header").map(Previous))
+                    }
+                })
+        })
+    }
+}
+
+/// A revision of a file.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Revision {
+    /// The generation of the revision.
+    ///
+    /// The generation is incremented by every new version of the file.
+    pub generation: u64,
+    /// The time of the revision in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The index of the revision in the history.
+    index: u64,
+    /// The header of the revision.
+    header: page::Pointer,
+}
+
+/// A retention policy for the revision history.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Retention {
+    /// Keep every revision.
+    All,
+    /// Keep some number of the latest revisions.
+    Last(u64),
+    /// Keep the revisions newer than some age.
+    NewerThan(Duration),
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention::Last(DEFAULT_REVISIONS)
+    }
+}
+
+impl Retention {
+    /// Find the revisions, which are not retained.
+    ///
+    /// `revisions` is the history (oldest first), and `now` the current time in seconds since the
+    /// Unix epoch.
+    fn expired<'a>(self, revisions: &'a [Revision], now: u64) -> &'a [Revision] {
+        let keep = match self {
+            Retention::All => revisions.len(),
+            Retention::Last(n) => cmp::min(n, revisions.len() as u64) as usize,
+            Retention::NewerThan(age) => {
+                let limit = now.saturating_sub(age.as_secs());
+                revisions.iter().rev().take_while(|x| x.timestamp >= limit).count()
+            },
+        };
+
+        &revisions[..revisions.len() - keep]
+    }
+}
+
+/// A read-only view of a revision.
+#[derive(Clone, Copy)]
+pub struct View {
+    /// The revision, as a file.
+    file: File,
+}
+
+impl View {
+    /// Get the generation of the revision.
+    pub fn generation(&self) -> u64 {
+        self.file.generation()
+    }
+
+    /// Get the time of the revision in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.file.timestamp()
+    }
+
+    /// Get the length of the revision in bytes.
+    pub fn len(&self) -> u64 {
+        self.file.len()
+    }
+
+    /// Is the revision empty?
+    pub fn is_empty(&self) -> bool {
+        self.file.is_empty()
+    }
+
+    /// Read a byte range of the revision.
+    ///
+    /// See `File::read()`.
+    pub fn read(&self, fs: &fs::State, offset: u64, len: usize) -> future!(Vec<u8>) {
+        self.file.read(fs, offset, len)
+    }
+
+    /// Get the extended attributes of the revision.
+    pub fn xattrs(&self, fs: &fs::State) -> future!(fs::Xattrs) {
+        self.file.xattrs(fs)
+    }
+}
+
+/// A file.
+#[derive(Clone, Copy)]
+pub struct File {
+    /// The pointer to the header page.
+    header: page::Pointer,
+    /// The content of the header page.
+    meta: Header,
+    /// The locality hint of the data pages.
+    ///
+    /// This isn't stored on disk. It is derived from the header, when the file is loaded, and
+    /// carried over to the new versions, so successive writes pack their pages together.
+    hint: locality::Hint,
+}
+
+impl<'a> From<&'a File> for locality::Hint {
+    fn from(file: &'a File) -> locality::Hint {
+        file.hint
+    }
+}
+
+impl File {
+    /// Create a new, empty file.
+    pub fn create(fs: &fs::State) -> future!(File) {
+        File::write_header(fs, Header {
+            len: 0,
+            data: None,
+            generation: 0,
+            timestamp: fs::now(),
+            history: None,
+            history_len: 0,
+            xattrs: None,
+            inline: fs::Inline::new(&[]),
+            inline_xattrs: None,
+        }, None)
+    }
+
+    /// Load a file from its header.
+    pub fn open(fs: &fs::State, header: page::Pointer) -> future!(File) {
+        read_header(fs, header).map(move |meta| File {
+            header: header,
+            meta: meta,
+            hint: locality::Hint::from(header),
+        })
+    }
+
+    /// Write a header.
+    ///
+    /// The new version gets locality hint `hint`, or one derived from the header, if `None`.
+    fn write_header(
+        fs: &fs::State,
+        meta: Header,
+        hint: Option<locality::Hint>,
+    ) -> future!(File) {
+        fs.alloc(meta.encode(), "file header").map(move |ptr| File {
+            header: ptr,
+            meta: meta,
+            hint: hint.unwrap_or_else(|| locality::Hint::from(ptr)),
+        })
+    }
+
+    /// Use another locality hint for the data pages.
+    ///
+    /// By default, the hint is derived from the header, the file was loaded from. Writers, which
+    /// have a more stable identity for the file (e.g. an open handle), can use that instead.
+    pub fn with_hint(&self, hint: locality::Hint) -> File {
+        File {
+            hint: hint,
+            .. *self
+        }
+    }
+
+    /// Create a new version.
+    ///
+    /// This creates the next revision of the file with the content (length, data pages, inline
+    /// content and extended attribute table) of `content`, and adds this version to its history.
+    /// The history is pruned according to the retention policy of `fs`.
+    fn commit(&self, fs: &fs::State, content: Header) -> future!(File) {
+        let meta = self.meta;
+        let hint = self.hint;
+        let retention = fs.retention();
+
+        self.meta.history().push(fs, Some(self.header)).and_then(move |history| {
+            retain(fs, history, retention)
+        }).join(content.fit_xattrs(fs)).and_then(move |(history, content)| {
+            File::write_header(fs, Header {
+                generation: meta.generation + 1,
+                timestamp: fs::now(),
+                history: history.root(),
+                history_len: history.len(),
+                .. content
+            }, Some(hint))
+        })
+    }
+
+    /// Create a new version with new data pages.
+    ///
+    /// Any inline content is dropped, as it is replaced by `data`.
+    fn commit_data(&self, fs: &fs::State, len: u64, data: fs::Array<fs::Data>) -> future!(File) {
+        self.commit(fs, Header {
+            len: len,
+            data: data.root(),
+            inline: None,
+            .. self.meta
+        })
+    }
+
+    /// Create a new version with new inline content.
+    fn commit_inline(&self, fs: &fs::State, inline: fs::Inline) -> future!(File) {
+        self.commit(fs, Header {
+            len: inline.as_slice().len() as u64,
+            data: None,
+            inline: Some(inline),
+            .. self.meta
+        })
+    }
+
+    /// Is the content stored inline?
+    pub fn is_inline(&self) -> bool {
+        self.meta.inline.is_some()
+    }
+
+    /// Get the pointer to the header page.
+    ///
+    /// This is the pointer, which refers to this version of the file.
+    pub fn root(&self) -> page::Pointer {
+        self.header
+    }
+
+    /// Get the length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.meta.len
+    }
+
+    /// Is the file empty?
+    pub fn is_empty(&self) -> bool {
+        self.meta.len == 0
+    }
+
+    /// Get the generation of this version.
+    pub fn generation(&self) -> u64 {
+        self.meta.generation
+    }
+
+    /// Get the time of this version in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.meta.timestamp
+    }
+
+    /// Read a byte range.
+    ///
+    /// This reads (up to) `len` bytes starting at byte `offset`. If the range exceeds the end of
+    /// the file, the read is cut short, so fewer bytes (possibly none) are returned.
+    pub fn read(&self, fs: &fs::State, offset: u64, len: usize) -> future!(Vec<u8>) {
+        // Cut the range at the end of the file.
+        let end = cmp::min(self.meta.len, offset.saturating_add(len as u64));
+        let start = cmp::min(offset, end);
+        let first = start / PAGE_SIZE;
+
+        if let Some(inline) = self.meta.inline {
+            return Either::A(future::ok(inline.as_slice()[start as usize..end as usize].to_vec()));
+        }
+
+        // Read the pointers of the pages overlapping the range, then the pages themselves.
+        Either::B(self.meta.data().collect(fs, first..pages(end)).and_then(move |ptrs| {
+            future::join_all(ptrs.into_iter().map(|ptr| read_page(fs, ptr)).collect::<Vec<_>>())
+        }).map(move |pages| {
+            let mut buf = Vec::with_capacity((end - start) as usize);
+
+            // Copy the overlapping part of every page.
+            for (n, page) in pages.iter().enumerate() {
+                let page_start = (first + n as u64) * PAGE_SIZE;
+                let from = cmp::max(start, page_start) - page_start;
+                let to = cmp::min(end, page_start + PAGE_SIZE) - page_start;
+
+                buf.extend_from_slice(&page[from as usize..to as usize]);
+            }
+
+            buf
+        }))
+    }
+
+    /// Write a byte range.
+    ///
+    /// This creates a new version of the file, where the bytes starting at `offset` are replaced
+    /// by `buf`. If the range exceeds the end of the file, the file is extended, and if `offset`
+    /// is after the end, the gap is filled with zeros.
+    ///
+    /// Only the pages overlapping the range are reallocated. Pages, which are only partially
+    /// covered, are read and merged with `buf`. If the file is stored inline, and still fits, the
+    /// inline content is updated instead.
+    pub fn write(&self, fs: &fs::State, offset: u64, buf: &[u8]) -> future!(File) {
+        self.write_with(fs, offset, buf, false)
+    }
+
+    /// Write a byte range, turning zero pages into holes.
+    ///
+    /// This is like `self.write()`, but the pages, which end up entirely zero, become holes
+    /// instead of being allocated.
+    pub fn write_sparse(&self, fs: &fs::State, offset: u64, buf: &[u8]) -> future!(File) {
+        self.write_with(fs, offset, buf, true)
+    }
+
+    /// Write a byte range.
+    ///
+    /// See `self.write()` and `self.write_sparse()`.
+    fn write_with(&self, fs: &fs::State, offset: u64, buf: &[u8], sparse: bool) -> future!(File) {
+        if buf.is_empty() {
+            // Nothing changes.
+            return Either::A(future::ok(*self));
+        }
+
+        let end = match offset.checked_add(buf.len() as u64) {
+            Some(end) => end,
+            None => return Either::A(future::err(err!(Implementation,
+                                                      "file write at {} overflows", offset))),
+        };
+        if end <= fs::inline::MAX_LEN as u64 {
+            if let Some(inline) = self.meta.inline.and_then(|x| x.write(offset as usize, buf)) {
+                return Either::B(Either::A(self.commit_inline(fs, inline)));
+            }
+        }
+
+        let len = cmp::max(self.meta.len, end);
+        let buf = buf.to_vec();
+        let file = *self;
+
+        // Make room for the new pages, and then write the pages one by one.
+        Either::B(Either::B(self.meta.promote(fs, self.hint)
+            .and_then(move |data| data.truncate(fs, pages(len)))
+            .and_then(move |data| write_pages(fs, data, offset, buf, sparse, file.hint))
+            .and_then(move |data| file.commit_data(fs, len, data))))
+    }
+
+    /// Append bytes.
+    ///
+    /// This creates a new version of the file with `buf` added to the end.
+    pub fn append(&self, fs: &fs::State, buf: &[u8]) -> future!(File) {
+        self.write(fs, self.meta.len, buf)
+    }
+
+    /// Resize the file.
+    ///
+    /// This creates a new version of the file with length `len`. If it is shorter than the file,
+    /// the excess bytes are removed, and if it is longer, the file is extended by zeros (which
+    /// take no space).
+    pub fn truncate(&self, fs: &fs::State, len: u64) -> future!(File) {
+        if len <= fs::inline::MAX_LEN as u64 {
+            if let Some(inline) = self.meta.inline.and_then(|x| x.truncate(len as usize)) {
+                return Either::A(self.commit_inline(fs, inline));
+            }
+        }
+
+        let shrink = len < self.meta.len;
+        // The number of bytes kept in the new last page.
+        let tail = (len % PAGE_SIZE) as usize;
+        let file = *self;
+
+        let data = self.meta.promote(fs, self.hint)
+            .and_then(move |data| data.truncate(fs, pages(len)));
+        Either::B(data.and_then(move |data| {
+            if !shrink || tail == 0 {
+                // No page is cut in the middle.
+                return Either::A(future::ok(data));
+            }
+
+            // Zero the removed bytes of the last page, to maintain the invariant that the bytes
+            // after the end of the file are zero.
+            let index = len / PAGE_SIZE;
+            Either::B(data.get(fs, index).and_then(move |ptr| match ptr {
+                // Null pages are already zero.
+                None => Either::A(future::ok(data)),
+                Some(ptr) => Either::B(fs.read(ptr).and_then(move |mut page| {
+                    for byte in &mut page[tail..] {
+                        *byte = 0;
+                    }
+                    fs.alloc_near(page, "file page", file.hint)
+                }).and_then(move |ptr| data.set(fs, index, Some(ptr)))),
+            }))
+        }).and_then(move |data| file.commit_data(fs, len, data)))
+    }
+
+    /// Punch a hole.
+    ///
+    /// This creates a new version of the file, in which the bytes in `offset..offset + len` are
+    /// zero. The pages covered entirely become holes, freeing their space. The length of the file
+    /// is unchanged. Inline content has no pages, so it is merely zeroed.
+    pub fn punch_hole(&self, fs: &fs::State, offset: u64, len: u64) -> future!(File) {
+        let file = *self;
+        let end = cmp::min(offset.saturating_add(len), self.meta.len);
+        if offset >= end {
+            return Either::A(future::ok(file));
+        }
+
+        if let Some(inline) = self.meta.inline {
+            let zeros = vec![0; (end - offset) as usize];
+            // The range is within the content, so it still fits.
+            let inline = inline.write(offset as usize, &zeros).unwrap();
+            return Either::B(Either::A(self.commit_inline(fs, inline)));
+        }
+
+        let (whole, head, tail) = split_range(offset..end);
+        let zeros = |range: Range<u64>| vec![0; (range.end - range.start) as usize];
+
+        Either::B(Either::B(stream::iter_ok(whole).fold(self.meta.data(), move |data, index| {
+            data.set(fs, index, None)
+        }).and_then(move |data| {
+            write_pages(fs, data, head.start, zeros(head.clone()), true, file.hint)
+        }).and_then(move |data| {
+            write_pages(fs, data, tail.start, zeros(tail.clone()), true, file.hint)
+        })
+            .and_then(move |data| file.commit_data(fs, file.meta.len, data))))
+    }
+
+    /// Find the next data at or after some offset.
+    ///
+    /// This is like `SEEK_DATA`: The offset of the first byte at or after `offset`, which is not
+    /// in a hole, is returned. If there is none, `None` is returned.
+    pub fn seek_data(&self, fs: &fs::State, offset: u64) -> future!(Option<u64>) {
+        self.seek(fs, offset, true)
+    }
+
+    /// Find the next hole at or after some offset.
+    ///
+    /// This is like `SEEK_HOLE`: The offset of the first byte at or after `offset`, which is in a
+    /// hole, is returned, the end of the file counting as a hole. If `offset` is at or after the
+    /// end, `None` is returned.
+    pub fn seek_hole(&self, fs: &fs::State, offset: u64) -> future!(Option<u64>) {
+        self.seek(fs, offset, false)
+    }
+
+    /// Find the next data or hole, like `lseek` with `SEEK_DATA` or `SEEK_HOLE`.
+    ///
+    /// If `data` is set, this finds the first byte at or after `offset`, which is in a non-null
+    /// page, and otherwise the first byte in a hole. The data array is searched by
+    /// `fs::Array::find()`, so holes cost nothing to skip.
+    fn seek(&self, fs: &fs::State, offset: u64, data: bool) -> future!(Option<u64>) {
+        let len = self.meta.len;
+        if offset >= len {
+            return Either::A(future::ok(None));
+        } else if self.meta.inline.is_some() {
+            // Inline content is data.
+            return Either::A(future::ok(Some(if data { offset } else { len })));
+        }
+
+        Either::B(self.meta.data().find(fs, offset / PAGE_SIZE..pages(len), data)
+            .map(move |found| match found {
+                Some(index) => Some(cmp::max(offset, index * PAGE_SIZE)),
+                None if data => None,
+                // The end of the file is an implicit hole.
+                None => Some(len),
+            }))
+    }
+
+    /// Get the pointers to the data pages.
+    ///
+    /// Inline content counts as data, so it is represented by the header page.
+    fn page_pointers(&self, fs: &fs::State) -> future!(Vec<Option<page::Pointer>>) {
+        match self.meta.inline {
+            Some(_) => {
+                Either::A(future::ok(vec![Some(self.header); pages(self.meta.len) as usize]))
+            },
+            None => Either::B(self.meta.data().collect(fs, 0..pages(self.meta.len))),
+        }
+    }
+
+    /// Find the byte range, in which this file differs from another file.
+    ///
+    /// As files are copy-on-write, pages are compared by their pointers, so the range is found
+    /// without reading the data pages, but pages which were rewritten with the same content count
+    /// as changed. Inline content is compared bytewise. If the files are equal, `None` is
+    /// returned.
+    pub fn changed_range(&self, fs: &fs::State, other: &File) -> future!(Option<Range<u64>>) {
+        let (a, b) = (self.meta, other.meta);
+        let len = cmp::max(a.len, b.len);
+        let same_len = a.len == b.len;
+
+        if let (Some(a), Some(b)) = (a.inline, b.inline) {
+            let (a, b) = (a.as_slice(), b.as_slice());
+            // The shorter content is padded with nothing, so the extension counts as changed.
+            let differs = |n: &usize| a.get(*n) != b.get(*n);
+            let count = cmp::max(a.len(), b.len());
+            let first = (0..count).find(&differs);
+            let last = (0..count).rev().find(&differs);
+
+            return Either::A(future::ok(first.and_then(|first| {
+                last.map(|last| first as u64..last as u64 + 1)
+            })));
+        }
+
+        Either::B(self.page_pointers(fs).join(other.page_pointers(fs))
+            .map(move |(a, b)| {
+                // Compare the pages pairwise. The shorter file is padded with null pages.
+                let differs = |n: &usize| a.get(*n).cloned().unwrap_or(None)
+                    != b.get(*n).cloned().unwrap_or(None);
+                let count = cmp::max(a.len(), b.len());
+                let first = (0..count).find(&differs);
+                let last = (0..count).rev().find(&differs);
+
+                match (first, last) {
+                    (Some(first), Some(last)) => {
+                        // If the length changed, everything up to the new end changed too.
+                        let end = if same_len { (last as u64 + 1) * PAGE_SIZE } else { len };
+                        Some(first as u64 * PAGE_SIZE..cmp::min(end, len))
+                    },
+                    // The pages are the same, but the length may still differ within the last
+                    // page.
+                    _ if !same_len => Some(cmp::min(a.len(), b.len()) as u64 * PAGE_SIZE..len),
+                    _ => None,
+                }
+            }))
+    }
+
+    /// List the prior revisions.
+    ///
+    /// The revisions are returned oldest first. The current version is not included. Pruned
+    /// revisions are skipped.
+    pub fn revisions(&self, fs: &fs::State) -> future!(Vec<Revision>) {
+        revisions(fs, self.meta.history())
+    }
+
+    /// Check if this is the version of the file with header `header`, or a later version of it.
+    ///
+    /// Later versions are recognized by their history, so a version, which was pruned from the
+    /// history, has no recognized descendants.
+    pub fn descends_from(&self, fs: &fs::State, header: page::Pointer) -> future!(bool) {
+        if self.header == header {
+            return Either::A(future::ok(true));
+        }
+
+        Either::B(self.revisions(fs).map(move |revisions| {
+            revisions.iter().any(|revision| revision.header == header)
+        }))
+    }
+
+    /// Open a read-only view of a revision.
+    pub fn open_revision(&self, fs: &fs::State, revision: &Revision) -> future!(View) {
+        File::open(fs, revision.header).map(|file| View {
+            file: file,
+        })
+    }
+
+    /// Revert to a revision.
+    ///
+    /// This creates a new version of the file with the content of `revision`. The history is
+    /// kept, so the revert can itself be reverted.
+    pub fn revert(&self, fs: &fs::State, revision: &Revision) -> future!(File) {
+        let file = *self;
+
+        read_header(fs, revision.header).and_then(move |header| file.commit(fs, header))
+    }
+
+    /// Get the extended attributes.
+    pub fn xattrs(&self, fs: &fs::State) -> future!(fs::Xattrs) {
+        self.meta.xattrs(fs)
+    }
+
+    /// Replace the extended attributes.
+    ///
+    /// This creates a new version of the file with the attributes `xattrs`. They are stored
+    /// inline, if they fit along with the inline content.
+    pub fn set_xattrs(&self, fs: &fs::State, xattrs: &fs::Xattrs) -> future!(File) {
+        let file = *self;
+
+        self.meta.set_xattrs(fs, xattrs.clone()).and_then(move |meta| file.commit(fs, meta))
+    }
+
+    /// Prune the revision history.
+    ///
+    /// This creates a new version of the file, in which the revisions not retained by `retention`
+    /// are removed from the history. Their pages are then freed by the garbage collector, unless
+    /// they're shared with other revisions.
+    ///
+    /// The content and generation are unchanged, so this doesn't count as a revision.
+    pub fn prune(&self, fs: &fs::State, retention: Retention) -> future!(File) {
+        let meta = self.meta;
+        let hint = self.hint;
+
+        prune(fs, meta.history(), retention).and_then(move |history| {
+            File::write_header(fs, Header {
+                history: history.root(),
+                .. meta
+            }, Some(hint))
+        })
+    }
+}
+
+/// List the revisions of a history.
+///
+/// See `File::revisions()`.
+fn revisions(fs: &fs::State, history: fs::Array<Previous>) -> future!(Vec<Revision>) {
+    history.collect(fs, 0..history.len()).and_then(move |headers| {
+        future::join_all(headers.into_iter().enumerate().filter_map(|(index, ptr)| {
+            ptr.map(|ptr| read_header(fs, ptr).map(move |header| Revision {
+                generation: header.generation,
+                timestamp: header.timestamp,
+                index: index as u64,
+                header: ptr,
+            }))
+        }).collect::<Vec<_>>())
+    })
+}
+
+/// Remove the revisions not retained by `retention` from a history.
+///
+/// The expired revisions are replaced by null pointers, and the new history is returned.
+fn prune(
+    fs: &fs::State,
+    history: fs::Array<Previous>,
+    retention: Retention,
+) -> future!(fs::Array<Previous>) {
+    revisions(fs, history).and_then(move |revisions| {
+        let expired = retention.expired(&revisions, fs::now()).to_vec();
+        if !expired.is_empty() {
+            debug!(fs, "pruning file revisions"; "revisions" => expired.len());
+        }
+
+        stream::iter_ok(expired).fold(history, move |history, revision| {
+            history.set(fs, revision.index, None)
+        })
+    })
+}
+
+/// Apply a retention policy to a history, which a revision was just pushed to.
+///
+/// Under `Retention::Last(n)`, only the revision falling out of the window is pruned, so the
+/// history doesn't have to be read. This keeps `n` revisions, as long as the policy was applied
+/// on every commit. Age-based policies examine the whole history (see `prune()`).
+fn retain(
+    fs: &fs::State,
+    history: fs::Array<Previous>,
+    retention: Retention,
+) -> fs::BoxFuture<fs::Array<Previous>> {
+    match retention {
+        Retention::Last(n) if n < history.len() => {
+            Box::new(history.set(fs, history.len() - n - 1, None))
+        },
+        Retention::All | Retention::Last(_) => Box::new(future::ok(history)),
+        Retention::NewerThan(_) => Box::new(prune(fs, history, retention)),
+    }
+}
+
+impl fs::Object for File {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        visitor.page(self.header);
+        if self.meta.inline.is_some() {
+            visitor.inline_file(self.header);
+        }
+
+        self.meta.data().visit(fs, visitor)
+            .join3(self.meta.history().visit(fs, visitor), self.meta.visit_xattrs(fs, visitor))
+            .map(|_| ())
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(File) {
+        let file = *self;
+
+        self.meta.data().relocate(fs, relocations)
+            .join3(self.meta.history().relocate(fs, relocations),
+                   self.meta.relocate_xattrs(fs, relocations))
+            .and_then(move |(data, history, (xattrs, inline_xattrs))| {
+                if data.root() == file.meta.data && history.root() == file.meta.history
+                    && xattrs == file.meta.xattrs && inline_xattrs == file.meta.inline_xattrs {
+                    // The data pages, the history and the attributes are unchanged, so we only
+                    // need to follow the header, if it was moved.
+                    Either::A(future::ok(File {
+                        header: relocations.get(file.header).unwrap_or(file.header),
+                        .. file
+                    }))
+                } else {
+                    // Relocation doesn't count as a revision, so the header is simply copied.
+                    Either::B(File::write_header(fs, Header {
+                        data: data.root(),
+                        history: history.root(),
+                        xattrs: xattrs,
+                        inline_xattrs: inline_xattrs,
+                        .. file.meta
+                    }, Some(file.hint)))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+
+    fn ptr(cluster: u64) -> page::Pointer {
+        page::Pointer {
+            cluster: cluster::Pointer::new(cluster).unwrap(),
+            offset: Some(1),
+            checksum: 42,
+        }
+    }
+
+    fn revision(generation: u64, timestamp: u64) -> Revision {
+        Revision {
+            generation: generation,
+            timestamp: timestamp,
+            index: generation,
+            header: ptr(generation + 1),
+        }
+    }
+
+    #[test]
+    fn page_count() {
+        assert_eq!(pages(0), 0);
+        assert_eq!(pages(1), 1);
+        assert_eq!(pages(PAGE_SIZE), 1);
+        assert_eq!(pages(PAGE_SIZE + 1), 2);
+    }
+
+    #[test]
+    fn header_inverse_identity() {
+        let mut header = Header {
+            len: 0,
+            data: None,
+            generation: 0,
+            timestamp: 0,
+            history: None,
+            history_len: 0,
+            xattrs: None,
+            inline: None,
+            inline_xattrs: None,
+        };
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+        assert!(header.encode().iter().all(|&x| x == 0));
+
+        header.len = 0xDEADBEEFCAFE;
+        header.data = Some(ptr(9));
+        header.generation = 7;
+        header.timestamp = 1500000000;
+        header.history = Some(ptr(10));
+        header.history_len = 7;
+        header.xattrs = Some(ptr(11));
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+
+        header.len = 5;
+        header.data = None;
+        header.inline = fs::Inline::new(b"hello");
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+
+        // The attributes follow the content.
+        header.xattrs = None;
+        header.inline_xattrs = fs::Inline::new(&[1; 100]);
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+
+        // Inline content must fit in the header.
+        let mut buf = header.encode();
+        little_endian::write(&mut buf[..], PAGE_SIZE);
+        assert!(Header::decode(&buf).is_err());
+        // So must the inline attributes.
+        let mut buf = header.encode();
+        little_endian::write(&mut buf[fs::inline::OFFSET + 5..], 1000u16);
+        assert!(Header::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn inline_xattrs() {
+        let fs = fs::memory(1024);
+        let xattrs = fs::Xattrs::default().set(&fs, b"user.label", b"blue").wait().unwrap();
+        let file = File::create(&fs).wait().unwrap()
+            .write(&fs, 0, b"hello").wait().unwrap()
+            .set_xattrs(&fs, &xattrs).wait().unwrap();
+
+        // Both the content and the attributes are stored in the header.
+        assert!(file.is_inline());
+        assert!(file.meta.inline_xattrs.is_some() && file.meta.xattrs.is_none());
+        assert_eq!(file.xattrs(&fs).wait().unwrap(), xattrs);
+
+        // Content filling the inline area moves the attributes to a table.
+        let content = [2; fs::inline::MAX_LEN];
+        let file = file.write(&fs, 0, &content).wait().unwrap();
+        assert!(file.is_inline());
+        assert!(file.meta.inline_xattrs.is_none() && file.meta.xattrs.is_some());
+        assert_eq!(file.xattrs(&fs).wait().unwrap(), xattrs);
+        assert_eq!(file.read(&fs, 0, content.len()).wait().unwrap(), &content[..]);
+
+        // Once the content is promoted, they fit inline again.
+        let file = file.write(&fs, PAGE_SIZE, b"more").wait().unwrap()
+            .set_xattrs(&fs, &xattrs).wait().unwrap();
+        assert!(!file.is_inline());
+        assert!(file.meta.inline_xattrs.is_some());
+        assert_eq!(file.xattrs(&fs).wait().unwrap(), xattrs);
+    }
+
+    #[test]
+    fn seeking() {
+        let fs = fs::memory(1024);
+        // Data, hole, data, hole.
+        let len = 4 * PAGE_SIZE - 10;
+        let file = File::create(&fs).wait().unwrap()
+            .truncate(&fs, len).wait().unwrap()
+            .write(&fs, 0, &[1; PAGE_SIZE as usize]).wait().unwrap()
+            .write(&fs, 2 * PAGE_SIZE, b"data").wait().unwrap();
+        let seek = |offset, data| file.seek(&fs, offset, data).wait().unwrap();
+
+        assert_eq!(seek(0, true), Some(0));
+        assert_eq!(seek(5, false), Some(PAGE_SIZE));
+        assert_eq!(seek(PAGE_SIZE + 1, true), Some(2 * PAGE_SIZE));
+        assert_eq!(seek(PAGE_SIZE + 1, false), Some(PAGE_SIZE + 1));
+        assert_eq!(seek(3 * PAGE_SIZE, true), None);
+        assert_eq!(seek(len, false), None);
+
+        // No holes but the end.
+        let file = File::create(&fs).wait().unwrap()
+            .write(&fs, 0, &[1; 2 * PAGE_SIZE as usize]).wait().unwrap();
+        assert_eq!(file.seek(&fs, 50, false).wait().unwrap(), Some(2 * PAGE_SIZE));
+
+        // A sparse file far larger than the disk.
+        let len = 1 << 40;
+        let file = File::create(&fs).wait().unwrap()
+            .truncate(&fs, len).wait().unwrap()
+            .write(&fs, len - PAGE_SIZE, b"tail").wait().unwrap();
+        assert_eq!(file.seek_data(&fs, 0).wait().unwrap(), Some(len - PAGE_SIZE));
+        assert_eq!(file.seek_hole(&fs, 0).wait().unwrap(), Some(0));
+        assert_eq!(file.seek_hole(&fs, len - PAGE_SIZE).wait().unwrap(), Some(len));
+    }
+
+    #[test]
+    fn punch_hole() {
+        let fs = fs::memory(1024);
+        let content: Vec<u8> = (0..4 * PAGE_SIZE).map(|x| (x % 251) as u8 + 1).collect();
+        let file = File::create(&fs).wait().unwrap().write(&fs, 0, &content).wait().unwrap();
+        let pointers = file.page_pointers(&fs).wait().unwrap();
+
+        // Cover the second and third page entirely, and the others partially.
+        let punched = file.punch_hole(&fs, PAGE_SIZE - 10, 2 * PAGE_SIZE + 20).wait().unwrap();
+        assert_eq!(punched.len(), file.len());
+        let new = punched.page_pointers(&fs).wait().unwrap();
+        assert!(new[0].is_some() && new[0] != pointers[0]);
+        assert_eq!(&new[1..3], &[None, None]);
+        assert!(new[3].is_some() && new[3] != pointers[3]);
+
+        let mut expected = content.clone();
+        for byte in &mut expected[PAGE_SIZE as usize - 10..3 * PAGE_SIZE as usize + 10] {
+            *byte = 0;
+        }
+        assert_eq!(punched.read(&fs, 0, content.len()).wait().unwrap(), expected);
+        assert_eq!(punched.seek_hole(&fs, 0).wait().unwrap(), Some(PAGE_SIZE));
+        assert_eq!(punched.seek_data(&fs, PAGE_SIZE).wait().unwrap(), Some(3 * PAGE_SIZE));
+
+        // Punching past the end changes nothing, and inline content is merely zeroed.
+        let same = punched.punch_hole(&fs, 4 * PAGE_SIZE, 100).wait().unwrap();
+        assert_eq!(same.root(), punched.root());
+        let inline = File::create(&fs).wait().unwrap().write(&fs, 0, b"hello").wait().unwrap()
+            .punch_hole(&fs, 1, 3).wait().unwrap();
+        assert_eq!(inline.read(&fs, 0, 5).wait().unwrap(), b"h\0\0\0o");
+    }
+
+    #[test]
+    fn write_sparse() {
+        let fs = fs::memory(1024);
+        let file = File::create(&fs).wait().unwrap()
+            .write(&fs, 0, &[1; 3 * PAGE_SIZE as usize]).wait().unwrap();
+
+        // Zero pages become holes, but partially zero pages are written.
+        let mut buf = vec![0; 2 * PAGE_SIZE as usize];
+        buf[PAGE_SIZE as usize + 1] = 7;
+        let sparse = file.write_sparse(&fs, 0, &buf).wait().unwrap();
+        let pointers = sparse.page_pointers(&fs).wait().unwrap();
+        assert!(pointers[0].is_none() && pointers[1].is_some() && pointers[2].is_some());
+        assert_eq!(sparse.read(&fs, 0, buf.len()).wait().unwrap(), buf);
+        assert_eq!(sparse.seek_data(&fs, 0).wait().unwrap(), Some(PAGE_SIZE));
+
+        // Plain writes allocate zero pages.
+        let dense = file.write(&fs, 0, &buf).wait().unwrap();
+        assert!(dense.page_pointers(&fs).wait().unwrap()[0].is_some());
+        assert_eq!(dense.read(&fs, 0, buf.len()).wait().unwrap(), buf);
+        assert_eq!(dense.seek_data(&fs, 0).wait().unwrap(), Some(0));
+    }
+
+    /// Write to both a file and a model of its content.
+    fn write_both(
+        fs: &fs::State,
+        file: File,
+        model: &mut Vec<u8>,
+        offset: u64,
+        buf: &[u8],
+    ) -> File {
+        let end = offset as usize + buf.len();
+        if model.len() < end {
+            model.resize(end, 0);
+        }
+        model[offset as usize..end].copy_from_slice(buf);
+
+        file.write(fs, offset, buf).wait().unwrap()
+    }
+
+    #[test]
+    fn unaligned_writes() {
+        let fs = fs::memory(1024);
+        let mut model = vec![1; 3 * PAGE_SIZE as usize];
+        let mut file = File::create(&fs).wait().unwrap().write(&fs, 0, &model).wait().unwrap();
+        let pattern: Vec<u8> = (0..PAGE_SIZE + 200).map(|x| (x % 253) as u8 + 2).collect();
+
+        // Spanning three pages, starting and ending in the middle of a page.
+        file = write_both(&fs, file, &mut model, PAGE_SIZE - 100, &pattern);
+        // Within a single page, and across a single boundary.
+        file = write_both(&fs, file, &mut model, 2 * PAGE_SIZE + 3, b"within");
+        file = write_both(&fs, file, &mut model, PAGE_SIZE - 2, b"across");
+        // Past the end, leaving a gap of zeros.
+        file = write_both(&fs, file, &mut model, 5 * PAGE_SIZE + 7, &pattern);
+
+        assert_eq!(file.len(), model.len() as u64);
+        assert_eq!(file.read(&fs, 0, model.len()).wait().unwrap(), model);
+        // Unaligned reads see the same bytes.
+        let (start, end) = (PAGE_SIZE as usize - 3, 4 * PAGE_SIZE as usize + 5);
+        assert_eq!(file.read(&fs, start as u64, end - start).wait().unwrap(),
+                   &model[start..end]);
+    }
+
+    #[test]
+    fn append() {
+        let fs = fs::memory(1024);
+        let mut model = Vec::new();
+        let mut file = File::create(&fs).wait().unwrap();
+
+        // Append odd-sized chunks, starting inline and moving on to pages.
+        for n in 0..10u8 {
+            let chunk = vec![n + 1; 300];
+            file = file.append(&fs, &chunk).wait().unwrap();
+            model.extend_from_slice(&chunk);
+            assert_eq!(file.len(), model.len() as u64);
+        }
+
+        assert!(!file.is_inline());
+        assert_eq!(file.read(&fs, 0, model.len()).wait().unwrap(), model);
+        assert_eq!(file.append(&fs, b"").wait().unwrap().root(), file.root());
+    }
+
+    #[test]
+    fn truncate_then_extend() {
+        let fs = fs::memory(1024);
+        let file = File::create(&fs).wait().unwrap()
+            .write(&fs, 0, &[1; 3 * PAGE_SIZE as usize]).wait().unwrap();
+
+        // Cut in the middle of the second page, and extend past the old end.
+        let cut = PAGE_SIZE + 100;
+        let shrunk = file.truncate(&fs, cut).wait().unwrap();
+        assert_eq!(shrunk.len(), cut);
+        assert_eq!(shrunk.read(&fs, 0, 3 * PAGE_SIZE as usize).wait().unwrap(),
+                   vec![1; cut as usize]);
+
+        let extended = shrunk.truncate(&fs, 4 * PAGE_SIZE).wait().unwrap();
+        let content = extended.read(&fs, 0, 4 * PAGE_SIZE as usize).wait().unwrap();
+        assert_eq!(content.len() as u64, 4 * PAGE_SIZE);
+        // The removed bytes don't come back: The tail reads as zero.
+        assert!(content[..cut as usize].iter().all(|&x| x == 1));
+        assert!(content[cut as usize..].iter().all(|&x| x == 0));
+        // The extension takes no space.
+        assert_eq!(&extended.page_pointers(&fs).wait().unwrap()[2..], &[None, None]);
+
+        // Writing after the cut doesn't revive the removed bytes either.
+        let written = extended.write(&fs, cut + 10, b"x").wait().unwrap();
+        let page = written.read(&fs, PAGE_SIZE, PAGE_SIZE as usize).wait().unwrap();
+        assert!(page[..100].iter().all(|&x| x == 1));
+        assert_eq!(&page[100..111], b"\0\0\0\0\0\0\0\0\0\0x");
+        assert!(page[111..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn rewrite_shares_pages() {
+        let fs = fs::memory(1024);
+        let content: Vec<u8> = (0..4 * PAGE_SIZE).map(|x| (x % 251) as u8 + 1).collect();
+        let file = File::create(&fs).wait().unwrap().write(&fs, 0, &content).wait().unwrap();
+        let old = file.page_pointers(&fs).wait().unwrap();
+
+        // Rewrite a few bytes in the middle of the third page.
+        let new = file.write(&fs, 2 * PAGE_SIZE + 17, b"changed").wait().unwrap()
+            .page_pointers(&fs).wait().unwrap();
+        assert_eq!(new.len(), old.len());
+        assert!(new[2].is_some() && new[2] != old[2]);
+        assert_eq!((new[0], new[1], new[3]), (old[0], old[1], old[3]));
+
+        // The old version is unchanged.
+        assert_eq!(file.read(&fs, 0, content.len()).wait().unwrap(), content);
+    }
+
+    #[test]
+    fn hole_ranges() {
+        // Spanning several pages.
+        assert_eq!(split_range(100..PAGE_SIZE * 2 + 76),
+                   (1..2, 100..PAGE_SIZE, PAGE_SIZE * 2..PAGE_SIZE * 2 + 76));
+        // Within a single page.
+        assert_eq!(split_range(100..200), (1..1, 100..200, 200..200));
+        // Page-aligned.
+        assert_eq!(split_range(0..PAGE_SIZE), (0..1, 0..0, PAGE_SIZE..PAGE_SIZE));
+    }
+
+    #[test]
+    fn retention() {
+        let revisions: Vec<_> = (0..5).map(|n| revision(n, 1000 + n * 100)).collect();
+
+        assert!(Retention::All.expired(&revisions, 2000).is_empty());
+
+        assert_eq!(Retention::Last(2).expired(&revisions, 2000), &revisions[..3]);
+        assert_eq!(Retention::Last(0).expired(&revisions, 2000), &revisions[..]);
+        assert!(Retention::Last(10).expired(&revisions, 2000).is_empty());
+
+        let age = |secs| Retention::NewerThan(Duration::from_secs(secs));
+        assert_eq!(age(650).expired(&revisions, 1950), &revisions[..3]);
+        assert_eq!(age(10).expired(&revisions, 2000), &revisions[..]);
+        assert!(age(1000).expired(&revisions, 2000).is_empty());
+    }
+
+    #[test]
+    fn pages_share_clusters() {
+        let fs = fs::memory(1024);
+        let clusters = |file: &File| file.page_pointers(&fs).wait().unwrap().into_iter()
+            .map(|ptr| ptr.unwrap().cluster).collect::<Vec<_>>();
+
+        // Interleave the writes to two files, so the pages would be interleaved in the last
+        // cluster of the thread, if it weren't for the hints.
+        let a = File::create(&fs).wait().unwrap().write(&fs, 0, &[1; 512]).wait().unwrap();
+        let b = File::create(&fs).wait().unwrap().write(&fs, 0, &[2; 512]).wait().unwrap();
+        let a = a.write(&fs, 512, &[3; 512]).wait().unwrap();
+
+        let (a, b) = (clusters(&a), clusters(&b));
+        assert_eq!(a.len(), 2);
+        assert_eq!(a[0], a[1]);
+        assert!(a[0] != b[0]);
+    }
+
+    #[test]
+    fn default_retention() {
+        let fs = fs::memory(1024);
+        let mut file = File::create(&fs).wait().unwrap();
+        for n in 0..DEFAULT_REVISIONS + 4 {
+            file = file.write(&fs, 0, &[n as u8]).wait().unwrap();
+        }
+
+        // Only the latest revisions are kept, and the older ones are pruned.
+        let revisions = file.revisions(&fs).wait().unwrap();
+        assert_eq!(revisions.len() as u64, DEFAULT_REVISIONS);
+        assert_eq!(revisions[0].generation, 4);
+        let view = file.open_revision(&fs, &revisions[0]).wait().unwrap();
+        assert_eq!(view.read(&fs, 0, 1).wait().unwrap(), [3]);
+    }
+}
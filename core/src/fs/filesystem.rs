@@ -0,0 +1,2156 @@
+//! The public filesystem API.
+//!
+//! `Filesystem` is the handle to an open filesystem, which applications program against. Every
+//! operation is asynchronous: It returns a future, which resolves to the result, or fails with an
+//! `Error`.
+//!
+//! Paths are byte strings of names separated by slashes, starting from the root directory. Every
+//! mutation is committed on its own, so it is persistent and visible atomically, once its future
+//! has completed. Mutations are serialized, so polling their futures may block on concurrent
+//! mutations.
+//!
+//! Open files are referred to by handles. A handle keeps the version of the file, which was last
+//! read or written through it, accessible even if the file is removed, until the handle is closed.
+
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use {alloc, disk, fs, metrics, Error};
+use alloc::{compact, compress, gc, locality};
+use alloc::state_block::{CompressionAlgorithm, DedupVerification};
+use disk::Disk;
+use disk::header::{ChecksumAlgorithm, Vdev};
+use fs::link::modify_file;
+use fs::path::{create, deref, resolve, update};
+
+/// The permission bits of new files.
+const FILE_PERMISSIONS: u32 = 0o644;
+/// The permission bits of new directories.
+const DIRECTORY_PERMISSIONS: u32 = 0o755;
+
+/// The options for creating a filesystem.
+pub struct MkfsOptions {
+    /// The vdev setup (see `Vdev`).
+    pub vdev_stack: Vec<Vdev>,
+    /// The checksum algorithm.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// The compression algorithm.
+    pub compression_algorithm: CompressionAlgorithm,
+    /// The deduplication verification policy.
+    pub dedup_verification: DedupVerification,
+    /// The label of the filesystem (at most 64 bytes).
+    pub label: Vec<u8>,
+    /// The compression algorithms to make available.
+    ///
+    /// If `compression_algorithm` is implementation-defined, it must be registered here.
+    pub codecs: compress::Registry,
+    /// The metrics sink to report to.
+    pub metrics: metrics::Metrics,
+    /// The garbage collection options.
+    ///
+    /// If `None`, no background collector is started (see `OpenOptions::gc`).
+    pub gc: Option<gc::Options>,
+    /// The compaction options.
+    ///
+    /// If `None`, no background compactor is started (see `OpenOptions::compaction`).
+    pub compaction: Option<compact::Options>,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> MkfsOptions {
+        MkfsOptions {
+            vdev_stack: Vec::new(),
+            checksum_algorithm: ChecksumAlgorithm::SeaHash,
+            compression_algorithm: CompressionAlgorithm::Lz4,
+            dedup_verification: DedupVerification::Fingerprint,
+            label: Vec::new(),
+            codecs: compress::Registry::default(),
+            metrics: metrics::Metrics::default(),
+            gc: Some(gc::Options::default()),
+            compaction: Some(compact::Options::default()),
+        }
+    }
+}
+
+/// The options for opening a filesystem.
+#[derive(Clone)]
+pub struct OpenOptions {
+    /// The compression algorithms to make available.
+    ///
+    /// If the filesystem uses an implementation-defined compression algorithm, it must be
+    /// registered here.
+    pub codecs: compress::Registry,
+    /// Open the filesystem read-only.
+    ///
+    /// If set, every mutation fails.
+    pub read_only: bool,
+    /// The retention policy of the revision history.
+    ///
+    /// It is applied to a file, whenever it is written.
+    pub retention: fs::Retention,
+    /// The access time policy.
+    ///
+    /// Reads through a handle update the access time of the file as required by the policy.
+    /// Every update is a commit, so `fs::Atime::Never` keeps reads from writing at all.
+    pub atime: fs::Atime,
+    /// The user ID owning the objects created.
+    pub uid: u32,
+    /// The group ID owning the objects created.
+    pub gid: u32,
+    /// The metrics sink to report to.
+    pub metrics: metrics::Metrics,
+    /// The garbage collection options.
+    ///
+    /// Unless this is `None`, the unreachable clusters are freed by a background thread.
+    /// Collection can also be run on request (see `Filesystem::collect()`), and it is always run,
+    /// when the filesystem is unmounted. No collector is started, if the filesystem is read-only.
+    pub gc: Option<gc::Options>,
+    /// The compaction options.
+    ///
+    /// Unless this is `None`, the sparse clusters are repacked by a background thread.
+    /// Compaction can also be run on request (see `Filesystem::compact()`). No compactor is
+    /// started, if the filesystem is read-only.
+    pub compaction: Option<compact::Options>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions {
+            codecs: compress::Registry::default(),
+            read_only: false,
+            retention: fs::Retention::default(),
+            atime: fs::Atime::default(),
+            uid: 0,
+            gid: 0,
+            metrics: metrics::Metrics::default(),
+            gc: Some(gc::Options::default()),
+            compaction: Some(compact::Options::default()),
+        }
+    }
+}
+
+/// The type of an object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+}
+
+/// An entry of a directory listing.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DirEntry {
+    /// The name of the entry.
+    pub name: Vec<u8>,
+    /// The type of the object.
+    pub file_type: FileType,
+}
+
+/// The status of an object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stat {
+    /// The type of the object.
+    pub file_type: FileType,
+    /// The length in bytes (for directories, the number of entries).
+    pub len: u64,
+    /// The POSIX metadata.
+    ///
+    /// This is `None`, if the object has no inode.
+    pub metadata: Option<fs::Metadata>,
+}
+
+/// The properties of an image, which can be read without opening it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ImageInfo {
+    /// The version of the format, which the image was last opened with.
+    pub version: u32,
+    /// The vdev setup.
+    pub vdev_stack: Vec<Vdev>,
+    /// The checksum algorithm.
+    pub checksum_algorithm: ChecksumAlgorithm,
+}
+
+impl ImageInfo {
+    /// Check if the image is encrypted.
+    ///
+    /// Encrypted images cannot be opened yet.
+    pub fn is_encrypted(&self) -> bool {
+        self.vdev_stack.contains(&Vdev::Speck)
+    }
+}
+
+/// The properties of an open filesystem.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Info {
+    /// The properties of the image.
+    pub image: ImageInfo,
+    /// The compression algorithm.
+    pub compression_algorithm: CompressionAlgorithm,
+    /// The deduplication verification policy.
+    pub dedup_verification: DedupVerification,
+    /// The label of the filesystem.
+    pub label: Vec<u8>,
+    /// The number of commits.
+    pub generation: u64,
+    /// The time the filesystem was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// The time of the last commit, in seconds since the Unix epoch.
+    pub committed: u64,
+}
+
+/// A snapshot of the filesystem.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotInfo {
+    /// The name of the snapshot.
+    pub name: Vec<u8>,
+    /// The generation of the filesystem at the time of the snapshot.
+    pub generation: u64,
+    /// The time the snapshot was created, in seconds since the Unix epoch.
+    pub created: u64,
+}
+
+/// The space usage of a filesystem.
+///
+/// Data is counted in uncompressed pages, so the space taken on the disk is generally smaller.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Usage {
+    /// The size of the disk in bytes.
+    pub size: u64,
+    /// The number of bytes used by the current tree.
+    pub live: u64,
+    /// The number of bytes used only by snapshots and filesystem metadata.
+    pub snapshots: u64,
+    /// The number of files storing their content inline.
+    pub inline_files: u64,
+}
+
+/// A prior revision of a file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RevisionInfo {
+    /// The generation of the revision.
+    ///
+    /// The generation is incremented by every new version of the file, so it identifies the
+    /// revision (see `Filesystem::read_revision()`).
+    pub generation: u64,
+    /// The time of the revision, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// A handle to an open file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Handle(u64);
+
+/// An open filesystem.
+pub struct Filesystem<D> {
+    /// The state of the filesystem.
+    ///
+    /// This is shared with the background threads.
+    state: Arc<fs::State<D>>,
+    /// The options the filesystem was opened with.
+    options: OpenOptions,
+    /// The paths the open handles were opened at.
+    ///
+    /// Writes through a handle are committed to its path, if the path still refers to the file.
+    paths: Mutex<HashMap<u64, Vec<u8>>>,
+    /// The garbage collector.
+    ///
+    /// It is used for the cycles run on request too, so it counts every cycle.
+    collector: Arc<gc::Collector>,
+    /// The compactor.
+    ///
+    /// Like the collector, it is used for the cycles run on request too.
+    compactor: Arc<compact::Compactor>,
+    /// The running background threads.
+    threads: Mutex<Vec<thread::JoinHandle<Result<(), Error>>>>,
+}
+
+/// The error of a missing path.
+fn not_found(path: &[u8]) -> Error {
+    err!(NotFound, "'{}' doesn't exist", String::from_utf8_lossy(path)).with_path(path)
+}
+
+/// The error of a missing revision.
+fn no_revision(path: &[u8], generation: u64) -> Error {
+    err!(NotFound, "'{}' has no revision {}", String::from_utf8_lossy(path), generation)
+        .with_path(path)
+}
+
+/// The error of an invalid handle.
+fn invalid_handle(handle: Handle) -> Error {
+    err!(NotFound, "invalid handle {}", handle.0)
+}
+
+/// Find the object of an entry.
+///
+/// Hard links and inodes are followed. The entry of the object and its inode (if any) are
+/// returned.
+fn object(
+    fs: &fs::State,
+    superpage: &fs::Superpage,
+    entry: fs::Entry,
+) -> future!((fs::Entry, Option<fs::Inode>)) {
+    superpage.follow_link(fs, entry).and_then(move |entry| match entry.kind {
+        fs::EntryKind::Inode => Either::A(fs::Inode::open(fs, entry.target).map(|inode| {
+            (inode.entry(), Some(inode))
+        })),
+        _ => Either::B(future::ok((entry, None))),
+    })
+}
+
+/// Find a revision of a file by its generation.
+fn revision(fs: &fs::State, file: fs::File, path: Vec<u8>, generation: u64)
+    -> future!(fs::Revision) {
+    file.revisions(fs).and_then(move |revisions| {
+        revisions.into_iter().find(|x| x.generation == generation)
+            .ok_or_else(|| no_revision(&path, generation))
+    })
+}
+
+/// Get the type of an object.
+///
+/// See `object()`.
+fn file_type(entry: fs::Entry, inode: Option<fs::Inode>) -> FileType {
+    match entry.kind {
+        _ if inode.map_or(false, |x| x.is_symlink()) => FileType::Symlink,
+        fs::EntryKind::Directory => FileType::Directory,
+        _ => FileType::File,
+    }
+}
+
+impl<D: Disk + Send + Sync + 'static> Filesystem<D> {
+    /// Read the properties of the image on a disk.
+    ///
+    /// This doesn't open the filesystem. It can be used to find out if the image is encrypted.
+    pub fn probe(disk: &D) -> future!(ImageInfo) {
+        // The disk header is the first sector of the raw disk.
+        disk.read(0).and_then(|buf| {
+            let header = disk::header::DiskHeader::decode(&buf)?;
+
+            Ok(ImageInfo {
+                version: header.version_number,
+                vdev_stack: header.options.vdev_stack.clone(),
+                checksum_algorithm: header.options.checksum_algorithm,
+            })
+        })
+    }
+
+    /// Create a new, empty filesystem on a disk.
+    ///
+    /// Any existing data on the disk is lost. The new filesystem is returned open.
+    pub fn mkfs(disk: D, options: MkfsOptions) -> future!(Filesystem<D>) {
+        let label = options.label;
+        let codecs = options.codecs.clone();
+        let metrics = options.metrics.clone();
+        let gc = options.gc;
+        let compaction = options.compaction;
+
+        fs::State::init(disk, alloc::Options {
+            state_block: alloc::state_block::Options {
+                compression_algorithm: options.compression_algorithm,
+                dedup_verification: options.dedup_verification,
+            },
+            disk_header: disk::header::Options {
+                vdev_stack: options.vdev_stack,
+                checksum_algorithm: options.checksum_algorithm,
+            },
+            codecs: options.codecs,
+            metrics: options.metrics,
+        }).and_then(move |state| {
+            info!(state, "creating filesystem");
+
+            fs::Directory::create(&state).and_then(move |root| {
+                fs::Superpage::new(root, &label)
+            }).and_then(|superpage| superpage.commit(&state)).map(move |_| {
+                Filesystem::start(state, OpenOptions {
+                    codecs: codecs,
+                    metrics: metrics,
+                    gc: gc,
+                    compaction: compaction,
+                    .. OpenOptions::default()
+                })
+            })
+        })
+    }
+
+    /// Open the filesystem on a disk.
+    ///
+    /// Encryption is not supported yet, so if the disk is encrypted, an error is returned.
+    pub fn open(disk: D, options: OpenOptions) -> future!(Filesystem<D>) {
+        let metrics = options.metrics.clone();
+        let state = fs::State::open(disk, options.read_only, &options.codecs, metrics);
+
+        state.and_then(move |state| {
+            let filesystem = Filesystem::start(state, options);
+
+            // Check that there is a filesystem at all.
+            filesystem.superpage().map(move |superpage| {
+                info!(filesystem.state, "opened filesystem"; "generation" => superpage.generation,
+                      "read-only" => filesystem.options.read_only);
+                // Publish the opened tree, so watchers see the changes of the first commit.
+                filesystem.state.publish(superpage.generation, superpage.root, superpage.links);
+                filesystem
+            })
+        })
+    }
+
+    /// Set up the filesystem around an open state.
+    ///
+    /// The background threads are started, unless the filesystem is read-only.
+    fn start(mut state: fs::State<D>, options: OpenOptions) -> Filesystem<D> {
+        state.set_retention(options.retention);
+
+        let filesystem = Filesystem {
+            state: Arc::new(state),
+            collector: Arc::new(gc::Collector::new(options.gc.unwrap_or_default())),
+            compactor: Arc::new(compact::Compactor::new(options.compaction.unwrap_or_default())),
+            threads: Mutex::new(Vec::new()),
+            paths: Mutex::new(HashMap::new()),
+            options: options,
+        };
+
+        if !filesystem.options.read_only {
+            let mut threads = filesystem.threads.lock().unwrap();
+
+            if filesystem.options.gc.is_some() {
+                let state = filesystem.state.clone();
+                threads.push(filesystem.collector.clone()
+                    .spawn(move |collector| state.collect(collector)));
+            }
+            if filesystem.options.compaction.is_some() {
+                let state = filesystem.state.clone();
+                threads.push(filesystem.compactor.clone()
+                    .spawn(move |compactor| state.compact(compactor)));
+            }
+        }
+
+        filesystem
+    }
+
+    /// Load the current superpage.
+    fn superpage(&self) -> future!(fs::Superpage) {
+        fs::Superpage::load(&self.state).and_then(|superpage| {
+            superpage.ok_or_else(|| err!(Corruption, "no superpage"))
+        })
+    }
+
+    /// Look up the entry at a path of the current tree.
+    ///
+    /// The current superpage is returned too.
+    fn lookup(&self, path: &[u8]) -> future!((fs::Superpage, fs::Entry)) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.superpage().and_then(move |superpage| {
+            resolve(fs, superpage.root, &path).and_then(move |entry| {
+                Ok((superpage, entry.ok_or_else(|| not_found(&path))?))
+            })
+        })
+    }
+
+    /// Open the file at a path.
+    ///
+    /// Hard links and inodes are followed. If the path refers to a directory or a symbolic link,
+    /// an error is returned.
+    fn file(&self, path: &[u8]) -> future!(fs::File) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.lookup(&path).and_then(move |(superpage, entry)| object(fs, &superpage, entry))
+            .and_then(move |(entry, inode)| match file_type(entry, inode) {
+                FileType::File => Ok(fs::File::open(fs, entry.target)),
+                FileType::Directory => Err(err!(IsDirectory, "'{}' is a directory",
+                                                String::from_utf8_lossy(&path)).with_path(&path)),
+                FileType::Symlink => Err(err!(InvalidInput, "'{}' is a symbolic link",
+                                              String::from_utf8_lossy(&path)).with_path(&path)),
+            }).flatten()
+    }
+
+    /// Get the inode of the object at a path.
+    ///
+    /// Hard links are followed. If the object has no inode, an error is returned.
+    fn inode(&self, path: &[u8]) -> future!(fs::Inode) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.lookup(&path).and_then(move |(superpage, entry)| object(fs, &superpage, entry))
+            .and_then(move |(_, inode)| inode.ok_or_else(|| {
+                err!(Unsupported, "'{}' has no inode", String::from_utf8_lossy(&path))
+                    .with_path(&path)
+            }))
+    }
+
+    /// Get the file version of an open handle.
+    fn handle_file(&self, handle: Handle) -> future!(fs::File) {
+        let fs = &self.state;
+
+        future::result(fs.handles.get(handle.0).ok_or_else(|| invalid_handle(handle)))
+            .and_then(move |entry| deref(fs, entry))
+            .and_then(move |entry| fs::File::open(fs, entry.target))
+    }
+
+    /// Commit a mutation.
+    ///
+    /// `f` is applied to the current superpage, and the result is committed. Mutations are
+    /// serialized, so this blocks while another mutation is committed.
+    fn commit<F>(&self, f: F) -> future!(fs::Superpage)
+    where F: FnOnce(fs::Superpage) -> fs::BoxFuture<fs::Superpage> {
+        let fs = &self.state;
+        let read_only = self.options.read_only;
+
+        future::lazy(move || {
+            if read_only {
+                return Err(err!(ReadOnly, "the filesystem is read-only"));
+            }
+
+            let mut last = fs.commits.lock().unwrap();
+            let current = self.superpage().wait()?;
+
+            f(current).wait()?.commit_locked(fs, &mut last)
+        })
+    }
+
+    /// Create a new object at a path of a superpage.
+    ///
+    /// `object` creates the object, and the entry at `path` refers to it through a new inode with
+    /// the permission bits `permissions`, owned by the owner of the options. If the path exists,
+    /// an error is returned. The new superpage is returned.
+    fn create_object<F>(
+        &self,
+        superpage: fs::Superpage,
+        path: Vec<u8>,
+        permissions: u32,
+        object: F,
+    ) -> fs::BoxFuture<fs::Superpage>
+    where F: FnOnce() -> fs::BoxFuture<fs::Entry> {
+        let fs = &self.state;
+        let (uid, gid) = (self.options.uid, self.options.gid);
+
+        let inode = object().and_then(move |entry| {
+            fs::Inode::create(fs, entry, permissions, uid, gid)
+        }).map(|inode| fs::Entry {
+            kind: fs::EntryKind::Inode,
+            target: inode.root(),
+        });
+
+        Box::new(update(fs, superpage.root, &path.clone(), create(path, Box::new(inode)))
+            .map(move |root| fs::Superpage {
+                root: root,
+                .. superpage
+            }))
+    }
+
+    /// Create an empty file, and open it.
+    ///
+    /// If the path exists, an error is returned.
+    pub fn create(&self, path: &[u8]) -> future!(Handle) {
+        let fs = &self.state;
+        let path = path.to_vec();
+        let created = path.clone();
+
+        self.commit(move |superpage| {
+            self.create_object(superpage, path, FILE_PERMISSIONS, move || {
+                Box::new(fs::File::create(fs).map(|file| fs::Entry {
+                    kind: fs::EntryKind::File,
+                    target: file.root(),
+                }))
+            })
+        }).and_then(move |_| self.open_file(&created))
+    }
+
+    /// Replace the content of the file at a path.
+    ///
+    /// The file is created, if it doesn't exist. Its content is replaced by `buf` in a single
+    /// commit, so the file is never seen partially written.
+    pub fn put(&self, path: &[u8], buf: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (path, buf) = (path.to_vec(), buf.to_vec());
+
+        self.commit(move |superpage| {
+            Box::new(resolve(fs, superpage.root, &path).and_then(move |entry| match entry {
+                Some(_) => Either::A(superpage.modify_file(fs, &path, move |file| {
+                    Box::new(file.truncate(fs, 0).and_then(move |file| file.write(fs, 0, &buf)))
+                })),
+                None => Either::B(self.create_object(superpage, path, FILE_PERMISSIONS, move || {
+                    Box::new(fs::File::create(fs).and_then(move |file| file.write(fs, 0, &buf))
+                        .map(|file| fs::Entry {
+                            kind: fs::EntryKind::File,
+                            target: file.root(),
+                        }))
+                })),
+            }))
+        }).map(|_| ())
+    }
+
+    /// Create an empty directory.
+    ///
+    /// If the path exists, an error is returned.
+    pub fn mkdir(&self, path: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.commit(move |superpage| {
+            self.create_object(superpage, path, DIRECTORY_PERMISSIONS, move || {
+                Box::new(fs::Directory::create(fs).map(|dir| fs::Entry {
+                    kind: fs::EntryKind::Directory,
+                    target: dir.root(),
+                }))
+            })
+        }).map(|_| ())
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`.
+    ///
+    /// If the path exists, an error is returned.
+    pub fn symlink(&self, path: &[u8], target: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (path, target) = (path.to_vec(), target.to_vec());
+        let (uid, gid) = (self.options.uid, self.options.gid);
+
+        self.commit(move |superpage| {
+            Box::new(superpage.symlink(fs, &path, &target, uid, gid))
+        }).map(|_| ())
+    }
+
+    /// Read the target of the symbolic link at a path.
+    pub fn read_link(&self, path: &[u8]) -> future!(Vec<u8>) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.lookup(&path).and_then(move |(superpage, entry)| object(fs, &superpage, entry))
+            .and_then(move |(_, inode)| match inode {
+                Some(inode) if inode.is_symlink() => Ok(inode.read_link(fs)),
+                _ => Err(err!(InvalidInput, "'{}' is not a symbolic link",
+                              String::from_utf8_lossy(&path)).with_path(&path)),
+            }).flatten()
+    }
+
+    /// Create a hard link at `new` to the file at `existing`.
+    ///
+    /// Directories cannot be hard linked. If `new` exists, an error is returned.
+    pub fn link(&self, existing: &[u8], new: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (existing, new) = (existing.to_vec(), new.to_vec());
+
+        self.commit(move |superpage| Box::new(superpage.link(fs, &existing, &new))).map(|_| ())
+    }
+
+    /// Clone the object at `source` to `target`.
+    ///
+    /// The object (a file or a whole directory tree) is shared rather than copied, so this takes
+    /// no space until either copy is modified. The copies are independent: Modifying one leaves
+    /// the other unchanged, even if the object is hard linked. If `target` exists, an error is
+    /// returned.
+    pub fn clone_entry(&self, source: &[u8], target: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (source, target) = (source.to_vec(), target.to_vec());
+
+        self.commit(move |superpage| Box::new(superpage.clone_entry(fs, &source, &target)))
+            .map(|_| ())
+    }
+
+    /// Set the permission bits of the object at a path.
+    pub fn chmod(&self, path: &[u8], permissions: u32) -> future!(()) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.commit(move |superpage| {
+            Box::new(superpage.modify_inode(fs, &path, move |inode| {
+                Box::new(inode.chmod(fs, permissions))
+            }))
+        }).map(|_| ())
+    }
+
+    /// List the names of the extended attributes of the object at a path.
+    ///
+    /// Only objects with an inode have extended attributes, so for other objects, an error is
+    /// returned.
+    pub fn list_xattrs(&self, path: &[u8]) -> future!(Vec<Vec<u8>>) {
+        let fs = &self.state;
+
+        self.inode(path).and_then(move |inode| inode.list_xattrs(fs))
+    }
+
+    /// Get the value of an extended attribute of the object at a path.
+    ///
+    /// If there is no such attribute, `None` is returned.
+    pub fn get_xattr(&self, path: &[u8], name: &[u8]) -> future!(Option<Vec<u8>>) {
+        let fs = &self.state;
+        let name = name.to_vec();
+
+        self.inode(path).and_then(move |inode| inode.get_xattr(fs, &name))
+    }
+
+    /// Set the value of an extended attribute of the object at a path.
+    ///
+    /// The attributes of a regular file are part of its content, so this creates a new revision
+    /// of the file.
+    pub fn set_xattr(&self, path: &[u8], name: &[u8], value: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (path, name, value) = (path.to_vec(), name.to_vec(), value.to_vec());
+
+        self.commit(move |superpage| {
+            Box::new(superpage.modify_inode(fs, &path, move |inode| {
+                Box::new(inode.set_xattr(fs, &name, &value))
+            }))
+        }).map(|_| ())
+    }
+
+    /// Remove an extended attribute of the object at a path.
+    ///
+    /// If there is no such attribute, an error is returned.
+    pub fn remove_xattr(&self, path: &[u8], name: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (path, name) = (path.to_vec(), name.to_vec());
+
+        self.commit(move |superpage| {
+            Box::new(superpage.modify_inode(fs, &path, move |inode| {
+                Box::new(inode.remove_xattr(fs, &name))
+            }))
+        }).map(|_| ())
+    }
+
+    /// Open the file at a path.
+    pub fn open_file(&self, path: &[u8]) -> future!(Handle) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.lookup(&path).and_then(move |(superpage, entry)| {
+            superpage.follow_link(fs, entry).join(object(fs, &superpage, entry))
+        }).and_then(move |(entry, (object, _))| {
+            if object.kind == fs::EntryKind::Directory {
+                return Err(err!(IsDirectory, "'{}' is a directory",
+                                String::from_utf8_lossy(&path)));
+            }
+
+            let handle = fs.handles.open(entry);
+            self.paths.lock().unwrap().insert(handle, path);
+
+            Ok(Handle(handle))
+        })
+    }
+
+    /// Read a byte range of an open file.
+    ///
+    /// This reads (up to) `len` bytes starting at byte `offset`. If the range exceeds the end of
+    /// the file, fewer bytes (possibly none) are returned.
+    ///
+    /// The access time of the file is updated according to the access time policy (see
+    /// `OpenOptions::atime`).
+    pub fn read(&self, handle: Handle, offset: u64, len: usize) -> future!(Vec<u8>) {
+        let fs = &self.state;
+
+        self.handle_file(handle).and_then(move |file| file.read(fs, offset, len))
+            .and_then(move |buf| self.access(handle).map(|_| buf))
+    }
+
+    /// Find the file of a handle in a superpage.
+    ///
+    /// If `path` (the path of the handle) refers to the version `old` of the file of the handle,
+    /// or to a later version of it, the entry at `path` is returned, with hard links followed.
+    /// Otherwise, the file was removed or replaced, so `None` is returned. This blocks.
+    fn attached(&self, superpage: &fs::Superpage, path: &[u8], old: fs::Entry)
+        -> Result<Option<fs::Entry>, Error> {
+        let fs = &self.state;
+
+        let current = match resolve(fs, superpage.root, path).wait()? {
+            Some(entry) => superpage.follow_link(fs, entry).wait()?,
+            None => return Ok(None),
+        };
+        if current.kind == fs::EntryKind::Directory {
+            return Ok(None);
+        }
+
+        let (file, old) = deref(fs, current).join(deref(fs, old)).wait()?;
+        let attached = fs::File::open(fs, file.target)
+            .and_then(move |file| file.descends_from(fs, old.target)).wait()?;
+
+        Ok(if attached { Some(current) } else { None })
+    }
+
+    /// Record an access to an open file.
+    ///
+    /// The access time of the file at the path of the handle is updated and committed, if the
+    /// access time policy requires it. Nothing is written, if the filesystem is read-only, or if
+    /// the path no longer refers to the file of the handle.
+    fn access(&self, handle: Handle) -> future!(()) {
+        let fs = &self.state;
+        let (read_only, policy) = (self.options.read_only, self.options.atime);
+
+        future::lazy(move || {
+            if read_only || policy == fs::Atime::Never {
+                return Ok(());
+            }
+
+            let old = fs.handles.get(handle.0).ok_or_else(|| invalid_handle(handle))?;
+            let path = self.paths.lock().unwrap().get(&handle.0).cloned()
+                .ok_or_else(|| invalid_handle(handle))?;
+
+            let mut last = fs.commits.lock().unwrap();
+            let superpage = self.superpage().wait()?;
+
+            let inode = match self.attached(&superpage, &path, old)? {
+                Some(entry) if entry.kind == fs::EntryKind::Inode => {
+                    fs::Inode::open(fs, entry.target).wait()?
+                },
+                // Objects without an inode have no access time.
+                _ => return Ok(()),
+            };
+            let inode = match inode.accessed(fs, policy).wait()? {
+                Some(inode) => inode,
+                None => return Ok(()),
+            };
+
+            superpage.modify_inode(fs, &path, move |_| Box::new(future::ok(inode))).wait()?
+                .commit_locked(fs, &mut last)?;
+            fs.handles.set(handle.0, fs::Entry {
+                kind: fs::EntryKind::Inode,
+                target: inode.root(),
+            })
+        })
+    }
+
+    /// Modify an open file.
+    ///
+    /// `f` is applied to the current version of the file at the path of the handle, and the
+    /// result is committed, if the path still refers to the file (or a later version of it, e.g.
+    /// written through another handle). Otherwise, the file was removed or replaced, so `f` is
+    /// applied to the version of the handle, which only the handle sees.
+    ///
+    /// The file is read, modified and committed under the commit lock, so concurrent writes are
+    /// applied on top of each other rather than lost.
+    fn modify<F>(&self, handle: Handle, f: F) -> future!(())
+    where F: FnOnce(fs::File) -> fs::BoxFuture<fs::File> + 'static {
+        let fs = &self.state;
+        let read_only = self.options.read_only;
+
+        future::lazy(move || {
+            if read_only {
+                return Err(err!(ReadOnly, "the filesystem is read-only"));
+            }
+
+            let old = fs.handles.get(handle.0).ok_or_else(|| invalid_handle(handle))?;
+            // The handle was opened at its path.
+            let path = self.paths.lock().unwrap().get(&handle.0).cloned()
+                .ok_or_else(|| invalid_handle(handle))?;
+
+            // Pack the pages written through the handle together (see `fs::File::with_hint()`).
+            let hint = locality::Hint(handle.0);
+            let f = move |file: fs::File| f(file.with_hint(hint));
+
+            let mut last = fs.commits.lock().unwrap();
+            let superpage = self.superpage().wait()?;
+
+            let new = if self.attached(&superpage, &path, old)?.is_some() {
+                let superpage = superpage.modify_file(fs, &path, f).wait()?
+                    .commit_locked(fs, &mut last)?;
+                let entry = resolve(fs, superpage.root, &path).wait()?
+                    .ok_or_else(|| not_found(&path))?;
+
+                superpage.follow_link(fs, entry).wait()?
+            } else {
+                modify_file(fs, old, f).wait()?
+            };
+
+            fs.handles.set(handle.0, new)
+        })
+    }
+
+    /// Write a byte range of an open file.
+    ///
+    /// The bytes starting at `offset` are replaced by `buf`. If the range exceeds the end of the
+    /// file, the file is extended.
+    pub fn write(&self, handle: Handle, offset: u64, buf: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let buf = buf.to_vec();
+
+        self.modify(handle, move |file| Box::new(file.write(fs, offset, &buf)))
+    }
+
+    /// Resize an open file.
+    pub fn truncate(&self, handle: Handle, len: u64) -> future!(()) {
+        let fs = &self.state;
+
+        self.modify(handle, move |file| Box::new(file.truncate(fs, len)))
+    }
+
+    /// Write a byte range of an open file, turning zero pages into holes.
+    ///
+    /// This is like `self.write()`, but the pages, which end up entirely zero, become holes
+    /// rather than taking space.
+    pub fn write_sparse(&self, handle: Handle, offset: u64, buf: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let buf = buf.to_vec();
+
+        self.modify(handle, move |file| Box::new(file.write_sparse(fs, offset, &buf)))
+    }
+
+    /// Punch a hole into an open file.
+    ///
+    /// The bytes in `offset..offset + len` become zero, and the pages covered entirely are freed.
+    /// The length of the file is unchanged.
+    pub fn punch_hole(&self, handle: Handle, offset: u64, len: u64) -> future!(()) {
+        let fs = &self.state;
+
+        self.modify(handle, move |file| Box::new(file.punch_hole(fs, offset, len)))
+    }
+
+    /// Find the next data in an open file.
+    ///
+    /// This is like `SEEK_DATA`: The offset of the first byte at or after `offset`, which is not
+    /// in a hole, is returned. If there is none, `None` is returned.
+    pub fn seek_data(&self, handle: Handle, offset: u64) -> future!(Option<u64>) {
+        let fs = &self.state;
+
+        self.handle_file(handle).and_then(move |file| file.seek_data(fs, offset))
+    }
+
+    /// Find the next hole in an open file.
+    ///
+    /// This is like `SEEK_HOLE`: The offset of the first byte at or after `offset`, which is in a
+    /// hole, is returned, the end of the file counting as a hole. If `offset` is at or after the
+    /// end, `None` is returned.
+    pub fn seek_hole(&self, handle: Handle, offset: u64) -> future!(Option<u64>) {
+        let fs = &self.state;
+
+        self.handle_file(handle).and_then(move |file| file.seek_hole(fs, offset))
+    }
+
+    /// Close a handle.
+    pub fn close(&self, handle: Handle) -> future!(()) {
+        self.paths.lock().unwrap().remove(&handle.0);
+        self.state.close_hint(locality::Hint(handle.0));
+
+        future::result(self.state.handles.close(handle.0).map(|_| ()))
+    }
+
+    /// Get the status of the object at a path.
+    pub fn stat(&self, path: &[u8]) -> future!(Stat) {
+        let fs = &self.state;
+
+        self.lookup(path).and_then(move |(superpage, entry)| object(fs, &superpage, entry))
+            .and_then(move |(entry, inode)| {
+                let len = match entry.kind {
+                    fs::EntryKind::Directory => {
+                        Either::A(fs::Directory::from_raw(entry.target).list(fs)
+                            .map(|entries| entries.len() as u64))
+                    },
+                    _ => Either::B(fs::File::open(fs, entry.target).map(|file| file.len())),
+                };
+
+                len.map(move |len| Stat {
+                    file_type: file_type(entry, inode),
+                    len: len,
+                    metadata: inode.map(|x| x.metadata()),
+                })
+            })
+    }
+
+    /// List the directory at a path.
+    pub fn readdir(&self, path: &[u8]) -> future!(Vec<DirEntry>) {
+        let fs = &self.state;
+
+        self.lookup(path).and_then(move |(superpage, entry)| {
+            object(fs, &superpage, entry).and_then(move |(entry, _)| match entry.kind {
+                fs::EntryKind::Directory => Ok(fs::Directory::from_raw(entry.target).list(fs)),
+                _ => Err(err!(NotDirectory, "not a directory")),
+            }).flatten().and_then(move |entries| {
+                future::join_all(entries.into_iter().map(|(name, entry)| {
+                    object(fs, &superpage, entry).map(move |(entry, inode)| DirEntry {
+                        name: name,
+                        file_type: file_type(entry, inode),
+                    })
+                }).collect::<Vec<_>>())
+            })
+        })
+    }
+
+    /// Remove the non-directory entry at a path.
+    ///
+    /// Open handles to the file remain valid until closed.
+    pub fn unlink(&self, path: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.commit(move |superpage| Box::new(superpage.unlink(fs, &path))).map(|_| ())
+    }
+
+    /// Remove the empty directory at a path.
+    pub fn rmdir(&self, path: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.commit(move |superpage| Box::new(superpage.rmdir(fs, &path))).map(|_| ())
+    }
+
+    /// Move the entry at `from` to `to`.
+    ///
+    /// An existing entry at `to` is replaced, following POSIX.
+    pub fn rename(&self, from: &[u8], to: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let (from, to) = (from.to_vec(), to.to_vec());
+
+        self.commit(move |superpage| Box::new(superpage.rename(fs, &from, &to))).map(|_| ())
+    }
+
+    /// List the prior revisions of the file at a path.
+    ///
+    /// The revisions are returned oldest first. The current version is not included, and neither
+    /// are the revisions pruned by the retention policy (see `OpenOptions::retention`).
+    pub fn revisions(&self, path: &[u8]) -> future!(Vec<RevisionInfo>) {
+        let fs = &self.state;
+
+        self.file(path).and_then(move |file| file.revisions(fs))
+            .map(|revisions| revisions.into_iter().map(|revision| RevisionInfo {
+                generation: revision.generation,
+                timestamp: revision.timestamp,
+            }).collect())
+    }
+
+    /// Read a byte range of a prior revision of the file at a path.
+    ///
+    /// The revision is identified by its generation (see `self.revisions()`), and the range is
+    /// read like by `self.read()`. If there is no such revision, an error is returned.
+    pub fn read_revision(&self, path: &[u8], generation: u64, offset: u64, len: usize)
+        -> future!(Vec<u8>) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.file(&path.clone()).and_then(move |file| {
+            revision(fs, file, path, generation)
+                .and_then(move |revision| file.open_revision(fs, &revision))
+        }).and_then(move |view| view.read(fs, offset, len))
+    }
+
+    /// Revert the file at a path to a prior revision.
+    ///
+    /// This writes a new version of the file with the content of the revision identified by
+    /// `generation`. The history is kept, so the revert can itself be reverted. If there is no
+    /// such revision, an error is returned.
+    pub fn revert(&self, path: &[u8], generation: u64) -> future!(()) {
+        let fs = &self.state;
+        let path = path.to_vec();
+
+        self.commit(move |superpage| {
+            Box::new(superpage.modify_file(fs, &path.clone(), move |file| {
+                Box::new(revision(fs, file, path, generation)
+                    .and_then(move |revision| file.revert(fs, &revision)))
+            }))
+        }).map(|_| ())
+    }
+
+    /// Get the properties of the filesystem.
+    pub fn info(&self) -> future!(Info) {
+        let header = self.state.alloc.disk_header();
+        let image = ImageInfo {
+            version: header.version_number,
+            vdev_stack: header.options.vdev_stack.clone(),
+            checksum_algorithm: header.options.checksum_algorithm,
+        };
+        let options = *self.state.alloc.options();
+
+        self.superpage().map(move |superpage| Info {
+            image: image,
+            compression_algorithm: options.compression_algorithm,
+            dedup_verification: options.dedup_verification,
+            label: superpage.label,
+            generation: superpage.generation,
+            created: superpage.created,
+            committed: superpage.committed,
+        })
+    }
+
+    /// Create a snapshot of the current tree.
+    ///
+    /// If a snapshot named `name` exists, an error is returned.
+    pub fn snapshot(&self, name: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let name = name.to_vec();
+
+        self.commit(move |superpage| Box::new(superpage.create_snapshot(fs, &name))).map(|_| ())
+    }
+
+    /// List the snapshots, ordered by their name.
+    pub fn snapshots(&self) -> future!(Vec<SnapshotInfo>) {
+        let fs = &self.state;
+
+        self.superpage().and_then(move |superpage| superpage.snapshots(fs))
+            .map(|snapshots| snapshots.into_iter().map(|snapshot| SnapshotInfo {
+                name: snapshot.name,
+                generation: snapshot.generation,
+                created: snapshot.created,
+            }).collect())
+    }
+
+    /// Delete a snapshot.
+    ///
+    /// The space used only by the snapshot is freed by the garbage collector. If no snapshot
+    /// named `name` exists, an error is returned.
+    pub fn delete_snapshot(&self, name: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let name = name.to_vec();
+
+        self.commit(move |superpage| Box::new(superpage.delete_snapshot(fs, &name))).map(|_| ())
+    }
+
+    /// Roll the tree back to a snapshot.
+    ///
+    /// The current tree is replaced by the tree of snapshot `name`, which is kept. Open handles
+    /// keep the versions they refer to, but writes through them no longer reach the tree, unless
+    /// the snapshot has the same version of the file. If no snapshot named `name` exists, an
+    /// error is returned.
+    pub fn rollback(&self, name: &[u8]) -> future!(()) {
+        let fs = &self.state;
+        let name = name.to_vec();
+
+        self.commit(move |superpage| Box::new(superpage.rollback(fs, &name))).map(|_| ())
+    }
+
+    /// Watch a path for changes.
+    ///
+    /// This returns a stream of the changes to `path` (and everything below it) made by every
+    /// commit from now on. If `since` is given, the changes made after generation `since` (see
+    /// `Info::generation`) are replayed first. This requires that `since` is the generation of
+    /// one of the latest commits or of a snapshot; otherwise, the stream fails.
+    pub fn watch<'a>(&'a self, path: &[u8], since: Option<u64>)
+        -> Box<Stream<Item = fs::WatchEvent, Error = Error> + 'a> {
+        fs::watch(&self.state, path, since)
+    }
+
+    /// Begin a transaction.
+    ///
+    /// The transaction begins from the current tree. See `Transaction`.
+    pub fn begin(&self) -> future!(Transaction<D>) {
+        fs::transaction::Transaction::begin(&self.state).map(move |transaction| Transaction {
+            filesystem: self,
+            transaction: transaction,
+        })
+    }
+
+    /// Account the space used by the filesystem.
+    ///
+    /// This traverses every object, so it is slow on large filesystems.
+    pub fn usage(&self) -> future!(Usage) {
+        let fs = &self.state;
+        let size = fs.alloc.number_of_clusters() * disk::SECTOR_SIZE as u64;
+
+        self.superpage().and_then(move |superpage| {
+            // The pages of the superpage, which aren't shared with the current tree, are used by
+            // the snapshots (or are metadata).
+            let space = fs.space(&superpage, &superpage.root)?;
+
+            Ok(Usage {
+                size: size,
+                live: space.shared,
+                snapshots: space.exclusive,
+                inline_files: space.inline_files,
+            })
+        })
+    }
+
+    /// Run a garbage collection cycle.
+    ///
+    /// This frees the clusters, which became unreachable before the previous cycle. Clusters are
+    /// given a cycle to get linked into the tree, so it takes two cycles to free everything, which
+    /// is unreachable now.
+    ///
+    /// The freed clusters are buffered, until the filesystem is synced.
+    pub fn collect(&self) -> future!(()) {
+        let read_only = self.options.read_only;
+
+        future::lazy(move || {
+            if read_only {
+                return Err(err!(ReadOnly, "the filesystem is read-only"));
+            }
+
+            self.state.collect(&self.collector)
+        })
+    }
+
+    /// Run a compaction cycle.
+    ///
+    /// This repacks the live pages of the sparse clusters into fewer clusters, and commits the
+    /// moved pages. The old clusters are freed.
+    pub fn compact(&self) -> future!(()) {
+        let read_only = self.options.read_only;
+
+        future::lazy(move || {
+            if read_only {
+                return Err(err!(ReadOnly, "the filesystem is read-only"));
+            }
+
+            self.state.compact(&self.compactor)
+        })
+    }
+
+    /// Flush buffered state to the disk.
+    pub fn sync(&self) -> future!(()) {
+        future::lazy(move || self.state.sync())
+    }
+
+    /// Close the filesystem.
+    ///
+    /// This stops the background threads, frees every unreachable cluster, flushes the buffered
+    /// state, and marks the disk as properly closed. The open handles are closed. Freeing the
+    /// unreachable clusters traverses the whole tree (twice), so this is slow on large
+    /// filesystems.
+    pub fn unmount(self) -> future!(()) {
+        info!(self.state, "closing filesystem";
+              "open handles" => self.paths.lock().unwrap().len());
+        let res = self.stop_background().and_then(|_| {
+            if !self.options.read_only {
+                // Leave no leaked clusters behind. See `self.collect()` for why it takes two
+                // cycles.
+                self.state.collect(&self.collector)?;
+                self.state.collect(&self.collector)?;
+            }
+
+            self.state.sync()
+        });
+
+        // The disk is marked as closed, when it is dropped.
+        drop(self);
+        future::result(res)
+    }
+}
+
+/// A transaction.
+///
+/// A transaction groups several mutations, such that they become visible atomically, by a single
+/// commit. The mutations are recorded, and nothing is written before the transaction is committed.
+/// If another commit changed a path touched by the transaction since it began, the commit fails
+/// with a `Conflict` error, and nothing is committed.
+///
+/// Transactions are created by `Filesystem::begin()`. The objects created by a transaction have no
+/// inode (see `Stat::metadata`).
+pub struct Transaction<'a, D: 'a> {
+    /// The filesystem.
+    filesystem: &'a Filesystem<D>,
+    /// The log of the transaction.
+    transaction: fs::transaction::Transaction,
+}
+
+impl<'a, D: Disk + Send + Sync + 'static> Transaction<'a, D> {
+    /// Get the generation the transaction began from.
+    pub fn generation(&self) -> u64 {
+        self.transaction.generation()
+    }
+
+    /// Check if a path exists, as of the beginning of the transaction.
+    ///
+    /// The transaction conflicts, if the path is changed concurrently. The mutations recorded by
+    /// the transaction are not visible.
+    pub fn exists(&mut self, path: &[u8]) -> future!(bool) {
+        self.transaction.lookup(&self.filesystem.state, path).map(|entry| entry.is_some())
+    }
+
+    /// Create an empty file.
+    pub fn create_file(&mut self, path: &[u8]) {
+        self.transaction.create_file(path);
+    }
+
+    /// Create an empty directory.
+    pub fn create_directory(&mut self, path: &[u8]) {
+        self.transaction.create_directory(path);
+    }
+
+    /// Write to a file at some offset.
+    pub fn write(&mut self, path: &[u8], offset: u64, buf: &[u8]) {
+        self.transaction.write(path, offset, buf);
+    }
+
+    /// Set the length of a file.
+    pub fn truncate(&mut self, path: &[u8], len: u64) {
+        self.transaction.truncate(path, len);
+    }
+
+    /// Remove an entry.
+    pub fn remove(&mut self, path: &[u8]) {
+        self.transaction.remove(path);
+    }
+
+    /// Move an entry to another path.
+    pub fn rename(&mut self, from: &[u8], to: &[u8]) {
+        self.transaction.rename(from, to);
+    }
+
+    /// Abort the transaction.
+    ///
+    /// Nothing has been written, so this merely discards the recorded mutations.
+    pub fn abort(self) {
+        self.transaction.abort(&self.filesystem.state);
+    }
+
+    /// Commit the transaction.
+    ///
+    /// The mutations are applied to the current tree in the order they were recorded, and
+    /// committed. If the transaction conflicts, or a mutation fails, nothing is committed, and an
+    /// error is returned.
+    pub fn commit(self) -> future!(()) {
+        let filesystem = self.filesystem;
+        let transaction = self.transaction;
+
+        future::lazy(move || {
+            if filesystem.options.read_only {
+                return Err(err!(ReadOnly, "the filesystem is read-only"));
+            }
+
+            transaction.commit(&filesystem.state).map(|_| ())
+        })
+    }
+}
+
+impl<D> Filesystem<D> {
+    /// Stop the background threads, and wait for them to finish.
+    ///
+    /// If a thread failed, its error is returned.
+    fn stop_background(&self) -> Result<(), Error> {
+        self.collector.stop();
+        self.compactor.stop();
+
+        let mut res = Ok(());
+        for thread in self.threads.lock().unwrap().drain(..) {
+            // Wake the thread up, if it is pausing between cycles.
+            thread.thread().unpark();
+            let thread_res = thread.join().unwrap_or_else(|_| {
+                Err(err!(Implementation, "a background thread panicked"))
+            });
+            res = res.and(thread_res);
+        }
+
+        res
+    }
+}
+
+impl<D> Drop for Filesystem<D> {
+    fn drop(&mut self) {
+        // The filesystem wasn't unmounted, so there is nobody to report errors to.
+        let _ = self.stop_background();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::page;
+    use disk::cluster;
+    use disk::memory::Memory;
+    use error;
+    use fs::fsck;
+    use rand;
+    use std::time::Duration;
+
+    /// Create a filesystem on an in-memory disk.
+    ///
+    /// No background threads are started, so the tests control when cycles are run.
+    fn mkfs(disk: &Memory) -> Filesystem<Memory> {
+        Filesystem::mkfs(disk.clone(), MkfsOptions {
+            label: b"test".to_vec(),
+            gc: None,
+            compaction: None,
+            .. MkfsOptions::default()
+        }).wait().unwrap()
+    }
+
+    /// Check the image on a disk, without repairing it.
+    fn fsck(disk: &Memory) -> fsck::Report {
+        fsck::check(disk.clone(), fsck::Options::default()).unwrap()
+    }
+
+    /// Read a whole file.
+    fn read_file(fs: &Filesystem<Memory>, path: &[u8]) -> Vec<u8> {
+        let handle = fs.open_file(path).wait().unwrap();
+        let len = fs.stat(path).wait().unwrap().len as usize;
+        let buf = fs.read(handle, 0, len).wait().unwrap();
+        fs.close(handle).wait().unwrap();
+
+        buf
+    }
+
+    #[test]
+    fn file_types() {
+        let entry = |kind| fs::Entry {
+            kind: kind,
+            target: page::Pointer {
+                cluster: cluster::Pointer::new(1).unwrap(),
+                offset: None,
+                checksum: 0,
+            },
+        };
+
+        assert_eq!(file_type(entry(fs::EntryKind::File), None), FileType::File);
+        assert_eq!(file_type(entry(fs::EntryKind::Directory), None), FileType::Directory);
+    }
+
+    #[test]
+    fn mkfs_then_open() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.mkdir(b"/a").wait().unwrap();
+        fs.unmount().wait().unwrap();
+
+        let info = Filesystem::probe(&disk).wait().unwrap();
+        assert_eq!(info.checksum_algorithm, ChecksumAlgorithm::SeaHash);
+        assert!(!info.is_encrypted());
+
+        let fs = Filesystem::open(disk, OpenOptions::default()).wait().unwrap();
+        assert_eq!(fs.info().wait().unwrap().label, b"test");
+        assert_eq!(fs.readdir(b"/").wait().unwrap(), [DirEntry {
+            name: b"a".to_vec(),
+            file_type: FileType::Directory,
+        }]);
+    }
+
+    #[test]
+    fn unlink_while_open() {
+        let fs = mkfs(&Memory::new(1024));
+        let handle = fs.create(b"/a").wait().unwrap();
+        fs.write(handle, 0, b"hello").wait().unwrap();
+        fs.unlink(b"/a").wait().unwrap();
+
+        // The file is gone from the tree, but remains accessible through the handle.
+        assert_eq!(fs.stat(b"/a").wait().unwrap_err().kind, error::Kind::NotFound);
+        assert_eq!(fs.read(handle, 0, 5).wait().unwrap(), b"hello");
+        fs.write(handle, 5, b" world").wait().unwrap();
+        assert_eq!(fs.read(handle, 0, 11).wait().unwrap(), b"hello world");
+        fs.close(handle).wait().unwrap();
+        assert!(fs.readdir(b"/").wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rename_into_own_subtree() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.mkdir(b"/a").wait().unwrap();
+        fs.mkdir(b"/a/b").wait().unwrap();
+
+        assert_eq!(fs.rename(b"/a", b"/a/b/c").wait().unwrap_err().kind,
+                   error::Kind::InvalidInput);
+        assert_eq!(fs.rename(b"/a", b"/a/c").wait().unwrap_err().kind, error::Kind::InvalidInput);
+        // Nothing was moved.
+        assert_eq!(fs.readdir(b"/a").wait().unwrap(), [DirEntry {
+            name: b"b".to_vec(),
+            file_type: FileType::Directory,
+        }]);
+
+        // Moving a directory next to itself is fine.
+        fs.rename(b"/a/b", b"/b").wait().unwrap();
+        assert_eq!(fs.stat(b"/b").wait().unwrap().file_type, FileType::Directory);
+    }
+
+    #[test]
+    fn read_only() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.mkdir(b"/a").wait().unwrap();
+        fs.unmount().wait().unwrap();
+        let image = disk.dump();
+
+        let fs = Filesystem::open(disk.clone(), OpenOptions {
+            read_only: true,
+            .. OpenOptions::default()
+        }).wait().unwrap();
+        assert_eq!(fs.stat(b"/a").wait().unwrap().file_type, FileType::Directory);
+        assert_eq!(fs.mkdir(b"/b").wait().unwrap_err().kind, error::Kind::ReadOnly);
+        fs.unmount().wait().unwrap();
+
+        // Neither opening nor closing touched the disk.
+        assert!(disk.dump() == image);
+    }
+
+    #[test]
+    fn two_handles() {
+        let fs = mkfs(&Memory::new(1024));
+        let a = fs.create(b"/a").wait().unwrap();
+        let b = fs.open_file(b"/a").wait().unwrap();
+
+        // Neither write is lost, although each handle wrote to its own version.
+        fs.write(a, 0, b"hello").wait().unwrap();
+        fs.write(b, 5, b" world").wait().unwrap();
+        let c = fs.open_file(b"/a").wait().unwrap();
+        assert_eq!(fs.read(c, 0, 11).wait().unwrap(), b"hello world");
+        assert_eq!(fs.read(b, 0, 11).wait().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn write_after_replace() {
+        let fs = mkfs(&Memory::new(1024));
+        let old = fs.create(b"/a").wait().unwrap();
+        let new = fs.create(b"/b").wait().unwrap();
+        fs.write(new, 0, b"new").wait().unwrap();
+        fs.rename(b"/b", b"/a").wait().unwrap();
+
+        // The path refers to another file now, so only the old handle sees its write.
+        fs.write(old, 0, b"old").wait().unwrap();
+        assert_eq!(fs.read(old, 0, 3).wait().unwrap(), b"old");
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        assert_eq!(fs.read(handle, 0, 3).wait().unwrap(), b"new");
+    }
+
+    #[test]
+    fn create_write_read() {
+        let fs = mkfs(&Memory::new(1024));
+        let handle = fs.create(b"/a").wait().unwrap();
+        assert_eq!(fs.create(b"/a").wait().unwrap_err().kind, error::Kind::AlreadyExists);
+        fs.write(handle, 0, b"hello world").wait().unwrap();
+        fs.truncate(handle, 5).wait().unwrap();
+        fs.close(handle).wait().unwrap();
+
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        assert_eq!(fs.read(handle, 0, 100).wait().unwrap(), b"hello");
+        assert_eq!(fs.read(handle, 3, 100).wait().unwrap(), b"lo");
+
+        let stat = fs.stat(b"/a").wait().unwrap();
+        assert_eq!(stat.file_type, FileType::File);
+        assert_eq!(stat.len, 5);
+        let metadata = stat.metadata.unwrap();
+        assert_eq!(metadata.mode & 0o777, FILE_PERMISSIONS);
+        assert_eq!(metadata.nlink, 1);
+    }
+
+    #[test]
+    fn rename_and_remove() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.mkdir(b"/a").wait().unwrap();
+        let handle = fs.create(b"/a/b").wait().unwrap();
+        fs.write(handle, 0, b"hello").wait().unwrap();
+        fs.close(handle).wait().unwrap();
+
+        fs.rename(b"/a/b", b"/c").wait().unwrap();
+        assert!(fs.readdir(b"/a").wait().unwrap().is_empty());
+        let handle = fs.open_file(b"/c").wait().unwrap();
+        assert_eq!(fs.read(handle, 0, 5).wait().unwrap(), b"hello");
+        fs.close(handle).wait().unwrap();
+
+        assert_eq!(fs.unlink(b"/a").wait().unwrap_err().kind, error::Kind::IsDirectory);
+        assert_eq!(fs.rmdir(b"/c").wait().unwrap_err().kind, error::Kind::NotDirectory);
+        fs.unlink(b"/c").wait().unwrap();
+        fs.rmdir(b"/a").wait().unwrap();
+        assert!(fs.readdir(b"/").wait().unwrap().is_empty());
+        assert_eq!(fs.stat(b"/a").wait().unwrap_err().kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn links_and_permissions() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.mkdir(b"/a").wait().unwrap();
+        assert_eq!(fs.stat(b"/a").wait().unwrap().metadata.unwrap().mode & 0o777,
+                   DIRECTORY_PERMISSIONS);
+        let handle = fs.create(b"/b").wait().unwrap();
+
+        // Writes through one link are seen through the other.
+        fs.link(b"/b", b"/a/c").wait().unwrap();
+        fs.write(handle, 0, b"hello").wait().unwrap();
+        let linked = fs.open_file(b"/a/c").wait().unwrap();
+        assert_eq!(fs.read(linked, 0, 5).wait().unwrap(), b"hello");
+        assert_eq!(fs.stat(b"/b").wait().unwrap().metadata.unwrap().nlink, 2);
+
+        // So are metadata changes.
+        fs.chmod(b"/a/c", 0o600).wait().unwrap();
+        assert_eq!(fs.stat(b"/b").wait().unwrap().metadata.unwrap().mode & 0o777, 0o600);
+        fs.unlink(b"/b").wait().unwrap();
+        assert_eq!(fs.stat(b"/a/c").wait().unwrap().metadata.unwrap().nlink, 1);
+
+        fs.symlink(b"/d", b"/a/c").wait().unwrap();
+        assert_eq!(fs.stat(b"/d").wait().unwrap().file_type, FileType::Symlink);
+        assert_eq!(fs.read_link(b"/d").wait().unwrap(), b"/a/c");
+        assert_eq!(fs.read_link(b"/a/c").wait().unwrap_err().kind, error::Kind::InvalidInput);
+        assert_eq!(fs.link(b"/a", b"/e").wait().unwrap_err().kind,
+                   error::Kind::PermissionDenied);
+    }
+
+    #[test]
+    fn put() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.put(b"/a", b"hello world").wait().unwrap();
+        let generation = fs.info().wait().unwrap().generation;
+
+        // Replacing the content is a single commit.
+        fs.put(b"/a", b"bye").wait().unwrap();
+        assert_eq!(fs.info().wait().unwrap().generation, generation + 1);
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        assert_eq!(fs.read(handle, 0, 100).wait().unwrap(), b"bye");
+        assert!(fs.stat(b"/a").wait().unwrap().metadata.is_some());
+
+        fs.mkdir(b"/b").wait().unwrap();
+        assert_eq!(fs.put(b"/b", b"").wait().unwrap_err().kind, error::Kind::IsDirectory);
+    }
+
+    #[test]
+    fn encrypted_mkfs() {
+        let res = Filesystem::mkfs(Memory::new(1024), MkfsOptions {
+            vdev_stack: vec![Vdev::Speck],
+            .. MkfsOptions::default()
+        }).wait();
+        assert_eq!(res.err().unwrap().kind, error::Kind::Unsupported);
+    }
+
+    #[test]
+    fn encrypted_open() {
+        let disk = Memory::new(1024);
+        mkfs(&disk).unmount().wait().unwrap();
+        // Mark the image as encrypted.
+        let mut header = disk::header::DiskHeader::decode(&disk.read(0).wait().unwrap()).unwrap();
+        header.options.vdev_stack.push(Vdev::Speck);
+        disk.write(0, &header.encode()).wait().unwrap();
+
+        assert!(Filesystem::probe(&disk).wait().unwrap().is_encrypted());
+        let res = Filesystem::open(disk.clone(), OpenOptions::default()).wait();
+        assert_eq!(res.err().unwrap().kind, error::Kind::Unsupported);
+        let res = fsck::check(disk.clone(), fsck::Options::default());
+        assert_eq!(res.unwrap_err().kind, error::Kind::Unsupported);
+    }
+
+    #[test]
+    fn collect_after_unlink() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        let data: Vec<u8> = (0..8192).map(|x| (x / 7) as u8).collect();
+        fs.put(b"/a", &data).wait().unwrap();
+        fs.put(b"/b", b"hello").wait().unwrap();
+        fs.mkdir(b"/c").wait().unwrap();
+        fs.put(b"/c/d", &data[..5000]).wait().unwrap();
+        fs.unlink(b"/a").wait().unwrap();
+
+        // The removed file (and the replaced metadata) is leaked, until it is collected.
+        fs.sync().wait().unwrap();
+        assert!(!fsck(&disk).is_consistent());
+        fs.collect().wait().unwrap();
+        fs.collect().wait().unwrap();
+        fs.sync().wait().unwrap();
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+        assert_eq!(fs.collector.progress.report().cycles, 2);
+
+        // The live files survived.
+        assert_eq!(read_file(&fs, b"/b"), b"hello");
+        assert_eq!(read_file(&fs, b"/c/d"), &data[..5000]);
+        fs.unmount().wait().unwrap();
+
+        let fs = Filesystem::open(disk.clone(), OpenOptions::default()).wait().unwrap();
+        assert_eq!(read_file(&fs, b"/c/d"), &data[..5000]);
+        assert_eq!(fs.stat(b"/a").wait().unwrap_err().kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn collect_on_unmount() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.put(b"/a", &[1; 8192]).wait().unwrap();
+        fs.put(b"/b", b"hello").wait().unwrap();
+        fs.unlink(b"/a").wait().unwrap();
+        fs.unmount().wait().unwrap();
+
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn background_collector() {
+        let disk = Memory::new(1024);
+        let fs = Filesystem::mkfs(disk.clone(), MkfsOptions {
+            gc: Some(gc::Options {
+                interval: Duration::from_millis(1),
+            }),
+            compaction: None,
+            .. MkfsOptions::default()
+        }).wait().unwrap();
+        fs.put(b"/a", &[1; 8192]).wait().unwrap();
+        fs.put(b"/b", b"hello").wait().unwrap();
+        fs.unlink(b"/a").wait().unwrap();
+
+        // Wait for the collector to run a few cycles concurrently with the mutations.
+        while fs.collector.progress.report().cycles < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read_file(&fs, b"/b"), b"hello");
+
+        // Unmounting stops the collector, although its interval hasn't passed.
+        fs.unmount().wait().unwrap();
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+
+        // Nothing is collected by a read-only filesystem.
+        let fs = Filesystem::open(disk.clone(), OpenOptions {
+            read_only: true,
+            .. OpenOptions::default()
+        }).wait().unwrap();
+        assert!(fs.threads.lock().unwrap().is_empty());
+        assert_eq!(fs.collect().wait().unwrap_err().kind, error::Kind::ReadOnly);
+    }
+
+    #[test]
+    fn compact_after_unlink() {
+        let disk = Memory::new(4096);
+        let fs = mkfs(&disk);
+        // Every file has compressible content of its own.
+        let content = |n: usize| -> Vec<u8> {
+            (0..4 * disk::SECTOR_SIZE).map(|x| if x % 2 == 0 { n as u8 } else { (x / 64) as u8 })
+                .collect()
+        };
+        let path = |n: usize| format!("/{}", n).into_bytes();
+
+        // Fill the image, and then remove most of it, leaving the clusters sparse.
+        for n in 0..64 {
+            fs.put(&path(n), &content(n)).wait().unwrap();
+        }
+        for n in (0..64).filter(|n| n % 4 != 0) {
+            fs.unlink(&path(n)).wait().unwrap();
+        }
+        let handle = fs.open_file(&path(0)).wait().unwrap();
+        let generation = fs.info().wait().unwrap().generation;
+
+        fs.compact().wait().unwrap();
+        let report = fs.compactor.progress.report();
+        assert!(report.clusters_compacted > 0);
+        assert!(report.pages_moved > 0);
+        assert_eq!(report.clusters_freed, report.clusters_compacted);
+        // The moved pages were committed.
+        assert_eq!(fs.info().wait().unwrap().generation, generation + 1);
+
+        // Every surviving file is intact, through the tree and through the open handle.
+        for n in (0..64).filter(|n| n % 4 == 0) {
+            assert_eq!(read_file(&fs, &path(n)), content(n));
+        }
+        assert_eq!(fs.read(handle, 0, 4 * disk::SECTOR_SIZE).wait().unwrap(), content(0));
+        fs.close(handle).wait().unwrap();
+        fs.unmount().wait().unwrap();
+
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+        let fs = Filesystem::open(disk.clone(), OpenOptions::default()).wait().unwrap();
+        for n in (0..64).filter(|n| n % 4 == 0) {
+            assert_eq!(read_file(&fs, &path(n)), content(n));
+        }
+        assert_eq!(fs.stat(&path(1)).wait().unwrap_err().kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn clone_entry() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.mkdir(b"/a").wait().unwrap();
+        fs.put(b"/a/b", b"hello").wait().unwrap();
+        fs.clone_entry(b"/a", b"/c").wait().unwrap();
+        fs.clone_entry(b"/a/b", b"/d").wait().unwrap();
+        assert_eq!(fs.clone_entry(b"/a", b"/c").wait().unwrap_err().kind,
+                   error::Kind::AlreadyExists);
+        assert_eq!(fs.clone_entry(b"/e", b"/f").wait().unwrap_err().kind,
+                   error::Kind::NotFound);
+
+        // Mutating a clone leaves its source unchanged, and vice versa.
+        fs.put(b"/c/b", b"bye").wait().unwrap();
+        fs.put(b"/d", b"world").wait().unwrap();
+        fs.chmod(b"/d", 0o600).wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a/b"), b"hello");
+        fs.put(b"/a/b", b"hi").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/c/b"), b"bye");
+        assert_eq!(read_file(&fs, b"/d"), b"world");
+        assert_eq!(fs.stat(b"/a/b").wait().unwrap().metadata.unwrap().mode & 0o777,
+                   FILE_PERMISSIONS);
+
+        // Removing the source leaves the clones.
+        fs.unlink(b"/a/b").wait().unwrap();
+        fs.rmdir(b"/a").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/c/b"), b"bye");
+        fs.unlink(b"/c/b").wait().unwrap();
+        assert!(fs.readdir(b"/c").wait().unwrap().is_empty());
+        assert_eq!(read_file(&fs, b"/d"), b"world");
+    }
+
+    #[test]
+    fn clone_hard_links() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.mkdir(b"/a").wait().unwrap();
+        fs.put(b"/a/b", b"hello").wait().unwrap();
+        fs.link(b"/a/b", b"/a/c").wait().unwrap();
+        fs.link(b"/a/b", b"/d").wait().unwrap();
+
+        // The clone of a hard link is a file of its own.
+        fs.clone_entry(b"/d", b"/e").wait().unwrap();
+        assert_eq!(fs.stat(b"/e").wait().unwrap().metadata.unwrap().nlink, 1);
+        assert_eq!(fs.stat(b"/d").wait().unwrap().metadata.unwrap().nlink, 3);
+        fs.put(b"/e", b"bye").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/d"), b"hello");
+
+        // So are the hard links in a cloned directory.
+        fs.clone_entry(b"/a", b"/f").wait().unwrap();
+        fs.put(b"/f/b", b"world").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a/b"), b"hello");
+        assert_eq!(read_file(&fs, b"/a/c"), b"hello");
+        assert_eq!(read_file(&fs, b"/f/b"), b"world");
+        assert_eq!(read_file(&fs, b"/f/c"), b"hello");
+        assert_eq!(fs.stat(b"/f/b").wait().unwrap().metadata.unwrap().nlink, 1);
+
+        // Unlinking the clones doesn't touch the link counts of the source.
+        fs.unlink(b"/e").wait().unwrap();
+        fs.unlink(b"/f/b").wait().unwrap();
+        fs.unlink(b"/f/c").wait().unwrap();
+        assert_eq!(fs.stat(b"/a/b").wait().unwrap().metadata.unwrap().nlink, 3);
+        fs.unlink(b"/a/b").wait().unwrap();
+        fs.unlink(b"/d").wait().unwrap();
+        assert_eq!(fs.stat(b"/a/c").wait().unwrap().metadata.unwrap().nlink, 1);
+        assert_eq!(read_file(&fs, b"/a/c"), b"hello");
+        fs.unmount().wait().unwrap();
+
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn rollback() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.put(b"/a", b"hello").wait().unwrap();
+        fs.snapshot(b"s").wait().unwrap();
+        fs.put(b"/a", b"bye").wait().unwrap();
+        fs.mkdir(b"/b").wait().unwrap();
+
+        fs.rollback(b"s").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a"), b"hello");
+        assert_eq!(fs.stat(b"/b").wait().unwrap_err().kind, error::Kind::NotFound);
+        // The snapshot is kept, so it can be rolled back to again.
+        fs.put(b"/a", b"bye").wait().unwrap();
+        fs.rollback(b"s").wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a"), b"hello");
+        assert_eq!(fs.snapshots().wait().unwrap().len(), 1);
+
+        assert_eq!(fs.rollback(b"t").wait().unwrap_err().kind, error::Kind::NotFound);
+        assert_eq!(fs.delete_snapshot(b"t").wait().unwrap_err().kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn delete_snapshot() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        // Random data is incompressible, so every page takes a cluster of its own.
+        let data: Vec<u8> = (0..16 * disk::SECTOR_SIZE).map(|_| rand::random()).collect();
+        fs.put(b"/a", &data).wait().unwrap();
+        fs.snapshot(b"s").wait().unwrap();
+        fs.unlink(b"/a").wait().unwrap();
+
+        // The snapshot keeps the file alive.
+        fs.collect().wait().unwrap();
+        fs.collect().wait().unwrap();
+        fs.sync().wait().unwrap();
+        let before = fsck(&disk);
+        assert!(before.is_consistent(), "{:?}", before.problems);
+        assert!(fs.usage().wait().unwrap().snapshots >= data.len() as u64);
+
+        fs.delete_snapshot(b"s").wait().unwrap();
+        assert!(fs.snapshots().wait().unwrap().is_empty());
+        assert!(fs.usage().wait().unwrap().snapshots < data.len() as u64);
+        fs.collect().wait().unwrap();
+        fs.collect().wait().unwrap();
+        fs.sync().wait().unwrap();
+
+        // The pages of the file were freed.
+        let after = fsck(&disk);
+        assert!(after.is_consistent(), "{:?}", after.problems);
+        assert!(after.free_clusters >= before.free_clusters + 12);
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let fs = mkfs(&Memory::new(1024));
+        let handle = fs.create(b"/a").wait().unwrap();
+        fs.close(handle).wait().unwrap();
+
+        assert_eq!(fs.read(handle, 0, 1).wait().unwrap_err().kind, error::Kind::NotFound);
+        assert_eq!(fs.write(handle, 0, b"a").wait().unwrap_err().kind, error::Kind::NotFound);
+        assert_eq!(fs.close(handle).wait().unwrap_err().kind, error::Kind::NotFound);
+
+        // A handle, which is being closed concurrently, has lost its path already.
+        let (_, entry) = fs.lookup(b"/a").wait().unwrap();
+        let handle = Handle(fs.state.handles.open(entry));
+        assert_eq!(fs.write(handle, 0, b"a").wait().unwrap_err().kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn revisions_and_revert() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.put(b"/a", b"one").wait().unwrap();
+        fs.put(b"/a", b"two").wait().unwrap();
+        fs.put(b"/a", b"three").wait().unwrap();
+
+        // The truncations of `put()` are revisions too, so find the revisions by their content.
+        let revisions = fs.revisions(b"/a").wait().unwrap();
+        let content: Vec<_> = revisions.iter().map(|revision| {
+            fs.read_revision(b"/a", revision.generation, 0, 10).wait().unwrap()
+        }).collect();
+        let one = content.iter().position(|x| x == b"one").unwrap();
+        let two = content.iter().position(|x| x == b"two").unwrap();
+        assert!(revisions[one].generation < revisions[two].generation);
+        let first = revisions[one].generation;
+
+        fs.revert(b"/a", first).wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a"), b"one");
+        // The revert is a revision of its own, so it can be undone.
+        let reverted = fs.revisions(b"/a").wait().unwrap();
+        assert_eq!(reverted.len(), revisions.len() + 1);
+        fs.revert(b"/a", reverted.last().unwrap().generation).wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a"), b"three");
+
+        assert_eq!(fs.revert(b"/a", 1000).wait().unwrap_err().kind, error::Kind::NotFound);
+        assert_eq!(fs.read_revision(b"/a", 1000, 0, 1).wait().unwrap_err().kind,
+                   error::Kind::NotFound);
+        fs.mkdir(b"/d").wait().unwrap();
+        assert_eq!(fs.revisions(b"/d").wait().unwrap_err().kind, error::Kind::IsDirectory);
+        assert_eq!(fs.revert(b"/d", first).wait().unwrap_err().kind, error::Kind::IsDirectory);
+    }
+
+    #[test]
+    fn transaction() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.put(b"/a", b"hello").wait().unwrap();
+
+        let mut transaction = fs.begin().wait().unwrap();
+        assert!(transaction.exists(b"/a").wait().unwrap());
+        transaction.create_directory(b"/d");
+        transaction.create_file(b"/d/b");
+        transaction.write(b"/d/b", 0, b"world");
+        transaction.remove(b"/a");
+        // Nothing is visible before the commit.
+        assert!(fs.stat(b"/d").wait().is_err());
+        transaction.commit().wait().unwrap();
+        assert_eq!(read_file(&fs, b"/d/b"), b"world");
+        assert_eq!(fs.stat(b"/a").wait().unwrap_err().kind, error::Kind::NotFound);
+
+        // A concurrent change to a path read by the transaction makes it conflict.
+        let mut transaction = fs.begin().wait().unwrap();
+        assert!(transaction.exists(b"/d/b").wait().unwrap());
+        transaction.rename(b"/d/b", b"/c");
+        fs.put(b"/d/b", b"changed").wait().unwrap();
+        assert_eq!(transaction.commit().wait().unwrap_err().kind, error::Kind::Conflict);
+        assert_eq!(fs.stat(b"/c").wait().unwrap_err().kind, error::Kind::NotFound);
+
+        let mut transaction = fs.begin().wait().unwrap();
+        transaction.create_file(b"/e");
+        transaction.abort();
+        assert_eq!(fs.stat(b"/e").wait().unwrap_err().kind, error::Kind::NotFound);
+        fs.unmount().wait().unwrap();
+
+        let fs = Filesystem::open(disk, OpenOptions {
+            read_only: true,
+            .. OpenOptions::default()
+        }).wait().unwrap();
+        let mut transaction = fs.begin().wait().unwrap();
+        transaction.create_file(b"/e");
+        assert_eq!(transaction.commit().wait().unwrap_err().kind, error::Kind::ReadOnly);
+    }
+
+    #[test]
+    fn atime() {
+        let fs = mkfs(&Memory::new(1024));
+        fs.put(b"/a", b"hello").wait().unwrap();
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        let generation = || fs.info().wait().unwrap().generation;
+        let atime = || fs.stat(b"/a").wait().unwrap().metadata.unwrap().atime;
+
+        // The access time starts out at the creation time, so let the clock pass it.
+        thread::sleep(Duration::from_millis(1100));
+        let created = generation();
+        assert_eq!(fs.read(handle, 0, 5).wait().unwrap(), b"hello");
+        assert_eq!(generation(), created + 1);
+        let accessed = atime();
+
+        // The access time is newer than the modification time now, so the reads within the
+        // relatime interval write nothing.
+        for _ in 0..4 {
+            fs.read(handle, 0, 5).wait().unwrap();
+        }
+        assert_eq!(generation(), created + 1);
+        assert_eq!(atime(), accessed);
+
+        // The first read after a modification updates the access time again.
+        fs.write(handle, 0, b"j").wait().unwrap();
+        thread::sleep(Duration::from_millis(1100));
+        let written = generation();
+        assert_eq!(fs.read(handle, 0, 5).wait().unwrap(), b"jello");
+        assert_eq!(generation(), written + 1);
+        assert!(atime() > accessed);
+        fs.read(handle, 0, 5).wait().unwrap();
+        assert_eq!(generation(), written + 1);
+        // Writes through the handle still reach the file.
+        fs.write(handle, 0, b"h").wait().unwrap();
+        fs.close(handle).wait().unwrap();
+        assert_eq!(read_file(&fs, b"/a"), b"hello");
+    }
+
+    #[test]
+    fn atime_never() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        fs.put(b"/a", b"hello").wait().unwrap();
+        fs.unmount().wait().unwrap();
+
+        let fs = Filesystem::open(disk, OpenOptions {
+            atime: fs::Atime::Never,
+            gc: None,
+            compaction: None,
+            .. OpenOptions::default()
+        }).wait().unwrap();
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        let generation = fs.info().wait().unwrap().generation;
+        thread::sleep(Duration::from_millis(1100));
+        fs.read(handle, 0, 5).wait().unwrap();
+        assert_eq!(fs.info().wait().unwrap().generation, generation);
+    }
+
+    #[test]
+    fn watch() {
+        let fs = mkfs(&Memory::new(4096));
+        fs.mkdir(b"/d").wait().unwrap();
+        let events = fs.watch(b"/d", None);
+        fs.put(b"/d/a", b"hello").wait().unwrap();
+        fs.rename(b"/d/a", b"/d/b").wait().unwrap();
+        // Changes outside the watched directory aren't reported.
+        fs.put(b"/x", b"elsewhere").wait().unwrap();
+        let handle = fs.open_file(b"/d/b").wait().unwrap();
+        fs.write(handle, 1, b"ipp").wait().unwrap();
+        fs.close(handle).wait().unwrap();
+        fs.unlink(b"/d/b").wait().unwrap();
+
+        assert_eq!(events.take(4).collect().wait().unwrap(), [
+            fs::WatchEvent::Created { path: b"/d/a".to_vec() },
+            fs::WatchEvent::Renamed {
+                from: b"/d/a".to_vec(),
+                to: b"/d/b".to_vec(),
+            },
+            fs::WatchEvent::Modified {
+                path: b"/d/b".to_vec(),
+                range: 1..4,
+            },
+            fs::WatchEvent::Removed { path: b"/d/b".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn watch_from_snapshot() {
+        let fs = mkfs(&Memory::new(4096));
+        fs.mkdir(b"/d").wait().unwrap();
+        fs.snapshot(b"s").wait().unwrap();
+        let snapshot = fs.snapshots().wait().unwrap()[0].generation;
+        fs.put(b"/d/a", b"hello").wait().unwrap();
+        let missed = fs.info().wait().unwrap().generation;
+        // Push the snapshotted generation out of the retained history.
+        for n in 0..100 {
+            fs.put(b"/x", &[n]).wait().unwrap();
+        }
+
+        // The snapshot still serves as a resumption point, but other old generations don't.
+        let events = fs.watch(b"/d", Some(snapshot)).take(1).collect().wait().unwrap();
+        assert_eq!(events, [fs::WatchEvent::Created { path: b"/d/a".to_vec() }]);
+        let err = fs.watch(b"/d", Some(missed)).take(1).collect().wait().unwrap_err();
+        assert_eq!(err.kind, error::Kind::NotFound);
+    }
+
+    #[test]
+    fn xattrs() {
+        let fs = mkfs(&Memory::new(1024));
+        let large: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        fs.put(b"/a", b"hello").wait().unwrap();
+        fs.mkdir(b"/d").wait().unwrap();
+
+        for path in &[b"/a", b"/d"] {
+            assert!(fs.list_xattrs(*path).wait().unwrap().is_empty());
+            fs.set_xattr(*path, b"user.small", b"blue").wait().unwrap();
+            fs.set_xattr(*path, b"user.large", &large).wait().unwrap();
+            assert_eq!(fs.list_xattrs(*path).wait().unwrap(),
+                       [b"user.large".to_vec(), b"user.small".to_vec()]);
+            assert_eq!(fs.get_xattr(*path, b"user.large").wait().unwrap(), Some(large.clone()));
+
+            fs.remove_xattr(*path, b"user.small").wait().unwrap();
+            assert_eq!(fs.get_xattr(*path, b"user.small").wait().unwrap(), None);
+            assert_eq!(fs.remove_xattr(*path, b"user.small").wait().unwrap_err().kind,
+                       error::Kind::NotFound);
+        }
+        // The content is untouched.
+        assert_eq!(read_file(&fs, b"/a"), b"hello");
+        assert_eq!(fs.get_xattr(b"/b", b"user.small").wait().unwrap_err().kind,
+                   error::Kind::NotFound);
+    }
+
+    #[test]
+    fn xattrs_in_history() {
+        let disk = Memory::new(1024);
+        let fs = mkfs(&disk);
+        let large: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        fs.put(b"/a", b"hello").wait().unwrap();
+        fs.mkdir(b"/d").wait().unwrap();
+        fs.set_xattr(b"/a", b"user.large", &large).wait().unwrap();
+        fs.set_xattr(b"/d", b"user.large", &large).wait().unwrap();
+        fs.snapshot(b"s").wait().unwrap();
+
+        // The attributes of a file are part of its revisions.
+        fs.set_xattr(b"/a", b"user.large", b"small").wait().unwrap();
+        let revision = fs.revisions(b"/a").wait().unwrap().last().unwrap().generation;
+        fs.remove_xattr(b"/a", b"user.large").wait().unwrap();
+        fs.revert(b"/a", revision).wait().unwrap();
+        assert_eq!(fs.get_xattr(b"/a", b"user.large").wait().unwrap(), Some(b"small".to_vec()));
+
+        // The snapshot keeps the pages of the large values alive, when the current tree no
+        // longer refers to them.
+        fs.remove_xattr(b"/d", b"user.large").wait().unwrap();
+        fs.put(b"/a", b"bye").wait().unwrap();
+        fs.collect().wait().unwrap();
+        fs.collect().wait().unwrap();
+        fs.sync().wait().unwrap();
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+
+        fs.rollback(b"s").wait().unwrap();
+        for path in &[b"/a", b"/d"] {
+            assert_eq!(fs.get_xattr(*path, b"user.large").wait().unwrap(), Some(large.clone()));
+        }
+        assert_eq!(read_file(&fs, b"/a"), b"hello");
+    }
+
+    #[test]
+    fn sparse_files() {
+        let disk = Memory::new(1024);
+        mkfs(&disk).unmount().wait().unwrap();
+        // Keep no revisions, so the punched pages are no longer used at all.
+        let fs = Filesystem::open(disk.clone(), OpenOptions {
+            retention: fs::Retention::Last(0),
+            gc: None,
+            compaction: None,
+            .. OpenOptions::default()
+        }).wait().unwrap();
+        let page = disk::SECTOR_SIZE as u64;
+        let content: Vec<u8> = (0..8 * page).map(|_| rand::random()).collect();
+        fs.put(b"/a", &content).wait().unwrap();
+        let handle = fs.open_file(b"/a").wait().unwrap();
+        let before = fs.usage().wait().unwrap();
+
+        fs.punch_hole(handle, page, 4 * page).wait().unwrap();
+        let after = fs.usage().wait().unwrap();
+        assert!(after.live + 4 * page <= before.live, "{:?} {:?}", before, after);
+        assert_eq!(fs.stat(b"/a").wait().unwrap().len, 8 * page);
+        let buf = fs.read(handle, 0, content.len()).wait().unwrap();
+        assert_eq!(&buf[..page as usize], &content[..page as usize]);
+        assert!(buf[page as usize..5 * page as usize].iter().all(|&x| x == 0));
+        assert_eq!(&buf[5 * page as usize..], &content[5 * page as usize..]);
+        assert_eq!(fs.seek_hole(handle, 0).wait().unwrap(), Some(page));
+        assert_eq!(fs.seek_data(handle, page).wait().unwrap(), Some(5 * page));
+
+        // All-zero writes become holes, if they're sparse.
+        let zeros = vec![0; page as usize];
+        fs.write_sparse(handle, 5 * page, &zeros).wait().unwrap();
+        fs.write(handle, 6 * page, &zeros).wait().unwrap();
+        assert_eq!(fs.seek_hole(handle, 5 * page).wait().unwrap(), Some(5 * page));
+        assert_eq!(fs.seek_data(handle, 5 * page).wait().unwrap(), Some(6 * page));
+        assert_eq!(fs.seek_hole(handle, 7 * page).wait().unwrap(), Some(8 * page));
+        assert_eq!(fs.seek_data(handle, 8 * page).wait().unwrap(), None);
+        assert!(fs.usage().wait().unwrap().live < after.live);
+        fs.close(handle).wait().unwrap();
+
+        fs.unmount().wait().unwrap();
+        let report = fsck(&disk);
+        assert!(report.is_consistent(), "{:?}", report.problems);
+    }
+}
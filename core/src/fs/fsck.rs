@@ -0,0 +1,449 @@
+//! Offline consistency checking.
+//!
+//! The checker verifies an image, which isn't in use, layer by layer:
+//!
+//! 1. The disk header is decoded and checked against its checksum, and the system must not be
+//!    marked inconsistent. The format has no backup headers, so there is nothing to fall back to.
+//! 2. The state block is checked against its checksum.
+//! 3. The metaclusters of the freelist are checked against their checksums.
+//! 4. Every page reachable from the superpage is read and checked against the checksum of its
+//!    page pointer.
+//! 5. The clusters are cross-checked: A cluster must not be both free and referenced, and every
+//!    cluster must be either free or referenced (otherwise it is leaked).
+//!
+//! If a layer is broken, the layers above it cannot be checked. In repair mode, the freelist is
+//! rebuilt from the reachable clusters, which fixes the problems of the freelist and the cluster
+//! cross-check.
+//!
+//! With a mirror vdev, only the lower half of the mirror is checked. Reads are served from the
+//! lower half alone, so the mirrored copies cannot affect the filesystem, until they are used to
+//! recover from errors of the lower half.
+
+use futures::Future;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use {alloc, fs, metrics, Error};
+use fs::Object;
+use alloc::compress;
+use disk::{self, cluster, Disk};
+
+/// The class of a problem.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Class {
+    /// The disk header is invalid.
+    DiskHeader,
+    /// The state block is invalid.
+    StateBlock,
+    /// A metacluster of the freelist is corrupt.
+    Freelist,
+    /// A reachable page is corrupt or unreadable.
+    Page,
+    /// A cluster is both free and referenced.
+    FreeAndReferenced,
+    /// A cluster is neither free nor referenced.
+    Leaked,
+}
+
+/// A problem found by the checker.
+#[derive(Debug)]
+pub struct Problem {
+    /// The class of the problem.
+    pub class: Class,
+    /// The description of the problem.
+    pub error: Error,
+    /// Was the problem repaired?
+    pub repaired: bool,
+}
+
+/// The options of the checker.
+#[derive(Default)]
+pub struct Options {
+    /// The compression algorithms to make available.
+    pub codecs: compress::Registry,
+    /// Rebuild the freelist from the reachable clusters.
+    pub repair: bool,
+}
+
+/// The result of a check.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The problems found.
+    pub problems: Vec<Problem>,
+    /// The number of clusters of the disk.
+    pub clusters: u64,
+    /// The number of free clusters.
+    pub free_clusters: u64,
+    /// The number of reachable pages.
+    pub reachable_pages: u64,
+}
+
+impl Report {
+    /// Check if no problems remain, i.e. every problem found was repaired.
+    pub fn is_consistent(&self) -> bool {
+        self.problems.iter().all(|problem| problem.repaired)
+    }
+
+    /// Add a problem.
+    ///
+    /// I/O errors are not problems of the image, so they are returned instead.
+    fn add(&mut self, class: Class, error: Error) -> Result<(), Error> {
+        if error.kind == ::error::Kind::Io {
+            return Err(error);
+        }
+
+        self.problems.push(Problem {
+            class: class,
+            error: error,
+            repaired: false,
+        });
+
+        Ok(())
+    }
+}
+
+/// Check the image on a disk.
+///
+/// The disk must not be in use. Problems of the image are reported in the returned report, while
+/// the errors of the disk itself (I/O errors) are returned. So are the errors, which prevent
+/// checking the image at all: An option (e.g. encryption or a compression algorithm), which isn't
+/// supported.
+///
+/// Nothing is written to the disk, unless `options.repair` is set. In particular, the disk header
+/// is left as it is, so the state flag still tells if the system was shut down cleanly.
+pub fn check<D: Disk>(disk: D, options: Options) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    // Check the disk header. It is the first sector of the raw disk.
+    let header = match disk.read(0).wait()
+        .and_then(|buf| disk::header::DiskHeader::decode(&buf)) {
+        Ok(header) => header,
+        Err(err) => {
+            report.add(Class::DiskHeader, err.with_sector(0))?;
+            return Ok(report);
+        },
+    };
+
+    // An unclean shutdown leaves the state flag open, which is fine, as commits are atomic. An
+    // inconsistent system is reported, but the rest of it can still be checked.
+    if header.state_flag == disk::header::StateFlag::Inconsistent {
+        report.add(Class::DiskHeader, err!(Corruption, "the file system is marked inconsistent")
+                   .with_sector(0))?;
+    }
+    let checksum_algorithm = header.options.checksum_algorithm;
+
+    // Set up the disk without touching the header.
+    let cache = disk::load(disk, header, !options.repair, metrics::Metrics::default())?;
+
+    // Check the state block.
+    let state_block = match cache.read(0).wait()
+        .and_then(|buf| alloc::state_block::StateBlock::decode(&buf, checksum_algorithm)) {
+        Ok(state_block) => state_block,
+        Err(err) => {
+            report.add(Class::StateBlock, err.with_cluster(0))?;
+            return Ok(report);
+        },
+    };
+
+    let mut fs = fs::State::new(alloc::Allocator::load(cache, state_block, &options.codecs)?);
+    report.clusters = fs.alloc.number_of_clusters();
+
+    // Read the freelist. If a metacluster is corrupt, the rest of the freelist is unknown.
+    let mut freelist = alloc::Freelist::default();
+    let freelist_complete = match fs.alloc.walk_freelist(&mut freelist) {
+        Ok(()) => true,
+        Err(err) => {
+            report.add(Class::Freelist, err)?;
+            false
+        },
+    };
+    report.free_clusters = freelist.free.len() as u64;
+
+    // Find the reachable pages. If the object graph is corrupt, the reachable clusters are
+    // unknown.
+    let pages = match reachable_pages(&fs) {
+        Ok(pages) => Some(pages),
+        Err(err) => {
+            report.add(Class::Page, err)?;
+            None
+        },
+    };
+
+    if let Some(pages) = pages {
+        report.reachable_pages = pages.len() as u64;
+
+        // Check every page against its checksum.
+        for &page in &pages {
+            if let Err(err) = fs.alloc.read(page).wait() {
+                report.add(Class::Page, err.with_cluster(page.cluster.into()))?;
+            }
+        }
+
+        let referenced: HashSet<cluster::Pointer> = pages.iter().map(|page| page.cluster)
+            .collect();
+        let free: HashSet<cluster::Pointer> = freelist.free.iter()
+            .chain(&freelist.metaclusters).cloned().collect();
+
+        // Find the clusters which are both free and referenced.
+        let mut doubly_used: Vec<_> = referenced.intersection(&free).cloned().collect();
+        doubly_used.sort_by_key(|&cluster| u64::from(cluster));
+        for cluster in doubly_used {
+            let cluster = u64::from(cluster);
+            report.add(Class::FreeAndReferenced, err!(Corruption, "cluster {:x} is both free and \
+                                                      referenced", cluster)
+                       .with_cluster(cluster))?;
+        }
+
+        // Find the leaked clusters. If the freelist is incomplete, every cluster after the
+        // corrupt metacluster would appear leaked, so this is skipped.
+        if freelist_complete {
+            // Cluster 0 is the state block.
+            for cluster in (1..report.clusters).filter_map(cluster::Pointer::new) {
+                if !referenced.contains(&cluster) && !free.contains(&cluster) {
+                    let cluster = u64::from(cluster);
+                    report.add(Class::Leaked, err!(Corruption, "cluster {:x} is leaked", cluster)
+                               .with_cluster(cluster))?;
+                }
+            }
+        }
+
+        if options.repair {
+            // Every cluster, which isn't referenced, is free.
+            fs.alloc.rebuild_freelist((1..report.clusters).filter_map(cluster::Pointer::new)
+                .filter(|cluster| !referenced.contains(cluster))
+                .collect())?;
+
+            // Read the new freelist back, which also verifies it.
+            let mut freelist = alloc::Freelist::default();
+            fs.alloc.walk_freelist(&mut freelist)?;
+            report.free_clusters = freelist.free.len() as u64;
+
+            for problem in &mut report.problems {
+                match problem.class {
+                    Class::Freelist | Class::FreeAndReferenced | Class::Leaked => {
+                        problem.repaired = true;
+                    },
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+impl fs::Visitor for Mutex<HashSet<alloc::page::Pointer>> {
+    fn page(&self, ptr: alloc::page::Pointer) {
+        self.lock().unwrap().insert(ptr);
+    }
+}
+
+/// Find the pages reachable from the current superpage.
+///
+/// This traverses the whole object graph: The superpage, the snapshot table and the snapshots,
+/// the link table and the inodes, the directories, and the files along with their revisions and
+/// extended attributes. Only the committed graph is traversed, as the image isn't in use.
+fn reachable_pages<D: Disk>(fs: &fs::State<D>) -> Result<HashSet<alloc::page::Pointer>, Error> {
+    let pages = Mutex::new(HashSet::new());
+
+    // If nothing was committed yet, nothing is reachable.
+    if let Some(superpage) = fs::Superpage::load(fs).wait()? {
+        superpage.visit(fs, &pages).wait()?;
+    }
+
+    Ok(pages.into_inner().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::memory::Memory;
+
+    /// Create an image with a file, which only a snapshot refers to.
+    ///
+    /// The disk and the pages of the file are returned.
+    fn image() -> (Memory, HashSet<alloc::page::Pointer>) {
+        let disk = Memory::new(1024);
+        let fs = fs::memory_on(disk.clone());
+
+        let file = fs::File::create(&fs).wait().unwrap().write(&fs, 0, &[1; 2048]).wait()
+            .unwrap();
+        let superpage = fs::Superpage::load(&fs).wait().unwrap().unwrap();
+        let root = superpage.root.insert(&fs, b"file", fs::Entry {
+            kind: fs::EntryKind::File,
+            target: file.root(),
+        }).wait().unwrap();
+        let superpage = fs::Superpage { root: root, .. superpage }.commit(&fs).wait().unwrap()
+            .create_snapshot(&fs, b"snapshot").wait().unwrap().commit(&fs).wait().unwrap();
+        let (root, _) = superpage.root.remove(&fs, b"file").wait().unwrap();
+        fs::Superpage { root: root, .. superpage }.commit(&fs).wait().unwrap();
+
+        (disk, fs.account(&file).unwrap().pages)
+    }
+
+    /// Open the image on a disk.
+    fn open(disk: &Memory) -> fs::State<Memory> {
+        fs::State::open(disk.clone(), false, &compress::Registry::default(),
+                        metrics::Metrics::default()).wait().unwrap()
+    }
+
+    /// Check the image on a disk.
+    fn run(disk: &Memory, repair: bool) -> Report {
+        check(disk.clone(), Options {
+            repair: repair,
+            .. Options::default()
+        }).unwrap()
+    }
+
+    /// Get the classes of the problems of a report.
+    fn classes(report: &Report) -> Vec<Class> {
+        report.problems.iter().map(|problem| problem.class).collect()
+    }
+
+    /// Read the on-disk freelist of the image on a disk.
+    fn freelist(disk: &Memory) -> alloc::Freelist {
+        let mut freelist = alloc::Freelist::default();
+        open(disk).alloc.walk_freelist(&mut freelist).unwrap();
+        freelist
+    }
+
+    /// Replace the on-disk freelist of the image on a disk.
+    fn rebuild(disk: &Memory, free: Vec<cluster::Pointer>) {
+        open(disk).alloc.rebuild_freelist(free).unwrap();
+    }
+
+    /// Get the free clusters of a freelist, including the metaclusters.
+    fn free(freelist: &alloc::Freelist) -> HashSet<cluster::Pointer> {
+        freelist.free.iter().chain(&freelist.metaclusters).cloned().collect()
+    }
+
+    #[test]
+    fn freelist_round_trip() {
+        let (disk, _) = image();
+        let clusters: Vec<_> = free(&freelist(&disk)).into_iter().collect();
+        rebuild(&disk, clusters.clone());
+
+        // Every cluster is either a metacluster or free, exactly once.
+        let freelist = freelist(&disk);
+        assert!(freelist.metaclusters.len() > 1);
+        assert_eq!(freelist.metaclusters.len() + freelist.free.len(), clusters.len());
+        assert_eq!(free(&freelist), clusters.into_iter().collect());
+    }
+
+    #[test]
+    fn check_only() {
+        let (disk, _) = image();
+        let before = disk.dump();
+
+        run(&disk, false);
+        assert!(disk.dump() == before);
+    }
+
+    #[test]
+    fn reachable_through_snapshot() {
+        let (disk, pages) = image();
+        let reachable = reachable_pages(&open(&disk)).unwrap();
+
+        assert!(!pages.is_empty());
+        assert!(pages.is_subset(&reachable));
+
+        // Removing the file didn't free its clusters.
+        let report = run(&disk, false);
+        assert!(!classes(&report).contains(&Class::Page));
+        assert!(!classes(&report).contains(&Class::FreeAndReferenced));
+    }
+
+    #[test]
+    fn leaked() {
+        let (disk, _) = image();
+        // The old versions of the tree are garbage, which is only freed by repairing.
+        assert!(run(&disk, true).is_consistent());
+        assert!(run(&disk, false).problems.is_empty());
+
+        // Leak a free cluster.
+        let mut clusters = free(&freelist(&disk));
+        let leaked = *clusters.iter().next().unwrap();
+        clusters.remove(&leaked);
+        rebuild(&disk, clusters.into_iter().collect());
+
+        let report = run(&disk, false);
+        assert_eq!(classes(&report), [Class::Leaked]);
+        assert_eq!(report.problems[0].error.context().cluster, Some(u64::from(leaked)));
+
+        let report = run(&disk, true);
+        assert!(report.is_consistent());
+        assert!(report.problems[0].repaired);
+        assert!(run(&disk, false).problems.is_empty());
+    }
+
+    #[test]
+    fn free_and_referenced() {
+        let (disk, pages) = image();
+        assert!(run(&disk, true).is_consistent());
+
+        // Free a cluster of the file, which is still referenced by the snapshot. The other
+        // cluster becomes the only metacluster, so the referenced cluster isn't overwritten, but
+        // every other cluster is leaked.
+        let referenced = pages.iter().next().unwrap().cluster;
+        let spare = *free(&freelist(&disk)).iter().next().unwrap();
+        rebuild(&disk, vec![referenced, spare]);
+
+        let report = run(&disk, false);
+        let doubly_used: Vec<_> = report.problems.iter()
+            .filter(|problem| problem.class == Class::FreeAndReferenced)
+            .map(|problem| problem.error.context().cluster)
+            .collect();
+        assert_eq!(doubly_used, [Some(u64::from(referenced))]);
+        assert!(!classes(&report).contains(&Class::Page));
+
+        assert!(run(&disk, true).is_consistent());
+        assert!(run(&disk, false).problems.is_empty());
+    }
+
+    #[test]
+    fn corrupt_metacluster() {
+        let (disk, _) = image();
+        assert!(run(&disk, true).is_consistent());
+
+        // Overwrite the head metacluster. Cluster 0 is sector 1 of the raw disk, as the disk
+        // header precedes it.
+        let head = freelist(&disk).metaclusters[0];
+        disk.write(u64::from(head) as usize + 1, &[0xFF; disk::SECTOR_SIZE]).wait().unwrap();
+
+        let report = run(&disk, false);
+        assert_eq!(classes(&report), [Class::Freelist]);
+        assert_eq!(report.problems[0].error.context().cluster, Some(u64::from(head)));
+
+        // The freelist is rebuilt from scratch, and the corrupt metacluster becomes free.
+        assert!(run(&disk, true).is_consistent());
+        assert!(run(&disk, false).problems.is_empty());
+    }
+
+    #[test]
+    fn inconsistent() {
+        let (disk, _) = image();
+        let mut header = disk::header::DiskHeader::decode(&disk.read(0).wait().unwrap()).unwrap();
+        header.state_flag = disk::header::StateFlag::Inconsistent;
+        disk.write(0, &header.encode()).wait().unwrap();
+
+        // The flag is reported as a problem of the disk header, and the rest is still checked.
+        let report = run(&disk, false);
+        assert_eq!(report.problems[0].class, Class::DiskHeader);
+        assert!(report.reachable_pages > 0);
+    }
+
+    #[test]
+    fn consistency() {
+        let mut report = Report::default();
+        assert!(report.is_consistent());
+
+        report.add(Class::Leaked, err!(Corruption, "cluster 2 is leaked")).unwrap();
+        assert!(!report.is_consistent());
+        report.problems[0].repaired = true;
+        assert!(report.is_consistent());
+
+        // I/O errors are not problems of the image.
+        assert!(report.add(Class::Page, err!(Io, "failed")).is_err());
+        assert_eq!(report.problems.len(), 1);
+    }
+}
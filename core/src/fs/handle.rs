@@ -0,0 +1,127 @@
+//! Open handles.
+//!
+//! An object, which is open, must remain accessible until it is closed, even if it is unlinked in
+//! the meantime (like an open file descriptor on POSIX systems). Once unlinked, nothing on disk
+//! refers to the object, so the open handles are kept in memory, and visited by the garbage
+//! collector as additional roots.
+//!
+//! A handle refers to a version of an object. Writes through the handle must update it with the
+//! new version, so the version, which is visited, is the latest.
+
+use futures::future;
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use {fs, Error};
+use alloc::compact;
+
+/// The open handles.
+#[derive(Default)]
+pub struct Handles {
+    /// The open objects by their handle number.
+    open: Mutex<HashMap<u64, fs::Entry>>,
+    /// The number of the next handle.
+    next: Mutex<u64>,
+}
+
+impl Handles {
+    /// Open a handle to an object.
+    ///
+    /// Hard links must be followed first (see `Superpage::follow_link()`), as the link table entry
+    /// is removed when the last link is.
+    pub fn open(&self, entry: fs::Entry) -> u64 {
+        let handle = {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        self.open.lock().unwrap().insert(handle, entry);
+
+        handle
+    }
+
+    /// Get the object of a handle.
+    pub fn get(&self, handle: u64) -> Option<fs::Entry> {
+        self.open.lock().unwrap().get(&handle).cloned()
+    }
+
+    /// Update the object of a handle to a new version.
+    pub fn set(&self, handle: u64, entry: fs::Entry) -> Result<(), Error> {
+        match self.open.lock().unwrap().get_mut(&handle) {
+            Some(open) => {
+                *open = entry;
+                Ok(())
+            },
+            None => Err(err!(NotFound, "invalid handle {}", handle)),
+        }
+    }
+
+    /// Close a handle.
+    ///
+    /// If the object was unlinked, its pages are freed by the next garbage collection cycle.
+    pub fn close(&self, handle: u64) -> Result<fs::Entry, Error> {
+        self.open.lock().unwrap().remove(&handle)
+            .ok_or_else(|| err!(NotFound, "invalid handle {}", handle))
+    }
+
+    /// Visit the open objects.
+    ///
+    /// See `fs::Object::visit()`.
+    pub fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        let open: Vec<_> = self.open.lock().unwrap().values().cloned().collect();
+
+        future::join_all(open.into_iter().map(|entry| {
+            fs::directory::visit_child(fs, entry, visitor)
+        }).collect::<Vec<_>>()).map(|_| ())
+    }
+
+    /// Update the open objects after pages were moved by compaction.
+    ///
+    /// This blocks.
+    pub fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations)
+        -> Result<(), Error> {
+        let mut open = self.open.lock().unwrap();
+        for entry in open.values_mut() {
+            *entry = fs::directory::relocate_child(fs, *entry, relocations).wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::page;
+    use disk::cluster;
+
+    fn entry(cluster: u64) -> fs::Entry {
+        fs::Entry {
+            kind: fs::EntryKind::File,
+            target: page::Pointer {
+                cluster: cluster::Pointer::new(cluster).unwrap(),
+                offset: None,
+                checksum: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn open_close() {
+        let handles = Handles::default();
+        let a = handles.open(entry(1));
+        let b = handles.open(entry(2));
+        assert!(a != b);
+
+        // A write through the handle.
+        handles.set(a, entry(3)).unwrap();
+        assert_eq!(handles.get(a), Some(entry(3)));
+        assert_eq!(handles.get(b), Some(entry(2)));
+
+        assert_eq!(handles.close(a).unwrap(), entry(3));
+        assert!(handles.get(a).is_none());
+        assert!(handles.close(a).is_err());
+        assert!(handles.set(a, entry(4)).is_err());
+    }
+}
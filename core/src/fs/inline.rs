@@ -0,0 +1,168 @@
+//! Inline storage.
+//!
+//! Small content (e.g. tiny files or a few extended attributes) would waste most of a page of its
+//! own, so it is stored directly in the unused tail of the metadata page referring to it (a file
+//! header or an inode), and promoted to pages of its own, when it outgrows the tail.
+//!
+//! The inline area starts at byte `OFFSET` of the metadata page, right after a flag byte, which
+//! tells if it is in use. File headers share the area between the content and the extended
+//! attributes (see `fs::file`).
+//!
+//! Nothing is stored inline in directory entries: An entry is a fixed-size pointer to the metadata
+//! page, so the content stored inline there costs no page beyond the one the entry needs anyway.
+
+use std::{cmp, fmt};
+
+use {disk, Error};
+
+/// The offset of the flag byte in the metadata page.
+pub const FLAG_OFFSET: usize = 80;
+/// The offset of the inline area in the metadata page.
+pub const OFFSET: usize = FLAG_OFFSET + 1;
+/// The maximal number of bytes stored inline.
+pub const MAX_LEN: usize = disk::SECTOR_SIZE - OFFSET;
+
+/// Bytes stored inline.
+#[derive(Copy)]
+pub struct Inline {
+    /// The number of bytes.
+    len: usize,
+    /// The bytes, zero-padded.
+    buf: [u8; MAX_LEN],
+}
+
+impl Inline {
+    /// Store some bytes inline.
+    ///
+    /// If they don't fit, `None` is returned.
+    pub fn new(bytes: &[u8]) -> Option<Inline> {
+        if bytes.len() > MAX_LEN {
+            return None;
+        }
+
+        let mut buf = [0; MAX_LEN];
+        buf[..bytes.len()].copy_from_slice(bytes);
+
+        Some(Inline {
+            len: bytes.len(),
+            buf: buf,
+        })
+    }
+
+    /// Get the bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Read the inline area of a metadata page.
+    ///
+    /// `len` is the number of bytes stored. If the flag isn't set, `None` is returned.
+    pub fn decode(page: &disk::SectorBuf, len: u64) -> Result<Option<Inline>, Error> {
+        match page[FLAG_OFFSET] {
+            0 => Ok(None),
+            1 if len <= MAX_LEN as u64 => Ok(Inline::new(&page[OFFSET..][..len as usize])),
+            1 => Err(err!(Corruption, "inline content of {} bytes exceeds the page", len)),
+            x => Err(err!(Corruption, "invalid inline flag {:x}", x)),
+        }
+    }
+
+    /// Write the inline area of a metadata page.
+    pub fn encode(inline: Option<&Inline>, page: &mut disk::SectorBuf) {
+        if let Some(inline) = inline {
+            page[FLAG_OFFSET] = 1;
+            page[OFFSET..][..inline.len].copy_from_slice(inline.as_slice());
+        }
+    }
+
+    /// Replace a byte range.
+    ///
+    /// The content is extended by zeros as needed. If the result doesn't fit, `None` is returned.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Option<Inline> {
+        let end = offset.checked_add(bytes.len())?;
+        if end > MAX_LEN {
+            return None;
+        }
+
+        // The bytes after the content are zero, so they fill any gap.
+        let mut new = *self;
+        new.buf[offset..end].copy_from_slice(bytes);
+        new.len = cmp::max(self.len, end);
+
+        Some(new)
+    }
+
+    /// Resize the content.
+    ///
+    /// The content is cut or extended by zeros. If the result doesn't fit, `None` is returned.
+    pub fn truncate(&self, len: usize) -> Option<Inline> {
+        if len > MAX_LEN {
+            return None;
+        }
+
+        let mut new = *self;
+        for byte in &mut new.buf[cmp::min(len, self.len)..] {
+            *byte = 0;
+        }
+        new.len = len;
+
+        Some(new)
+    }
+}
+
+impl Clone for Inline {
+    fn clone(&self) -> Inline {
+        *self
+    }
+}
+
+impl PartialEq for Inline {
+    fn eq(&self, other: &Inline) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Inline {}
+
+impl fmt::Debug for Inline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Inline({:?})", self.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_identity() {
+        let inline = Inline::new(b"hello").unwrap();
+        let mut page = [0; disk::SECTOR_SIZE];
+        assert_eq!(Inline::decode(&page, 5).unwrap(), None);
+
+        Inline::encode(Some(&inline), &mut page);
+        assert_eq!(Inline::decode(&page, 5).unwrap(), Some(inline));
+
+        page[FLAG_OFFSET] = 2;
+        assert!(Inline::decode(&page, 5).is_err());
+    }
+
+    #[test]
+    fn write() {
+        let inline = Inline::new(b"hello").unwrap();
+
+        assert_eq!(inline.write(1, b"ipp").unwrap().as_slice(), b"hippo");
+        assert_eq!(inline.write(7, b"!").unwrap().as_slice(), b"hello\0\0!");
+        assert!(inline.write(MAX_LEN, b"!").is_none());
+        assert!(Inline::new(&[0; MAX_LEN + 1]).is_none());
+    }
+
+    #[test]
+    fn truncate() {
+        let inline = Inline::new(b"hello").unwrap();
+
+        assert_eq!(inline.truncate(2).unwrap().as_slice(), b"he");
+        // The cut bytes don't reappear.
+        assert_eq!(inline.truncate(2).unwrap().truncate(4).unwrap().as_slice(), b"he\0\0");
+        assert!(inline.truncate(MAX_LEN + 1).is_none());
+    }
+}
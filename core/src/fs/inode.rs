@@ -0,0 +1,646 @@
+//! Inodes.
+//!
+//! An inode holds the POSIX metadata of a file, directory or symbolic link (the mode, the
+//! ownership, the timestamps and the link count) together with the pointer to the object itself.
+//! Directory entries of kind `Inode` refer to inodes, so the metadata is stored in a single page
+//! per object.
+//!
+//! Inodes are copy-on-write like everything else: Changing the metadata or the object writes a new
+//! inode page.
+//!
+//! A symbolic link is an inode of type `S_IFLNK`, whose object is a file holding the target path.
+//! Short targets are thus stored inline in the header of the file.
+//!
+//! # Access times
+//!
+//! Updating the access time on every read would turn every read into a write (and, with
+//! copy-on-write, into a commit), so the access time is updated lazily according to a policy (see
+//! `Atime`). By default, it is only updated if it is older than the modification or change time,
+//! or older than a day.
+//!
+//! # Format
+//!
+//! 1. Byte 0-4: The mode, i.e. the type and permission bits as in `st_mode` (little-endian).
+//! 2. Byte 4-8: The user ID of the owner (little-endian).
+//! 3. Byte 8-12: The group ID of the owner (little-endian).
+//! 4. Byte 12-16: The number of hard links (little-endian).
+//! 5. Byte 16-24: The access time (little-endian).
+//! 6. Byte 24-32: The modification time (little-endian).
+//! 7. Byte 32-40: The change time (little-endian).
+//! 8. Byte 40-48: The birth time (little-endian).
+//! 9. Byte 48-64: The page pointer to the object.
+//! 10. Byte 64-80: The root pointer of the extended attribute table (null if none). This is only
+//!     used for objects other than regular files, as files hold their own attributes.
+//! 11. Byte 80: 1 if the extended attribute block is stored inline, and 0 otherwise.
+//! 12. Byte 81-512: The inline extended attribute block, or zero.
+//!
+//! The timestamps are in seconds since the Unix epoch.
+
+use futures::future::{self, Either};
+use futures::Future;
+
+use {disk, fs, little_endian, Error};
+use alloc::{compact, page};
+
+/// The mask of the type bits of the mode.
+pub const S_IFMT: u32 = 0o170000;
+/// The type bits of a regular file.
+pub const S_IFREG: u32 = 0o100000;
+/// The type bits of a directory.
+pub const S_IFDIR: u32 = 0o040000;
+/// The type bits of a symbolic link.
+pub const S_IFLNK: u32 = 0o120000;
+/// The mask of the permission bits (including setuid, setgid and sticky) of the mode.
+const PERMISSIONS: u32 = 0o7777;
+
+/// The interval after which the access time is updated under the relative policy.
+const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+/// The POSIX metadata of an object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Metadata {
+    /// The type and permission bits.
+    pub mode: u32,
+    /// The user ID of the owner.
+    pub uid: u32,
+    /// The group ID of the owner.
+    pub gid: u32,
+    /// The number of hard links.
+    pub nlink: u32,
+    /// The time of the last access.
+    pub atime: u64,
+    /// The time of the last modification of the content.
+    pub mtime: u64,
+    /// The time of the last change of the content or the metadata.
+    pub ctime: u64,
+    /// The time of creation.
+    pub btime: u64,
+}
+
+impl Metadata {
+    /// Get the kind of the object from the type bits.
+    ///
+    /// The target of a symbolic link is stored in a file.
+    fn kind(&self) -> Result<fs::EntryKind, Error> {
+        match self.mode & S_IFMT {
+            S_IFREG | S_IFLNK => Ok(fs::EntryKind::File),
+            S_IFDIR => Ok(fs::EntryKind::Directory),
+            x => Err(err!(Corruption, "invalid inode type bits {:o}", x)),
+        }
+    }
+}
+
+/// The access time policy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Atime {
+    /// Never update the access time.
+    Never,
+    /// Update the access time only if it is older than the modification or change time, or if it
+    /// is older than a day.
+    Relative,
+    /// Update the access time on every access.
+    Strict,
+}
+
+impl Default for Atime {
+    fn default() -> Atime {
+        Atime::Relative
+    }
+}
+
+impl Atime {
+    /// Does an access at time `now` require the access time to be updated?
+    fn needs_update(self, meta: &Metadata, now: u64) -> bool {
+        match self {
+            Atime::Never => false,
+            // Timestamps are in seconds, so an access time of now would be rewritten unchanged.
+            Atime::Relative => meta.atime != now && (meta.atime <= meta.mtime
+                || meta.atime <= meta.ctime || now.saturating_sub(meta.atime) >= RELATIME_INTERVAL),
+            Atime::Strict => meta.atime != now,
+        }
+    }
+}
+
+/// An inode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Inode {
+    /// The pointer to the inode page.
+    ptr: page::Pointer,
+    /// The metadata.
+    meta: Metadata,
+    /// The pointer to the object.
+    target: page::Pointer,
+    /// The root of the extended attribute table.
+    xattrs: Option<page::Pointer>,
+    /// The extended attribute block, if stored inline.
+    ///
+    /// If this is `Some`, `xattrs` is `None`.
+    inline_xattrs: Option<fs::Inline>,
+}
+
+impl Inode {
+    /// Parse the binary representation of an inode.
+    fn decode(ptr: page::Pointer, buf: &disk::SectorBuf) -> Result<Inode, Error> {
+        let meta = Metadata {
+            mode: little_endian::read(&buf[..]),
+            uid: little_endian::read(&buf[4..]),
+            gid: little_endian::read(&buf[8..]),
+            nlink: little_endian::read(&buf[12..]),
+            atime: little_endian::read(&buf[16..]),
+            mtime: little_endian::read(&buf[24..]),
+            ctime: little_endian::read(&buf[32..]),
+            btime: little_endian::read(&buf[40..]),
+        };
+        meta.kind()?;
+        let target: Option<page::Pointer> = little_endian::read(&buf[48..]);
+
+        Ok(Inode {
+            ptr: ptr,
+            meta: meta,
+            target: target.ok_or_else(|| err!(Corruption, "inode without object"))?,
+            xattrs: little_endian::read(&buf[64..]),
+            inline_xattrs: fs::Inline::decode(buf, fs::inline::MAX_LEN as u64)?,
+        })
+    }
+
+    /// Encode the inode into a sector-sized buffer.
+    fn encode(&self) -> disk::SectorBuf {
+        let meta = &self.meta;
+        let mut buf = [0; disk::SECTOR_SIZE];
+
+        little_endian::write(&mut buf[..], meta.mode);
+        little_endian::write(&mut buf[4..], meta.uid);
+        little_endian::write(&mut buf[8..], meta.gid);
+        little_endian::write(&mut buf[12..], meta.nlink);
+        little_endian::write(&mut buf[16..], meta.atime);
+        little_endian::write(&mut buf[24..], meta.mtime);
+        little_endian::write(&mut buf[32..], meta.ctime);
+        little_endian::write(&mut buf[40..], meta.btime);
+        little_endian::write(&mut buf[48..], Some(self.target));
+        little_endian::write(&mut buf[64..], self.xattrs);
+        fs::Inline::encode(self.inline_xattrs.as_ref(), &mut buf);
+
+        buf
+    }
+
+    /// Write a new inode.
+    ///
+    /// The pointer of `inode` is ignored, and replaced by the pointer to the written page.
+    fn write(fs: &fs::State, inode: Inode) -> future!(Inode) {
+        fs.alloc(inode.encode(), "inode").map(move |ptr| Inode {
+            ptr: ptr,
+            .. inode
+        })
+    }
+
+    /// Write a new inode for an object.
+    ///
+    /// The inode has no extended attributes.
+    fn write_new(fs: &fs::State, meta: Metadata, target: page::Pointer) -> future!(Inode) {
+        Inode::write(fs, Inode {
+            // This is replaced by the pointer to the written page.
+            ptr: target,
+            meta: meta,
+            target: target,
+            xattrs: None,
+            inline_xattrs: None,
+        })
+    }
+
+    /// Create an inode for an object.
+    ///
+    /// `entry` is the object (a file or a directory), and `permissions` the permission bits of
+    /// the mode. The link count starts at one.
+    pub fn create(
+        fs: &fs::State,
+        entry: fs::Entry,
+        permissions: u32,
+        uid: u32,
+        gid: u32,
+    ) -> future!(Inode) {
+        let kind = match entry.kind {
+            fs::EntryKind::File => Ok(S_IFREG),
+            fs::EntryKind::Directory => Ok(S_IFDIR),
+            kind => Err(err!(Implementation, "inodes cannot refer to {:?}", kind)),
+        };
+
+        future::result(kind).and_then(move |kind| {
+            let now = fs::now();
+            Inode::write_new(fs, Metadata {
+                mode: kind | permissions & PERMISSIONS,
+                uid: uid,
+                gid: gid,
+                nlink: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                btime: now,
+            }, entry.target)
+        })
+    }
+
+    /// Create a symbolic link to some target path.
+    pub fn symlink(fs: &fs::State, target: &[u8], uid: u32, gid: u32) -> future!(Inode) {
+        let target = target.to_vec();
+
+        fs::File::create(fs).and_then(move |file| file.write(fs, 0, &target)).and_then(move |file| {
+            let now = fs::now();
+            Inode::write_new(fs, Metadata {
+                // The permissions of symbolic links are not used.
+                mode: S_IFLNK | 0o777,
+                uid: uid,
+                gid: gid,
+                nlink: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                btime: now,
+            }, file.root())
+        })
+    }
+
+    /// Load an inode.
+    pub fn open(fs: &fs::State, ptr: page::Pointer) -> future!(Inode) {
+        fs.read(ptr).and_then(move |buf| Inode::decode(ptr, &buf))
+    }
+
+    /// Get the pointer to the inode page.
+    ///
+    /// This is the pointer, which refers to the inode.
+    pub fn root(&self) -> page::Pointer {
+        self.ptr
+    }
+
+    /// Get the metadata.
+    pub fn metadata(&self) -> Metadata {
+        self.meta
+    }
+
+    /// Get the object.
+    ///
+    /// This is the entry the inode stands in for.
+    pub fn entry(&self) -> fs::Entry {
+        fs::Entry {
+            // The type bits were checked when the inode was read.
+            kind: self.meta.kind().unwrap(),
+            target: self.target,
+        }
+    }
+
+    /// Is this a symbolic link?
+    pub fn is_symlink(&self) -> bool {
+        self.meta.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Read the target of a symbolic link.
+    pub fn read_link(&self, fs: &fs::State) -> future!(Vec<u8>) {
+        let target = if self.is_symlink() {
+            Ok(self.target)
+        } else {
+            Err(err!(InvalidInput, "not a symbolic link"))
+        };
+
+        future::result(target).and_then(move |target| fs::File::open(fs, target))
+            .and_then(move |file| file.read(fs, 0, file.len() as usize))
+    }
+
+    /// Replace the object by a new version of it.
+    ///
+    /// This updates the modification and change time.
+    pub fn set_target(&self, fs: &fs::State, target: page::Pointer) -> future!(Inode) {
+        let now = fs::now();
+        Inode::write(fs, Inode {
+            meta: Metadata {
+                mtime: now,
+                ctime: now,
+                .. self.meta
+            },
+            target: target,
+            .. *self
+        })
+    }
+
+    /// Change the metadata.
+    ///
+    /// This applies `f` to the metadata, and updates the change time. The type bits cannot be
+    /// changed.
+    pub fn change<F>(&self, fs: &fs::State, f: F) -> future!(Inode)
+    where F: FnOnce(&mut Metadata) {
+        let mut meta = self.meta;
+        f(&mut meta);
+        meta.mode = self.meta.mode & S_IFMT | meta.mode & PERMISSIONS;
+        meta.ctime = fs::now();
+
+        Inode::write(fs, Inode {
+            meta: meta,
+            .. *self
+        })
+    }
+
+    /// Set the permission bits.
+    pub fn chmod(&self, fs: &fs::State, permissions: u32) -> future!(Inode) {
+        self.change(fs, |meta| meta.mode = permissions)
+    }
+
+    /// Set the owner.
+    pub fn chown(&self, fs: &fs::State, uid: u32, gid: u32) -> future!(Inode) {
+        self.change(fs, |meta| {
+            meta.uid = uid;
+            meta.gid = gid;
+        })
+    }
+
+    /// Increment the link count.
+    pub fn link(&self, fs: &fs::State) -> future!(Inode) {
+        self.change(fs, |meta| meta.nlink += 1)
+    }
+
+    /// Decrement the link count.
+    pub fn unlink(&self, fs: &fs::State) -> future!(Inode) {
+        self.change(fs, |meta| meta.nlink = meta.nlink.saturating_sub(1))
+    }
+
+    /// Get the extended attributes.
+    ///
+    /// The attributes of a regular file are stored in the file, so they're part of its revision
+    /// history, while those of other objects are stored in the inode.
+    pub fn xattrs(&self, fs: &fs::State) -> future!(fs::Xattrs) {
+        if self.meta.mode & S_IFMT == S_IFREG {
+            Either::A(fs::File::open(fs, self.target).and_then(move |file| file.xattrs(fs)))
+        } else if let Some(inline) = self.inline_xattrs {
+            Either::B(Either::A(future::result(fs::Xattrs::from_inline(&inline))))
+        } else {
+            Either::B(Either::B(fs::Xattrs::load(fs, self.xattrs)))
+        }
+    }
+
+    /// Replace the extended attributes.
+    ///
+    /// If the attributes of an object other than a regular file fit in the inode, they're stored
+    /// inline. This updates the change time.
+    pub fn set_xattrs(&self, fs: &fs::State, xattrs: &fs::Xattrs) -> future!(Inode) {
+        let inode = Inode {
+            meta: Metadata {
+                ctime: fs::now(),
+                .. self.meta
+            },
+            .. *self
+        };
+
+        if self.meta.mode & S_IFMT == S_IFREG {
+            let xattrs = xattrs.clone();
+            Either::A(fs::File::open(fs, self.target)
+                .and_then(move |file| file.set_xattrs(fs, &xattrs))
+                .and_then(move |file| Inode::write(fs, Inode {
+                    target: file.root(),
+                    .. inode
+                })))
+        } else if let Some(inline) = xattrs.to_inline(fs::inline::MAX_LEN) {
+            Either::B(Either::A(Inode::write(fs, Inode {
+                xattrs: None,
+                inline_xattrs: Some(inline),
+                .. inode
+            })))
+        } else {
+            Either::B(Either::B(xattrs.write(fs).and_then(move |xattrs| {
+                Inode::write(fs, Inode {
+                    xattrs: xattrs,
+                    inline_xattrs: None,
+                    .. inode
+                })
+            })))
+        }
+    }
+
+    /// List the names of the extended attributes.
+    pub fn list_xattrs(&self, fs: &fs::State) -> future!(Vec<Vec<u8>>) {
+        self.xattrs(fs).map(|xattrs| xattrs.list())
+    }
+
+    /// Get the value of an extended attribute.
+    ///
+    /// If there is no such attribute, `None` is returned.
+    pub fn get_xattr(&self, fs: &fs::State, name: &[u8]) -> future!(Option<Vec<u8>>) {
+        let name = name.to_vec();
+
+        self.xattrs(fs).and_then(move |xattrs| xattrs.get(fs, &name))
+    }
+
+    /// Set the value of an extended attribute.
+    pub fn set_xattr(&self, fs: &fs::State, name: &[u8], value: &[u8]) -> future!(Inode) {
+        let inode = *self;
+        let (name, value) = (name.to_vec(), value.to_vec());
+
+        self.xattrs(fs).and_then(move |xattrs| xattrs.set(fs, &name, &value))
+            .and_then(move |xattrs| inode.set_xattrs(fs, &xattrs))
+    }
+
+    /// Remove an extended attribute.
+    ///
+    /// If there is no such attribute, an error is returned.
+    pub fn remove_xattr(&self, fs: &fs::State, name: &[u8]) -> future!(Inode) {
+        let inode = *self;
+        let name = name.to_vec();
+
+        self.xattrs(fs).and_then(move |xattrs| xattrs.remove(&name))
+            .and_then(move |xattrs| inode.set_xattrs(fs, &xattrs))
+    }
+
+    /// Record an access.
+    ///
+    /// If the policy requires the access time to be updated, the new inode is returned. Otherwise,
+    /// nothing is written, and `None` is returned.
+    pub fn accessed(&self, fs: &fs::State, policy: Atime) -> future!(Option<Inode>) {
+        let now = fs::now();
+        if !policy.needs_update(&self.meta, now) {
+            return Either::A(future::ok(None));
+        }
+
+        Either::B(Inode::write(fs, Inode {
+            meta: Metadata {
+                atime: now,
+                .. self.meta
+            },
+            .. *self
+        }).map(Some))
+    }
+}
+
+impl fs::Object for Inode {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        visitor.page(self.ptr);
+
+        let xattrs = match self.inline_xattrs {
+            Some(inline) => Either::A(future::result(fs::Xattrs::from_inline(&inline))
+                .and_then(move |xattrs| xattrs.visit(fs, visitor))),
+            None => Either::B(fs::xattr::visit(fs, self.xattrs, visitor)),
+        };
+
+        fs::directory::visit_child(fs, self.entry(), visitor).join(xattrs).map(|_| ())
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Inode) {
+        let inode = *self;
+
+        let xattrs = match self.inline_xattrs {
+            // The relocated attributes take the same space, so they still fit.
+            Some(inline) => Either::A(future::result(fs::Xattrs::from_inline(&inline))
+                .and_then(move |xattrs| xattrs.relocate(fs, relocations))
+                .map(|xattrs| (None, xattrs.to_inline(fs::inline::MAX_LEN)))),
+            None => {
+                Either::B(fs::xattr::relocate(fs, self.xattrs, relocations).map(|x| (x, None)))
+            },
+        };
+
+        fs::directory::relocate_child(fs, self.entry(), relocations).join(xattrs)
+            .and_then(move |(entry, (xattrs, inline_xattrs))| {
+                if entry.target == inode.target && xattrs == inode.xattrs
+                    && inline_xattrs == inode.inline_xattrs {
+                    // Follow the inode, if it was moved.
+                    Either::A(future::ok(Inode {
+                        ptr: relocations.get(inode.ptr).unwrap_or(inode.ptr),
+                        .. inode
+                    }))
+                } else {
+                    // Write a copy of the inode pointing to the relocated pages. This is not a
+                    // change, so the timestamps are kept.
+                    Either::B(Inode::write(fs, Inode {
+                        target: entry.target,
+                        xattrs: xattrs,
+                        inline_xattrs: inline_xattrs,
+                        .. inode
+                    }))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+    use fs::xattr;
+
+    fn inode(mode: u32) -> Inode {
+        let ptr = |cluster| page::Pointer {
+            cluster: cluster::Pointer::new(cluster).unwrap(),
+            offset: Some(2),
+            checksum: 0xDEAD,
+        };
+
+        Inode {
+            ptr: ptr(1),
+            meta: Metadata {
+                mode: mode,
+                uid: 1000,
+                gid: 100,
+                nlink: 2,
+                atime: 10,
+                mtime: 20,
+                ctime: 30,
+                btime: 5,
+            },
+            target: ptr(2),
+            xattrs: Some(ptr(3)),
+            inline_xattrs: None,
+        }
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let inode = inode(S_IFREG | 0o644);
+        let buf = inode.encode();
+
+        assert_eq!(Inode::decode(inode.ptr, &buf).unwrap(), inode);
+        assert_eq!(inode.entry().kind, fs::EntryKind::File);
+
+        let inline = Inode {
+            xattrs: None,
+            inline_xattrs: fs::Inline::new(&[7; fs::inline::MAX_LEN]),
+            .. self::inode(S_IFDIR | 0o755)
+        };
+        assert_eq!(Inode::decode(inline.ptr, &inline.encode()).unwrap(), inline);
+        assert_eq!(self::inode(S_IFDIR | 0o755).entry().kind, fs::EntryKind::Directory);
+        assert!(self::inode(S_IFLNK | 0o777).is_symlink());
+        assert!(!inode.is_symlink());
+    }
+
+    #[test]
+    fn invalid_type() {
+        let inode = inode(0o644);
+        let buf = inode.encode();
+
+        assert_eq!(Inode::decode(inode.ptr, &buf).unwrap_err().kind, ::error::Kind::Corruption);
+    }
+
+    #[test]
+    fn atime() {
+        let meta = inode(S_IFREG).meta;
+
+        assert!(!Atime::Never.needs_update(&meta, 1000));
+        assert!(Atime::Strict.needs_update(&meta, 1000));
+        // The access time is older than the change time.
+        assert!(Atime::Relative.needs_update(&meta, 1000));
+
+        let meta = Metadata {
+            atime: 40,
+            .. meta
+        };
+        assert!(!Atime::Relative.needs_update(&meta, 1000));
+        assert!(Atime::Relative.needs_update(&meta, 40 + RELATIME_INTERVAL));
+        // The access time is already up to date.
+        assert!(!Atime::Relative.needs_update(&meta, 40));
+    }
+
+    #[test]
+    fn xattrs() {
+        let fs = fs::memory(1024);
+        let large: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        let dir = fs::Directory::create(&fs).and_then(|dir| Inode::create(&fs, fs::Entry {
+            kind: fs::EntryKind::Directory,
+            target: dir.root(),
+        }, 0o755, 0, 0)).wait().unwrap();
+        let file = fs::File::create(&fs).and_then(|file| Inode::create(&fs, fs::Entry {
+            kind: fs::EntryKind::File,
+            target: file.root(),
+        }, 0o644, 0, 0)).wait().unwrap();
+
+        for inode in &[dir, file] {
+            let small = inode.set_xattr(&fs, b"user.small", b"blue").wait().unwrap();
+            assert_eq!(small.get_xattr(&fs, b"user.small").wait().unwrap(), Some(b"blue".to_vec()));
+            assert!(small.meta.ctime >= inode.meta.ctime);
+
+            let both = small.set_xattr(&fs, b"user.large", &large).wait().unwrap();
+            assert_eq!(both.list_xattrs(&fs).wait().unwrap(),
+                       [b"user.large".to_vec(), b"user.small".to_vec()]);
+            assert_eq!(both.get_xattr(&fs, b"user.large").wait().unwrap(), Some(large.clone()));
+
+            let removed = both.remove_xattr(&fs, b"user.small").wait().unwrap();
+            assert_eq!(removed.get_xattr(&fs, b"user.small").wait().unwrap(), None);
+            assert_eq!(removed.get_xattr(&fs, b"user.large").wait().unwrap(), Some(large.clone()));
+            assert_eq!(removed.remove_xattr(&fs, b"user.small").wait().unwrap_err().kind,
+                       ::error::Kind::NotFound);
+            // The old versions are unchanged.
+            assert_eq!(small.list_xattrs(&fs).wait().unwrap(), [b"user.small".to_vec()]);
+            assert!(inode.list_xattrs(&fs).wait().unwrap().is_empty());
+        }
+
+        // The attributes of the directory are stored in its inode, inline as long as they fit.
+        // Large values are stored in page arrays, which the inline block merely refers to.
+        let mut inode = dir.set_xattr(&fs, b"user.large", &large).wait().unwrap();
+        assert!(inode.inline_xattrs.is_some() && inode.xattrs.is_none());
+        for n in 0..8 {
+            let name = format!("user.{}", n).into_bytes();
+            inode = inode.set_xattr(&fs, &name, &[n; xattr::MAX_INLINE_LEN]).wait().unwrap();
+        }
+        assert!(inode.inline_xattrs.is_none() && inode.xattrs.is_some());
+        assert_eq!(inode.get_xattr(&fs, b"user.large").wait().unwrap(), Some(large));
+        assert_eq!(inode.get_xattr(&fs, b"user.7").wait().unwrap(),
+                   Some(vec![7; xattr::MAX_INLINE_LEN]));
+
+        // Those of the file are stored in the file.
+        let small = file.set_xattr(&fs, b"user.small", b"blue").wait().unwrap();
+        assert!(small.inline_xattrs.is_none() && small.xattrs.is_none());
+        assert_ne!(small.target, file.target);
+    }
+}
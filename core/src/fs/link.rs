@@ -0,0 +1,695 @@
+//! Links and renames.
+//!
+//! # Hard links
+//!
+//! Directory entries refer to objects by page pointers, and since objects are copy-on-write,
+//! every modification of an object changes its pointer. Several entries referring to the same
+//! inode would thus diverge as soon as the object is modified through one of them.
+//!
+//! Instead, an inode with several links is moved into the link table of the superpage, which is a
+//! directory mapping link numbers (in decimal) to inodes. The directory entries of the links are
+//! of kind `Link`, and refer to a link page holding the link number. Link pages never change, so
+//! modifying the object only updates the link table, and every link sees the new version.
+//!
+//! The link count of the inode is the number of links. When the last link is removed, the inode
+//! is removed from the link table. Link numbers are never reused.
+//!
+//! Hard links to directories are not supported.
+//!
+//! # Link page format
+//!
+//! 1. Byte 0-8: The link number (little-endian).
+//! 2. Byte 8-512: Reserved (zero).
+//!
+//! # Renames
+//!
+//! A rename moves an entry from one path to another, possibly across directories, within a single
+//! superpage, so it is atomic once committed. Following POSIX, it replaces an existing entry at
+//! the new path, if both are directories (in which case the replaced directory must be empty) or
+//! both are not. A directory cannot be moved into its own subtree.
+
+use futures::future::{self, Either};
+use futures::Future;
+
+use {disk, fs, little_endian, Error};
+use alloc::page;
+use fs::path::{components, create, deref, resolve, update};
+
+/// Get the name of a link number in the link table.
+fn link_name(number: u64) -> Vec<u8> {
+    number.to_string().into_bytes()
+}
+
+/// Write a link page.
+fn write_link(fs: &fs::State, number: u64) -> future!(page::Pointer) {
+    let mut buf = [0; disk::SECTOR_SIZE];
+    little_endian::write(&mut buf[..], number);
+
+    fs.alloc(buf, "link")
+}
+
+/// Read the link number of a link page.
+fn read_link(fs: &fs::State, ptr: page::Pointer) -> future!(u64) {
+    fs.read(ptr).map(|buf| little_endian::read(&buf[..]))
+}
+
+/// Look up a hard-linked inode in a link table.
+fn linked_inode(
+    fs: &fs::State,
+    links: Option<fs::Directory>,
+    number: u64,
+) -> future!(fs::Inode) {
+    let lookup = match links {
+        Some(table) => Either::A(table.lookup(fs, &link_name(number))),
+        None => Either::B(future::ok(None)),
+    };
+
+    lookup.and_then(move |entry| match entry {
+        Some(fs::Entry { kind: fs::EntryKind::Inode, target }) => {
+            Either::A(fs::Inode::open(fs, target))
+        },
+        _ => Either::B(future::err(err!(Corruption, "dangling hard link {}", number))),
+    })
+}
+
+/// Follow a hard link through a link table.
+///
+/// If `entry` is a hard link, the entry of the inode it links to in `links` is returned.
+/// Otherwise, `entry` is returned as it is.
+pub fn follow(
+    fs: &fs::State,
+    links: Option<fs::Directory>,
+    entry: fs::Entry,
+) -> future!(fs::Entry) {
+    match entry.kind {
+        fs::EntryKind::Link => Either::A(read_link(fs, entry.target).and_then(move |number| {
+            linked_inode(fs, links, number)
+        }).map(|inode| fs::Entry {
+            kind: fs::EntryKind::Inode,
+            target: inode.root(),
+        })),
+        _ => Either::B(future::ok(entry)),
+    }
+}
+
+/// Are two entries hard links to the same inode?
+fn same_link(fs: &fs::State, a: fs::Entry, b: Option<fs::Entry>) -> future!(bool) {
+    match b {
+        Some(b) if a.kind == fs::EntryKind::Link && b.kind == fs::EntryKind::Link => {
+            Either::A(read_link(fs, a.target).join(read_link(fs, b.target)).map(|(a, b)| a == b))
+        },
+        _ => Either::B(future::ok(false)),
+    }
+}
+
+/// Release a removed entry.
+///
+/// If the entry is a hard link, the link count is decremented, and the inode is removed from the
+/// link table, if this was its last link. The new link table is returned.
+fn release(
+    fs: &fs::State,
+    links: Option<fs::Directory>,
+    entry: Option<fs::Entry>,
+) -> future!(Option<fs::Directory>) {
+    let link = match entry {
+        Some(fs::Entry { kind: fs::EntryKind::Link, target }) => target,
+        _ => return Either::A(future::ok(links)),
+    };
+
+    Either::B(read_link(fs, link).and_then(move |number| {
+        linked_inode(fs, links, number).and_then(move |inode| {
+            // The inode was found, so the table exists.
+            let table = links.unwrap();
+            let name = link_name(number);
+
+            if inode.metadata().nlink <= 1 {
+                Either::A(table.remove(fs, &name).map(|(table, _)| Some(table)))
+            } else {
+                Either::B(inode.unlink(fs).and_then(move |inode| {
+                    table.insert(fs, &name, fs::Entry {
+                        kind: fs::EntryKind::Inode,
+                        target: inode.root(),
+                    })
+                }).map(Some))
+            }
+        })
+    }))
+}
+
+/// Detach the hard links of a clone.
+///
+/// A clone must not share the hard links of its source, as it would then be another link to the
+/// same inode rather than a copy. Every hard link of `entry` (or of its subtree, if it is a
+/// directory) is replaced by a copy of the linked inode with a link count of one, which shares the
+/// object. The directories are traversed, but the files are not. The new entry is returned.
+///
+/// Without a link table, there are no hard links, so nothing is traversed.
+fn detach(
+    fs: &fs::State,
+    links: Option<fs::Directory>,
+    entry: fs::Entry,
+) -> fs::BoxFuture<fs::Entry> {
+    if links.is_none() {
+        return Box::new(future::ok(entry));
+    }
+
+    match entry.kind {
+        fs::EntryKind::Link => Box::new(follow(fs, links, entry)
+            .and_then(move |linked| fs::Inode::open(fs, linked.target))
+            .and_then(move |inode| inode.change(fs, |meta| meta.nlink = 1))
+            .map(|inode| fs::Entry {
+                kind: fs::EntryKind::Inode,
+                target: inode.root(),
+            })),
+        fs::EntryKind::Directory => {
+            let dir = fs::Directory::from_raw(entry.target);
+
+            Box::new(dir.list(fs).and_then(move |entries| {
+                future::join_all(entries.into_iter().map(|(name, entry)| {
+                    detach(fs, links, entry).map(move |new| (name, entry, new))
+                }).collect::<Vec<_>>())
+            }).and_then(move |entries| {
+                // Only the entries with hard links in them are replaced.
+                entries.into_iter().filter(|&(_, old, new)| old != new)
+                    .fold(Box::new(future::ok(dir)) as fs::BoxFuture<_>, |dir, (name, _, new)| {
+                        Box::new(dir.and_then(move |dir| dir.insert(fs, &name, new)))
+                    })
+            }).map(|dir| fs::Entry {
+                kind: fs::EntryKind::Directory,
+                target: dir.root(),
+            }))
+        },
+        fs::EntryKind::Inode => Box::new(fs::Inode::open(fs, entry.target).and_then(move |inode| {
+            // Only directories can have hard links in them.
+            let object = inode.entry();
+            if object.kind != fs::EntryKind::Directory {
+                return Either::A(future::ok(entry));
+            }
+
+            Either::B(detach(fs, links, object).and_then(move |new| if new == object {
+                Either::A(future::ok(entry))
+            } else {
+                Either::B(inode.set_target(fs, new.target).map(|inode| fs::Entry {
+                    kind: fs::EntryKind::Inode,
+                    target: inode.root(),
+                }))
+            }))
+        })),
+        _ => Box::new(future::ok(entry)),
+    }
+}
+
+/// Find out if an entry is a directory, and if so, if it is empty.
+fn directory_state(fs: &fs::State, entry: fs::Entry) -> future!((bool, bool)) {
+    deref(fs, entry).and_then(move |entry| match entry.kind {
+        fs::EntryKind::Directory => Either::A(fs::Directory::from_raw(entry.target).list(fs)
+            .map(|entries| (true, entries.is_empty()))),
+        _ => Either::B(future::ok((false, false))),
+    })
+}
+
+/// Is `path` in the subtree of `dir` (or `dir` itself)?
+fn within(path: &[u8], dir: &[u8]) -> bool {
+    components(path).starts_with(&components(dir))
+}
+
+/// Write a new version of a file.
+///
+/// `entry` refers to the file, either directly or through its inode (but not through a hard
+/// link, see `fs::Superpage::modify_file()`), and `f` is applied to the file. The entry of the new
+/// version is returned.
+pub fn modify_file<F>(fs: &fs::State, entry: fs::Entry, f: F) -> fs::BoxFuture<fs::Entry>
+where F: FnOnce(fs::File) -> fs::BoxFuture<fs::File> + 'static {
+    match entry.kind {
+        fs::EntryKind::File => Box::new(fs::File::open(fs, entry.target).and_then(f)
+            .map(|file| fs::Entry {
+                kind: fs::EntryKind::File,
+                target: file.root(),
+            })),
+        fs::EntryKind::Inode => Box::new(fs::Inode::open(fs, entry.target).and_then(move |inode| {
+            fs::File::open(fs, inode.entry().target).and_then(f)
+                .and_then(move |file| inode.set_target(fs, file.root()))
+        }).map(|inode| fs::Entry {
+            kind: fs::EntryKind::Inode,
+            target: inode.root(),
+        })),
+        fs::EntryKind::Directory => Box::new(future::err(err!(IsDirectory, "not a file"))),
+        _ => Box::new(future::err(err!(InvalidInput, "not a file"))),
+    }
+}
+
+/// The error of a missing path.
+fn not_found(path: &[u8]) -> Error {
+    err!(NotFound, "'{}' doesn't exist", String::from_utf8_lossy(path)).with_path(path)
+}
+
+impl fs::Superpage {
+    /// Get the link table, creating it if it doesn't exist.
+    fn link_table(&self, fs: &fs::State) -> future!(fs::Directory) {
+        match self.links {
+            Some(table) => Either::A(future::ok(table)),
+            None => Either::B(fs::Directory::create(fs)),
+        }
+    }
+
+    /// Follow a hard link.
+    ///
+    /// If `entry` is a hard link, the entry of the inode it links to is returned. Otherwise,
+    /// `entry` is returned as it is.
+    pub fn follow_link(&self, fs: &fs::State, entry: fs::Entry) -> future!(fs::Entry) {
+        follow(fs, self.links, entry)
+    }
+
+    /// Replace a hard-linked inode by a new version of it.
+    ///
+    /// `link` is an entry of kind `Link`, and `inode` the new version of the inode it links to.
+    /// Every link sees the new version. The new superpage is returned.
+    pub fn set_linked(
+        &self,
+        fs: &fs::State,
+        link: fs::Entry,
+        inode: fs::Inode,
+    ) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+
+        read_link(fs, link.target).and_then(move |number| {
+            let table = superpage.links
+                .ok_or_else(|| err!(Corruption, "dangling hard link {}", number))?;
+
+            Ok(table.insert(fs, &link_name(number), fs::Entry {
+                kind: fs::EntryKind::Inode,
+                target: inode.root(),
+            }).map(move |table| fs::Superpage {
+                links: Some(table),
+                .. superpage
+            }))
+        }).flatten()
+    }
+
+    /// Write a new version of the file at `path`.
+    ///
+    /// The file is found through its inode or hard link, and replaced by the result of applying
+    /// `f` to it. The new superpage is returned.
+    pub fn modify_file<F>(&self, fs: &fs::State, path: &[u8], f: F) -> future!(fs::Superpage)
+    where F: FnOnce(fs::File) -> fs::BoxFuture<fs::File> + 'static {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        resolve(fs, self.root, &path).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&path))?;
+
+            Ok(match entry.kind {
+                // Hard-linked files are updated in the link table, so every link sees the new
+                // version.
+                fs::EntryKind::Link => Either::A(superpage.follow_link(fs, entry)
+                    .and_then(move |linked| modify_file(fs, linked, f))
+                    .and_then(move |new| fs::Inode::open(fs, new.target))
+                    .and_then(move |inode| superpage.set_linked(fs, entry, inode))),
+                _ => Either::B(modify_file(fs, entry, f).and_then(move |new| {
+                    update(fs, superpage.root, &path, move |_| Box::new(future::ok(Some(new))))
+                        .map(move |root| fs::Superpage {
+                            root: root,
+                            .. superpage
+                        })
+                })),
+            })
+        }).flatten()
+    }
+
+    /// Change the inode of the object at `path`.
+    ///
+    /// The inode is found directly or through a hard link, and replaced by the result of applying
+    /// `f` to it. The new superpage is returned.
+    pub fn modify_inode<F>(&self, fs: &fs::State, path: &[u8], f: F) -> future!(fs::Superpage)
+    where F: FnOnce(fs::Inode) -> fs::BoxFuture<fs::Inode> + 'static {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        resolve(fs, self.root, &path).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&path))?;
+
+            Ok(match entry.kind {
+                fs::EntryKind::Link => Either::A(superpage.follow_link(fs, entry)
+                    .and_then(move |linked| fs::Inode::open(fs, linked.target))
+                    .and_then(f)
+                    .and_then(move |inode| superpage.set_linked(fs, entry, inode))),
+                fs::EntryKind::Inode => Either::B(Either::A(fs::Inode::open(fs, entry.target)
+                    .and_then(f)
+                    .and_then(move |inode| {
+                        let entry = fs::Entry {
+                            kind: fs::EntryKind::Inode,
+                            target: inode.root(),
+                        };
+
+                        update(fs, superpage.root, &path, move |_| {
+                            Box::new(future::ok(Some(entry)))
+                        }).map(move |root| fs::Superpage {
+                            root: root,
+                            .. superpage
+                        })
+                    }))),
+                _ => Either::B(Either::B(future::err(err!(Unsupported, "'{}' has no inode",
+                                                          String::from_utf8_lossy(&path))
+                                                     .with_path(&path)))),
+            })
+        }).flatten()
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`.
+    ///
+    /// The new superpage is returned.
+    pub fn symlink(
+        &self,
+        fs: &fs::State,
+        path: &[u8],
+        target: &[u8],
+        uid: u32,
+        gid: u32,
+    ) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        fs::Inode::symlink(fs, target, uid, gid).and_then(move |inode| {
+            let entry = fs::Entry {
+                kind: fs::EntryKind::Inode,
+                target: inode.root(),
+            };
+
+            update(fs, superpage.root, &path.clone(), create(path, Box::new(future::ok(entry))))
+                .map(move |root| fs::Superpage {
+                    root: root,
+                    .. superpage
+                })
+        })
+    }
+
+    /// Create a hard link at `new` to the object at `existing`.
+    ///
+    /// The object must have an inode, and must not be a directory. The new superpage is returned.
+    pub fn link(&self, fs: &fs::State, existing: &[u8], new: &[u8]) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let (existing, new) = (existing.to_vec(), new.to_vec());
+
+        resolve(fs, self.root, &existing).and_then(move |entry| match entry {
+            Some(fs::Entry { kind: fs::EntryKind::Inode, target }) => {
+                // This is the first hard link, so the inode is moved to the link table.
+                Either::A(Either::A(fs::Inode::open(fs, target).and_then(move |inode| {
+                    if inode.entry().kind == fs::EntryKind::Directory {
+                        return Either::A(future::err(err!(PermissionDenied,
+                                                          "cannot hard link directories")));
+                    }
+
+                    let number = superpage.next_link;
+                    let table = superpage.link_table(fs);
+                    Either::B(inode.link(fs).join3(table, write_link(fs, number))
+                        .and_then(move |(inode, table, link)| {
+                            let link = fs::Entry {
+                                kind: fs::EntryKind::Link,
+                                target: link,
+                            };
+                            let table = table.insert(fs, &link_name(number), fs::Entry {
+                                kind: fs::EntryKind::Inode,
+                                target: inode.root(),
+                            });
+                            // Replace the original entry by a link as well.
+                            let root = update(fs, superpage.root, &existing, move |_| {
+                                Box::new(future::ok(Some(link)))
+                            }).and_then(move |root| {
+                                let new_link = create(new.clone(), Box::new(future::ok(link)));
+                                update(fs, root, &new, new_link)
+                            });
+
+                            table.join(root).map(move |(table, root)| fs::Superpage {
+                                root: root,
+                                links: Some(table),
+                                next_link: number + 1,
+                                .. superpage
+                            })
+                        }))
+                })))
+            },
+            Some(link @ fs::Entry { kind: fs::EntryKind::Link, .. }) => {
+                // Add another link to the linked inode.
+                Either::A(Either::B(read_link(fs, link.target).and_then(move |number| {
+                    let links = superpage.links;
+                    linked_inode(fs, links, number).and_then(move |inode| inode.link(fs))
+                        .and_then(move |inode| {
+                            let table = links.unwrap().insert(fs, &link_name(number), fs::Entry {
+                                kind: fs::EntryKind::Inode,
+                                target: inode.root(),
+                            });
+                            let root = update(fs, superpage.root, &new.clone(),
+                                              create(new, Box::new(future::ok(link))));
+
+                            table.join(root).map(move |(table, root)| fs::Superpage {
+                                root: root,
+                                links: Some(table),
+                                .. superpage
+                            })
+                        })
+                })))
+            },
+            Some(_) => Either::B(future::err(err!(Unsupported,
+                                                  "hard links require an inode"))),
+            None => Either::B(future::err(not_found(&existing))),
+        })
+    }
+
+    /// Clone the object at `source` to `target`.
+    ///
+    /// The object (a file or a whole subtree) is shared rather than copied. As objects are
+    /// copy-on-write, mutating either copy afterwards only copies the modified parts, and leaves
+    /// the other copy unchanged. A cloned file shares the revision history of its source. Hard
+    /// links are not shared, but replaced by copies of their inodes (see `detach()`).
+    ///
+    /// If `target` exists, an error is returned. The new superpage is returned.
+    pub fn clone_entry(
+        &self,
+        fs: &fs::State,
+        source: &[u8],
+        target: &[u8],
+    ) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let (source, target) = (source.to_vec(), target.to_vec());
+
+        resolve(fs, self.root, &source).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&source))?;
+
+            Ok(detach(fs, superpage.links, entry).and_then(move |entry| {
+                trace!(fs, "cloning object"; "kind" => format!("{:?}", entry.kind));
+
+                update(fs, superpage.root, &target.clone(),
+                       create(target, Box::new(future::ok(entry))))
+                    .map(move |root| fs::Superpage {
+                        root: root,
+                        .. superpage
+                    })
+            }))
+        }).flatten()
+    }
+
+    /// Remove a non-directory entry.
+    ///
+    /// If the entry is a hard link, the link count is decremented. Objects, which are open, remain
+    /// accessible through their handles until closed. The new superpage is returned.
+    pub fn unlink(&self, fs: &fs::State, path: &[u8]) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        resolve(fs, self.root, &path).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&path))?;
+
+            Ok(directory_state(fs, entry).and_then(move |(directory, _)| {
+                if directory {
+                    return Either::A(future::err(err!(IsDirectory, "'{}' is a directory",
+                                                      String::from_utf8_lossy(&path))
+                                                 .with_path(&path)));
+                }
+
+                Either::B(release(fs, superpage.links, Some(entry)).and_then(move |links| {
+                    update(fs, superpage.root, &path, |_| Box::new(future::ok(None)))
+                        .map(move |root| fs::Superpage {
+                            root: root,
+                            links: links,
+                            .. superpage
+                        })
+                }))
+            }))
+        }).flatten()
+    }
+
+    /// Remove an empty directory.
+    ///
+    /// The new superpage is returned.
+    pub fn rmdir(&self, fs: &fs::State, path: &[u8]) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        resolve(fs, self.root, &path).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&path))?;
+
+            Ok(directory_state(fs, entry).and_then(move |state| match state {
+                (true, true) => Either::A(update(fs, superpage.root, &path, |_| {
+                    Box::new(future::ok(None))
+                }).map(move |root| fs::Superpage {
+                    root: root,
+                    .. superpage
+                })),
+                (true, false) => Either::B(future::err(err!(NotEmpty,
+                                                            "directory not empty"))),
+                (false, _) => Either::B(future::err(err!(NotDirectory, "not a directory"))),
+            }))
+        }).flatten()
+    }
+
+    /// Remove an entry.
+    ///
+    /// Directories must be empty (see `rmdir()`), and hard links are released (see `unlink()`). The
+    /// new superpage is returned.
+    pub fn remove(&self, fs: &fs::State, path: &[u8]) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let path = path.to_vec();
+
+        resolve(fs, self.root, &path).and_then(move |entry| {
+            let entry = entry.ok_or_else(|| not_found(&path))?;
+
+            Ok(directory_state(fs, entry).and_then(move |(directory, _)| if directory {
+                Either::A(superpage.rmdir(fs, &path))
+            } else {
+                Either::B(superpage.unlink(fs, &path))
+            }))
+        }).flatten()
+    }
+
+    /// Move the entry at `from` to `to`.
+    ///
+    /// If an entry exists at `to`, it is replaced (see the module documentation). The new
+    /// superpage is returned.
+    pub fn rename(&self, fs: &fs::State, from: &[u8], to: &[u8]) -> future!(fs::Superpage) {
+        let superpage = self.clone();
+        let (from, to) = (from.to_vec(), to.to_vec());
+
+        if components(&from) == components(&to) {
+            // Renaming an entry to itself does nothing.
+            return Either::A(future::ok(superpage));
+        } else if components(&from).is_empty() || components(&to).is_empty() {
+            return Either::A(future::err(err!(InvalidInput,
+                                              "cannot rename the root directory")));
+        } else if within(&to, &from) {
+            return Either::A(future::err(err!(InvalidInput,
+                                              "cannot move a directory into its own subtree")));
+        }
+
+        let source_path = from.clone();
+        Either::B(resolve(fs, self.root, &from).join(resolve(fs, self.root, &to))
+            .and_then(move |(source, target)| {
+                let source = source.ok_or_else(|| not_found(&source_path))?;
+
+                Ok(same_link(fs, source, target).map(move |same| (source, target, same)))
+            }).flatten()
+            .and_then(move |(source, target, same)| {
+                if same {
+                    // Both are links to the same inode, so nothing is done.
+                    return Ok(Either::A(future::ok(superpage)));
+                }
+
+                let target_state = match target {
+                    Some(target) => Either::A(directory_state(fs, target).map(Some)),
+                    None => Either::B(future::ok(None)),
+                };
+
+                Ok(Either::B(directory_state(fs, source).join(target_state)
+                    .and_then(move |((directory, _), target_state)| {
+                        match target_state {
+                            Some((true, false)) if directory => {
+                                Err(err!(NotEmpty, "directory not empty"))
+                            },
+                            Some((true, _)) if !directory => {
+                                Err(err!(IsDirectory, "cannot replace a directory by a file"))
+                            },
+                            Some((false, _)) if directory => {
+                                Err(err!(NotDirectory, "cannot replace a file by a directory"))
+                            },
+                            _ => Ok(()),
+                        }
+                    }).and_then(move |_| release(fs, superpage.links, target))
+                    .and_then(move |links| {
+                        update(fs, superpage.root, &from, |_| Box::new(future::ok(None)))
+                            .and_then(move |root| update(fs, root, &to, move |_| {
+                                Box::new(future::ok(Some(source)))
+                            })).map(move |root| fs::Superpage {
+                                root: root,
+                                links: links,
+                                .. superpage
+                            })
+                    })))
+            }).flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an inode-backed empty file at a path of the current tree, and commit it.
+    fn create_file(fs: &fs::State, path: &[u8]) -> fs::Superpage {
+        let superpage = fs::Superpage::load(fs).wait().unwrap().unwrap();
+        let file = fs::File::create(fs).wait().unwrap();
+        let inode = fs::Inode::create(fs, fs::Entry {
+            kind: fs::EntryKind::File,
+            target: file.root(),
+        }, 0o644, 0, 0).wait().unwrap();
+        let entry = Box::new(future::ok(fs::Entry {
+            kind: fs::EntryKind::Inode,
+            target: inode.root(),
+        }));
+        let root = update(fs, superpage.root, path, create(path.to_vec(), entry)).wait().unwrap();
+
+        fs::Superpage {
+            root: root,
+            .. superpage
+        }.commit(fs).wait().unwrap()
+    }
+
+    /// Get the link count of the inode at a path.
+    fn nlink(fs: &fs::State, superpage: &fs::Superpage, path: &[u8]) -> u32 {
+        let entry = resolve(fs, superpage.root, path).wait().unwrap().unwrap();
+        let entry = superpage.follow_link(fs, entry).wait().unwrap();
+
+        fs::Inode::open(fs, entry.target).wait().unwrap().metadata().nlink
+    }
+
+    #[test]
+    fn own_subtree() {
+        assert!(within(b"/a/b/c", b"/a"));
+        assert!(within(b"/a/b/", b"/a/b"));
+        assert!(within(b"/a", b"/"));
+        assert!(!within(b"/ab", b"/a"));
+        assert!(!within(b"/a", b"/a/b"));
+    }
+
+    #[test]
+    fn link_names() {
+        assert_eq!(link_name(0), b"0");
+        assert_eq!(link_name(1234), b"1234");
+    }
+
+    #[test]
+    fn rename_between_links() {
+        let fs = fs::memory(1024);
+        let superpage = create_file(&fs, b"/a")
+            .link(&fs, b"/a", b"/b").wait().unwrap()
+            .commit(&fs).wait().unwrap();
+        assert_eq!(nlink(&fs, &superpage, b"/a"), 2);
+
+        // Both entries link to the same inode, so the rename does nothing.
+        let renamed = superpage.rename(&fs, b"/a", b"/b").wait().unwrap();
+        assert_eq!(renamed, superpage);
+        assert_eq!(nlink(&fs, &renamed, b"/a"), 2);
+        assert!(resolve(&fs, renamed.root, b"/b").wait().unwrap().is_some());
+
+        // Unlinking one of them keeps the inode alive through the other.
+        let unlinked = renamed.unlink(&fs, b"/a").wait().unwrap();
+        assert_eq!(nlink(&fs, &unlinked, b"/b"), 1);
+    }
+}
@@ -1,19 +1,213 @@
 mod array;
+mod directory;
+mod file;
+mod filesystem;
+mod handle;
+mod inline;
+mod inode;
+mod link;
 mod object;
+mod path;
+mod superpage;
+mod transaction;
+mod watch;
+mod xattr;
+pub mod fsck;
 
-pub use self::object::Object;
+pub use self::array::Array;
+pub use self::directory::{Directory, Entry, Kind as EntryKind};
+pub use self::file::{File, Retention, Revision, View as FileView};
+pub use self::filesystem::{DirEntry, FileType, Filesystem, Handle, ImageInfo, Info, MkfsOptions,
+                           OpenOptions, RevisionInfo, SnapshotInfo, Stat, Transaction, Usage};
+pub use self::inline::Inline;
+pub use self::inode::{Atime, Inode, Metadata};
+pub use self::object::{Data, Object, Visitor};
+pub use self::superpage::{Snapshot, Superpage};
+pub use self::watch::{watch, Event as WatchEvent};
+pub use self::xattr::Xattrs;
 
-use {type_name, cbloom, alloc, Error};
-use alloc::page;
-use futures::Future;
+use {type_name, cbloom, alloc, metrics, Error};
+use alloc::{compact, compress, gc, locality, page};
+use futures::{future, Future};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use disk::{self, Disk};
 
-struct State<D> {
+/// A boxed future.
+///
+/// `future!()` cannot be used for recursive functions, as the type would be infinite, so such
+/// functions return this instead.
+pub type BoxFuture<T> = Box<Future<Item = T, Error = Error>>;
+
+/// The size of the filter of reachable clusters in bytes.
+const REACHABLE_FILTER_SIZE: usize = 1 << 20;
+/// The number of clusters, which the filter of reachable clusters is dimensioned for.
+///
+/// This gives 8 bits per cluster.
+const REACHABLE_FILTER_CLUSTERS: usize = 1 << 17;
+
+/// The current time in seconds since the Unix epoch.
+///
+/// This is the format of the timestamps stored on disk.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+/// The state of an open filesystem.
+pub struct State<D> {
     alloc: alloc::Allocator<D>,
     reachable: cbloom::Filter,
+    /// The publisher of commits to the change subscribers.
+    watch: watch::Hub,
+    /// The open handles.
+    handles: handle::Handles,
+    /// The commit lock.
+    ///
+    /// Every committer holds this, from reading the current superpage until the new superpage has
+    /// replaced it, so commits cannot overwrite each other. It guards the generation of the latest
+    /// commit (zero if nothing was committed since the filesystem was opened).
+    commits: Mutex<u64>,
+    /// The cycle lock.
+    ///
+    /// Garbage collection and compaction cycles can be started both by the background threads
+    /// and on request, so this is held for the duration of every cycle, making sure that only one
+    /// runs at a time.
+    cycles: Mutex<()>,
+    /// The retention policy applied to the revision history of every new file version.
+    retention: Retention,
+}
+
+/// The objects found by space accounting.
+#[derive(Default)]
+struct Accounting {
+    /// The pages visited.
+    pages: HashSet<page::Pointer>,
+    /// The headers of the files visited, which store their content inline.
+    inline_files: HashSet<page::Pointer>,
+}
+
+impl Visitor for Mutex<Accounting> {
+    fn page(&self, ptr: page::Pointer) {
+        self.lock().unwrap().pages.insert(ptr);
+    }
+
+    fn inline_file(&self, header: page::Pointer) {
+        self.lock().unwrap().inline_files.insert(header);
+    }
+}
+
+impl Visitor for Mutex<compact::Census> {
+    fn page(&self, ptr: page::Pointer) {
+        self.lock().unwrap().add(ptr);
+    }
+}
+
+/// The space used by an object compared to another object.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Space {
+    /// The number of bytes shared with the other object.
+    pub shared: u64,
+    /// The number of bytes used exclusively by the object.
+    pub exclusive: u64,
+    /// The number of files of the object, which store their content inline.
+    ///
+    /// These take no pages besides their header.
+    pub inline_files: u64,
+}
+
+impl Space {
+    /// Compare the pages of an object to the pages of another object.
+    fn compare(pages: &HashSet<page::Pointer>, other: &HashSet<page::Pointer>) -> Space {
+        let shared = pages.intersection(other).count() as u64;
+
+        Space {
+            shared: shared * disk::SECTOR_SIZE as u64,
+            exclusive: (pages.len() as u64 - shared) * disk::SECTOR_SIZE as u64,
+            inline_files: 0,
+        }
+    }
 }
 
 impl<D: Disk> State<D> {
+    /// Open the filesystem on a disk.
+    ///
+    /// `codecs` provides the compression algorithms. If `read_only` is set, nothing is written to
+    /// the disk. The metrics are reported to `metrics`.
+    pub fn open(
+        disk: D,
+        read_only: bool,
+        codecs: &compress::Registry,
+        metrics: metrics::Metrics,
+    ) -> future!(State<D>) {
+        let alloc = alloc::Allocator::open(disk, read_only, codecs, metrics);
+
+        alloc.map(State::new).and_then(|fs| {
+            fs.discover()?;
+            Ok(fs)
+        })
+    }
+
+    /// Track the clusters reachable from the current superpage for garbage collection.
+    ///
+    /// The collector only sweeps the clusters it tracks, and the tracking isn't persisted, so the
+    /// clusters allocated before the filesystem was opened are found by marking the whole graph
+    /// once. This blocks.
+    fn discover(&self) -> Result<(), Error> {
+        info!(self, "discovering allocated clusters");
+
+        match Superpage::load(self).wait()? {
+            Some(superpage) => superpage.visit(self, self).wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a new, empty filesystem on a disk.
+    ///
+    /// Nothing is committed, so the superpage must be committed afterwards (see
+    /// `Superpage::commit()`).
+    pub fn init(disk: D, options: alloc::Options) -> future!(State<D>) {
+        alloc::Allocator::init(disk, options).map(State::new)
+    }
+
+    /// Set up the state around an allocator.
+    fn new(alloc: alloc::Allocator<D>) -> State<D> {
+        State {
+            alloc: alloc,
+            reachable: cbloom::Filter::new(REACHABLE_FILTER_SIZE, REACHABLE_FILTER_CLUSTERS),
+            watch: watch::Hub::default(),
+            handles: handle::Handles::default(),
+            commits: Mutex::new(0),
+            cycles: Mutex::new(()),
+            retention: Retention::default(),
+        }
+    }
+
+    /// Get the retention policy of the revision history.
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Set the retention policy of the revision history.
+    ///
+    /// The policy is applied whenever a file is written (see `File::prune()`).
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    /// Flush the buffered state to the disk.
+    ///
+    /// Commits are persistent on their own, so this only flushes the buffered free clusters.
+    ///
+    /// This blocks.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.alloc.flush_free()
+    }
+
+    /// Allocate a buffer.
+    ///
+    /// This allocates a page holding `buf`, and returns the pointer to it. `description` tells
+    /// what the page is, and is only used for logging.
     pub fn alloc(
         &self,
         buf: disk::SectorBuf,
@@ -21,20 +215,373 @@ impl<D: Disk> State<D> {
     ) -> future!(page::Pointer) {
         debug!(self, "allocating buffer"; "description" => description);
 
-        // Allocate the buffer and insert it into the set of currently reachable pages in case that
-        // it is reachable right now.
-        Ok(self.alloc.alloc(buf).map(|ptr| self.visit(ptr)))
+        self.alloc.alloc(Box::new(buf)).map(move |ptr| {
+            // Insert the page into the set of currently reachable pages in case that it is
+            // reachable right now (i.e. a garbage collection cycle is marking).
+            self.set_reachable(ptr);
+            ptr
+        })
+    }
+
+    /// Allocate a buffer near related pages.
+    ///
+    /// This is similar to `self.alloc()`, but packs the page with the other pages allocated with
+    /// locality hint `hint` (e.g. the pages of the same file).
+    pub fn alloc_near(
+        &self,
+        buf: disk::SectorBuf,
+        description: &'static str,
+        hint: locality::Hint,
+    ) -> future!(page::Pointer) {
+        debug!(self, "allocating buffer"; "description" => description, "hint" => hint.0);
+
+        self.alloc.alloc_near(Box::new(buf), hint).map(move |ptr| {
+            // See `self.alloc()`.
+            self.set_reachable(ptr);
+            ptr
+        })
+    }
+
+    /// Close a locality hint.
+    ///
+    /// This should be called when no more pages are expected to be allocated with `hint` (e.g.
+    /// when a file is closed).
+    pub fn close_hint(&self, hint: locality::Hint) {
+        self.alloc.close_hint(hint);
+    }
+
+    /// Read a page.
+    ///
+    /// This reads page `ptr` and returns its content, wrapped in a future. The page is checked
+    /// against its checksum.
+    pub fn read(&self, ptr: page::Pointer) -> future!(disk::SectorBuf) {
+        trace!(self, "reading page"; "page" => ptr);
+
+        self.alloc.read(ptr).map(|buf| *buf)
+    }
+
+    /// Get the unique identifier of the disk.
+    ///
+    /// This is secret, and is used as a seed for hashing, such that the hashes cannot be predicted
+    /// by an attacker.
+    pub fn uid(&self) -> u128 {
+        self.alloc.disk_header().uid.0
+    }
+
+    /// Get the pointer to the current superpage.
+    ///
+    /// If no superpage is initialized, `None` is returned.
+    pub fn superpage(&self) -> Option<page::Pointer> {
+        self.alloc.superpage()
+    }
+
+    /// Point the state block to a new superpage.
+    ///
+    /// See `Superpage::commit()`.
+    pub fn set_superpage(&self, ptr: page::Pointer) -> future!(()) {
+        self.alloc.set_superpage(ptr)
+    }
+
+    /// Publish a commit to the change subscribers.
+    ///
+    /// `root` is the committed tree, and `links` its link table. See `fs::watch()`.
+    pub fn publish(&self, generation: u64, root: Directory, links: Option<Directory>) {
+        self.watch.publish(generation, root, links);
     }
 
+    /// Mark a page as reachable for the running garbage collection cycle.
     pub fn set_reachable(&self, ptr: page::Pointer) {
-        self.reachable.insert(ptr);
+        // Garbage is swept a cluster at a time, so it is the cluster which is marked.
+        self.reachable.insert(ptr.cluster.into());
+        self.alloc.discover(ptr.cluster);
+    }
+
+    /// Visit an object as a part of the GC cycle.
+    ///
+    /// This marks the object and everything reachable from it.
+    pub fn visit<T: Object>(&self, obj: &T) -> future!(()) {
+        trace!(self, "visiting object"; "type" => type_name::get::<T>());
+
+        obj.visit(self, self)
+    }
+
+    /// Visit everything reachable.
+    ///
+    /// This visits the current superpage, as well as the roots retained for the change
+    /// subscribers, and the open objects. If nothing was committed yet, nothing is visited, and
+    /// `false` is returned.
+    fn visit_roots(&self, visitor: &Visitor) -> future!(bool) {
+        Superpage::load(self).and_then(move |superpage| match superpage {
+            Some(superpage) => future::Either::A(superpage.visit(self, visitor)
+                .join3(self.watch.visit(self, visitor), self.handles.visit(self, visitor))
+                .map(|_| true)),
+            None => future::Either::B(future::ok(false)),
+        })
+    }
+
+    /// Find the pages and inline files reachable from some object.
+    ///
+    /// This blocks, as it traverses the object.
+    fn account<T: Object>(&self, obj: &T) -> Result<Accounting, Error> {
+        let accounting = Mutex::new(Accounting::default());
+        obj.visit(self, &accounting).wait()?;
+
+        Ok(accounting.into_inner().unwrap())
+    }
+
+    /// Account the space used by an object, compared to another object.
+    ///
+    /// This finds the pages reachable from `obj`, and tells how many of them are shared with
+    /// `other` (e.g. because one is a clone of the other), and how many are used exclusively by
+    /// `obj`. Space is counted in uncompressed pages. The files of `obj`, which store their
+    /// content inline, are counted too.
+    ///
+    /// This blocks, as it traverses both objects.
+    pub fn space<A: Object, B: Object>(&self, obj: &A, other: &B) -> Result<Space, Error> {
+        let accounting = self.account(obj)?;
+
+        Ok(Space {
+            inline_files: accounting.inline_files.len() as u64,
+            .. Space::compare(&accounting.pages, &self.account(other)?.pages)
+        })
+    }
+
+    /// Run a garbage collection cycle.
+    ///
+    /// This marks every cluster reachable from the current superpage, and then frees the
+    /// candidate clusters, which were not marked. Allocations can happen concurrently, as pages
+    /// allocated during the cycle are marked on allocation.
+    ///
+    /// This blocks, as it is meant to be run from the background collection thread.
+    pub fn collect(&self, collector: &gc::Collector) -> Result<(), Error> {
+        let _cycle = self.cycles.lock().unwrap();
+        let start = Instant::now();
+
+        // Choose the candidates and clear the reachable set before loading the superpage, so that
+        // every page allocated after the snapshot of the graph is marked.
+        self.alloc.begin_collection();
+        self.reachable.clear();
+
+        // Mark everything reachable.
+        match self.visit_roots(self).wait() {
+            Ok(true) => (),
+            // Nothing was committed yet, so we cannot tell what is garbage.
+            Ok(false) => {
+                self.alloc.abort_collection();
+                return Ok(());
+            },
+            Err(err) => {
+                self.alloc.abort_collection();
+                return Err(err);
+            },
+        }
+
+        // Sweep the clusters, which are definitely unreachable.
+        let reachable = &self.reachable;
+        self.alloc.sweep(|cluster| reachable.maybe_contains(cluster.into()), collector)?;
+
+        // Only completed cycles are timed.
+        self.alloc.metrics().time(metrics::Histogram::GcCycleDuration, start);
+
+        Ok(())
+    }
+
+    /// Run a compaction cycle.
+    ///
+    /// This finds the live pages by traversing the object graph from the current superpage, has
+    /// the allocator repack the sparse clusters, and updates the pointers of the graph. The
+    /// relocations are applied to the superpage, which is current at that point, and committed,
+    /// with the commit lock held, so no concurrent commit is lost.
+    ///
+    /// This blocks, as it is meant to be run from the background compaction thread.
+    pub fn compact(&self, compactor: &compact::Compactor) -> Result<(), Error> {
+        let _cycle = self.cycles.lock().unwrap();
+
+        // Pin the clusters referenced from now on, so pages referenced after the census was
+        // taken aren't moved from under the feet of their referrers.
+        self.alloc.begin_compaction();
+
+        match self.relocate_census(compactor) {
+            // Nothing refers to the old clusters anymore, so they can be freed.
+            Ok(relocations) => {
+                self.alloc.finish_compaction(relocations, compactor);
+                Ok(())
+            },
+            Err(err) => {
+                self.alloc.abort_compaction();
+                Err(err)
+            },
+        }
     }
 
-    pub fn visit<T: Object>(&self, obj: T) -> Result<(), Error> {
-        trace!(self, "visting object"; "type" => type_name::get::<T>());
+    /// Take the census, repack the sparse clusters, and apply the relocations.
+    ///
+    /// See `self.compact()`.
+    fn relocate_census(&self, compactor: &compact::Compactor)
+        -> Result<compact::Relocations, Error> {
+        info!(self, "building compaction census");
+
+        // Traverse the graph to find the live pages.
+        let census = Mutex::new(compact::Census::default());
+        self.visit_roots(&census).wait()?;
+        let census = census.into_inner().unwrap();
+
+        // Repack the clusters.
+        let relocations = self.alloc.compact(census, compactor)?;
+        if relocations.is_empty() {
+            return Ok(relocations);
+        }
 
-        obj.gc_visit(self)
+        // Other commits may have happened since the census, so the relocations are applied to
+        // the current superpage.
+        let mut last = self.commits.lock().unwrap();
+        if let Some(superpage) = Superpage::load(self).wait()? {
+            superpage.relocate(self, &relocations).wait()?.commit_locked(self, &mut last)?;
+        }
+        self.watch.relocate(self, &relocations)?;
+        self.handles.relocate(self, &relocations)?;
+
+        Ok(relocations)
+    }
+}
+
+impl<D: Disk> Visitor for State<D> {
+    fn page(&self, ptr: page::Pointer) {
+        self.set_reachable(ptr);
     }
 }
 
 delegate_log!(State.alloc);
+
+/// Create a filesystem state on an in-memory disk of some number of sectors.
+///
+/// An empty root directory is committed.
+#[cfg(test)]
+pub fn memory(sectors: disk::Sector) -> State<disk::memory::Memory> {
+    memory_on(disk::memory::Memory::new(sectors))
+}
+
+/// Create a filesystem state on an in-memory disk.
+///
+/// This is similar to `memory()`, but the caller can keep a clone of the disk to inspect it after
+/// the state was dropped.
+#[cfg(test)]
+pub fn memory_on(disk: disk::memory::Memory) -> State<disk::memory::Memory> {
+    let fs = State::init(disk, alloc::Options {
+        state_block: alloc::state_block::Options {
+            compression_algorithm: alloc::state_block::CompressionAlgorithm::Lz4,
+            dedup_verification: alloc::state_block::DedupVerification::Fingerprint,
+        },
+        disk_header: disk::header::Options {
+            vdev_stack: Vec::new(),
+            checksum_algorithm: disk::header::ChecksumAlgorithm::SeaHash,
+        },
+        codecs: compress::Registry::default(),
+        metrics: metrics::Metrics::default(),
+    }).wait().unwrap();
+
+    Directory::create(&fs).and_then(|root| Superpage::new(root, b""))
+        .and_then(|superpage| superpage.commit(&fs))
+        .wait().unwrap();
+
+    fs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+    use rand;
+
+    fn page(cluster: u64) -> page::Pointer {
+        page::Pointer {
+            cluster: cluster::Pointer::new(cluster).unwrap(),
+            offset: None,
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn space() {
+        let a: HashSet<_> = (1..5).map(page).collect();
+        let b: HashSet<_> = (3..10).map(page).collect();
+        let page_size = disk::SECTOR_SIZE as u64;
+
+        assert_eq!(Space::compare(&a, &b), Space {
+            shared: 2 * page_size,
+            exclusive: 2 * page_size,
+            inline_files: 0,
+        });
+        assert_eq!(Space::compare(&a, &a), Space {
+            shared: 4 * page_size,
+            exclusive: 0,
+            inline_files: 0,
+        });
+        assert_eq!(Space::compare(&a, &HashSet::new()), Space {
+            shared: 0,
+            exclusive: 4 * page_size,
+            inline_files: 0,
+        });
+    }
+
+    #[test]
+    fn account() {
+        let fs = memory(1024);
+        let small = File::create(&fs).wait().unwrap().write(&fs, 0, b"hello").wait().unwrap();
+        let large = File::create(&fs).wait().unwrap().write(&fs, 0, &[1; 2048]).wait().unwrap();
+
+        let accounting = fs.account(&small).unwrap();
+        assert!(accounting.pages.contains(&small.root()));
+        assert_eq!(accounting.inline_files.into_iter().collect::<Vec<_>>(), [small.root()]);
+
+        // The header and the four data pages are found, and the file doesn't count as inline.
+        let accounting = fs.account(&large).unwrap();
+        assert!(accounting.pages.contains(&large.root()));
+        assert!(accounting.pages.len() >= 5);
+        assert!(accounting.inline_files.is_empty());
+    }
+
+    /// Link a file into the root directory under `name`, and commit.
+    fn commit_file(fs: &State<disk::memory::Memory>, name: &[u8], file: &File) {
+        let superpage = Superpage::load(fs).wait().unwrap().unwrap();
+        let root = superpage.root.insert(fs, name, Entry {
+            kind: EntryKind::File,
+            target: file.root(),
+        }).wait().unwrap();
+
+        Superpage {
+            root: root,
+            .. superpage
+        }.commit(fs).wait().unwrap();
+    }
+
+    #[test]
+    fn dedup_against_old_cluster() {
+        let fs = memory(1024);
+        let collector = gc::Collector::new(gc::Options::default());
+        // Random data is incompressible, so every page gets a cluster of its own.
+        let data: Vec<u8> = (0..4 * disk::SECTOR_SIZE).map(|_| rand::random()).collect();
+
+        let file = File::create(&fs).wait().unwrap().write(&fs, 0, &data).wait().unwrap();
+        commit_file(&fs, b"a", &file);
+        // The clusters of the file become old.
+        fs.collect(&collector).unwrap();
+
+        // Remove the file, and write its content again, without linking the copy into the tree.
+        let superpage = Superpage::load(&fs).wait().unwrap().unwrap();
+        let (root, _) = superpage.root.remove(&fs, b"a").wait().unwrap();
+        Superpage {
+            root: root,
+            .. superpage
+        }.commit(&fs).wait().unwrap();
+        let copy = File::create(&fs).wait().unwrap().write(&fs, 0, &data).wait().unwrap();
+
+        // The copy refers to the deduplicated pages of the removed file, so their clusters must
+        // survive, although nothing committed refers to them yet.
+        fs.collect(&collector).unwrap();
+        commit_file(&fs, b"b", &copy);
+        fs.collect(&collector).unwrap();
+
+        assert_eq!(copy.read(&fs, 0, data.len()).wait().unwrap(), data);
+    }
+}
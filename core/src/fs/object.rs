@@ -1,17 +1,75 @@
 //! Unifying types and traits for on-disk structures.
 
-use futures::Future;
+use futures::{future, Future};
 
 use {fs, Error};
+use alloc::{compact, page};
 
 /// An on-disk object.
 ///
 /// This trait encompasses types which represents on-disk objects. It defines certain operations
 /// which such objects have in common.
 pub trait Object {
-    /// "Visit" the node as a part of the GC cycle.
+    /// Traverse the node and its adjacent nodes.
     ///
-    /// Garbage collection works by traversing a graph and creating a set of visited nodes. This
-    /// visits the node (the object) and adds it to `visited`, and then visits its adjacent nodes.
-    fn gc_visit(&self, fs: &fs::State) -> future!(());
+    /// This passes every page of the node (the object) to `visitor`, and then visits its adjacent
+    /// nodes. Garbage collection uses this to mark the reachable clusters, compaction to take the
+    /// census of the live pages, and space accounting to find the pages of an object.
+    fn visit(&self, fs: &fs::State, visitor: &Visitor) -> future!(());
+    /// Update the object after some pages were moved.
+    ///
+    /// Compaction moves pages between clusters, and afterwards every pointer to a moved page must
+    /// be updated. This applies `relocations` to the node and its adjacent nodes, copy-on-write,
+    /// and returns the updated object. Nodes which neither were moved nor point to moved nodes are
+    /// shared with the old object.
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Self)
+    where Self: Sized;
+}
+
+/// A traversal of the object graph.
+///
+/// See `Object::visit()`.
+pub trait Visitor {
+    /// Visit a page.
+    ///
+    /// A page may be visited more than once, if it is reachable through multiple paths (e.g.
+    /// deduplicated pages).
+    fn page(&self, ptr: page::Pointer);
+    /// Visit the header page of a file, which stores its content inline.
+    ///
+    /// The header is visited through `self.page()` too.
+    fn inline_file(&self, _header: page::Pointer) {}
+}
+
+/// A raw data page.
+///
+/// This is the leaf object of the graph: It holds some data, but has no adjacent nodes. It is used
+/// e.g. as the element type of arrays holding file contents.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Data(pub page::Pointer);
+
+impl From<page::Pointer> for Data {
+    fn from(ptr: page::Pointer) -> Data {
+        Data(ptr)
+    }
+}
+
+impl From<Data> for page::Pointer {
+    fn from(data: Data) -> page::Pointer {
+        data.0
+    }
+}
+
+impl Object for Data {
+    fn visit(&self, _: &fs::State, visitor: &Visitor) -> future!(()) {
+        // There are no adjacent nodes, so we simply visit the page itself.
+        visitor.page(self.0);
+
+        future::ok(())
+    }
+
+    fn relocate(&self, _: &fs::State, relocations: &compact::Relocations) -> future!(Data) {
+        // Follow the page, if it was moved.
+        future::ok(Data(relocations.get(self.0).unwrap_or(self.0)))
+    }
 }
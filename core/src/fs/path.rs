@@ -0,0 +1,159 @@
+//! Paths.
+//!
+//! A path is a sequence of names separated by slashes, starting from the root directory. Empty
+//! components (e.g. from repeated or trailing slashes) are ignored.
+
+use futures::future::{self, Either};
+use futures::{stream, Future, Stream};
+
+use {fs, Error};
+
+/// Split a path into its components.
+pub fn components(path: &[u8]) -> Vec<Vec<u8>> {
+    path.split(|&x| x == b'/').filter(|x| !x.is_empty()).map(|x| x.to_vec()).collect()
+}
+
+/// Join a path and a name.
+pub fn join(path: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut joined = path.to_vec();
+    if !joined.ends_with(b"/") {
+        joined.push(b'/');
+    }
+    joined.extend_from_slice(name);
+
+    joined
+}
+
+/// Follow an entry through its inode, if it has one.
+///
+/// This returns the entry of the object itself.
+pub fn deref(fs: &fs::State, entry: fs::Entry) -> future!(fs::Entry) {
+    match entry.kind {
+        fs::EntryKind::Inode => Either::A(fs::Inode::open(fs, entry.target).map(|x| x.entry())),
+        _ => Either::B(future::ok(entry)),
+    }
+}
+
+/// Resolve a path to an entry in a tree.
+///
+/// Directories are followed through their inodes, but the entry of the path itself is returned
+/// as it is. If the path doesn't exist, `None` is returned.
+pub fn resolve(fs: &fs::State, root: fs::Directory, path: &[u8]) -> future!(Option<fs::Entry>) {
+    let entry = fs::Entry {
+        kind: fs::EntryKind::Directory,
+        target: root.root(),
+    };
+
+    stream::iter_ok(components(path)).fold(Some(entry), move |entry, name| match entry {
+        Some(entry) => Either::A(deref(fs, entry).and_then(move |entry| match entry.kind {
+            fs::EntryKind::Directory => {
+                Either::A(fs::Directory::from_raw(entry.target).lookup(fs, &name))
+            },
+            // The path goes through something, which isn't a directory.
+            _ => Either::B(future::ok(None)),
+        })),
+        None => Either::B(future::ok(None)),
+    })
+}
+
+/// Update the entry at some path of a tree.
+///
+/// This applies `f` to the entry at `path` (`None` if there is none), and sets the entry to the
+/// result (removing it, if `None`). The directories on the path, which must exist, are rewritten
+/// bottom-up, and the new root is returned.
+pub fn update<F>(fs: &fs::State, root: fs::Directory, path: &[u8], f: F)
+    -> fs::BoxFuture<fs::Directory>
+where F: FnOnce(Option<fs::Entry>) -> fs::BoxFuture<Option<fs::Entry>> + 'static {
+    let mut parents = components(path);
+
+    match parents.pop() {
+        Some(name) => update_in(fs, root, parents, name, f),
+        None => Box::new(future::err(err!(InvalidInput, "cannot replace the root directory"))),
+    }
+}
+
+/// Update the entry `name` in the directory found by following `parents` from `dir`.
+///
+/// See `update()`.
+fn update_in<F>(
+    fs: &fs::State,
+    dir: fs::Directory,
+    mut parents: Vec<Vec<u8>>,
+    name: Vec<u8>,
+    f: F,
+) -> fs::BoxFuture<fs::Directory>
+where F: FnOnce(Option<fs::Entry>) -> fs::BoxFuture<Option<fs::Entry>> + 'static {
+    if parents.is_empty() {
+        // Update the entry itself.
+        return Box::new(dir.lookup(fs, &name).and_then(move |old| {
+            f(old).and_then(move |new| match (old, new) {
+                (_, Some(new)) => Either::A(dir.insert(fs, &name, new)),
+                (Some(_), None) => Either::B(Either::A(dir.remove(fs, &name).map(|(dir, _)| dir))),
+                (None, None) => Either::B(Either::B(future::ok(dir))),
+            })
+        }));
+    }
+
+    // Descend into the next directory, and write it back afterwards.
+    let next = parents.remove(0);
+    let lookup = dir.lookup(fs, &next);
+    let err_name = next.clone();
+    Box::new(lookup.and_then(move |entry| match entry {
+        Some(fs::Entry { kind: fs::EntryKind::Directory, target }) => {
+            Either::A(update_in(fs, fs::Directory::from_raw(target), parents, name, f)
+                .map(|child| fs::Entry {
+                    kind: fs::EntryKind::Directory,
+                    target: child.root(),
+                }))
+        },
+        Some(fs::Entry { kind: fs::EntryKind::Inode, target }) => {
+            // Update the directory behind the inode, and then the inode.
+            Either::B(Either::A(fs::Inode::open(fs, target).and_then(move |inode| {
+                match inode.entry() {
+                    fs::Entry { kind: fs::EntryKind::Directory, target } => {
+                        Ok(update_in(fs, fs::Directory::from_raw(target), parents, name, f)
+                            .and_then(move |child| inode.set_target(fs, child.root())))
+                    },
+                    _ => Err(not_directory(&err_name)),
+                }
+            }).flatten().map(|inode| fs::Entry {
+                kind: fs::EntryKind::Inode,
+                target: inode.root(),
+            })))
+        },
+        _ => Either::B(Either::B(future::err(not_directory(&err_name)))),
+    }).and_then(move |entry| dir.insert(fs, &next, entry)))
+}
+
+/// Create an entry with `update()`.
+///
+/// This returns an update function, which sets a missing entry to `new`. If the entry exists, an
+/// error is returned.
+pub fn create(
+    path: Vec<u8>,
+    new: fs::BoxFuture<fs::Entry>,
+) -> impl FnOnce(Option<fs::Entry>) -> fs::BoxFuture<Option<fs::Entry>> {
+    move |old| match old {
+        Some(_) => Box::new(future::err(err!(AlreadyExists, "'{}' already exists",
+                                             String::from_utf8_lossy(&path)).with_path(&path))),
+        None => Box::new(new.map(Some)),
+    }
+}
+
+/// The error of a path going through something, which isn't a directory.
+fn not_directory(name: &[u8]) -> Error {
+    err!(NotDirectory, "'{}' is not a directory", String::from_utf8_lossy(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(components(b"/a//b/c/"), [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert!(components(b"/").is_empty());
+        assert_eq!(join(b"/", b"a"), b"/a");
+        assert_eq!(join(b"/a", b"b"), b"/a/b");
+    }
+}
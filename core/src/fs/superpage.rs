@@ -0,0 +1,505 @@
+//! The superpage.
+//!
+//! The superpage is the root of the object graph: It points to the root directory, and holds the
+//! filesystem-wide metadata. The state block points to the current superpage.
+//!
+//! The superpage is never modified in place. Instead, a commit writes a new superpage, and then
+//! updates the state block to point to it. Since the state block is a single sector, this makes
+//! the commit atomic: After a crash, the state block points to the superpage of the last
+//! successful commit, and everything reachable from it was written before.
+//!
+//! # Format
+//!
+//! 1. Byte 0-8: The incompatible feature flags (little-endian).
+//! 2. Byte 8-16: The compatible feature flags (little-endian).
+//! 3. Byte 16-24: The generation (little-endian).
+//! 4. Byte 24-40: The page pointer to the root directory.
+//! 5. Byte 40-48: The creation time in seconds since the Unix epoch (little-endian).
+//! 6. Byte 48-56: The time of the commit in seconds since the Unix epoch (little-endian).
+//! 7. Byte 56: The length of the label.
+//! 8. Byte 57-121: The label, zero-padded.
+//! 9. Byte 121-137: The page pointer to the snapshot table (null if there are no snapshots).
+//! 10. Byte 137-153: The page pointer to the link table (null if there are no hard links).
+//! 11. Byte 153-161: The next link number (little-endian).
+//! 12. Byte 161-512: Reserved (zero).
+//!
+//! # Snapshots
+//!
+//! A snapshot is a named, read-only copy of the whole tree at some point in time. Snapshots are
+//! kept in the snapshot table, a directory mapping the names to snapshot records. A record is
+//! simply a copy of the superpage at the time of the snapshot (with no snapshot table), so
+//! creating a snapshot only writes a single page, no matter the size of the tree: Everything else
+//! is shared with the live tree, copy-on-write.
+//!
+//! The snapshot table is reachable from the superpage, so the garbage collector keeps the pages
+//! of every snapshot, until it is deleted.
+
+use futures::future::{self, Either};
+use futures::Future;
+use std::cmp;
+
+use {disk, fs, little_endian, Error};
+use alloc::{compact, page};
+use fs::Object;
+
+/// The maximal length of the label in bytes.
+pub const MAX_LABEL_LEN: usize = 64;
+/// The incompatible features supported by this implementation.
+///
+/// A superpage with any other incompatible feature flag set cannot be opened.
+pub const SUPPORTED_FEATURES: u64 = 0;
+
+/// The superpage.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Superpage {
+    /// The incompatible feature flags.
+    ///
+    /// Implementations must refuse to open a filesystem with flags they don't know of set, as
+    /// they change the format in a way that older implementations cannot handle.
+    pub incompatible_features: u64,
+    /// The compatible feature flags.
+    ///
+    /// These can safely be ignored by implementations not knowing of them.
+    pub compatible_features: u64,
+    /// The generation.
+    ///
+    /// This is incremented by every commit.
+    pub generation: u64,
+    /// The root directory.
+    pub root: fs::Directory,
+    /// The time the filesystem was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// The time of the commit, in seconds since the Unix epoch.
+    pub committed: u64,
+    /// The label of the filesystem.
+    pub label: Vec<u8>,
+    /// The snapshot table.
+    ///
+    /// This is `None` if no snapshot was ever created.
+    pub snapshots: Option<fs::Directory>,
+    /// The link table.
+    ///
+    /// This maps the link numbers to the inodes with several hard links. It is `None` if no hard
+    /// link was ever created. See `fs::link`.
+    pub links: Option<fs::Directory>,
+    /// The number of the next hard-linked inode.
+    pub next_link: u64,
+}
+
+/// A snapshot of the filesystem.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    /// The name of the snapshot.
+    pub name: Vec<u8>,
+    /// The generation of the superpage at the time of the snapshot.
+    pub generation: u64,
+    /// The time the snapshot was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// The root directory of the snapshot.
+    pub root: fs::Directory,
+    /// The link table of the snapshot.
+    pub links: Option<fs::Directory>,
+}
+
+/// Read a snapshot record.
+fn read_snapshot(fs: &fs::State, ptr: page::Pointer) -> future!(Superpage) {
+    fs.read(ptr).and_then(|buf| Superpage::decode(&buf))
+}
+
+/// Visit a snapshot record.
+///
+/// This is used for the entries of the snapshot table (see `fs::Object::visit()`).
+pub fn visit_snapshot(fs: &fs::State, ptr: page::Pointer, visitor: &fs::Visitor)
+    -> fs::BoxFuture<()> {
+    visitor.page(ptr);
+
+    Box::new(read_snapshot(fs, ptr).and_then(move |record| {
+        let links = match record.links {
+            Some(table) => Either::A(table.visit(fs, visitor)),
+            None => Either::B(future::ok(())),
+        };
+
+        record.root.visit(fs, visitor).join(links).map(|_| ())
+    }))
+}
+
+/// Relocate a snapshot record.
+///
+/// This is used for the entries of the snapshot table. The pointer to the updated record is
+/// returned.
+pub fn relocate_snapshot(
+    fs: &fs::State,
+    ptr: page::Pointer,
+    relocations: &compact::Relocations,
+) -> fs::BoxFuture<page::Pointer> {
+    Box::new(read_snapshot(fs, ptr).and_then(move |record| {
+        let links = match record.links {
+            Some(table) => Either::A(table.relocate(fs, relocations).map(Some)),
+            None => Either::B(future::ok(None)),
+        };
+
+        record.root.relocate(fs, relocations).join(links).and_then(move |(root, links)| {
+            if root == record.root && links == record.links {
+                // Follow the record, if it was moved.
+                Either::A(future::ok(relocations.get(ptr).unwrap_or(ptr)))
+            } else {
+                Either::B(fs.alloc(Superpage {
+                    root: root,
+                    links: links,
+                    .. record
+                }.encode(), "snapshot record"))
+            }
+        })
+    }))
+}
+
+impl Superpage {
+    /// Create the superpage of a new filesystem.
+    pub fn new(root: fs::Directory, label: &[u8]) -> Result<Superpage, Error> {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(err!(InvalidInput, "label too long ({} bytes)", label.len()));
+        }
+
+        let now = fs::now();
+        Ok(Superpage {
+            incompatible_features: 0,
+            compatible_features: 0,
+            generation: 0,
+            root: root,
+            created: now,
+            committed: now,
+            label: label.to_vec(),
+            snapshots: None,
+            links: None,
+            next_link: 0,
+        })
+    }
+
+    /// Parse the binary representation of a superpage.
+    fn decode(buf: &disk::SectorBuf) -> Result<Superpage, Error> {
+        let incompatible_features: u64 = little_endian::read(&buf[..]);
+        if incompatible_features & !SUPPORTED_FEATURES != 0 {
+            return Err(err!(Unsupported, "unsupported incompatible features {:x}",
+                            incompatible_features & !SUPPORTED_FEATURES));
+        }
+
+        let root = little_endian::read(&buf[24..])
+            .ok_or_else(|| err!(Corruption, "null root directory pointer in superpage"))?;
+
+        let label_len = buf[56] as usize;
+        if label_len > MAX_LABEL_LEN {
+            return Err(err!(Corruption, "invalid label length {} in superpage", label_len));
+        }
+
+        Ok(Superpage {
+            incompatible_features: incompatible_features,
+            compatible_features: little_endian::read(&buf[8..]),
+            generation: little_endian::read(&buf[16..]),
+            root: fs::Directory::from_raw(root),
+            created: little_endian::read(&buf[40..]),
+            committed: little_endian::read(&buf[48..]),
+            label: buf[57..][..label_len].to_vec(),
+            snapshots: little_endian::read::<Option<page::Pointer>>(&buf[121..])
+                .map(fs::Directory::from_raw),
+            links: little_endian::read::<Option<page::Pointer>>(&buf[137..])
+                .map(fs::Directory::from_raw),
+            next_link: little_endian::read(&buf[153..]),
+        })
+    }
+
+    /// Encode the superpage into a sector-sized buffer.
+    fn encode(&self) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+
+        little_endian::write(&mut buf[..], self.incompatible_features);
+        little_endian::write(&mut buf[8..], self.compatible_features);
+        little_endian::write(&mut buf[16..], self.generation);
+        little_endian::write(&mut buf[24..], self.root.root());
+        little_endian::write(&mut buf[40..], self.created);
+        little_endian::write(&mut buf[48..], self.committed);
+        buf[56] = self.label.len() as u8;
+        buf[57..][..self.label.len()].copy_from_slice(&self.label);
+        little_endian::write(&mut buf[121..], self.snapshots.map(|x| x.root()));
+        little_endian::write(&mut buf[137..], self.links.map(|x| x.root()));
+        little_endian::write(&mut buf[153..], self.next_link);
+
+        buf
+    }
+
+    /// Load the current superpage.
+    ///
+    /// This reads the superpage, which the state block points to. If no superpage is initialized
+    /// (i.e. the filesystem is new), `None` is returned.
+    pub fn load(fs: &fs::State) -> future!(Option<Superpage>) {
+        match fs.superpage() {
+            Some(ptr) => Either::A(fs.read(ptr).and_then(|buf| Superpage::decode(&buf)).map(Some)),
+            None => Either::B(future::ok(None)),
+        }
+    }
+
+    /// Commit the superpage.
+    ///
+    /// This writes the superpage as the next generation, and then atomically makes it the current
+    /// superpage by updating the state block. The committed superpage is returned.
+    ///
+    /// Every page reachable from the superpage must have been written before this is called. This
+    /// takes the commit lock, so it must not be called with the lock held. Committers, which
+    /// modify the current superpage, must use `self.commit_locked()` instead.
+    pub fn commit(&self, fs: &fs::State) -> future!(Superpage) {
+        let superpage = self.clone();
+
+        future::lazy(move || superpage.commit_locked(fs, &mut fs.commits.lock().unwrap()))
+    }
+
+    /// Commit the superpage with the commit lock held.
+    ///
+    /// This is like `self.commit()`, but `last` must be the generation guarded by the commit lock
+    /// (see `fs::State::commits`), and it is updated to the new generation. Committers, which read
+    /// the current superpage and commit a modification of it, must hold the lock throughout, so no
+    /// concurrent commit is lost.
+    ///
+    /// This blocks.
+    pub fn commit_locked(&self, fs: &fs::State, last: &mut u64) -> Result<Superpage, Error> {
+        let superpage = Superpage {
+            generation: self.generation + 1,
+            committed: fs::now(),
+            .. self.clone()
+        };
+
+        info!(fs, "committing superpage"; "generation" => superpage.generation);
+
+        // Write the superpage first, and the state block pointing to it afterwards.
+        let ptr = fs.alloc(superpage.encode(), "superpage").wait()?;
+        fs.set_superpage(ptr).wait()?;
+        *last = superpage.generation;
+        fs.publish(superpage.generation, superpage.root, superpage.links);
+
+        Ok(superpage)
+    }
+
+    /// Get the snapshot table, creating it if it doesn't exist.
+    fn snapshot_table(&self, fs: &fs::State) -> future!(fs::Directory) {
+        match self.snapshots {
+            Some(table) => Either::A(future::ok(table)),
+            None => Either::B(fs::Directory::create(fs)),
+        }
+    }
+
+    /// Create a snapshot.
+    ///
+    /// This creates a snapshot named `name` of the tree as of this superpage, and returns the new
+    /// superpage (which must be committed to persist the snapshot). If a snapshot with the same
+    /// name exists, an error is returned.
+    pub fn create_snapshot(&self, fs: &fs::State, name: &[u8]) -> future!(Superpage) {
+        let superpage = self.clone();
+        let name = name.to_vec();
+        // The record is the superpage itself, without the snapshot table.
+        let record = Superpage {
+            committed: fs::now(),
+            snapshots: None,
+            .. self.clone()
+        };
+
+        info!(fs, "creating snapshot"; "generation" => self.generation);
+
+        self.snapshot_table(fs).and_then(move |table| {
+            table.lookup(fs, &name).and_then(move |entry| if entry.is_some() {
+                Either::A(future::err(err!(AlreadyExists, "snapshot already exists")))
+            } else {
+                Either::B(fs.alloc(record.encode(), "snapshot record").and_then(move |ptr| {
+                    table.insert(fs, &name, fs::Entry {
+                        kind: fs::EntryKind::Snapshot,
+                        target: ptr,
+                    })
+                }))
+            })
+        }).map(move |table| Superpage {
+            snapshots: Some(table),
+            .. superpage
+        })
+    }
+
+    /// List the snapshots.
+    ///
+    /// The snapshots are returned ordered by their name.
+    pub fn snapshots(&self, fs: &fs::State) -> future!(Vec<Snapshot>) {
+        let entries = match self.snapshots {
+            Some(table) => Either::A(table.list(fs)),
+            None => Either::B(future::ok(Vec::new())),
+        };
+
+        entries.and_then(move |entries| {
+            future::join_all(entries.into_iter().map(|(name, entry)| {
+                read_snapshot(fs, entry.target).map(move |record| Snapshot {
+                    name: name,
+                    generation: record.generation,
+                    created: record.committed,
+                    root: record.root,
+                    links: record.links,
+                })
+            }).collect::<Vec<_>>())
+        })
+    }
+
+    /// Find a snapshot by its name.
+    fn find_snapshot(&self, fs: &fs::State, name: &[u8]) -> future!(Superpage) {
+        let lookup = match self.snapshots {
+            Some(table) => Either::A(table.lookup(fs, name)),
+            None => Either::B(future::ok(None)),
+        };
+
+        lookup.and_then(move |entry| match entry {
+            Some(entry) => Either::A(read_snapshot(fs, entry.target)),
+            None => Either::B(future::err(err!(NotFound, "snapshot does not exist"))),
+        })
+    }
+
+    /// Delete a snapshot.
+    ///
+    /// This removes snapshot `name`, and returns the new superpage (which must be committed). The
+    /// pages used exclusively by the snapshot are then freed by the garbage collector. If no such
+    /// snapshot exists, an error is returned.
+    pub fn delete_snapshot(&self, fs: &fs::State, name: &[u8]) -> future!(Superpage) {
+        let superpage = self.clone();
+        let table = self.snapshots;
+        let name = name.to_vec();
+
+        self.find_snapshot(fs, &name).and_then(move |_| {
+            info!(fs, "deleting snapshot");
+
+            // The snapshot exists, so the table does too.
+            table.unwrap().remove(fs, &name)
+        }).map(move |(table, _)| Superpage {
+            snapshots: Some(table),
+            .. superpage
+        })
+    }
+
+    /// Roll back to a snapshot.
+    ///
+    /// This returns a new superpage (which must be committed), in which the tree is replaced by
+    /// the tree of snapshot `name`. The snapshots are kept, including the rolled back one. If no
+    /// such snapshot exists, an error is returned.
+    pub fn rollback(&self, fs: &fs::State, name: &[u8]) -> future!(Superpage) {
+        let superpage = self.clone();
+
+        self.find_snapshot(fs, name).map(move |record| {
+            info!(fs, "rolling back to snapshot"; "generation" => record.generation);
+
+            Superpage {
+                root: record.root,
+                links: record.links,
+                // Link numbers must not be reused, as they may still be in use by other
+                // snapshots.
+                next_link: cmp::max(superpage.next_link, record.next_link),
+                .. superpage
+            }
+        })
+    }
+}
+
+impl fs::Object for Superpage {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        // The superpage isn't referred to by any page, so we find it through the state block.
+        if let Some(ptr) = fs.superpage() {
+            visitor.page(ptr);
+        }
+
+        let snapshots = match self.snapshots {
+            Some(table) => Either::A(table.visit(fs, visitor)),
+            None => Either::B(future::ok(())),
+        };
+        let links = match self.links {
+            Some(table) => Either::A(table.visit(fs, visitor)),
+            None => Either::B(future::ok(())),
+        };
+
+        self.root.visit(fs, visitor).join3(snapshots, links).map(|_| ())
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Superpage) {
+        // The superpage itself isn't rewritten here, but when the relocated superpage is
+        // committed.
+        let superpage = self.clone();
+        let snapshots = match self.snapshots {
+            Some(table) => Either::A(table.relocate(fs, relocations).map(Some)),
+            None => Either::B(future::ok(None)),
+        };
+        let links = match self.links {
+            Some(table) => Either::A(table.relocate(fs, relocations).map(Some)),
+            None => Either::B(future::ok(None)),
+        };
+
+        self.root.relocate(fs, relocations).join3(snapshots, links)
+            .map(move |(root, snapshots, links)| Superpage {
+                root: root,
+                snapshots: snapshots,
+                links: links,
+                .. superpage
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+    use error;
+
+    fn superpage() -> Superpage {
+        Superpage::new(fs::Directory::from_raw(page::Pointer {
+            cluster: cluster::Pointer::new(3).unwrap(),
+            offset: None,
+            checksum: 0xBEEF,
+        }), b"tfs").unwrap()
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let mut superpage = superpage();
+        assert_eq!(Superpage::decode(&superpage.encode()).unwrap(), superpage);
+
+        superpage.generation = 42;
+        superpage.compatible_features = !0;
+        superpage.label = vec![0xFF; MAX_LABEL_LEN];
+        assert_eq!(Superpage::decode(&superpage.encode()).unwrap(), superpage);
+
+        superpage.snapshots = Some(fs::Directory::from_raw(page::Pointer {
+            cluster: cluster::Pointer::new(4).unwrap(),
+            offset: Some(2),
+            checksum: 0xCAFE,
+        }));
+        assert_eq!(Superpage::decode(&superpage.encode()).unwrap(), superpage);
+
+        superpage.links = Some(fs::Directory::from_raw(page::Pointer {
+            cluster: cluster::Pointer::new(5).unwrap(),
+            offset: None,
+            checksum: 0xF00D,
+        }));
+        superpage.next_link = 7;
+        assert_eq!(Superpage::decode(&superpage.encode()).unwrap(), superpage);
+    }
+
+    #[test]
+    fn unsupported_features() {
+        let mut superpage = superpage();
+        superpage.incompatible_features = 1 << 63;
+        assert_eq!(Superpage::decode(&superpage.encode()).unwrap_err().kind,
+                   error::Kind::Unsupported);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut buf = superpage().encode();
+        buf[56] = MAX_LABEL_LEN as u8 + 1;
+        assert_eq!(Superpage::decode(&buf).unwrap_err().kind, error::Kind::Corruption);
+
+        let buf = [0; disk::SECTOR_SIZE];
+        assert_eq!(Superpage::decode(&buf).unwrap_err().kind, error::Kind::Corruption);
+    }
+
+    #[test]
+    fn label_too_long() {
+        assert!(Superpage::new(superpage().root, &[0; MAX_LABEL_LEN + 1]).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Transactions.
+//!
+//! A transaction groups several mutations, possibly of different objects, such that they become
+//! visible atomically, by a single superpage commit (and thus a single update of the state
+//! block).
+//!
+//! Transactions are optimistic: The mutations are recorded in a log, and nothing is written
+//! before the commit. The commit then checks that no path touched by the transaction was changed
+//! by another commit since the transaction began, replays the log onto the current tree, and
+//! commits the result. If a touched path was changed, the transaction conflicts, and fails as a
+//! whole.
+//!
+//! Thanks to copy-on-write, a path is unchanged exactly when it refers to the same page in both
+//! trees, so the conflict check doesn't read any objects beyond the directories on the paths.
+
+use futures::future;
+use futures::{stream, Future, Stream};
+
+use {fs, Error};
+use fs::path::{create, resolve, update};
+
+/// A mutation recorded in a transaction.
+#[derive(Clone, Debug)]
+enum Op {
+    /// Create an empty file.
+    CreateFile(Vec<u8>),
+    /// Create an empty directory.
+    CreateDirectory(Vec<u8>),
+    /// Write to a file at some offset.
+    Write(Vec<u8>, u64, Vec<u8>),
+    /// Set the length of a file.
+    Truncate(Vec<u8>, u64),
+    /// Remove an entry.
+    Remove(Vec<u8>),
+    /// Move an entry to another path.
+    Rename(Vec<u8>, Vec<u8>),
+}
+
+impl Op {
+    /// Apply the mutation to a superpage.
+    ///
+    /// The new superpage is returned.
+    fn apply(self, fs: &fs::State, superpage: fs::Superpage) -> fs::BoxFuture<fs::Superpage> {
+        match self {
+            Op::CreateFile(path) => {
+                let file = Box::new(fs::File::create(fs).map(|file| fs::Entry {
+                    kind: fs::EntryKind::File,
+                    target: file.root(),
+                }));
+                let root = update(fs, superpage.root, &path.clone(), create(path, file));
+                set_root(superpage, root)
+            },
+            Op::CreateDirectory(path) => {
+                let dir = Box::new(fs::Directory::create(fs).map(|dir| fs::Entry {
+                    kind: fs::EntryKind::Directory,
+                    target: dir.root(),
+                }));
+                let root = update(fs, superpage.root, &path.clone(), create(path, dir));
+                set_root(superpage, root)
+            },
+            Op::Write(path, offset, buf) => Box::new(superpage.modify_file(fs, &path, move |file| {
+                Box::new(file.write(fs, offset, &buf))
+            })),
+            Op::Truncate(path, len) => Box::new(superpage.modify_file(fs, &path, move |file| {
+                Box::new(file.truncate(fs, len))
+            })),
+            Op::Remove(path) => Box::new(superpage.remove(fs, &path)),
+            Op::Rename(from, to) => Box::new(superpage.rename(fs, &from, &to)),
+        }
+    }
+}
+
+/// Replace the tree of a superpage by the result of a future.
+fn set_root<F>(superpage: fs::Superpage, root: F) -> fs::BoxFuture<fs::Superpage>
+where F: Future<Item = fs::Directory, Error = Error> + 'static {
+    Box::new(root.map(move |root| fs::Superpage {
+        root: root,
+        .. superpage
+    }))
+}
+
+/// A transaction.
+///
+/// The mutations are only recorded, and are applied by `commit()`.
+pub struct Transaction {
+    /// The superpage the transaction began from.
+    base: fs::Superpage,
+    /// The mutations (in order).
+    log: Vec<Op>,
+    /// The paths read or written by the transaction.
+    touched: Vec<Vec<u8>>,
+}
+
+impl Transaction {
+    /// Begin a transaction from the current superpage.
+    pub fn begin(fs: &fs::State) -> future!(Transaction) {
+        fs::Superpage::load(fs).and_then(|superpage| {
+            let superpage = superpage.ok_or_else(|| err!(Corruption, "no superpage"))?;
+            debug!(fs, "beginning transaction"; "generation" => superpage.generation);
+
+            Ok(Transaction {
+                base: superpage,
+                log: Vec::new(),
+                touched: Vec::new(),
+            })
+        })
+    }
+
+    /// Get the generation the transaction began from.
+    pub fn generation(&self) -> u64 {
+        self.base.generation
+    }
+
+    /// Record a mutation.
+    fn push(&mut self, op: Op, paths: &[&[u8]]) {
+        self.touched.extend(paths.iter().map(|x| x.to_vec()));
+        self.log.push(op);
+    }
+
+    /// Look up a path as of the beginning of the transaction.
+    ///
+    /// The path is added to the paths of the transaction, so the transaction conflicts if it is
+    /// changed concurrently. Mutations recorded by the transaction are not visible.
+    pub fn lookup(&mut self, fs: &fs::State, path: &[u8]) -> future!(Option<fs::Entry>) {
+        self.touched.push(path.to_vec());
+
+        resolve(fs, self.base.root, path)
+    }
+
+    /// Create an empty file.
+    pub fn create_file(&mut self, path: &[u8]) {
+        self.push(Op::CreateFile(path.to_vec()), &[path]);
+    }
+
+    /// Create an empty directory.
+    pub fn create_directory(&mut self, path: &[u8]) {
+        self.push(Op::CreateDirectory(path.to_vec()), &[path]);
+    }
+
+    /// Write to a file at some offset.
+    pub fn write(&mut self, path: &[u8], offset: u64, buf: &[u8]) {
+        self.push(Op::Write(path.to_vec(), offset, buf.to_vec()), &[path]);
+    }
+
+    /// Set the length of a file.
+    pub fn truncate(&mut self, path: &[u8], len: u64) {
+        self.push(Op::Truncate(path.to_vec(), len), &[path]);
+    }
+
+    /// Remove an entry.
+    pub fn remove(&mut self, path: &[u8]) {
+        self.push(Op::Remove(path.to_vec()), &[path]);
+    }
+
+    /// Move an entry to another path.
+    pub fn rename(&mut self, from: &[u8], to: &[u8]) {
+        self.push(Op::Rename(from.to_vec(), to.to_vec()), &[from, to]);
+    }
+
+    /// Abort the transaction.
+    ///
+    /// Nothing has been written, so this merely discards the log.
+    pub fn abort(self, fs: &fs::State) {
+        debug!(fs, "aborting transaction"; "generation" => self.base.generation,
+               "mutations" => self.log.len());
+    }
+
+    /// Find a touched path, which was changed in some tree since the transaction began.
+    fn conflict(&self, fs: &fs::State, current: fs::Directory) -> future!(Option<Vec<u8>>) {
+        let base = self.base.root;
+
+        future::join_all(self.touched.iter().cloned().map(|path| {
+            resolve(fs, base, &path).join(resolve(fs, current, &path))
+                .map(move |(old, new)| if old == new { None } else { Some(path) })
+        }).collect::<Vec<_>>()).map(|paths| paths.into_iter().filter_map(|x| x).next())
+    }
+
+    /// Commit the transaction.
+    ///
+    /// This replays the mutations onto the current tree, and commits it. If another commit
+    /// changed a path touched by this transaction in the meantime, or a mutation fails, nothing
+    /// is committed, and an error is returned. The committed superpage is returned.
+    ///
+    /// The commit lock is held throughout, so this blocks while other commits run.
+    pub fn commit(self, fs: &fs::State) -> Result<fs::Superpage, Error> {
+        let mut last = fs.commits.lock().unwrap();
+        let current = fs::Superpage::load(fs).wait()?
+            .ok_or_else(|| err!(Corruption, "no superpage"))?;
+
+        // If nothing was committed in the meantime, there can be no conflicts.
+        if current.generation != self.base.generation {
+            if let Some(path) = self.conflict(fs, current.root).wait()? {
+                info!(fs, "transaction conflict"; "generation" => self.base.generation,
+                      "path" => String::from_utf8_lossy(&path).into_owned());
+
+                return Err(err!(Conflict, "transaction conflicts on '{}'",
+                                String::from_utf8_lossy(&path)));
+            }
+        }
+
+        // Replay the mutations onto the current superpage.
+        stream::iter_ok(self.log).fold(current, move |superpage, op| op.apply(fs, superpage))
+            .wait()?
+            .commit_locked(fs, &mut last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::page;
+    use disk::cluster;
+
+    /// Get the current superpage.
+    fn current(fs: &fs::State) -> fs::Superpage {
+        fs::Superpage::load(fs).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn touched_paths() {
+        let root = fs::Directory::from_raw(page::Pointer {
+            cluster: cluster::Pointer::new(1).unwrap(),
+            offset: None,
+            checksum: 0,
+        });
+        let mut transaction = Transaction {
+            base: fs::Superpage::new(root, b"").unwrap(),
+            log: Vec::new(),
+            touched: Vec::new(),
+        };
+        transaction.create_file(b"/a");
+        transaction.rename(b"/b", b"/c/d");
+        transaction.write(b"/a", 0, b"hello");
+
+        assert_eq!(transaction.log.len(), 3);
+        assert_eq!(transaction.touched, [b"/a".to_vec(), b"/b".to_vec(), b"/c/d".to_vec(),
+                                         b"/a".to_vec()]);
+    }
+
+    #[test]
+    fn replay() {
+        let fs = fs::memory(1024);
+        let mut first = Transaction::begin(&fs).wait().unwrap();
+        let mut second = Transaction::begin(&fs).wait().unwrap();
+
+        second.create_directory(b"/b");
+        second.commit(&fs).unwrap();
+
+        // The paths are disjoint, so the log is replayed onto the tree of the second transaction.
+        first.create_file(b"/a");
+        first.write(b"/a", 0, b"hello");
+        first.rename(b"/a", b"/b/a");
+        first.commit(&fs).unwrap();
+
+        let superpage = current(&fs);
+        assert_eq!(superpage.generation, 3);
+        let entry = resolve(&fs, superpage.root, b"/b/a").wait().unwrap().unwrap();
+        assert_eq!(entry.kind, fs::EntryKind::File);
+        let file = fs::File::open(&fs, entry.target).wait().unwrap();
+        assert_eq!(file.read(&fs, 0, 5).wait().unwrap(), b"hello");
+        assert_eq!(resolve(&fs, superpage.root, b"/a").wait().unwrap(), None);
+    }
+
+    #[test]
+    fn conflict_aborts() {
+        let fs = fs::memory(1024);
+        let mut setup = Transaction::begin(&fs).wait().unwrap();
+        setup.create_file(b"/a");
+        setup.create_file(b"/c");
+        setup.commit(&fs).unwrap();
+
+        let mut first = Transaction::begin(&fs).wait().unwrap();
+        let mut second = Transaction::begin(&fs).wait().unwrap();
+
+        second.write(b"/a", 0, b"second");
+        second.commit(&fs).unwrap();
+        let before = current(&fs);
+
+        first.remove(b"/c");
+        first.write(b"/a", 0, b"first");
+        assert_eq!(first.commit(&fs).unwrap_err().kind, ::error::Kind::Conflict);
+
+        // Nothing of the conflicting transaction was committed.
+        let after = current(&fs);
+        assert_eq!(after.generation, before.generation);
+        assert!(resolve(&fs, after.root, b"/c").wait().unwrap().is_some());
+        let entry = resolve(&fs, after.root, b"/a").wait().unwrap().unwrap();
+        let file = fs::File::open(&fs, entry.target).wait().unwrap();
+        assert_eq!(file.read(&fs, 0, 6).wait().unwrap(), b"second");
+    }
+
+    #[test]
+    fn remove_and_replace() {
+        let fs = fs::memory(1024);
+        let mut transaction = Transaction::begin(&fs).wait().unwrap();
+        transaction.create_file(b"/a");
+        transaction.create_file(b"/b");
+        transaction.create_directory(b"/d");
+        transaction.write(b"/b", 0, b"b");
+        // Renames replace existing files.
+        transaction.rename(b"/b", b"/a");
+        // Empty directories can be removed.
+        transaction.remove(b"/d");
+        transaction.commit(&fs).unwrap();
+
+        let superpage = current(&fs);
+        assert_eq!(resolve(&fs, superpage.root, b"/b").wait().unwrap(), None);
+        assert_eq!(resolve(&fs, superpage.root, b"/d").wait().unwrap(), None);
+        let entry = resolve(&fs, superpage.root, b"/a").wait().unwrap().unwrap();
+        let file = fs::File::open(&fs, entry.target).wait().unwrap();
+        assert_eq!(file.read(&fs, 0, 1).wait().unwrap(), b"b");
+    }
+}
@@ -0,0 +1,537 @@
+//! Change notification.
+//!
+//! Subscribers watch a path (and, if it is a directory, everything below it), and receive the
+//! changes as a stream of events.
+//!
+//! The events are not recorded as the changes are made. Instead, every commit publishes its root
+//! directory, and the events are found by diffing the root of the previous commit against the new
+//! one. Since the trees are copy-on-write, unmodified subtrees are shared, and are skipped by
+//! comparing pointers, so the cost of diffing is proportional to the size of the change.
+//!
+//! The trees of the latest commits are retained, which allows a subscriber to resume from the
+//! generation it last saw, and replay the changes it missed while it was disconnected. Snapshots
+//! can serve as resumption points as well, no matter their age.
+
+use futures::future::{self, Either};
+use futures::sync::mpsc;
+use futures::{stream, Future, Stream};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use {fs, Error};
+use alloc::compact;
+use fs::Object;
+use fs::link::follow;
+use fs::path::{deref, join, resolve};
+
+/// The number of commits, whose trees are retained.
+const HISTORY_LEN: usize = 64;
+
+/// A change to the filesystem.
+///
+/// Paths are absolute, and their components are separated by slashes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// An entry was created.
+    ///
+    /// If the entry is a directory, no events are generated for its content.
+    Created {
+        /// The path of the new entry.
+        path: Vec<u8>,
+    },
+    /// A file was modified.
+    Modified {
+        /// The path of the file.
+        path: Vec<u8>,
+        /// The range of bytes, which changed.
+        ///
+        /// If the length of the file changed, this extends to the end of the longer version.
+        range: Range<u64>,
+    },
+    /// An entry was removed.
+    Removed {
+        /// The path of the old entry.
+        path: Vec<u8>,
+    },
+    /// An entry was renamed within its directory.
+    ///
+    /// Moves to another directory are reported as removal and creation.
+    Renamed {
+        /// The old path.
+        from: Vec<u8>,
+        /// The new path.
+        to: Vec<u8>,
+    },
+}
+
+/// A committed tree.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Tree {
+    /// The root directory.
+    root: fs::Directory,
+    /// The link table, which the hard links of the tree refer to.
+    links: Option<fs::Directory>,
+}
+
+impl Tree {
+    /// Visit the tree.
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        let links = match self.links {
+            Some(table) => Either::A(table.visit(fs, visitor)),
+            None => Either::B(future::ok(())),
+        };
+
+        self.root.visit(fs, visitor).join(links).map(|_| ())
+    }
+
+    /// Relocate the tree.
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Tree) {
+        let links = match self.links {
+            Some(table) => Either::A(table.relocate(fs, relocations).map(Some)),
+            None => Either::B(future::ok(None)),
+        };
+
+        self.root.relocate(fs, relocations).join(links).map(|(root, links)| Tree {
+            root: root,
+            links: links,
+        })
+    }
+}
+
+/// The publisher of commits.
+#[derive(Default)]
+pub struct Hub {
+    /// The generations and trees of the latest commits (oldest first).
+    history: Mutex<VecDeque<(u64, Tree)>>,
+    /// The subscribers, which are notified of the generation of every commit.
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<u64>>>,
+}
+
+impl Hub {
+    /// Publish a commit.
+    ///
+    /// `root` is the committed tree, and `links` its link table.
+    pub fn publish(&self, generation: u64, root: fs::Directory, links: Option<fs::Directory>) {
+        {
+            let mut history = self.history.lock().unwrap();
+            history.push_back((generation, Tree {
+                root: root,
+                links: links,
+            }));
+            if history.len() > HISTORY_LEN {
+                history.pop_front();
+            }
+        }
+
+        // Notify the subscribers, and drop those, which are gone.
+        self.subscribers.lock().unwrap().retain(|x| x.unbounded_send(generation).is_ok());
+    }
+
+    /// Get the generation of the latest commit.
+    fn latest(&self) -> Option<u64> {
+        self.history.lock().unwrap().back().map(|&(generation, _)| generation)
+    }
+
+    /// Get the retained tree of some generation.
+    fn tree(&self, generation: u64) -> Option<Tree> {
+        self.history.lock().unwrap().iter()
+            .find(|&&(x, _)| x == generation)
+            .map(|&(_, tree)| tree)
+    }
+
+    /// Subscribe to the generations of the commits.
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<u64> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Visit the retained trees.
+    ///
+    /// The retained trees are not reachable from the superpage, so they must be visited
+    /// separately to keep them alive (see `fs::Object::visit()`).
+    pub fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        let trees: Vec<_> = self.history.lock().unwrap().iter().map(|&(_, tree)| tree).collect();
+
+        future::join_all(trees.into_iter().map(|tree| tree.visit(fs, visitor))
+            .collect::<Vec<_>>()).map(|_| ())
+    }
+
+    /// Update the retained trees after pages were moved by compaction.
+    ///
+    /// This blocks.
+    pub fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations)
+        -> Result<(), Error> {
+        let mut history = self.history.lock().unwrap();
+        for &mut (_, ref mut tree) in history.iter_mut() {
+            *tree = tree.relocate(fs, relocations).wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the tree of some generation.
+///
+/// The retained trees are searched first, and then the snapshots.
+fn tree(fs: &fs::State, generation: u64) -> future!(Tree) {
+    if let Some(tree) = fs.watch.tree(generation) {
+        return Either::A(future::ok(tree));
+    }
+
+    Either::B(fs::Superpage::load(fs).and_then(move |superpage| match superpage {
+        Some(superpage) => Either::A(superpage.snapshots(fs)),
+        None => Either::B(future::ok(Vec::new())),
+    }).and_then(move |snapshots| {
+        snapshots.into_iter()
+            .find(|x| x.generation == generation)
+            .map(|x| Tree {
+                root: x.root,
+                links: x.links,
+            })
+            .ok_or_else(|| err!(NotFound, "generation {} is no longer retained", generation))
+    }))
+}
+
+/// Pair the removed and created entries of a directory, which refer to the same object.
+///
+/// Such pairs are renames. The renames are returned together with the remaining removed and
+/// created entries.
+fn pair_renames(
+    path: &[u8],
+    mut removed: Vec<(Vec<u8>, fs::Entry)>,
+    created: Vec<(Vec<u8>, fs::Entry)>,
+) -> (Vec<Event>, Vec<(Vec<u8>, fs::Entry)>, Vec<(Vec<u8>, fs::Entry)>) {
+    let mut renames = Vec::new();
+    let mut unpaired = Vec::new();
+
+    for (name, entry) in created {
+        match removed.iter().position(|&(_, x)| x == entry) {
+            Some(n) => {
+                let (from, _) = removed.remove(n);
+                renames.push(Event::Renamed {
+                    from: join(path, &from),
+                    to: join(path, &name),
+                });
+            },
+            None => unpaired.push((name, entry)),
+        }
+    }
+
+    (renames, removed, unpaired)
+}
+
+/// Find the changes between two versions of the entry at some path.
+///
+/// `links` are the link tables of the old and the new tree.
+fn diff(
+    fs: &fs::State,
+    path: Vec<u8>,
+    old: Option<fs::Entry>,
+    new: Option<fs::Entry>,
+    links: (Option<fs::Directory>, Option<fs::Directory>),
+) -> fs::BoxFuture<Vec<Event>> {
+    let events = match (old, new) {
+        (None, None) => Vec::new(),
+        (None, Some(_)) => vec![Event::Created { path: path }],
+        (Some(_), None) => vec![Event::Removed { path: path }],
+        // Link pages never change, so hard links are compared by the inodes they link to. This
+        // also covers an entry becoming a hard link, or ceasing to be one.
+        (Some(old), Some(new))
+            if old.kind == fs::EntryKind::Link || new.kind == fs::EntryKind::Link => {
+            return Box::new(follow(fs, links.0, old).join(follow(fs, links.1, new))
+                .and_then(move |(old, new)| diff(fs, path, Some(old), Some(new), links)));
+        },
+        // The object wasn't touched, and neither was anything below it.
+        (Some(old), Some(new)) if old == new => Vec::new(),
+        (Some(old), Some(new)) => match (old.kind, new.kind) {
+            (fs::EntryKind::Directory, fs::EntryKind::Directory) => {
+                let (old, new) = (fs::Directory::from_raw(old.target),
+                                  fs::Directory::from_raw(new.target));
+                return Box::new(diff_directories(fs, path, old, new, links));
+            },
+            (fs::EntryKind::Inode, fs::EntryKind::Inode) => {
+                // Compare the objects behind the inodes. Changes to the metadata alone are not
+                // reported.
+                return Box::new(deref(fs, old).join(deref(fs, new)).and_then(move |(old, new)| {
+                    diff(fs, path, Some(old), Some(new), links)
+                }));
+            },
+            (fs::EntryKind::File, fs::EntryKind::File) => {
+                return Box::new(fs::File::open(fs, old.target).join(fs::File::open(fs, new.target))
+                    .and_then(move |(old, new)| new.changed_range(fs, &old))
+                    .map(move |range| range.into_iter().map(|range| Event::Modified {
+                        path: path.clone(),
+                        range: range,
+                    }).collect()));
+            },
+            // The entry was replaced by another kind of object.
+            _ => vec![Event::Removed { path: path.clone() }, Event::Created { path: path }],
+        },
+    };
+
+    Box::new(future::ok(events))
+}
+
+/// Find the changes between two versions of a directory.
+///
+/// `links` are the link tables of the old and the new tree.
+fn diff_directories(
+    fs: &fs::State,
+    path: Vec<u8>,
+    old: fs::Directory,
+    new: fs::Directory,
+    links: (Option<fs::Directory>, Option<fs::Directory>),
+) -> future!(Vec<Event>) {
+    old.list(fs).join(new.list(fs)).and_then(move |(old, new)| {
+        // Merge the sorted lists of entries.
+        let mut old = old.into_iter().peekable();
+        let mut new = new.into_iter().peekable();
+        let mut removed = Vec::new();
+        let mut created = Vec::new();
+        let mut changed = Vec::new();
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) => a.0.cmp(&b.0),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+
+            match order {
+                Ordering::Less => removed.push(old.next().unwrap()),
+                Ordering::Greater => created.push(new.next().unwrap()),
+                Ordering::Equal => {
+                    let (name, a) = old.next().unwrap();
+                    let (_, b) = new.next().unwrap();
+                    changed.push((name, a, b));
+                },
+            }
+        }
+
+        let (renames, removed, created) = pair_renames(&path, removed, created);
+        let removed = removed.into_iter().map(|(name, entry)| (name, Some(entry), None));
+        let created = created.into_iter().map(|(name, entry)| (name, None, Some(entry)));
+        let changed = changed.into_iter().map(|(name, a, b)| (name, Some(a), Some(b)));
+
+        future::join_all(removed.chain(created).chain(changed).map(|(name, a, b)| {
+            diff(fs, join(&path, &name), a, b, links)
+        }).collect::<Vec<_>>()).map(move |events| {
+            renames.into_iter().chain(events.into_iter().flat_map(|x| x)).collect()
+        })
+    })
+}
+
+/// Find the changes to a path between two generations.
+fn changes(fs: &fs::State, path: Vec<u8>, from: u64, to: u64) -> future!(Vec<Event>) {
+    tree(fs, from).join(tree(fs, to)).and_then(move |(old, new)| {
+        resolve(fs, old.root, &path).join(resolve(fs, new.root, &path))
+            .and_then(move |(old_entry, new_entry)| {
+                diff(fs, path, old_entry, new_entry, (old.links, new.links))
+            })
+    })
+}
+
+/// Watch a path for changes.
+///
+/// This returns a stream of the changes to `path` (and everything below it) made by every commit
+/// from now on. If `since` is given, the changes made after generation `since` are replayed first.
+/// This requires that the tree of that generation is either retained or snapshotted.
+///
+/// If nothing has been committed since the filesystem was opened, and `since` is not given, the
+/// first commit only serves as the base for the following ones.
+pub fn watch<'a>(
+    fs: &'a fs::State,
+    path: &[u8],
+    since: Option<u64>,
+) -> Box<Stream<Item = Event, Error = Error> + 'a> {
+    let path = path.to_vec();
+    // Subscribe before reading the latest generation, so no commit is missed. A commit may be
+    // delivered twice, but older generations are skipped anyway.
+    let commits = fs.watch.subscribe().map_err(|()| err!(Implementation, "publisher dropped"));
+    let latest = fs.watch.latest();
+    let mut last = since.or(latest);
+
+    Box::new(stream::iter_ok(latest).chain(commits).and_then(move |generation| {
+        match mem::replace(&mut last, Some(generation)) {
+            Some(from) if from < generation => {
+                Either::A(changes(fs, path.clone(), from, generation))
+            },
+            Some(from) => {
+                // Keep the newer generation as the base.
+                last = Some(from);
+                Either::B(future::ok(Vec::new()))
+            },
+            None => Either::B(future::ok(Vec::new())),
+        }
+    }).map(stream::iter_ok).flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::page;
+    use disk::cluster;
+    use fs::path::{create, update};
+
+    fn entry(cluster: u64) -> fs::Entry {
+        fs::Entry {
+            kind: fs::EntryKind::File,
+            target: page::Pointer {
+                cluster: cluster::Pointer::new(cluster).unwrap(),
+                offset: None,
+                checksum: 0,
+            },
+        }
+    }
+
+    /// Commit a new, empty directory at some path.
+    fn mkdir(fs: &fs::State, path: &[u8]) -> fs::Superpage {
+        let superpage = fs::Superpage::load(fs).wait().unwrap().unwrap();
+        let dir = Box::new(fs::Directory::create(fs).map(|dir| fs::Entry {
+            kind: fs::EntryKind::Directory,
+            target: dir.root(),
+        }));
+        let root = update(fs, superpage.root, path, create(path.to_vec(), dir)).wait().unwrap();
+
+        fs::Superpage {
+            root: root,
+            .. superpage
+        }.commit(fs).wait().unwrap()
+    }
+
+    /// The event of creating an entry.
+    fn created(path: &[u8]) -> Event {
+        Event::Created { path: path.to_vec() }
+    }
+
+    #[test]
+    fn renames() {
+        let removed = vec![(b"a".to_vec(), entry(1)), (b"b".to_vec(), entry(2))];
+        let created = vec![(b"c".to_vec(), entry(2)), (b"d".to_vec(), entry(3))];
+        let (renames, removed, created) = pair_renames(b"/x", removed, created);
+
+        assert_eq!(renames, [Event::Renamed {
+            from: b"/x/b".to_vec(),
+            to: b"/x/c".to_vec(),
+        }]);
+        assert_eq!(removed, [(b"a".to_vec(), entry(1))]);
+        assert_eq!(created, [(b"d".to_vec(), entry(3))]);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let hub = Hub::default();
+        for generation in 0..HISTORY_LEN as u64 + 10 {
+            hub.publish(generation, fs::Directory::from_raw(entry(generation + 1).target), None);
+        }
+
+        assert_eq!(hub.latest(), Some(HISTORY_LEN as u64 + 9));
+        assert!(hub.tree(9).is_none());
+        assert_eq!(hub.tree(10).map(|x| x.root), Some(fs::Directory::from_raw(entry(11).target)));
+    }
+
+    #[test]
+    fn hard_links() {
+        let fs = fs::memory(1024);
+        let superpage = fs::Superpage::load(&fs).wait().unwrap().unwrap();
+        let file = fs::File::create(&fs).wait().unwrap();
+        let inode = fs::Inode::create(&fs, fs::Entry {
+            kind: fs::EntryKind::File,
+            target: file.root(),
+        }, 0o644, 0, 0).wait().unwrap();
+        let entry = Box::new(future::ok(fs::Entry {
+            kind: fs::EntryKind::Inode,
+            target: inode.root(),
+        }));
+        let root = update(&fs, superpage.root, b"/a", create(b"/a".to_vec(), entry))
+            .wait().unwrap();
+        let linked = fs::Superpage {
+            root: root,
+            .. superpage
+        }.link(&fs, b"/a", b"/b").wait().unwrap().commit(&fs).wait().unwrap();
+
+        // Modify the file through one link.
+        let state = &fs;
+        let modified = linked.modify_file(&fs, b"/a", move |file| {
+            Box::new(file.write(state, 0, b"hello"))
+        }).wait().unwrap().commit(&fs).wait().unwrap();
+
+        // The entries of the links are unchanged, but the modification is visible through both.
+        let from = linked.generation;
+        for path in &[b"/a", b"/b"] {
+            let events = changes(&fs, path.to_vec(), from, modified.generation).wait().unwrap();
+            assert_eq!(events, [Event::Modified {
+                path: path.to_vec(),
+                range: 0..5,
+            }]);
+        }
+    }
+
+    #[test]
+    fn commits_after_subscribing() {
+        let fs = fs::memory(1024);
+        // The latest generation serves as the base, so only the later commits are reported.
+        mkdir(&fs, b"/a");
+        let events = watch(&fs, b"/", None);
+        mkdir(&fs, b"/b");
+        mkdir(&fs, b"/c");
+
+        assert_eq!(events.take(2).collect().wait().unwrap(), [created(b"/b"), created(b"/c")]);
+    }
+
+    #[test]
+    fn duplicate_generations() {
+        let fs = fs::memory(1024);
+        let base = fs::Superpage::load(&fs).wait().unwrap().unwrap().generation;
+        let a = mkdir(&fs, b"/a");
+        let events = watch(&fs, b"/", Some(base));
+        // A commit racing the subscription is seen both as the latest generation and as a
+        // notification. Older generations may be delivered again as well.
+        fs.publish(a.generation, a.root, a.links);
+        fs.publish(base, a.root, a.links);
+        mkdir(&fs, b"/b");
+
+        assert_eq!(events.take(2).collect().wait().unwrap(), [created(b"/a"), created(b"/b")]);
+    }
+
+    #[test]
+    fn resume() {
+        let fs = fs::memory(1024);
+        let base = fs::Superpage::load(&fs).wait().unwrap().unwrap().generation;
+        mkdir(&fs, b"/a");
+        let b = mkdir(&fs, b"/b");
+        mkdir(&fs, b"/c");
+
+        // The missed commits are replayed as a single change, followed by the new commits.
+        let events = watch(&fs, b"/", Some(base));
+        mkdir(&fs, b"/d");
+        assert_eq!(events.take(4).collect().wait().unwrap(),
+                   [created(b"/a"), created(b"/b"), created(b"/c"), created(b"/d")]);
+
+        // Only the changes to the watched path are reported.
+        let events = watch(&fs, b"/c", Some(b.generation));
+        mkdir(&fs, b"/e");
+        mkdir(&fs, b"/c/f");
+        assert_eq!(events.take(2).collect().wait().unwrap(), [created(b"/c"), created(b"/c/f")]);
+    }
+
+    #[test]
+    fn expired_generations() {
+        let fs = fs::memory(4096);
+        let base = fs::Superpage::load(&fs).wait().unwrap().unwrap().generation;
+        for n in 0..HISTORY_LEN {
+            mkdir(&fs, format!("/{}", n).as_bytes());
+        }
+
+        // The tree of the base generation has just fallen out of the history.
+        let err = watch(&fs, b"/", Some(base)).take(1).collect().wait().unwrap_err();
+        assert_eq!(err.kind, ::error::Kind::NotFound);
+        let events = watch(&fs, b"/", Some(base + 1)).take(1).collect().wait().unwrap();
+        assert_eq!(events.len(), 1);
+    }
+}
@@ -0,0 +1,576 @@
+//! Extended attributes.
+//!
+//! The extended attributes of an object are kept in an attribute table, which is an array (see
+//! `fs::array`) of attribute blocks. Every block is a page holding some of the attributes, and the
+//! attributes are sorted by name across the blocks, which are filled in order, so the table ends
+//! at the first null block. The table has a fixed length of `TABLE_LEN` blocks, and since null
+//! blocks take no space, it is referred to by the root of its array alone.
+//!
+//! Small values are stored inline in the block, while larger values are stored in arrays of data
+//! pages of their own, which the block refers to. Like every other object, the table is
+//! copy-on-write: Changing an attribute writes a new table.
+//!
+//! The attributes of a file are part of its header, so they're versioned by the revision history.
+//! Other objects keep their attributes in their inode. If the attributes fit in a single block in
+//! the inline area of the inode (see `fs::inline`), they are stored there instead of in a table.
+//!
+//! # Block format
+//!
+//! The block is a sequence of attributes, each consisting of:
+//!
+//! 1. Byte 0: The length of the name, $n$ (nonzero).
+//! 2. Byte 1: The storage of the value (0 for inline, 1 for a page array).
+//! 3. Byte 2-4: The length of the stored value, $v$ (little-endian).
+//! 4. $n$ bytes: The name.
+//! 5. $v$ bytes: The value, or the root pointer of the page array holding it, followed by the
+//!    length of the value (4 bytes, little-endian).
+//!
+//! The sequence ends at a zero byte in place of the name length, or at the end of the page (or
+//! inline area).
+
+use futures::future::{self, Either, Loop};
+use futures::{stream, Future, Stream};
+use std::cmp;
+
+use {disk, fs, little_endian, Error};
+use alloc::{compact, page};
+use fs::Object;
+
+/// The maximal length of an attribute name in bytes.
+pub const MAX_NAME_LEN: usize = 255;
+/// The maximal length of a value, which is stored inline.
+pub const MAX_INLINE_LEN: usize = 64;
+/// The maximal length of a value in bytes.
+pub const MAX_VALUE_LEN: usize = 1 << 16;
+/// The number of blocks in an attribute table.
+///
+/// This makes the array two levels deep. Every attribute fits in a block, so a table holds at
+/// least this many attributes.
+pub const TABLE_LEN: u64 = fs::array::POINTERS_IN_NODE * fs::array::POINTERS_IN_NODE;
+/// The size of the header of an attribute.
+const ATTR_HEADER_SIZE: usize = 4;
+/// The size of the stored value of an attribute held in a page array.
+const PAGES_VALUE_SIZE: usize = page::POINTER_SIZE + 4;
+/// The size of a page of a value.
+const PAGE_SIZE: usize = disk::SECTOR_SIZE;
+
+/// The storage of an attribute value.
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    /// The value is stored inline.
+    Inline(Vec<u8>),
+    /// The value is stored in the page array with this root, and is this many bytes long.
+    Pages(page::Pointer, u32),
+}
+
+impl Value {
+    /// Get the page array holding a value.
+    fn pages(root: page::Pointer, len: u32) -> fs::Array<fs::Data> {
+        let pages = (len as u64 + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+
+        fs::Array::from_raw(Some(root), pages)
+    }
+
+    /// Write a value to a page array.
+    fn write(fs: &fs::State, value: Vec<u8>) -> future!(Value) {
+        let len = value.len() as u32;
+
+        stream::iter_ok(0..(value.len() + PAGE_SIZE - 1) / PAGE_SIZE)
+            .fold(fs::Array::new(), move |array: fs::Array<fs::Data>, n| {
+                let chunk = &value[n * PAGE_SIZE..cmp::min(value.len(), (n + 1) * PAGE_SIZE)];
+                let mut buf = [0; disk::SECTOR_SIZE];
+                buf[..chunk.len()].copy_from_slice(chunk);
+
+                fs.alloc(buf, "attribute value").and_then(move |ptr| array.push(fs, Some(ptr)))
+            }).and_then(move |array| {
+                array.root().map(|root| Value::Pages(root, len))
+                    .ok_or_else(|| err!(Implementation, "empty attribute value array"))
+            })
+    }
+
+    /// Read a value.
+    fn read(&self, fs: &fs::State) -> future!(Vec<u8>) {
+        match *self {
+            Value::Inline(ref value) => Either::A(future::ok(value.clone())),
+            Value::Pages(root, len) => {
+                let array = Value::pages(root, len);
+
+                Either::B(array.collect(fs, 0..array.len()).and_then(move |ptrs| {
+                    future::join_all(ptrs.into_iter().map(|ptr| match ptr {
+                        Some(ptr) => Either::A(fs.read(ptr)),
+                        None => Either::B(future::ok([0; disk::SECTOR_SIZE])),
+                    }).collect::<Vec<_>>())
+                }).map(move |pages| {
+                    let mut value: Vec<u8> = pages.iter().flat_map(|x| x.iter().cloned()).collect();
+                    value.truncate(len as usize);
+                    value
+                }))
+            },
+        }
+    }
+
+    /// The size of the value as stored in a block.
+    fn stored_len(&self) -> usize {
+        match *self {
+            Value::Inline(ref value) => value.len(),
+            Value::Pages(..) => PAGES_VALUE_SIZE,
+        }
+    }
+}
+
+/// Check that an attribute name is valid.
+fn check_name(name: &[u8]) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        Err(err!(InvalidInput, "invalid attribute name length {}", name.len()))
+    } else if name.contains(&0) {
+        Err(err!(InvalidInput, "attribute name contains null byte"))
+    } else {
+        Ok(())
+    }
+}
+
+/// An attribute block, as an element of the attribute table.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Block(page::Pointer);
+
+impl From<page::Pointer> for Block {
+    fn from(ptr: page::Pointer) -> Block {
+        Block(ptr)
+    }
+}
+
+impl From<Block> for page::Pointer {
+    fn from(block: Block) -> page::Pointer {
+        block.0
+    }
+}
+
+impl Block {
+    /// Read the attributes of the block.
+    fn read(&self, fs: &fs::State) -> future!(Xattrs) {
+        fs.read(self.0).and_then(|buf| Xattrs::decode(&buf))
+    }
+}
+
+impl fs::Object for Block {
+    fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        visitor.page(self.0);
+
+        self.read(fs).and_then(move |xattrs| xattrs.visit(fs, visitor))
+    }
+
+    fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations) -> future!(Block) {
+        let ptr = self.0;
+
+        self.read(fs).and_then(move |xattrs| {
+            xattrs.relocate(fs, relocations).and_then(move |relocated| {
+                if relocated == xattrs {
+                    // Follow the block, if it was moved.
+                    Either::A(future::ok(Block(relocations.get(ptr).unwrap_or(ptr))))
+                } else {
+                    Either::B(future::result(relocated.encode())
+                        .and_then(move |buf| fs.alloc(buf, "attribute block"))
+                        .map(Block))
+                }
+            })
+        })
+    }
+}
+
+/// Get the attribute table with some root.
+fn table(root: Option<page::Pointer>) -> fs::Array<Block> {
+    fs::Array::from_raw(root, TABLE_LEN)
+}
+
+/// The extended attributes of an object.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Xattrs {
+    /// The attributes, sorted by name.
+    attrs: Vec<(Vec<u8>, Value)>,
+}
+
+impl Xattrs {
+    /// Parse the binary representation of an attribute block.
+    fn decode(buf: &[u8]) -> Result<Xattrs, Error> {
+        let mut attrs = Vec::new();
+        let mut window = &buf[..];
+
+        while window.len() >= ATTR_HEADER_SIZE && window[0] != 0 {
+            let name_len = window[0] as usize;
+            let value_len = little_endian::read::<u16>(&window[2..]) as usize;
+            if ATTR_HEADER_SIZE + name_len + value_len > window.len() {
+                return Err(err!(Corruption, "attribute exceeds the attribute block"));
+            }
+
+            let name = window[ATTR_HEADER_SIZE..][..name_len].to_vec();
+            let value = &window[ATTR_HEADER_SIZE + name_len..][..value_len];
+            let value = match window[1] {
+                0 => Value::Inline(value.to_vec()),
+                1 if value_len == PAGES_VALUE_SIZE => {
+                    let root = little_endian::read::<Option<page::Pointer>>(value)
+                        .ok_or_else(|| err!(Corruption, "null attribute value pointer"))?;
+
+                    Value::Pages(root, little_endian::read(&value[page::POINTER_SIZE..]))
+                },
+                x => return Err(err!(Corruption, "invalid attribute storage {:x}", x)),
+            };
+
+            attrs.push((name, value));
+            window = &window[ATTR_HEADER_SIZE + name_len + value_len..];
+        }
+
+        Ok(Xattrs {
+            attrs: attrs,
+        })
+    }
+
+    /// Encode the attributes into a sector-sized buffer.
+    ///
+    /// If the attributes don't fit in a block, an error is returned.
+    fn encode(&self) -> Result<disk::SectorBuf, Error> {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        self.encode_into(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Encode the attributes into a zeroed buffer.
+    ///
+    /// If the attributes don't fit in the buffer, an error is returned.
+    fn encode_into(&self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut pos = 0;
+
+        for &(ref name, ref value) in &self.attrs {
+            let (storage, value_len) = match *value {
+                Value::Inline(_) => (0, value.stored_len()),
+                Value::Pages(..) => (1, value.stored_len()),
+            };
+            if pos + ATTR_HEADER_SIZE + name.len() + value_len > buf.len() {
+                return Err(err!(Implementation, "extended attributes exceed the block"));
+            }
+
+            buf[pos] = name.len() as u8;
+            buf[pos + 1] = storage;
+            little_endian::write(&mut buf[pos + 2..], value_len as u16);
+            pos += ATTR_HEADER_SIZE;
+            buf[pos..][..name.len()].copy_from_slice(name);
+            pos += name.len();
+            match *value {
+                Value::Inline(ref value) => buf[pos..][..value.len()].copy_from_slice(value),
+                Value::Pages(root, len) => {
+                    little_endian::write(&mut buf[pos..], Some(root));
+                    little_endian::write(&mut buf[pos + page::POINTER_SIZE..], len);
+                },
+            }
+            pos += value_len;
+        }
+
+        Ok(())
+    }
+
+    /// Split the attributes into blocks.
+    ///
+    /// Every block is filled with as many attributes as fit in a page, in order.
+    fn blocks(&self) -> Vec<Xattrs> {
+        let mut blocks: Vec<Xattrs> = Vec::new();
+        let mut free = 0;
+
+        for attr in &self.attrs {
+            let size = ATTR_HEADER_SIZE + attr.0.len() + attr.1.stored_len();
+            if size > free {
+                // Start a new block.
+                blocks.push(Xattrs::default());
+                free = disk::SECTOR_SIZE;
+            }
+
+            blocks.last_mut().unwrap().attrs.push(attr.clone());
+            free -= size;
+        }
+
+        blocks
+    }
+
+    /// Check that the attributes fit in a table.
+    fn check_len(&self) -> Result<(), Error> {
+        if self.blocks().len() as u64 > TABLE_LEN {
+            Err(err!(OutOfSpace, "extended attributes exceed the attribute table"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Load the attributes from the inline area of an inode.
+    pub fn from_inline(inline: &fs::Inline) -> Result<Xattrs, Error> {
+        Xattrs::decode(inline.as_slice())
+    }
+
+    /// Store the attributes inline.
+    ///
+    /// The attributes are encoded into an inline area of `room` bytes (at most
+    /// `fs::inline::MAX_LEN`). If there are no attributes, or they don't fit, `None` is returned.
+    pub fn to_inline(&self, room: usize) -> Option<fs::Inline> {
+        let mut buf = [0; fs::inline::MAX_LEN];
+        let room = cmp::min(room, fs::inline::MAX_LEN);
+        if self.attrs.is_empty() || self.encode_into(&mut buf[..room]).is_err() {
+            return None;
+        }
+
+        fs::Inline::new(&buf[..room])
+    }
+
+    /// Load the attributes from an attribute table.
+    ///
+    /// If `ptr` is `None`, there are no attributes.
+    pub fn load(fs: &fs::State, ptr: Option<page::Pointer>) -> future!(Xattrs) {
+        let table = table(ptr);
+
+        // Read the blocks until the first null block.
+        future::loop_fn((Xattrs::default(), 0), move |(mut xattrs, index)| {
+            let block = if index < TABLE_LEN && ptr.is_some() {
+                Either::A(table.get(fs, index))
+            } else {
+                Either::B(future::ok(None))
+            };
+
+            block.and_then(move |block| match block {
+                Some(block) => Either::A(Block(block).read(fs).map(move |block| {
+                    xattrs.attrs.extend(block.attrs);
+                    Loop::Continue((xattrs, index + 1))
+                })),
+                None => Either::B(future::ok(Loop::Break(xattrs))),
+            })
+        })
+    }
+
+    /// Write the attributes to a new attribute table.
+    ///
+    /// If there are no attributes, nothing is written, and `None` is returned.
+    pub fn write(&self, fs: &fs::State) -> future!(Option<page::Pointer>) {
+        if self.attrs.is_empty() {
+            return Either::A(future::ok(None));
+        }
+
+        let blocks = self.blocks();
+        Either::B(future::result(self.check_len()).and_then(move |_| {
+            stream::iter_ok(blocks.into_iter().enumerate())
+                .fold(table(None), move |table, (n, block)| {
+                    future::result(block.encode())
+                        .and_then(move |buf| fs.alloc(buf, "attribute block"))
+                        .and_then(move |ptr| table.set(fs, n as u64, Some(ptr)))
+                })
+        }).map(|table| table.root()))
+    }
+
+    /// List the names of the attributes.
+    pub fn list(&self) -> Vec<Vec<u8>> {
+        self.attrs.iter().map(|&(ref name, _)| name.clone()).collect()
+    }
+
+    /// Get the value of an attribute.
+    ///
+    /// If there is no such attribute, `None` is returned.
+    pub fn get(&self, fs: &fs::State, name: &[u8]) -> future!(Option<Vec<u8>>) {
+        match self.attrs.binary_search_by(|x| x.0[..].cmp(name)) {
+            Ok(n) => Either::A(self.attrs[n].1.read(fs).map(Some)),
+            Err(_) => Either::B(future::ok(None)),
+        }
+    }
+
+    /// Set the value of an attribute.
+    ///
+    /// The new attributes are returned. If they don't fit in a table, an error is returned.
+    pub fn set(&self, fs: &fs::State, name: &[u8], value: &[u8]) -> future!(Xattrs) {
+        if let Err(err) = check_name(name) {
+            return Either::A(future::err(err));
+        } else if value.len() > MAX_VALUE_LEN {
+            return Either::A(future::err(err!(InvalidInput, "attribute value too long ({} bytes)",
+                                              value.len())));
+        }
+
+        let value = if value.len() <= MAX_INLINE_LEN {
+            Either::A(future::ok(Value::Inline(value.to_vec())))
+        } else {
+            Either::B(Value::write(fs, value.to_vec()))
+        };
+        let mut xattrs = self.clone();
+        let name = name.to_vec();
+
+        Either::B(value.and_then(move |value| {
+            match xattrs.attrs.binary_search_by(|x| x.0.cmp(&name)) {
+                Ok(n) => xattrs.attrs[n].1 = value,
+                Err(n) => xattrs.attrs.insert(n, (name, value)),
+            }
+
+            // Check that the attributes still fit.
+            xattrs.check_len().map(|_| xattrs)
+        }))
+    }
+
+    /// Visit the page arrays holding values.
+    ///
+    /// See `fs::Object::visit()`.
+    pub fn visit(&self, fs: &fs::State, visitor: &fs::Visitor) -> future!(()) {
+        future::join_all(self.attrs.iter().filter_map(|&(_, ref value)| match *value {
+            Value::Pages(root, len) => Some(Value::pages(root, len).visit(fs, visitor)),
+            Value::Inline(_) => None,
+        }).collect::<Vec<_>>()).map(|_| ())
+    }
+
+    /// Relocate the page arrays holding values.
+    ///
+    /// The updated attributes are returned.
+    pub fn relocate(&self, fs: &fs::State, relocations: &compact::Relocations)
+        -> future!(Xattrs) {
+        future::join_all(self.attrs.iter().cloned().map(|(name, value)| match value {
+            Value::Pages(root, len) => Either::A(Value::pages(root, len)
+                .relocate(fs, relocations)
+                .map(move |array| (name, Value::Pages(array.root().unwrap_or(root), len)))),
+            Value::Inline(value) => Either::B(future::ok((name, Value::Inline(value)))),
+        }).collect::<Vec<_>>()).map(|attrs| Xattrs {
+            attrs: attrs,
+        })
+    }
+
+    /// Remove an attribute.
+    ///
+    /// The new attributes are returned. If there is no such attribute, an error is returned.
+    pub fn remove(&self, name: &[u8]) -> Result<Xattrs, Error> {
+        let mut xattrs = self.clone();
+
+        match xattrs.attrs.binary_search_by(|x| x.0[..].cmp(name)) {
+            Ok(n) => {
+                xattrs.attrs.remove(n);
+                Ok(xattrs)
+            },
+            Err(_) => Err(err!(NotFound, "no attribute '{}'", String::from_utf8_lossy(name))),
+        }
+    }
+}
+
+/// Visit an attribute table.
+///
+/// See `fs::Object::visit()`.
+pub fn visit(
+    fs: &fs::State,
+    ptr: Option<page::Pointer>,
+    visitor: &fs::Visitor,
+) -> future!(()) {
+    table(ptr).visit(fs, visitor)
+}
+
+/// Relocate an attribute table.
+///
+/// The pointer to the updated table is returned.
+pub fn relocate(
+    fs: &fs::State,
+    ptr: Option<page::Pointer>,
+    relocations: &compact::Relocations,
+) -> future!(Option<page::Pointer>) {
+    table(ptr).relocate(fs, relocations).map(|table| table.root())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::cluster;
+
+    fn xattrs() -> Xattrs {
+        Xattrs {
+            attrs: vec![
+                (b"user.label".to_vec(), Value::Inline(b"blue".to_vec())),
+                (b"user.provenance".to_vec(), Value::Pages(page::Pointer {
+                    cluster: cluster::Pointer::new(7).unwrap(),
+                    offset: Some(1),
+                    checksum: 0xABC,
+                }, 1000)),
+                (vec![b'x'; MAX_NAME_LEN], Value::Inline(vec![0; MAX_INLINE_LEN])),
+            ],
+        }
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let xattrs = xattrs();
+        assert_eq!(Xattrs::decode(&xattrs.encode().unwrap()).unwrap(), xattrs);
+        assert_eq!(Xattrs::decode(&[0; disk::SECTOR_SIZE]).unwrap(), Xattrs::default());
+    }
+
+    #[test]
+    fn inline() {
+        let xattrs = xattrs();
+        let inline = xattrs.to_inline(fs::inline::MAX_LEN).unwrap();
+        assert_eq!(Xattrs::from_inline(&inline).unwrap(), xattrs);
+        assert_eq!(Xattrs::default().to_inline(fs::inline::MAX_LEN), None);
+        // The inline area may be smaller, if it is shared.
+        assert_eq!(inline.as_slice().len(), fs::inline::MAX_LEN);
+        assert_eq!(xattrs.to_inline(100), None);
+
+        // This fits in a page, but not inline.
+        let mut large = xattrs;
+        large.attrs.push((b"user.z".to_vec(), Value::Inline(vec![1; 60])));
+        assert!(large.encode().is_ok());
+        assert_eq!(large.to_inline(fs::inline::MAX_LEN), None);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut xattrs = xattrs();
+        xattrs.attrs.push((vec![b'y'; MAX_NAME_LEN], Value::Inline(Vec::new())));
+        assert!(xattrs.encode().is_err());
+    }
+
+    #[test]
+    fn corruption() {
+        let mut buf = xattrs().encode().unwrap();
+        buf[1] = 7;
+        assert_eq!(Xattrs::decode(&buf).unwrap_err().kind, ::error::Kind::Corruption);
+    }
+
+    #[test]
+    fn remove() {
+        let xattrs = xattrs();
+        let removed = xattrs.remove(b"user.label").unwrap();
+
+        assert_eq!(removed.list(), [b"user.provenance".to_vec(), vec![b'x'; MAX_NAME_LEN]]);
+        assert_eq!(removed.remove(b"user.label").unwrap_err().kind, ::error::Kind::NotFound);
+        assert!(check_name(b"").is_err());
+        assert!(check_name(b"a\0b").is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let mut xattrs = xattrs();
+        xattrs.attrs.push((vec![b'y'; MAX_NAME_LEN], Value::Inline(Vec::new())));
+        let blocks = xattrs.blocks();
+        assert_eq!(blocks.len(), 2);
+
+        // Every block fits in a page, and the blocks hold the attributes in order.
+        let mut attrs = Vec::new();
+        for block in blocks {
+            attrs.extend(Xattrs::decode(&block.encode().unwrap()).unwrap().attrs);
+        }
+        assert_eq!(attrs, xattrs.attrs);
+    }
+
+    #[test]
+    fn table() {
+        let fs = fs::memory(1024);
+        let large: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        let mut xattrs = Xattrs::default();
+        for n in 0..40 {
+            let name = format!("user.{:0200}", n).into_bytes();
+            xattrs = xattrs.set(&fs, &name, &[n as u8; MAX_INLINE_LEN]).wait().unwrap();
+        }
+        xattrs = xattrs.set(&fs, b"user.large", &large).wait().unwrap();
+        assert!(xattrs.blocks().len() > 1);
+
+        let ptr = xattrs.write(&fs).wait().unwrap();
+        let loaded = Xattrs::load(&fs, ptr).wait().unwrap();
+        assert_eq!(loaded, xattrs);
+        assert_eq!(loaded.get(&fs, b"user.large").wait().unwrap(), Some(large));
+        let name = format!("user.{:0200}", 7).into_bytes();
+        assert_eq!(loaded.get(&fs, &name).wait().unwrap(), Some(vec![7; MAX_INLINE_LEN]));
+        assert_eq!(loaded.get(&fs, b"user.missing").wait().unwrap(), None);
+        assert_eq!(Xattrs::load(&fs, None).wait().unwrap(), Xattrs::default());
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! This is the official implementation of the TFS specification. It implements the specification
 //! in its full form, and is accessible as a library.
+//!
+//! The entry point is `Filesystem`, which is created on or opened from a `Disk`.
 
 #![feature(conservative_impl_trait, i128_type, try_from)]
 
@@ -30,5 +32,19 @@ mod macros;
 mod alloc;
 mod disk;
 mod fs;
+mod metrics;
 
-pub use error::Error;
+// The public API. Everything else is private, so these items make up the semver surface of the
+// crate.
+pub use alloc::compact::Options as CompactionOptions;
+pub use alloc::compress::{Compressor, Registry as CompressionRegistry};
+pub use alloc::gc::Options as GcOptions;
+pub use alloc::state_block::{CompressionAlgorithm, DedupVerification};
+pub use disk::{Disk, Sector, SectorBuf, SECTOR_SIZE};
+pub use disk::header::{ChecksumAlgorithm, Vdev};
+pub use error::{Context as ErrorContext, Error, Kind as ErrorKind};
+pub use fs::fsck;
+pub use fs::{Atime, DirEntry, FileType, Filesystem, Handle, ImageInfo, Info, Metadata,
+             MkfsOptions, OpenOptions, Retention, RevisionInfo, SnapshotInfo, Stat, Transaction,
+             Usage, WatchEvent};
+pub use metrics::{Counter, Discard, Distribution, Histogram, MemorySink, Metrics, Sink};
@@ -0,0 +1,311 @@
+//! Metrics.
+//!
+//! The I/O stack reports structured metrics (counters and histograms) to a pluggable sink, such
+//! that the health of the system can be tracked, e.g. on a dashboard. By default, the metrics are
+//! discarded.
+//!
+//! Durations are recorded in microseconds.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A counter.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Counter {
+    /// Sectors read from the disk.
+    ///
+    /// `vdev` is the index in the vdev stack of the vdev issuing the read, or `None` if it was
+    /// issued directly by the layer above the vdevs.
+    DiskReads {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// Sectors written to the disk.
+    ///
+    /// `vdev` is the index of the issuing vdev (see `DiskReads`).
+    DiskWrites {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// Sectors trimmed on the disk.
+    ///
+    /// `vdev` is the index of the issuing vdev (see `DiskReads`).
+    DiskTrims {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// Sector reads served by the cache.
+    CacheHits,
+    /// Sector reads, which had to go to the disk.
+    CacheMisses,
+    /// Pages allocated (excluding deduplicated pages).
+    PagesAllocated,
+    /// Clusters taken from the freelist.
+    ClustersAllocated,
+    /// Clusters freed by the garbage collector.
+    ClustersFreed,
+    /// Allocations served by an existing duplicate page.
+    DedupHits,
+}
+
+/// A histogram.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Histogram {
+    /// The latency of sector reads in microseconds.
+    ///
+    /// `vdev` is the index of the issuing vdev (see `Counter::DiskReads`).
+    DiskReadLatency {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// The latency of sector writes in microseconds.
+    DiskWriteLatency {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// The latency of sector trims in microseconds.
+    DiskTrimLatency {
+        /// The issuing vdev.
+        vdev: Option<usize>,
+    },
+    /// The compression ratio of clusters in per mille.
+    ///
+    /// This is the size of the compressed stream relative to the uncompressed pages, recorded
+    /// every time a compressed cluster is written.
+    CompressionRatio,
+    /// The duration of garbage collection cycles in microseconds.
+    GcCycleDuration,
+}
+
+/// A metrics sink.
+///
+/// This receives the metrics reported by the system.
+pub trait Sink: Send + Sync {
+    /// Add `n` to a counter.
+    fn increment(&self, counter: Counter, n: u64);
+    /// Record a value in a histogram.
+    fn record(&self, histogram: Histogram, value: u64);
+}
+
+/// A sink discarding every metric.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Discard;
+
+impl Sink for Discard {
+    fn increment(&self, _: Counter, _: u64) {}
+    fn record(&self, _: Histogram, _: u64) {}
+}
+
+/// A handle to the metrics sink in use.
+#[derive(Clone)]
+pub struct Metrics {
+    /// The sink.
+    sink: Arc<Sink>,
+}
+
+impl Metrics {
+    /// Report metrics to some sink.
+    pub fn new(sink: Arc<Sink>) -> Metrics {
+        Metrics {
+            sink: sink,
+        }
+    }
+
+    /// Increment a counter by one.
+    pub fn increment(&self, counter: Counter) {
+        self.sink.increment(counter, 1);
+    }
+
+    /// Add `n` to a counter.
+    pub fn add(&self, counter: Counter, n: u64) {
+        self.sink.increment(counter, n);
+    }
+
+    /// Record a value in a histogram.
+    pub fn record(&self, histogram: Histogram, value: u64) {
+        self.sink.record(histogram, value);
+    }
+
+    /// Record the time elapsed since `start` (in microseconds) in a histogram.
+    pub fn time(&self, histogram: Histogram, start: Instant) {
+        let elapsed = start.elapsed();
+        self.record(histogram, elapsed.as_secs() * 1_000_000
+                               + elapsed.subsec_nanos() as u64 / 1000);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new(Arc::new(Discard))
+    }
+}
+
+/// The distribution of the values recorded in a histogram.
+///
+/// The values are grouped in power-of-two buckets: Bucket `i` holds values of `i` significant
+/// bits, i.e. bucket 0 holds 0, and bucket `i > 0` holds `2^(i-1)..2^i`.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Distribution {
+    /// The number of values.
+    pub count: u64,
+    /// The sum of the values.
+    pub sum: u64,
+    /// The minimal value.
+    pub min: u64,
+    /// The maximal value.
+    pub max: u64,
+    /// The number of values in every bucket.
+    pub buckets: Vec<u64>,
+}
+
+impl Distribution {
+    /// Add a value.
+    fn add(&mut self, value: u64) {
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+
+        let bucket = 64 - value.leading_zeros() as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// Get the mean value.
+    ///
+    /// If no values were recorded, `None` is returned.
+    pub fn mean(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count)
+        }
+    }
+
+    /// Estimate a percentile.
+    ///
+    /// This gives an upper bound of the `percent` percentile, namely the upper end of the bucket
+    /// containing it. If no values were recorded, `None` is returned.
+    pub fn percentile(&self, percent: u32) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        // The rank of the percentile, rounded up.
+        let rank = (self.count * percent as u64 + 99) / 100;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && seen > 0 {
+                // Values can't exceed the maximum, so we use it to tighten the bound.
+                return Some(if bucket >= 64 {
+                    self.max
+                } else {
+                    ((1u64 << bucket) - 1).min(self.max)
+                });
+            }
+        }
+
+        Some(self.max)
+    }
+}
+
+/// A sink keeping the metrics in memory.
+///
+/// This is mainly useful for testing, and for exporting the metrics on demand.
+#[derive(Default)]
+pub struct MemorySink {
+    /// The counters.
+    counters: Mutex<HashMap<Counter, u64>>,
+    /// The histograms.
+    histograms: Mutex<HashMap<Histogram, Distribution>>,
+}
+
+impl MemorySink {
+    /// Get the value of a counter.
+    pub fn counter(&self, counter: Counter) -> u64 {
+        self.counters.lock().unwrap().get(&counter).cloned().unwrap_or(0)
+    }
+
+    /// Get the distribution of a histogram.
+    pub fn histogram(&self, histogram: Histogram) -> Distribution {
+        self.histograms.lock().unwrap().get(&histogram).cloned().unwrap_or_default()
+    }
+
+    /// Get the fraction of sector reads, which were served by the cache.
+    ///
+    /// If no sectors were read, `None` is returned.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let hits = self.counter(Counter::CacheHits);
+        let total = hits + self.counter(Counter::CacheMisses);
+
+        if total == 0 {
+            None
+        } else {
+            Some(hits as f64 / total as f64)
+        }
+    }
+}
+
+impl Sink for MemorySink {
+    fn increment(&self, counter: Counter, n: u64) {
+        *self.counters.lock().unwrap().entry(counter).or_insert(0) += n;
+    }
+
+    fn record(&self, histogram: Histogram, value: u64) {
+        self.histograms.lock().unwrap().entry(histogram).or_insert_with(Distribution::default)
+            .add(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sink() {
+        let sink = Arc::new(MemorySink::default());
+        let metrics = Metrics::new(sink.clone());
+        assert_eq!(sink.cache_hit_rate(), None);
+
+        metrics.increment(Counter::CacheHits);
+        metrics.add(Counter::CacheHits, 2);
+        metrics.increment(Counter::CacheMisses);
+        metrics.increment(Counter::DiskReads { vdev: Some(0) });
+
+        assert_eq!(sink.counter(Counter::CacheHits), 3);
+        assert_eq!(sink.counter(Counter::DiskReads { vdev: Some(0) }), 1);
+        assert_eq!(sink.counter(Counter::DiskReads { vdev: None }), 0);
+        assert_eq!(sink.cache_hit_rate(), Some(0.75));
+
+        // The default handle discards everything.
+        Metrics::default().increment(Counter::CacheHits);
+        assert_eq!(sink.counter(Counter::CacheHits), 3);
+    }
+
+    #[test]
+    fn distribution() {
+        let sink = MemorySink::default();
+        assert_eq!(sink.histogram(Histogram::CompressionRatio).mean(), None);
+
+        for &value in &[0, 1, 3, 100, 900] {
+            sink.record(Histogram::CompressionRatio, value);
+        }
+
+        let dist = sink.histogram(Histogram::CompressionRatio);
+        assert_eq!(dist.count, 5);
+        assert_eq!((dist.min, dist.max), (0, 900));
+        assert_eq!(dist.mean(), Some(200));
+        assert_eq!(dist.buckets, [1, 1, 1, 0, 0, 0, 0, 1, 0, 0, 1]);
+        assert_eq!(dist.percentile(50), Some(3));
+        assert_eq!(dist.percentile(80), Some(127));
+        assert_eq!(dist.percentile(100), Some(900));
+    }
+}